@@ -7,6 +7,7 @@
 //! memfaultd systemd helper
 
 #include <stdbool.h>
+#include <stdint.h>
 #include <stdio.h>
 #include <stdlib.h>
 #include <systemd/sd-bus.h>
@@ -164,6 +165,57 @@ cleanup:
   return result;
 }
 
+/**
+ * @brief Get the main PID and the number of restarts of a service unit
+ *
+ * @param service_name Service name, e.g. collectd.service
+ * @param main_pid output variable for the main PID (0 when the service is not running)
+ * @param has_n_restarts output variable set to false when systemd does not report restarts
+ * (before systemd 235)
+ * @param n_restarts output variable for the number of automatic restarts of the service
+ * @return false Failed to get the main PID of the service
+ */
+bool memfaultd_get_systemd_service_info(const char *service_name, uint32_t *main_pid,
+                                        bool *has_n_restarts, uint32_t *n_restarts) {
+  static const char *const service_interface = "org.freedesktop.systemd1.Service";
+
+  bool result = true;
+  char *unit_path = NULL;
+  sd_bus_error error = SD_BUS_ERROR_NULL;
+
+  // Initialize connection to SystemD
+  sd_bus *bus;
+  if (sd_bus_default_system(&bus) < 0) {
+    fprintf(stderr, "memfaultd:: Failed to find systemd system bus\n");
+    return false;
+  }
+
+  if (sd_bus_path_encode("/org/freedesktop/systemd1/unit", service_name, &unit_path) < 0) {
+    fprintf(stderr, "memfaultd:: Failed to generate SystemD unit path\n");
+    result = false;
+    goto cleanup;
+  }
+
+  if (sd_bus_get_property_trivial(bus, systemd_service, unit_path, service_interface, "MainPID",
+                                  &error, 'u', main_pid) < 0) {
+    fprintf(stderr, "memfaultd:: Failed to get main PID of %s: %s\n", service_name, error.name);
+    result = false;
+    goto cleanup;
+  }
+
+  sd_bus_error_free(&error);
+  *has_n_restarts = sd_bus_get_property_trivial(bus, systemd_service, unit_path, service_interface,
+                                                "NRestarts", &error, 'u', n_restarts) >= 0;
+
+cleanup:
+  sd_bus_error_free(&error);
+  if (unit_path) {
+    free(unit_path);
+  }
+  sd_bus_unref(bus);
+  return result;
+}
+
 /**
  * @brief Checks if the current systemd state matches the requested state
  *
@@ -6,4 +6,10 @@ use libc::c_char;
 extern "C" {
     pub fn memfaultd_restart_systemd_service_if_running(service_name: *const c_char) -> bool;
     pub fn memfaultd_get_systemd_bus_state() -> *const c_char;
+    pub fn memfaultd_get_systemd_service_info(
+        service_name: *const c_char,
+        main_pid: *mut u32,
+        has_n_restarts: *mut bool,
+        n_restarts: *mut u32,
+    ) -> bool;
 }
@@ -17,3 +17,18 @@ pub unsafe fn memfaultd_restart_systemd_service_if_running(service_name: *const
 pub unsafe fn memfaultd_get_systemd_bus_state() -> *const c_char {
     null()
 }
+
+/// Get the main PID and the number of restarts of a service unit.
+/// # Safety
+pub unsafe fn memfaultd_get_systemd_service_info(
+    service_name: *const c_char,
+    _main_pid: *mut u32,
+    _has_n_restarts: *mut bool,
+    _n_restarts: *mut u32,
+) -> bool {
+    eprintln!(
+        "memfaultd_get_systemd_service_info is not implemented for this target (service {})",
+        std::ffi::CStr::from_ptr(service_name).to_string_lossy()
+    );
+    false
+}
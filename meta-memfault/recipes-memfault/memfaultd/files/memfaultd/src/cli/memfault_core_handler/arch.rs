@@ -1,7 +1,7 @@
 //
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
-use crate::cli::memfault_core_handler::elf;
+use crate::cli::memfault_core_handler::{elf, ElfPtrSize};
 use cfg_if::cfg_if;
 
 cfg_if! {
@@ -65,6 +65,84 @@ cfg_if! {
     }
 }
 
+/// Registers of a thread from which its stack can be unwound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnwindRegisters {
+    pub pc: ElfPtrSize,
+    pub sp: ElfPtrSize,
+    pub fp: ElfPtrSize,
+    /// Link register, on architectures which have one.
+    pub lr: Option<ElfPtrSize>,
+}
+
+// Register definitions for stack unwinding. The DWARF register numbers are the ones used by the
+// .eh_frame call frame information. FRAME_POINTER_LAYOUT holds the offsets (in words) from the
+// frame pointer of the caller's frame pointer and of the return address.
+cfg_if! {
+    if #[cfg(target_arch = "aarch64")] {
+        pub fn get_unwind_registers(regs: &ElfGRegSet) -> Option<UnwindRegisters> {
+            Some(UnwindRegisters {
+                pc: regs.pc,
+                sp: regs.sp,
+                fp: regs.regs[29],
+                lr: Some(regs.regs[30]),
+            })
+        }
+        pub const DWARF_SP: u16 = 31;
+        pub const DWARF_FP: u16 = 29;
+        pub const DWARF_RETURN_ADDRESS: u16 = 30;
+        pub const FRAME_POINTER_LAYOUT: (isize, isize) = (0, 1);
+    } else if #[cfg(target_arch = "x86_64")] {
+        pub fn get_unwind_registers(regs: &ElfGRegSet) -> Option<UnwindRegisters> {
+            Some(UnwindRegisters {
+                pc: regs.rip,
+                sp: regs.rsp,
+                fp: regs.rbp,
+                lr: None,
+            })
+        }
+        pub const DWARF_SP: u16 = 7;
+        pub const DWARF_FP: u16 = 6;
+        pub const DWARF_RETURN_ADDRESS: u16 = 16;
+        pub const FRAME_POINTER_LAYOUT: (isize, isize) = (0, 1);
+    } else if #[cfg(target_arch = "arm")] {
+        pub fn get_unwind_registers(regs: &ElfGRegSet) -> Option<UnwindRegisters> {
+            Some(UnwindRegisters {
+                pc: regs.arm_pc as ElfPtrSize,
+                sp: regs.arm_sp as ElfPtrSize,
+                fp: regs.arm_fp as ElfPtrSize,
+                lr: Some(regs.arm_lr as ElfPtrSize),
+            })
+        }
+        pub const DWARF_SP: u16 = 13;
+        pub const DWARF_FP: u16 = 11;
+        pub const DWARF_RETURN_ADDRESS: u16 = 14;
+        // GCC's ARM frame layout: the frame pointer points to the saved link register.
+        pub const FRAME_POINTER_LAYOUT: (isize, isize) = (-1, 0);
+    } else if #[cfg(target_arch = "x86")] {
+        pub fn get_unwind_registers(regs: &ElfGRegSet) -> Option<UnwindRegisters> {
+            Some(UnwindRegisters {
+                pc: regs.eip as ElfPtrSize,
+                sp: regs.esp as ElfPtrSize,
+                fp: regs.ebp as ElfPtrSize,
+                lr: None,
+            })
+        }
+        pub const DWARF_SP: u16 = 4;
+        pub const DWARF_FP: u16 = 5;
+        pub const DWARF_RETURN_ADDRESS: u16 = 8;
+        pub const FRAME_POINTER_LAYOUT: (isize, isize) = (0, 1);
+    } else {
+        pub fn get_unwind_registers(_regs: &ElfGRegSet) -> Option<UnwindRegisters> {
+            None
+        }
+        pub const DWARF_SP: u16 = 0;
+        pub const DWARF_FP: u16 = 0;
+        pub const DWARF_RETURN_ADDRESS: u16 = 0;
+        pub const FRAME_POINTER_LAYOUT: (isize, isize) = (0, 1);
+    }
+}
+
 #[cfg(target_endian = "little")]
 pub use elf::header::ELFDATA2LSB as ELF_TARGET_ENDIANNESS;
 
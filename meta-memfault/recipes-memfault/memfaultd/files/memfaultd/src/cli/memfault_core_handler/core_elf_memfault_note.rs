@@ -8,9 +8,9 @@
 //! 1. A note containing metadata about the core dump. This note is written by the
 //!   `CoreHandler` whenever it receives a core dump. It contains information about the device,
 //!    that will be used to associate the core dump with a device in the Memfault cloud.
-//! 2. A note containing debug data about the core dump. Currently this note only contains
-//!    logs written during the coredump capture process. These logs are used by Memfault to debug
-//!    issues with coredump capture.
+//! 2. A note containing debug data about the core dump. This note contains logs written during
+//!    the coredump capture process, which are used by Memfault to debug issues with coredump
+//!    capture, and the list of the memory regions which were redacted from the core dump.
 use std::time::SystemTime;
 
 use crate::build_info::VERSION;
@@ -21,6 +21,7 @@ use eyre::Result;
 use serde::Serialize;
 
 use super::core_elf_note::build_elf_note;
+use super::redaction::RedactedRegion;
 
 const NOTE_NAME: &str = "Memfault\0";
 const METADATA_NOTE_TYPE: u32 = 0x4154454d;
@@ -57,7 +58,11 @@ pub struct CoredumpMetadata {
 }
 
 impl CoredumpMetadata {
-    pub fn new(config: &crate::config::Config, cmd_line: String) -> Self {
+    pub fn new(
+        config: &crate::config::Config,
+        cmd_line: String,
+        capture_strategy: CoredumpCaptureStrategy,
+    ) -> Self {
         Self {
             device_id: config.device_info.device_id.clone(),
             hardware_version: config.device_info.hardware_version.clone(),
@@ -69,7 +74,7 @@ impl CoredumpMetadata {
                 .unwrap_or_default()
                 .as_secs(),
             cmd_line,
-            capture_strategy: config.config_file.coredump.capture_strategy,
+            capture_strategy,
         }
     }
 }
@@ -110,20 +115,28 @@ pub fn write_memfault_metadata_note(metadata: &CoredumpMetadata) -> Result<Vec<u
 /// A note containing a list of errors that occurred during coredump capture.
 ///
 /// This note is written by the `CoreHandlerLogWrapper` when it receives an error or warning log.
-/// These logs will help us debug issues with coredump capture.
+/// These logs will help us debug issues with coredump capture. Memory regions redacted from the
+/// coredump are listed too, so that their zeroed or missing contents are not mistaken for a
+/// capture issue.
 #[derive(Debug, Serialize)]
 pub struct CoredumpDebugData {
     pub schema_version: u32,
     pub capture_logs: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub redacted_regions: Vec<RedactedRegion>,
 }
 
 /// Write a core ELF note containing debug data about the coredump capture process.
 ///
 /// See `CoredumpDebugData` for more information.
-pub fn write_memfault_debug_data_note(errors: Vec<String>) -> Result<Vec<u8>> {
+pub fn write_memfault_debug_data_note(
+    errors: Vec<String>,
+    redacted_regions: Vec<RedactedRegion>,
+) -> Result<Vec<u8>> {
     let coredump_capture_logs = CoredumpDebugData {
         schema_version: MEMFAULT_CORE_ELF_DEBUG_DATA_SCHEMA_VERSION_V1,
         capture_logs: errors,
+        redacted_regions,
     };
 
     let mut buffer = Vec::new();
@@ -137,6 +150,8 @@ mod test {
     use ciborium::{from_reader, Value};
     use rstest::rstest;
 
+    use crate::cli::memfault_core_handler::core_elf_note::{iterate_elf_notes, ElfNote};
+    use crate::cli::memfault_core_handler::redaction::RedactionReason;
     use crate::test_utils::set_snapshot_suffix;
 
     use super::*;
@@ -177,6 +192,7 @@ mod test {
                 "Error 2".to_string(),
                 "Error 3".to_string(),
             ],
+            redacted_regions: vec![],
         };
 
         let mut capture_logs_buffer = Vec::new();
@@ -186,4 +202,33 @@ mod test {
 
         insta::assert_debug_snapshot!(deser_capture_logs);
     }
+
+    #[test]
+    fn serialize_debug_data_with_redacted_regions() {
+        let buffer = write_memfault_debug_data_note(
+            vec![],
+            vec![
+                RedactedRegion {
+                    start: 0x1000,
+                    end: 0x2000,
+                    reason: RedactionReason::Path("/dev/shm/secret".to_string()),
+                },
+                RedactedRegion {
+                    start: 0x5000,
+                    end: 0x6000,
+                    reason: RedactionReason::Anonymous,
+                },
+            ],
+        )
+        .unwrap();
+
+        let note = iterate_elf_notes(&buffer).next().unwrap();
+        let desc = match note {
+            ElfNote::Unknown { description, .. } => description,
+            _ => panic!("Unexpected note: {:?}", note),
+        };
+        let deser_debug_data: Value = from_reader(desc).unwrap();
+
+        insta::assert_debug_snapshot!(deser_debug_data);
+    }
 }
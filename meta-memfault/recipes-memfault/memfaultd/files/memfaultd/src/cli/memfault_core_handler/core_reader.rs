@@ -7,9 +7,11 @@ use crate::cli::memfault_core_handler::arch::{ELF_TARGET_ENDIANNESS, ELF_TARGET_
 use crate::{cli::memfault_core_handler::elf, util::io::ForwardOnlySeeker};
 
 use elf::header::{Header, EI_CLASS, EI_DATA, ELFCLASS, ELFMAG, EV_CURRENT, SELFMAG, SIZEOF_EHDR};
-use elf::program_header::{ProgramHeader, SIZEOF_PHDR};
+use elf::program_header::{ProgramHeader, PT_NOTE, SIZEOF_PHDR};
 use eyre::{eyre, Result};
 
+use super::core_elf_note::{iterate_elf_notes, ElfNote};
+
 pub trait CoreReader {
     /// Reads program headers from the input stream
     fn read_program_headers(&mut self) -> Result<Vec<ProgramHeader>>;
@@ -87,6 +89,51 @@ impl<R: Read> CoreReaderImpl<R> {
     }
 }
 
+/// A `CoreReader` serving the program headers and note segments read beforehand from another
+/// `CoreReader`, so that the notes can be inspected before the core is transformed (the core elf
+/// is streamed from stdin and can only be read once).
+pub struct CachedCoreReader {
+    program_headers: Vec<ProgramHeader>,
+    note_segments: Vec<(ProgramHeader, Vec<u8>)>,
+}
+
+impl CachedCoreReader {
+    pub fn new<R: CoreReader>(reader: &mut R) -> Result<Self> {
+        let program_headers = reader.read_program_headers()?;
+        let note_segments = program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_NOTE)
+            .map(|ph| Ok((*ph, reader.read_segment_data(ph)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            program_headers,
+            note_segments,
+        })
+    }
+
+    /// Parsed notes of all the note segments.
+    pub fn notes(&self) -> Vec<ElfNote<'_>> {
+        self.note_segments
+            .iter()
+            .flat_map(|(_, data)| iterate_elf_notes(data))
+            .collect()
+    }
+}
+
+impl CoreReader for CachedCoreReader {
+    fn read_program_headers(&mut self) -> Result<Vec<ProgramHeader>> {
+        Ok(self.program_headers.clone())
+    }
+
+    fn read_segment_data(&mut self, program_header: &ProgramHeader) -> Result<Vec<u8>> {
+        self.note_segments
+            .iter()
+            .find(|(ph, _)| ph == program_header)
+            .map(|(_, data)| data.clone())
+            .ok_or_else(|| eyre!("Only note segments can be read from a CachedCoreReader"))
+    }
+}
+
 /// Reads `count` ELF program headers from the provided input stream.
 pub fn read_program_headers<R: Read>(
     input_stream: &mut R,
@@ -102,6 +149,7 @@ pub fn read_program_headers<R: Read>(
 mod test {
     use std::fs::File;
     use std::io::Cursor;
+    use std::path::PathBuf;
 
     use super::*;
 
@@ -190,4 +238,38 @@ mod test {
         let elf_header = build_test_header(class, endianness, machine);
         assert!(!CoreReaderImpl::<File>::verify_elf_header(&elf_header));
     }
+
+    #[rstest]
+    fn test_cached_core_reader() {
+        let input_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/cli/memfault_core_handler/fixtures/elf-core-runtime-ld-paths.elf");
+        let mut reader = CoreReaderImpl::new(File::open(&input_path).unwrap()).unwrap();
+        let program_headers = reader.read_program_headers().unwrap();
+        let note_ph = *program_headers
+            .iter()
+            .find(|ph| ph.p_type == PT_NOTE)
+            .unwrap();
+        let note_data = reader.read_segment_data(&note_ph).unwrap();
+
+        let mut reader = CoreReaderImpl::new(File::open(&input_path).unwrap()).unwrap();
+        let mut cached_reader = CachedCoreReader::new(&mut reader).unwrap();
+
+        assert_eq!(
+            cached_reader.read_program_headers().unwrap(),
+            program_headers
+        );
+        assert_eq!(
+            cached_reader.read_segment_data(&note_ph).unwrap(),
+            note_data
+        );
+        assert!(cached_reader
+            .notes()
+            .iter()
+            .any(|note| matches!(note, ElfNote::ProcessStatus(_))));
+        let load_ph = program_headers
+            .iter()
+            .find(|ph| ph.p_type == elf::program_header::PT_LOAD)
+            .unwrap();
+        assert!(cached_reader.read_segment_data(load_ph).is_err());
+    }
 }
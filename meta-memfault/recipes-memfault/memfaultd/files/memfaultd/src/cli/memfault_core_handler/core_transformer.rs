@@ -19,6 +19,7 @@ use crate::cli::memfault_core_handler::find_elf_headers::find_elf_headers_and_bu
 use crate::cli::memfault_core_handler::find_stack::find_stack;
 use crate::cli::memfault_core_handler::memory_range::{merge_memory_ranges, MemoryRange};
 use crate::cli::memfault_core_handler::procfs::ProcMaps;
+use crate::cli::memfault_core_handler::redaction::{redact_segments, RedactedRegion, Redactor};
 use crate::cli::memfault_core_handler::ElfPtrSize;
 use crate::config::CoredumpCaptureStrategy;
 
@@ -29,7 +30,8 @@ use log::warn;
 use procfs::process::MemoryMap;
 
 use super::{
-    core_elf_memfault_note::write_memfault_debug_data_note, log_wrapper::CAPTURE_LOG_CHANNEL_SIZE,
+    arch::get_stack_pointer, core_elf_memfault_note::write_memfault_debug_data_note,
+    log_wrapper::CAPTURE_LOG_CHANNEL_SIZE,
 };
 
 #[derive(Debug)]
@@ -37,6 +39,7 @@ pub struct CoreTransformerOptions {
     pub max_size: usize,
     pub capture_strategy: CoredumpCaptureStrategy,
     pub thread_filter_supported: bool,
+    pub redactor: Option<Redactor>,
 }
 
 /// Reads segments from core elf stream and memory stream and builds a core new elf file.
@@ -112,17 +115,35 @@ where
             }
         };
 
+        let (segments_to_capture, redacted_regions) = match &self.options.redactor {
+            Some(redactor) => {
+                // Failing to read the memory maps would leak the memory which should be redacted,
+                // so the coredump is not captured in that case.
+                let memory_maps = self.proc_maps.get_process_maps()?;
+                let regions =
+                    redactor.redacted_regions(&memory_maps, &Self::stack_pointers(&all_notes));
+                redact_segments(segments_to_capture, &regions, redactor.mode())
+            }
+            None => (
+                segments_to_capture
+                    .into_iter()
+                    .map(|ph| (ph, SegmentData::ProcessMemory))
+                    .collect(),
+                vec![],
+            ),
+        };
+
         // Always copy over all note segments, regardless of the capturing strategy:
         for (ph, data) in all_notes {
             self.core_writer.add_segment(*ph, SegmentData::Buffer(data));
         }
 
-        for ph in segments_to_capture {
-            self.core_writer.add_segment(ph, SegmentData::ProcessMemory);
+        for (ph, data) in segments_to_capture {
+            self.core_writer.add_segment(ph, data);
         }
 
         self.add_memfault_metadata_note()?;
-        self.add_memfault_debug_data_note()?;
+        self.add_memfault_debug_data_note(redacted_regions)?;
         self.check_output_size()?;
         self.core_writer.write()?;
 
@@ -239,6 +260,18 @@ where
         merged_ranges.into_iter().map(ProgramHeader::from).collect()
     }
 
+    /// Stack pointers of all threads, from their NT_PRSTATUS notes.
+    fn stack_pointers(all_notes: &[(&ProgramHeader, Vec<u8>)]) -> Vec<ElfPtrSize> {
+        all_notes
+            .iter()
+            .flat_map(|(_, data)| iterate_elf_notes(data))
+            .filter_map(|note| match note {
+                ElfNote::ProcessStatus(s) => Some(get_stack_pointer(&s.pr_reg) as ElfPtrSize),
+                _ => None,
+            })
+            .collect()
+    }
+
     fn elf_metadata_ranges_for_mapped_file(&mut self, vaddr_base: u64) -> Result<Vec<MemoryRange>> {
         // Ignore unnecessary cast here as it is needed on 32-bit systems.
         #[allow(clippy::unnecessary_cast)]
@@ -269,9 +302,12 @@ where
         self.add_memfault_note(note_data)
     }
 
-    fn add_memfault_debug_data_note(&mut self) -> Result<()> {
+    fn add_memfault_debug_data_note(
+        &mut self,
+        redacted_regions: Vec<RedactedRegion>,
+    ) -> Result<()> {
         let mut capture_logs = self.capture_logs_rx.try_iter().collect::<Vec<_>>();
-        if capture_logs.is_empty() {
+        if capture_logs.is_empty() && redacted_regions.is_empty() {
             return Ok(());
         }
 
@@ -279,7 +315,7 @@ where
             capture_logs.push("Log overflow, some logs may have been dropped".to_string());
         }
 
-        let buffer = write_memfault_debug_data_note(capture_logs)?;
+        let buffer = write_memfault_debug_data_note(capture_logs, redacted_regions)?;
         self.add_memfault_note(buffer)
     }
 
@@ -318,15 +354,17 @@ mod test {
     use crate::cli::memfault_core_handler::test_utils::{
         FakeProcMaps, FakeProcMem, MockCoreWriter,
     };
+    use crate::config::{CoredumpRedactionConfig, CoredumpRedactionMode};
     use crate::test_utils::setup_logger;
     use crate::{
         cli::memfault_core_handler::core_reader::CoreReaderImpl, test_utils::set_snapshot_suffix,
     };
     use insta::assert_debug_snapshot;
     use rstest::rstest;
-    use std::fs::File;
+    use std::fs::{write, File};
     use std::path::PathBuf;
     use std::sync::mpsc::sync_channel;
+    use tempfile::tempdir;
 
     use super::*;
 
@@ -344,16 +382,66 @@ mod test {
         #[case] thread_filter_supported: bool,
         _setup_logger: (),
     ) {
-        let input_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-            .join("src/cli/memfault_core_handler/fixtures/elf-core-runtime-ld-paths.elf");
-        let input_stream = File::open(&input_path).unwrap();
-        let proc_mem_stream = FakeProcMem::new_from_path(&input_path).unwrap();
-        let proc_maps = FakeProcMaps::new_from_path(&input_path).unwrap();
         let opts = CoreTransformerOptions {
             max_size: 1024 * 1024,
             capture_strategy,
             thread_filter_supported,
+            redactor: None,
+        };
+        let segments = transform(opts);
+
+        set_snapshot_suffix!("{}", test_case_name);
+        assert_debug_snapshot!(segments);
+    }
+
+    #[rstest]
+    #[case("zero", CoredumpRedactionMode::Zero)]
+    #[case("omit", CoredumpRedactionMode::Omit)]
+    fn test_transform_with_redaction(
+        #[case] test_case_name: &str,
+        #[case] mode: CoredumpRedactionMode,
+        _setup_logger: (),
+    ) {
+        let denylist_dir = tempdir().unwrap();
+        write(denylist_dir.path().join("1234"), "0x5587ae8bd800 0x400\n").unwrap();
+        let redaction_config = CoredumpRedactionConfig {
+            paths: vec![],
+            anonymous: false,
+            denylist_dir: Some(denylist_dir.path().to_owned()),
+            mode,
+        };
+        let opts = CoreTransformerOptions {
+            max_size: 1024 * 1024,
+            capture_strategy: CoredumpCaptureStrategy::KernelSelection,
+            thread_filter_supported: true,
+            redactor: Some(Redactor::new(redaction_config, 1234).unwrap()),
         };
+        let segments = transform(opts);
+
+        // The first load segment is split around the redacted region, and the redacted region is
+        // listed in the debug data note:
+        set_snapshot_suffix!("{}", test_case_name);
+        assert_debug_snapshot!(&segments[..5]);
+        assert!(matches!(
+            segments.last(),
+            Some((
+                ProgramHeader {
+                    p_type: PT_NOTE,
+                    ..
+                },
+                SegmentData::Buffer(_)
+            ))
+        ));
+    }
+
+    /// Runs the transformer on the test core, returning the segments without the contents of the
+    /// notes.
+    fn transform(opts: CoreTransformerOptions) -> Vec<(ProgramHeader, SegmentData)> {
+        let input_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/cli/memfault_core_handler/fixtures/elf-core-runtime-ld-paths.elf");
+        let input_stream = File::open(&input_path).unwrap();
+        let proc_mem_stream = FakeProcMem::new_from_path(&input_path).unwrap();
+        let proc_maps = FakeProcMaps::new_from_path(&input_path).unwrap();
         let metadata = CoredumpMetadata {
             device_id: "12345678".to_string(),
             hardware_version: "evt".to_string(),
@@ -362,7 +450,7 @@ mod test {
             sdk_version: "SDK_VERSION".to_string(),
             captured_time_epoch_s: 1234,
             cmd_line: "binary -a -b -c".to_string(),
-            capture_strategy,
+            capture_strategy: opts.capture_strategy,
         };
 
         let (_capture_logs_tx, capture_log_rx) = sync_channel(32);
@@ -384,18 +472,15 @@ mod test {
         transformer.run_transformer().unwrap();
 
         // Omit the actual data from the notes:
-        let segments = segments
-            .iter()
+        segments
+            .into_iter()
             .map(|(ph, seg)| {
                 let seg = match seg {
-                    SegmentData::ProcessMemory => SegmentData::ProcessMemory,
                     SegmentData::Buffer(_) => SegmentData::Buffer(vec![]),
+                    seg => seg,
                 };
                 (ph, seg)
             })
-            .collect::<Vec<_>>();
-
-        set_snapshot_suffix!("{}", test_case_name);
-        assert_debug_snapshot!(segments);
+            .collect()
     }
 }
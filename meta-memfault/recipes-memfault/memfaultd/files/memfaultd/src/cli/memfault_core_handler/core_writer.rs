@@ -36,6 +36,8 @@ pub trait CoreWriter {
 pub enum SegmentData {
     Buffer(Vec<u8>),
     ProcessMemory,
+    /// Zeroes in place of the process memory, for redacted regions.
+    Zeroes,
 }
 
 #[derive(Debug)]
@@ -186,6 +188,12 @@ where
                         Self::fill_remaining_bytes(segment_end, &mut self.output_stream)?;
                     }
                 }
+                SegmentData::Zeroes => {
+                    Self::write_padding(
+                        &mut self.output_stream,
+                        segment.program_header.p_filesz as usize,
+                    )?;
+                }
             }
         }
 
@@ -261,6 +269,7 @@ mod test {
     #[case(SegmentData::Buffer(vec![0xa5; 1024]), vec![])]
     #[case(SegmentData::ProcessMemory, vec![0xaa; PROC_MEM_READ_CHUNK_SIZE])]
     #[case(SegmentData::ProcessMemory, vec![0xaa; PROC_MEM_READ_CHUNK_SIZE + PROC_MEM_READ_CHUNK_SIZE / 4])]
+    #[case(SegmentData::Zeroes, vec![0xaa; PROC_MEM_READ_CHUNK_SIZE])]
     fn test_added_segments(#[case] segment_data: SegmentData, #[case] mem_buffer: Vec<u8>) {
         let mem_stream = Cursor::new(mem_buffer.clone());
         let mut output_buf = Vec::new();
@@ -269,6 +278,7 @@ mod test {
         let segment_buffer = match &segment_data {
             SegmentData::Buffer(data) => data.clone(),
             SegmentData::ProcessMemory => mem_buffer,
+            SegmentData::Zeroes => vec![0; mem_buffer.len()],
         };
 
         core_writer.add_segment(
@@ -0,0 +1,224 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! Crash deduplication
+//!
+//! A crash loop produces identical coredumps, which can use up the whole coredump rate limit.
+//! Crashes are identified by a fingerprint, and a crash identical to one seen recently is
+//! only counted (see `CoredumpDeduplicationConfig`).
+//!
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use eyre::Result;
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use crate::mar::StackTrace;
+use crate::util::fs::write_atomically;
+
+/// Counter metric incremented for each crash which was not captured, being a duplicate.
+pub const DEDUPLICATED_CRASHES_METRIC: &str = "coredumps_deduplicated";
+
+/// Computes the fingerprint of a crash from the executable, the signal and the top `frames`
+/// frames of the crashed thread. Frames are identified by module and offset, which do not depend
+/// on where the modules were loaded.
+///
+/// Returns `None` when the crashed thread could not be unwound.
+pub fn crash_fingerprint(
+    executable: &str,
+    stack_trace: &StackTrace,
+    frames: usize,
+) -> Option<String> {
+    let thread = stack_trace.threads.iter().find(|thread| thread.crashed)?;
+    if thread.frames.is_empty() {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}\n{:?}\n", executable, stack_trace.signal));
+    for frame in thread.frames.iter().take(frames) {
+        let module = frame
+            .module
+            .and_then(|index| stack_trace.modules.get(index));
+        let frame = match (module, frame.module_offset) {
+            (Some(module), Some(offset)) => format!(
+                "{}+{:#x}\n",
+                module.build_id.as_deref().unwrap_or(&module.path),
+                offset
+            ),
+            // The PC of an unknown module depends on the memory layout of the process.
+            _ => "?\n".to_string(),
+        };
+        hasher.update(frame);
+    }
+    Some(
+        hasher.finalize()[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+/// Keeps track of the crashes seen within the deduplication window, in a file `path`. A crash
+/// counts as seen even when its coredump was not captured (rate limit reached for example).
+pub struct CrashDeduplicator {
+    path: PathBuf,
+    window: Duration,
+    /// Time at which each fingerprint was first seen within the window.
+    first_seen: HashMap<String, DateTime<Utc>>,
+}
+
+impl CrashDeduplicator {
+    /// Load the deduplicator state from disk.
+    ///
+    /// Non-existent or unparseable files are not considered an error.
+    pub fn load<P: AsRef<Path>>(path: P, window: Duration) -> Result<Self> {
+        let first_seen = match read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!("Ignoring invalid crash fingerprints file: {}", e);
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.as_ref().to_owned(),
+            window,
+            first_seen,
+        })
+    }
+
+    fn check_with_time(&mut self, fingerprint: &str, now: DateTime<Utc>) -> bool {
+        let window = self.window;
+        self.first_seen
+            .retain(|_, seen| now.signed_duration_since(*seen) < window);
+        if self.first_seen.contains_key(fingerprint) {
+            return false;
+        }
+        self.first_seen.insert(fingerprint.to_owned(), now);
+        true
+    }
+
+    /// Check if a crash with this fingerprint should be processed now, i.e. it is not a
+    /// duplicate of a crash seen within the window. The state is updated but not written to
+    /// disk. Call `save()` to persist it.
+    pub fn check(&mut self, fingerprint: &str) -> bool {
+        self.check_with_time(fingerprint, Utc::now())
+    }
+
+    /// Writes the deduplicator state to disk, atomically.
+    pub fn save(self) -> Result<()> {
+        write_atomically(
+            &self.path,
+            serde_json::to_string(&self.first_seen)?.as_bytes(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use chrono::TimeZone;
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    use crate::mar::{StackFrame, StackTraceModule, ThreadStackTrace};
+
+    use super::*;
+
+    fn stack_trace(signal: u32, load_address: u64, offsets: &[u64]) -> StackTrace {
+        StackTrace {
+            cmdline: "/usr/bin/app".into(),
+            signal: Some(signal),
+            modules: vec![StackTraceModule {
+                path: "/usr/bin/app".into(),
+                build_id: Some("0011223344".into()),
+                load_address,
+            }],
+            threads: vec![
+                ThreadStackTrace {
+                    tid: 2,
+                    crashed: false,
+                    frames: vec![],
+                },
+                ThreadStackTrace {
+                    tid: 1,
+                    crashed: true,
+                    frames: offsets
+                        .iter()
+                        .map(|offset| StackFrame {
+                            pc: load_address + offset,
+                            module: Some(0),
+                            module_offset: Some(*offset),
+                        })
+                        .collect(),
+                },
+            ],
+        }
+    }
+
+    #[rstest]
+    #[case::different_load_address(stack_trace(11, 0x7000, &[0x10, 0x20, 0x30]), true)]
+    #[case::different_bottom_frames(stack_trace(11, 0x5000, &[0x10, 0x20, 0x40]), true)]
+    #[case::different_top_frame(stack_trace(11, 0x5000, &[0x18, 0x20, 0x30]), false)]
+    #[case::different_signal(stack_trace(6, 0x5000, &[0x10, 0x20, 0x30]), false)]
+    fn fingerprints_crashes(#[case] other: StackTrace, #[case] same: bool) {
+        let fingerprint = crash_fingerprint(
+            "/usr/bin/app",
+            &stack_trace(11, 0x5000, &[0x10, 0x20, 0x30]),
+            2,
+        );
+        assert_eq!(
+            crash_fingerprint("/usr/bin/app", &other, 2) == fingerprint,
+            same
+        );
+    }
+
+    #[rstest]
+    fn does_not_fingerprint_crashes_without_frames() {
+        assert_eq!(
+            crash_fingerprint("/usr/bin/app", &stack_trace(11, 0x5000, &[]), 2),
+            None
+        );
+    }
+
+    #[rstest]
+    #[case(vec![("a", 0), ("a", 5), ("b", 5), ("a", 9), ("a", 10), ("b", 12)], vec![true, false, true, false, true, false])]
+    #[case(vec![("a", 0), ("a", 100), ("a", 200)], vec![true, true, true])]
+    fn deduplicates_crashes_within_window(
+        #[case] crashes: Vec<(&str, i64)>,
+        #[case] expected: Vec<bool>,
+    ) {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("fingerprints.json");
+
+        for ((fingerprint, time), expected) in crashes.into_iter().zip(expected) {
+            let mut deduplicator =
+                CrashDeduplicator::load(&path, Duration::seconds(10)).expect("load error");
+            assert_eq!(
+                deduplicator
+                    .check_with_time(fingerprint, Utc.timestamp_opt(time, 0).single().unwrap()),
+                expected,
+                "{} at {}",
+                fingerprint,
+                time
+            );
+            deduplicator.save().expect("save error");
+        }
+    }
+
+    #[rstest]
+    fn ignores_invalid_files() {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("fingerprints.json");
+        write(&path, "garbage").unwrap();
+
+        let mut deduplicator = CrashDeduplicator::load(&path, Duration::seconds(10)).unwrap();
+        assert!(deduplicator.check("a"));
+    }
+}
@@ -2,7 +2,7 @@
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
 use crate::cli::memfault_core_handler::ElfPtrSize;
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::fmt::{Debug, Formatter};
 
 /// Convenience struct to manage memory address ranges
@@ -36,6 +36,13 @@ impl MemoryRange {
     pub fn contains(&self, addr: ElfPtrSize) -> bool {
         self.start <= addr && addr < self.end
     }
+
+    /// Returns the part of the range which is also in `other`, if any
+    pub fn intersection(&self, other: &Self) -> Option<Self> {
+        let start = max(self.start, other.start);
+        let end = min(self.end, other.end);
+        (start < end).then(|| Self::new(start, end))
+    }
 }
 
 impl Debug for MemoryRange {
@@ -124,4 +131,29 @@ mod test {
     ) {
         assert_eq!(input_range.contains(addr), contains);
     }
+
+    #[rstest]
+    #[case(
+        MemoryRange::new(0x1000, 0x2000),
+        MemoryRange::new(0x1800, 0x3000),
+        Some(MemoryRange::new(0x1800, 0x2000))
+    )]
+    #[case(
+        MemoryRange::new(0x1000, 0x4000),
+        MemoryRange::new(0x2000, 0x3000),
+        Some(MemoryRange::new(0x2000, 0x3000))
+    )]
+    #[case(
+        MemoryRange::new(0x1000, 0x2000),
+        MemoryRange::new(0x2000, 0x3000),
+        None
+    )]
+    fn test_memory_range_intersection(
+        #[case] a: MemoryRange,
+        #[case] b: MemoryRange,
+        #[case] expected: Option<MemoryRange>,
+    ) {
+        assert_eq!(a.intersection(&b), expected);
+        assert_eq!(b.intersection(&a), expected);
+    }
 }
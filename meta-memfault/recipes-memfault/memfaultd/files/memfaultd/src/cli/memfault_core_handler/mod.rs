@@ -8,6 +8,7 @@ mod core_elf_note;
 mod core_reader;
 mod core_transformer;
 mod core_writer;
+mod crash_deduplicator;
 mod find_dynamic;
 mod find_elf_headers;
 mod find_stack;
@@ -15,31 +16,39 @@ mod log_wrapper;
 mod memory_range;
 mod procfs;
 mod r_debug;
+mod redaction;
+mod stack_unwinder;
 #[cfg(test)]
 mod test_utils;
 
-use self::core_reader::CoreReaderImpl;
+use self::core_reader::{CachedCoreReader, CoreReaderImpl};
 use self::core_writer::CoreWriterImpl;
+use self::crash_deduplicator::{crash_fingerprint, CrashDeduplicator, DEDUPLICATED_CRASHES_METRIC};
 use self::log_wrapper::CoreHandlerLogWrapper;
-use self::procfs::{proc_mem_stream, read_proc_cmdline, ProcMapsImpl};
+use self::procfs::{proc_mem_stream, read_proc_cmdline, ProcMaps, ProcMapsImpl};
+use self::redaction::Redactor;
+use self::stack_unwinder::unwind_stack_trace;
 use self::{arch::coredump_thread_filter_supported, log_wrapper::CAPTURE_LOG_CHANNEL_SIZE};
 use self::{core_elf_memfault_note::CoredumpMetadata, core_transformer::CoreTransformerOptions};
 use crate::cli;
-use crate::config::{Config, CoredumpCompression};
-use crate::mar::manifest::{CompressionAlgorithm, Metadata};
+use crate::config::{Config, CoredumpCompression, StackTraceConfig};
+use crate::mar::manifest::{CompressionAlgorithm, Metadata, StackTrace};
 use crate::mar::mar_entry_builder::MarEntryBuilder;
+use crate::metrics::{MetricStringKey, PushedMetricReading, PushedMetricValue};
 use crate::network::NetworkConfig;
 use crate::util::disk_size::get_disk_space;
-use crate::util::io::{ForwardOnlySeeker, StreamPositionTracker};
+use crate::util::io::StreamPositionTracker;
 use crate::util::persistent_rate_limiter::PersistentRateLimiter;
 use argh::FromArgs;
 use eyre::{eyre, Result, WrapErr};
-use flate2::write::GzEncoder;
 use kernlog::KernelLog;
 use log::{debug, error, info, warn, LevelFilter, Log};
 use prctl::set_dumpable;
+use std::fs::read_link;
 use std::io::BufWriter;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::sync_channel;
 use std::thread::scope;
 use std::{cmp::min, fs::File};
 use std::{
@@ -47,7 +56,6 @@ use std::{
     sync::mpsc::SyncSender,
 };
 use std::{io::BufReader, sync::mpsc::Receiver};
-use std::{io::Write, sync::mpsc::sync_channel};
 use uuid::Uuid;
 
 #[cfg(target_pointer_width = "64")]
@@ -127,17 +135,88 @@ pub fn main() -> Result<()> {
 }
 
 pub fn process_corefile(config: &Config, pid: i32, error_rx: Receiver<String>) -> Result<()> {
+    let coredump_config = &config.config_file.coredump;
+
+    let cmd_line_file_name = format!("/proc/{}/cmdline", pid);
+    let mut cmd_line_file = File::open(cmd_line_file_name)?;
+    let cmd_line = read_proc_cmdline(&mut cmd_line_file)?;
+    let executable = read_link(format!("/proc/{}/exe", pid))
+        .map(|path| path.display().to_string())
+        .unwrap_or_else(|_| cmd_line.split('\0').next().unwrap_or_default().to_string());
+
+    let policy = config.coredump_policy(&executable, &cmd_line);
+    if let Some(name) = &policy.name {
+        debug!("Coredump policy {} applies to {}", name, executable);
+    }
+    if policy.ignore {
+        info!(
+            "Ignoring corefile of {} as per its coredump policy",
+            executable
+        );
+        return Ok(());
+    }
+
+    // The notes are read first, as they are needed to unwind the stacks of the crashed process:
+    let input_stream = BufReader::new(std::io::stdin());
+    let mut input_core_reader = CoreReaderImpl::new(input_stream)?;
+    let elf_header = input_core_reader.elf_header();
+    let core_reader = CachedCoreReader::new(&mut input_core_reader)?;
+
+    let stack_trace = unwind_crashed_process(config, pid, &core_reader, &cmd_line);
+
+    if let (Some(deduplication), Some(stack_trace)) = (&coredump_config.deduplication, &stack_trace)
+    {
+        if let Some(fingerprint) = crash_fingerprint(&executable, stack_trace, deduplication.frames)
+        {
+            let mut crash_deduplicator = CrashDeduplicator::load(
+                config.coredump_fingerprints_file_path(),
+                chrono::Duration::from_std(deduplication.window)?,
+            )
+            .with_context(|| {
+                format!(
+                    "Unable to open crash fingerprints {}",
+                    config.coredump_fingerprints_file_path().display()
+                )
+            })?;
+            if !crash_deduplicator.check(&fingerprint) {
+                info!(
+                    "Duplicate of a recent crash ({}), not processing corefile",
+                    fingerprint
+                );
+                report_duplicate_crash(config);
+                return Ok(());
+            }
+            // Saved as soon as the fingerprint is first seen: duplicates of a crash which is not
+            // captured (rate limit reached for example) are deduplicated too.
+            if let Err(e) = crash_deduplicator.save() {
+                warn!("Failed to save crash fingerprints: {}", e);
+            }
+        }
+    }
+
+    if let (Some(stack_trace_config), Some(mut stack_trace)) =
+        (&coredump_config.stack_trace, stack_trace)
+    {
+        for thread in stack_trace.threads.iter_mut() {
+            thread.frames.truncate(stack_trace_config.max_frames);
+        }
+        // Saved before checking the rate limit: stack traces are uploaded even when the coredump
+        // is not.
+        if let Err(e) = save_stack_trace(config, stack_trace_config, stack_trace) {
+            warn!("Failed to save stack trace: {}", e);
+        }
+    }
+
     let rate_limiter = if !config.config_file.enable_dev_mode {
-        config.coredump_rate_limiter_file_path();
         let mut rate_limiter = PersistentRateLimiter::load(
-            config.coredump_rate_limiter_file_path(),
-            config.config_file.coredump.rate_limit_count,
-            chrono::Duration::from_std(config.config_file.coredump.rate_limit_duration)?,
+            &policy.rate_limiter_file_path,
+            policy.rate_limit_count,
+            chrono::Duration::from_std(policy.rate_limit_duration)?,
         )
         .with_context(|| {
             format!(
                 "Unable to open coredump rate limiter {}",
-                config.coredump_rate_limiter_file_path().display()
+                policy.rate_limiter_file_path.display()
             )
         })?;
         if !rate_limiter.check() {
@@ -149,7 +228,7 @@ pub fn process_corefile(config: &Config, pid: i32, error_rx: Receiver<String>) -
         None
     };
 
-    let max_size = calculate_available_space(config)?;
+    let max_size = calculate_available_space(config, policy.coredump_max_size)?;
     if max_size == 0 {
         error!("Not processing corefile, disk usage limits exceeded");
         return Ok(());
@@ -157,39 +236,32 @@ pub fn process_corefile(config: &Config, pid: i32, error_rx: Receiver<String>) -
 
     let mar_staging_path = config.mar_staging_path();
     let mar_builder = MarEntryBuilder::new(&mar_staging_path)?;
-    let compression = config.config_file.coredump.compression;
-    let capture_strategy = config.config_file.coredump.capture_strategy;
+    let compression = coredump_config.compression;
+    let capture_strategy = policy.capture_strategy;
     let output_file_name = generate_tmp_file_name(compression);
     let output_file_path = mar_builder.make_attachment_path_in_entry_dir(&output_file_name);
 
-    let cmd_line_file_name = format!("/proc/{}/cmdline", pid);
-    let mut cmd_line_file = File::open(cmd_line_file_name)?;
-    let cmd_line = read_proc_cmdline(&mut cmd_line_file)?;
-    let metadata = CoredumpMetadata::new(config, cmd_line);
+    let metadata = CoredumpMetadata::new(config, cmd_line, capture_strategy);
     let thread_filter_supported = coredump_thread_filter_supported();
+    let redactor = coredump_config
+        .redaction
+        .clone()
+        .map(|redaction_config| Redactor::new(redaction_config, pid))
+        .transpose()?;
     let transformer_options = CoreTransformerOptions {
         max_size,
         capture_strategy,
         thread_filter_supported,
+        redactor,
     };
 
     let output_file = BufWriter::new(File::create(&output_file_path)?);
-    let output_stream: Box<dyn Write> = match compression {
-        CoredumpCompression::Gzip => {
-            Box::new(GzEncoder::new(output_file, flate2::Compression::default()))
-        }
-        CoredumpCompression::None => Box::new(output_file),
-    };
+    let output_stream = CompressionAlgorithm::from(compression)
+        .encoder(output_file, flate2::Compression::default())?;
     let output_stream = StreamPositionTracker::new(output_stream);
 
-    let input_stream = ForwardOnlySeeker::new(BufReader::new(std::io::stdin()));
     let proc_maps = ProcMapsImpl::new(pid);
-    let core_reader = CoreReaderImpl::new(input_stream)?;
-    let core_writer = CoreWriterImpl::new(
-        core_reader.elf_header(),
-        output_stream,
-        proc_mem_stream(pid)?,
-    );
+    let core_writer = CoreWriterImpl::new(elf_header, output_stream, proc_mem_stream(pid)?);
     let mut core_transformer = core_transformer::CoreTransformer::new(
         core_reader,
         core_writer,
@@ -221,16 +293,109 @@ pub fn process_corefile(config: &Config, pid: i32, error_rx: Receiver<String>) -
     }
 }
 
+/// Unwinds the stacks of the threads of the crashed process, when stack traces or crash
+/// deduplication are enabled.
+fn unwind_crashed_process(
+    config: &Config,
+    pid: i32,
+    core_reader: &CachedCoreReader,
+    cmd_line: &str,
+) -> Option<StackTrace> {
+    let coredump_config = &config.config_file.coredump;
+    let max_frames = [
+        coredump_config.stack_trace.as_ref().map(|s| s.max_frames),
+        coredump_config.deduplication.as_ref().map(|d| d.frames),
+    ]
+    .into_iter()
+    .flatten()
+    .max()?;
+
+    let memory_maps = ProcMapsImpl::new(pid)
+        .get_process_maps()
+        .unwrap_or_else(|e| {
+            warn!("Failed to read memory maps: {}", e);
+            vec![]
+        });
+    match proc_mem_stream(pid) {
+        Ok(mut proc_mem_stream) => Some(unwind_stack_trace(
+            &mut proc_mem_stream,
+            &memory_maps,
+            &core_reader.notes(),
+            cmd_line,
+            max_frames,
+        )),
+        Err(e) => {
+            warn!("Failed to unwind stacks: {}", e);
+            None
+        }
+    }
+}
+
+/// Counts the crash with a metric, as it is not captured.
+fn report_duplicate_crash(config: &Config) {
+    let result = MemfaultdClient::from_config(config).and_then(|client| {
+        client.write_metrics(&[PushedMetricReading {
+            name: MetricStringKey::from_str(DEDUPLICATED_CRASHES_METRIC).map_err(|e| eyre!(e))?,
+            value: PushedMetricValue::Counter { value: 1.0 },
+            timestamp: None,
+        }])
+    });
+    if let Err(e) = result {
+        debug!("Failed to report duplicate crash to memfaultd: {:?}", e);
+    }
+}
+
+/// Saves the stack trace in its own MAR entry, within the limits of its own rate limiter.
+fn save_stack_trace(
+    config: &Config,
+    stack_trace_config: &StackTraceConfig,
+    stack_trace: StackTrace,
+) -> Result<()> {
+    let rate_limiter = if !config.config_file.enable_dev_mode {
+        let mut rate_limiter = PersistentRateLimiter::load(
+            config.stack_trace_rate_limiter_file_path(),
+            stack_trace_config.rate_limit_count,
+            chrono::Duration::from_std(stack_trace_config.rate_limit_duration)?,
+        )
+        .with_context(|| {
+            format!(
+                "Unable to open stack trace rate limiter {}",
+                config.stack_trace_rate_limiter_file_path().display()
+            )
+        })?;
+        if !rate_limiter.check() {
+            info!("Stack traces limit reached, not saving stack trace");
+            return Ok(());
+        }
+        Some(rate_limiter)
+    } else {
+        None
+    };
+
+    let mar_entry = MarEntryBuilder::new(&config.mar_staging_path())?
+        .set_metadata(Metadata::new_stack_trace(stack_trace))
+        .save(&NetworkConfig::from(config))?;
+
+    debug!(
+        "Stack trace MAR entry generated: {}",
+        mar_entry.path.display()
+    );
+
+    if let Some(rate_limiter) = rate_limiter {
+        rate_limiter.save()?;
+    }
+    Ok(())
+}
+
 fn generate_tmp_file_name(compression: CoredumpCompression) -> String {
     let id = Uuid::new_v4();
-    let extension = match compression {
-        CoredumpCompression::Gzip => "elf.gz",
-        CoredumpCompression::None => "elf",
-    };
-    format!("core-{}.{}", id, extension)
+    match CompressionAlgorithm::from(compression).file_extension() {
+        Some(extension) => format!("core-{}.elf.{}", id, extension),
+        None => format!("core-{}.elf", id),
+    }
 }
 
-fn calculate_available_space(config: &Config) -> Result<usize> {
+fn calculate_available_space(config: &Config, coredump_max_size: usize) -> Result<usize> {
     let min_headroom = config.tmp_dir_min_headroom();
     let available = get_disk_space(&config.tmp_dir())?;
     let has_headroom = available.exceeds(&min_headroom);
@@ -239,19 +404,10 @@ fn calculate_available_space(config: &Config) -> Result<usize> {
     }
     Ok(min(
         (available.bytes - min_headroom.bytes) as usize,
-        config.config_file.coredump.coredump_max_size,
+        coredump_max_size,
     ))
 }
 
-impl From<CoredumpCompression> for CompressionAlgorithm {
-    fn from(compression: CoredumpCompression) -> Self {
-        match compression {
-            CoredumpCompression::Gzip => CompressionAlgorithm::Gzip,
-            CoredumpCompression::None => CompressionAlgorithm::None,
-        }
-    }
-}
-
 fn init_kernel_logger(level: LevelFilter, capture_logs_tx: SyncSender<String>) {
     // kernlog::init() reads from the KERNLOG_LEVEL to set the level. There's no public interface
     // to set it otherwise, so: if this environment variable is not set, set it according to the
@@ -0,0 +1,416 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! Redaction of sensitive memory from coredumps.
+//!
+//! The regions to redact are selected by mapping path, anonymous mappings, or from a denylist
+//! written by the process itself. The parts of the captured segments which overlap with them are
+//! zeroed or left out of the coredump (see `CoredumpRedactionConfig`).
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+
+use eyre::{eyre, Result, WrapErr};
+use procfs::process::{MMapPath, MemoryMap};
+use serde::Serialize;
+
+use crate::cli::memfault_core_handler::core_writer::SegmentData;
+use crate::cli::memfault_core_handler::elf::program_header::ProgramHeader;
+use crate::cli::memfault_core_handler::memory_range::{merge_memory_ranges, MemoryRange};
+use crate::cli::memfault_core_handler::ElfPtrSize;
+use crate::config::{CoredumpRedactionConfig, CoredumpRedactionMode};
+use crate::util::patterns::wildcard_match;
+
+/// A region of the process memory which was redacted, as listed in the Memfault debug data note.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RedactedRegion {
+    pub start: ElfPtrSize,
+    pub end: ElfPtrSize,
+    pub reason: RedactionReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactionReason {
+    /// Mapping of a file matching one of the configured paths.
+    Path(String),
+    Anonymous,
+    Denylist,
+}
+
+impl RedactedRegion {
+    fn new(range: MemoryRange, reason: RedactionReason) -> Self {
+        Self {
+            start: range.start,
+            end: range.end,
+            reason,
+        }
+    }
+
+    fn range(&self) -> MemoryRange {
+        MemoryRange::new(self.start, self.end)
+    }
+}
+
+/// Selects the regions of the memory of a crashed process to redact.
+#[derive(Debug)]
+pub struct Redactor {
+    config: CoredumpRedactionConfig,
+    denylist: Vec<MemoryRange>,
+}
+
+impl Redactor {
+    /// Creates a redactor for the process `pid`, loading its denylist file if it wrote one.
+    pub fn new(config: CoredumpRedactionConfig, pid: i32) -> Result<Self> {
+        let denylist = match &config.denylist_dir {
+            Some(denylist_dir) => {
+                let path = denylist_dir.join(pid.to_string());
+                match read_to_string(&path) {
+                    Ok(contents) => parse_denylist(&contents)
+                        .wrap_err_with(|| format!("Invalid denylist {}", path.display()))?,
+                    Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            None => vec![],
+        };
+
+        Ok(Self { config, denylist })
+    }
+
+    pub fn mode(&self) -> CoredumpRedactionMode {
+        self.config.mode
+    }
+
+    /// Regions to redact, given the memory maps of the process and the stack pointers of its
+    /// threads (the stacks are not redacted with the anonymous mappings).
+    pub fn redacted_regions(
+        &self,
+        memory_maps: &[MemoryMap],
+        stack_pointers: &[ElfPtrSize],
+    ) -> Vec<RedactedRegion> {
+        let mut regions = memory_maps
+            .iter()
+            .filter_map(|mmap| {
+                let range = MemoryRange::from(mmap);
+                let reason = match &mmap.pathname {
+                    MMapPath::Path(path) => {
+                        let path = path.to_string_lossy();
+                        self.config
+                            .paths
+                            .iter()
+                            .any(|pattern| wildcard_match(pattern, &path))
+                            .then(|| RedactionReason::Path(path.into_owned()))
+                    }
+                    MMapPath::Anonymous | MMapPath::Heap => (self.config.anonymous
+                        && !stack_pointers.iter().any(|sp| range.contains(*sp)))
+                    .then_some(RedactionReason::Anonymous),
+                    _ => None,
+                }?;
+                Some(RedactedRegion::new(range, reason))
+            })
+            .collect::<Vec<_>>();
+
+        regions.extend(
+            self.denylist
+                .iter()
+                .map(|range| RedactedRegion::new(range.clone(), RedactionReason::Denylist)),
+        );
+        regions
+    }
+}
+
+/// Splits the PT_LOAD `segments` around the redacted `regions`. Depending on `mode`, the redacted
+/// parts are replaced with zeroes or left out.
+///
+/// Returns the segments to write, and the parts of the regions which were redacted from them.
+pub fn redact_segments(
+    segments: Vec<ProgramHeader>,
+    regions: &[RedactedRegion],
+    mode: CoredumpRedactionMode,
+) -> (Vec<(ProgramHeader, SegmentData)>, Vec<RedactedRegion>) {
+    let redacted_ranges = merge_memory_ranges(regions.iter().map(RedactedRegion::range).collect());
+
+    let mut output = Vec::new();
+    let mut redacted = Vec::new();
+    for ph in segments {
+        let dumped = MemoryRange::from_start_and_size(ph.p_vaddr, ph.p_filesz);
+        let overlaps = redacted_ranges
+            .iter()
+            .filter_map(|range| range.intersection(&dumped))
+            .collect::<Vec<_>>();
+        if overlaps.is_empty() {
+            output.push((ph, SegmentData::ProcessMemory));
+            continue;
+        }
+
+        redacted.extend(regions.iter().filter_map(|region| {
+            region
+                .range()
+                .intersection(&dumped)
+                .map(|range| RedactedRegion::new(range, region.reason.clone()))
+        }));
+
+        let mut cursor = dumped.start;
+        for range in overlaps {
+            if cursor < range.start {
+                output.push((
+                    segment_part(&ph, MemoryRange::new(cursor, range.start)),
+                    SegmentData::ProcessMemory,
+                ));
+            }
+            if mode == CoredumpRedactionMode::Zero {
+                output.push((segment_part(&ph, range.clone()), SegmentData::Zeroes));
+            }
+            cursor = range.end;
+        }
+        if cursor < dumped.end {
+            output.push((
+                segment_part(&ph, MemoryRange::new(cursor, dumped.end)),
+                SegmentData::ProcessMemory,
+            ));
+        }
+
+        // Part of the mapping which was not dumped by the kernel:
+        if ph.p_memsz > ph.p_filesz {
+            output.push((
+                ProgramHeader {
+                    p_vaddr: dumped.end,
+                    p_filesz: 0,
+                    p_memsz: ph.p_memsz - ph.p_filesz,
+                    p_align: 1,
+                    ..ph
+                },
+                SegmentData::ProcessMemory,
+            ));
+        }
+    }
+
+    (output, redacted)
+}
+
+/// The part of the segment `ph` covering `range`. Only the first part keeps the alignment of the
+/// segment: the next ones follow it in the coredump (or a part which was left out), so that the
+/// file offsets of the parts stay congruent with their addresses without any padding.
+fn segment_part(ph: &ProgramHeader, range: MemoryRange) -> ProgramHeader {
+    ProgramHeader {
+        p_vaddr: range.start,
+        p_filesz: range.size(),
+        p_memsz: range.size(),
+        p_align: if range.start == ph.p_vaddr {
+            ph.p_align
+        } else {
+            1
+        },
+        ..*ph
+    }
+}
+
+/// Parses a denylist: one region per line, as an address and a length (decimal, or hexadecimal
+/// with a `0x` prefix). Empty lines and `#` comments are ignored.
+fn parse_denylist(contents: &str) -> Result<Vec<MemoryRange>> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (start, length) = match fields[..] {
+                [start, length] => (parse_number(start)?, parse_number(length)?),
+                _ => return Err(eyre!("Expected an address and a length: {}", line)),
+            };
+            let end = start
+                .checked_add(length)
+                .ok_or_else(|| eyre!("Region out of bounds: {}", line))?;
+            Ok(MemoryRange::new(start, end))
+        })
+        .collect()
+}
+
+fn parse_number(s: &str) -> Result<ElfPtrSize> {
+    match s.strip_prefix("0x") {
+        Some(hex) => ElfPtrSize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| eyre!("Invalid number {}: {}", s, e))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::write;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+
+    use insta::assert_debug_snapshot;
+    use procfs::process::MMPermissions;
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    use crate::cli::memfault_core_handler::arch::{
+        ELF_TARGET_CLASS, ELF_TARGET_ENDIANNESS, ELF_TARGET_MACHINE,
+    };
+    use crate::cli::memfault_core_handler::core_writer::{CoreWriter, CoreWriterImpl};
+    use crate::cli::memfault_core_handler::elf::header::SIZEOF_EHDR;
+    use crate::cli::memfault_core_handler::elf::program_header::PT_LOAD;
+    use crate::cli::memfault_core_handler::test_utils::build_test_header;
+    use crate::test_utils::set_snapshot_suffix;
+    use crate::util::io::StreamPositionTracker;
+
+    use super::*;
+
+    fn memory_map(start: u64, end: u64, pathname: MMapPath) -> MemoryMap {
+        MemoryMap {
+            address: (start, end),
+            perms: MMPermissions::READ | MMPermissions::WRITE,
+            offset: 0,
+            dev: (0, 0),
+            inode: 0,
+            pathname,
+            extension: Default::default(),
+        }
+    }
+
+    fn config(paths: &[&str], anonymous: bool) -> CoredumpRedactionConfig {
+        CoredumpRedactionConfig {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            anonymous,
+            denylist_dir: None,
+            mode: CoredumpRedactionMode::Zero,
+        }
+    }
+
+    fn load_segment(start: ElfPtrSize, filesz: ElfPtrSize, memsz: ElfPtrSize) -> ProgramHeader {
+        ProgramHeader {
+            p_type: PT_LOAD,
+            p_flags: 0x6,
+            p_vaddr: start,
+            p_filesz: filesz,
+            p_memsz: memsz,
+            p_align: 4096,
+            ..Default::default()
+        }
+    }
+
+    #[rstest]
+    #[case("0x1000 0x2000\n", vec![MemoryRange::new(0x1000, 0x3000)])]
+    #[case(
+        "# keys\n0x1000 4096 # master key\n\n8192 0x10\n",
+        vec![MemoryRange::new(0x1000, 0x2000), MemoryRange::new(0x2000, 0x2010)]
+    )]
+    #[case("", vec![])]
+    fn parses_denylists(#[case] contents: &str, #[case] expected: Vec<MemoryRange>) {
+        assert_eq!(parse_denylist(contents).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case("0x1000\n")]
+    #[case("0x1000 0x10 0x10\n")]
+    #[case("0xzz 0x10\n")]
+    #[case("0x1000 -16\n")]
+    fn rejects_invalid_denylists(#[case] contents: &str) {
+        assert!(parse_denylist(contents).is_err());
+    }
+
+    #[rstest]
+    #[case::paths(config(&["/dev/shm/secret*"], false), vec![
+        RedactedRegion::new(MemoryRange::new(0x3000, 0x4000), RedactionReason::Path("/dev/shm/secret-keys".into())),
+    ])]
+    #[case::anonymous(config(&[], true), vec![
+        RedactedRegion::new(MemoryRange::new(0x1000, 0x2000), RedactionReason::Anonymous),
+        RedactedRegion::new(MemoryRange::new(0x5000, 0x6000), RedactionReason::Anonymous),
+    ])]
+    #[case::nothing(config(&[], false), vec![])]
+    fn selects_regions_to_redact(
+        #[case] config: CoredumpRedactionConfig,
+        #[case] expected: Vec<RedactedRegion>,
+    ) {
+        let memory_maps = vec![
+            memory_map(0x1000, 0x2000, MMapPath::Heap),
+            memory_map(
+                0x3000,
+                0x4000,
+                MMapPath::Path("/dev/shm/secret-keys".into()),
+            ),
+            memory_map(0x4000, 0x5000, MMapPath::Path("/usr/bin/app".into())),
+            memory_map(0x5000, 0x6000, MMapPath::Anonymous),
+            // Stack of a thread:
+            memory_map(0x6000, 0x8000, MMapPath::Anonymous),
+        ];
+        let redactor = Redactor::new(config, 1).unwrap();
+
+        assert_eq!(redactor.redacted_regions(&memory_maps, &[0x7f00]), expected);
+    }
+
+    #[rstest]
+    fn loads_denylist_of_process() {
+        let tmpdir = tempdir().unwrap();
+        write(tmpdir.path().join("1234"), "0x1000 0x100\n").unwrap();
+        let config = |denylist_dir: PathBuf| CoredumpRedactionConfig {
+            denylist_dir: Some(denylist_dir),
+            ..config(&[], false)
+        };
+
+        let redactor = Redactor::new(config(tmpdir.path().to_owned()), 1234).unwrap();
+        assert_eq!(
+            redactor.redacted_regions(&[], &[]),
+            vec![RedactedRegion::new(
+                MemoryRange::new(0x1000, 0x1100),
+                RedactionReason::Denylist
+            )]
+        );
+
+        let redactor = Redactor::new(config(tmpdir.path().to_owned()), 5678).unwrap();
+        assert_eq!(redactor.redacted_regions(&[], &[]), vec![]);
+    }
+
+    #[rstest]
+    #[case::zero(CoredumpRedactionMode::Zero)]
+    #[case::omit(CoredumpRedactionMode::Omit)]
+    fn redacts_segments(#[case] mode: CoredumpRedactionMode) {
+        let segments = vec![
+            load_segment(0x1000, 0x3000, 0x3000),
+            load_segment(0x5000, 0x1000, 0x1000),
+            // Only the first page was dumped by the kernel:
+            load_segment(0x8000, 0x1000, 0x4000),
+        ];
+        let regions = vec![
+            RedactedRegion::new(MemoryRange::new(0x2000, 0x2800), RedactionReason::Denylist),
+            RedactedRegion::new(MemoryRange::new(0x2400, 0x3000), RedactionReason::Anonymous),
+            RedactedRegion::new(MemoryRange::new(0x8800, 0xa000), RedactionReason::Denylist),
+        ];
+
+        let (segments, redacted) = redact_segments(segments, &regions, mode);
+
+        set_snapshot_suffix!("{:?}", mode);
+        assert_debug_snapshot!((segments, redacted));
+    }
+
+    #[rstest]
+    fn writes_redacted_segments_without_padding() {
+        let segments = vec![load_segment(0x1000, 0x2000, 0x2000)];
+        let regions = vec![RedactedRegion::new(
+            MemoryRange::new(0x1800, 0x1900),
+            RedactionReason::Denylist,
+        )];
+        let (segments, _) = redact_segments(segments, &regions, CoredumpRedactionMode::Zero);
+        assert_eq!(segments.len(), 3);
+
+        let mut output = Vec::new();
+        let mut core_writer = CoreWriterImpl::new(
+            build_test_header(ELF_TARGET_CLASS, ELF_TARGET_ENDIANNESS, ELF_TARGET_MACHINE),
+            StreamPositionTracker::new(&mut output),
+            Cursor::new(vec![0xaa; 0x3000]),
+        );
+        for (ph, data) in segments {
+            core_writer.add_segment(ph, data);
+        }
+        core_writer.write().unwrap();
+
+        let program_headers = ProgramHeader::from_bytes(&output[SIZEOF_EHDR..], 3);
+        let first = &program_headers[0];
+        assert_eq!(first.p_offset % 0x1000, first.p_vaddr % 0x1000);
+        for ph in &program_headers {
+            assert_eq!(ph.p_offset - first.p_offset, ph.p_vaddr - first.p_vaddr);
+        }
+        assert_eq!(output.len(), first.p_offset as usize + 0x2000);
+    }
+}
@@ -0,0 +1,1169 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! On-device stack unwinding
+//!
+//! Unwinds the stack of each thread of the crashed process, starting from the registers of its
+//! NT_PRSTATUS note and reading the process memory. The `.eh_frame` call frame information of
+//! the mapped ELF files is used when available (located through their `.eh_frame_hdr` search
+//! table), otherwise the frame pointer chain is followed.
+//!
+//! The resulting `StackTrace` is not symbolicated, but contains the build IDs and offsets needed
+//! to symbolicate it with the debug symbols of the modules.
+//!
+// Casts from ElfPtrSize are needed on 32-bit systems.
+#![allow(clippy::unnecessary_cast)]
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::mem::size_of;
+
+use elf::header::ET_DYN;
+use elf::program_header::{PT_GNU_EH_FRAME, PT_LOAD, PT_NOTE};
+use eyre::{eyre, Result};
+use log::{debug, trace};
+use procfs::process::{MMapPath, MemoryMap};
+use scroll::{Pread, Sleb128, Uleb128};
+
+use crate::cli::memfault_core_handler::arch::{
+    get_unwind_registers, UnwindRegisters, DWARF_FP, DWARF_RETURN_ADDRESS, DWARF_SP,
+    FRAME_POINTER_LAYOUT,
+};
+use crate::cli::memfault_core_handler::core_elf_note::{iterate_elf_notes, ElfNote};
+use crate::cli::memfault_core_handler::core_reader::{CoreReader, CoreReaderImpl};
+use crate::cli::memfault_core_handler::{elf, ElfPtrSize};
+use crate::mar::{StackFrame, StackTrace, StackTraceModule, ThreadStackTrace};
+
+const WORD_SIZE: u64 = size_of::<ElfPtrSize>() as u64;
+const ADDRESS_MASK: u64 = ElfPtrSize::MAX as u64;
+
+/// Upper bound of the size of a CIE or FDE, to avoid huge reads when the data is corrupted.
+const MAX_CFI_ENTRY_SIZE: u64 = 64 * 1024;
+
+/// Upper bound of the size of a PT_NOTE segment, which is read from the program headers of the
+/// mapped ELF files and may be corrupted as well. Build ID notes are much smaller.
+const MAX_NOTE_SEGMENT_SIZE: u64 = 64 * 1024;
+
+// Pointer encodings, see https://refspecs.linuxfoundation.org/LSB_5.0.0/LSB-Core-generic/LSB-Core-generic/dwarfext.html
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_ULEB128: u8 = 0x01;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SLEB128: u8 = 0x09;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+const DW_EH_PE_PCREL: u8 = 0x10;
+const DW_EH_PE_DATAREL: u8 = 0x30;
+const DW_EH_PE_INDIRECT: u8 = 0x80;
+
+/// Unwinds the stacks of all the threads of `notes` (the crashed thread first).
+pub fn unwind_stack_trace<P: Read + Seek>(
+    proc_mem_stream: &mut P,
+    memory_maps: &[MemoryMap],
+    notes: &[ElfNote],
+    cmdline: &str,
+    max_frames: usize,
+) -> StackTrace {
+    let mut unwinder = StackUnwinder::new(proc_mem_stream, memory_maps, max_frames);
+
+    let mut signal = None;
+    let mut threads = Vec::new();
+    for note in notes {
+        if let ElfNote::ProcessStatus(status) = note {
+            let crashed = threads.is_empty();
+            if crashed {
+                signal = match (status.si_signo, status.pr_cursig) {
+                    (0, 0) => None,
+                    (0, cursig) => Some(cursig as u32),
+                    (signo, _) => Some(signo),
+                };
+            }
+            let frames = match get_unwind_registers(&status.pr_reg) {
+                Some(registers) => unwinder.unwind(registers),
+                None => vec![],
+            };
+            threads.push(ThreadStackTrace {
+                tid: status.pr_pid,
+                crashed,
+                frames,
+            });
+        }
+    }
+
+    StackTrace {
+        cmdline: cmdline.trim_end_matches('\0').replace('\0', " "),
+        signal,
+        modules: unwinder
+            .modules
+            .iter()
+            .map(|module| StackTraceModule {
+                path: module.path.clone(),
+                build_id: module
+                    .build_id
+                    .as_ref()
+                    .map(|id| id.iter().map(|b| format!("{:02x}", b)).collect()),
+                load_address: module.start,
+            })
+            .collect(),
+        threads,
+    }
+}
+
+/// An ELF file mapped in the process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Module {
+    path: String,
+    start: u64,
+    end: u64,
+    /// Difference between the runtime addresses and the virtual addresses of the ELF file.
+    bias: u64,
+    build_id: Option<Vec<u8>>,
+    eh_frame_hdr: Option<u64>,
+}
+
+/// Register values of a frame, by DWARF register number.
+#[derive(Debug, Clone)]
+struct FrameRegisters {
+    pc: u64,
+    registers: HashMap<u16, u64>,
+}
+
+impl FrameRegisters {
+    fn sp(&self) -> Option<u64> {
+        self.registers.get(&DWARF_SP).copied()
+    }
+}
+
+impl From<UnwindRegisters> for FrameRegisters {
+    fn from(registers: UnwindRegisters) -> Self {
+        let mut values = HashMap::from([
+            (DWARF_SP, registers.sp as u64),
+            (DWARF_FP, registers.fp as u64),
+        ]);
+        if let Some(lr) = registers.lr {
+            values.insert(DWARF_RETURN_ADDRESS, lr as u64);
+        }
+        Self {
+            pc: registers.pc as u64,
+            registers: values,
+        }
+    }
+}
+
+struct StackUnwinder<'a, P: Read + Seek> {
+    proc_mem_stream: &'a mut P,
+    modules: Vec<Module>,
+    max_frames: usize,
+}
+
+impl<'a, P: Read + Seek> StackUnwinder<'a, P> {
+    fn new(proc_mem_stream: &'a mut P, memory_maps: &[MemoryMap], max_frames: usize) -> Self {
+        let mut unwinder = Self {
+            proc_mem_stream,
+            modules: vec![],
+            max_frames,
+        };
+        unwinder.modules = memory_maps
+            .iter()
+            .filter(|mmap| mmap.offset == 0)
+            .filter_map(|mmap| {
+                let path = match &mmap.pathname {
+                    MMapPath::Path(path) => path.to_string_lossy().into_owned(),
+                    MMapPath::Vdso => "[vdso]".to_string(),
+                    _ => return None,
+                };
+                let end = memory_maps
+                    .iter()
+                    .filter(|m| m.pathname == mmap.pathname && m.address.0 >= mmap.address.0)
+                    .map(|m| m.address.1)
+                    .max()
+                    .unwrap_or(mmap.address.1);
+                match unwinder.read_module(path, mmap.address.0, end) {
+                    Ok(module) => Some(module),
+                    Err(e) => {
+                        debug!("Not an ELF module {:?}: {}", mmap.pathname, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        unwinder
+    }
+
+    fn read_module(&mut self, path: String, start: u64, end: u64) -> Result<Module> {
+        self.proc_mem_stream.seek(SeekFrom::Start(start))?;
+        let mut elf_reader = CoreReaderImpl::new(&mut *self.proc_mem_stream)?;
+        let elf_header = elf_reader.elf_header();
+        let program_headers = elf_reader.read_program_headers()?;
+
+        let bias = match program_headers.iter().find(|ph| ph.p_type == PT_LOAD) {
+            Some(ph) if elf_header.e_type == ET_DYN => start
+                .wrapping_add(ph.p_offset as u64)
+                .wrapping_sub(ph.p_vaddr as u64),
+            _ => 0,
+        };
+
+        let mut build_id = None;
+        for ph in program_headers.iter().filter(|ph| ph.p_type == PT_NOTE) {
+            if ph.p_filesz as u64 > MAX_NOTE_SEGMENT_SIZE {
+                debug!("Skipping note segment of {} bytes in {}", ph.p_filesz, path);
+                continue;
+            }
+            let data = self.read_bytes(bias.wrapping_add(ph.p_vaddr as u64), ph.p_filesz as u64);
+            if let Some(id) = data.ok().and_then(|data| {
+                iterate_elf_notes(&data).find_map(|note| match note {
+                    ElfNote::GnuBuildId(id) => Some(id.to_vec()),
+                    _ => None,
+                })
+            }) {
+                build_id = Some(id);
+                break;
+            }
+        }
+
+        let eh_frame_hdr = program_headers
+            .iter()
+            .find(|ph| ph.p_type == PT_GNU_EH_FRAME)
+            .map(|ph| bias.wrapping_add(ph.p_vaddr as u64));
+
+        Ok(Module {
+            path,
+            start,
+            end,
+            bias,
+            build_id,
+            eh_frame_hdr,
+        })
+    }
+
+    fn unwind(&mut self, registers: UnwindRegisters) -> Vec<StackFrame> {
+        let mut frames = Vec::new();
+        let mut frame = FrameRegisters::from(registers);
+
+        while frames.len() < self.max_frames {
+            frames.push(self.stack_frame(frame.pc));
+
+            // Return addresses point after the call instruction, which may be the first
+            // instruction of another function: look up the call instruction instead.
+            let lookup_pc = if frames.len() == 1 {
+                frame.pc
+            } else {
+                frame.pc.wrapping_sub(1)
+            };
+            let caller = match self.step_with_cfi(&frame, lookup_pc) {
+                Ok(caller) => caller,
+                Err(e) => {
+                    trace!("No CFI for pc {:#x} ({}), using frame pointer", frame.pc, e);
+                    self.step_with_frame_pointer(&frame).unwrap_or(None)
+                }
+            };
+
+            match caller {
+                Some(caller) if caller.pc != 0 && is_caller_stack(&frame, &caller) => {
+                    frame = caller
+                }
+                _ => break,
+            }
+        }
+        frames
+    }
+
+    fn stack_frame(&self, pc: u64) -> StackFrame {
+        match self.find_module(pc) {
+            Some(index) => StackFrame {
+                pc,
+                module: Some(index),
+                module_offset: Some(pc.wrapping_sub(self.modules[index].bias)),
+            },
+            None => StackFrame {
+                pc,
+                module: None,
+                module_offset: None,
+            },
+        }
+    }
+
+    fn find_module(&self, pc: u64) -> Option<usize> {
+        self.modules
+            .iter()
+            .position(|module| module.start <= pc && pc < module.end)
+    }
+
+    /// Computes the registers of the caller with the call frame information of `lookup_pc`.
+    /// Returns `None` at the outermost frame.
+    fn step_with_cfi(
+        &mut self,
+        frame: &FrameRegisters,
+        lookup_pc: u64,
+    ) -> Result<Option<FrameRegisters>> {
+        let eh_frame_hdr = self
+            .find_module(lookup_pc)
+            .and_then(|index| self.modules[index].eh_frame_hdr)
+            .ok_or_else(|| eyre!("No .eh_frame_hdr"))?;
+        let fde_address = self.find_fde(eh_frame_hdr, lookup_pc)?;
+        let (cie, fde) = self.read_fde(fde_address, eh_frame_hdr)?;
+        if lookup_pc < fde.pc_begin || lookup_pc >= fde.pc_begin.wrapping_add(fde.pc_range) {
+            return Err(eyre!("No FDE covers pc"));
+        }
+        let row = execute_cfi(&cie, &fde, lookup_pc, eh_frame_hdr)?;
+
+        let cfa_base = frame
+            .registers
+            .get(&row.cfa_register)
+            .ok_or_else(|| eyre!("Unknown CFA register {}", row.cfa_register))?;
+        let cfa = cfa_base.wrapping_add(row.cfa_offset as u64) & ADDRESS_MASK;
+
+        let mut registers = frame.registers.clone();
+        registers.insert(DWARF_SP, cfa);
+        for (register, rule) in &row.rules {
+            let value = match rule {
+                RegisterRule::Undefined => None,
+                RegisterRule::SameValue => continue,
+                RegisterRule::Offset(offset) => {
+                    Some(self.read_word(cfa.wrapping_add(*offset as u64))?)
+                }
+                RegisterRule::ValOffset(offset) => {
+                    Some(cfa.wrapping_add(*offset as u64) & ADDRESS_MASK)
+                }
+                RegisterRule::Register(other) => frame.registers.get(other).copied(),
+            };
+            match value {
+                Some(value) => registers.insert(*register, value),
+                None => registers.remove(register),
+            };
+        }
+
+        Ok(registers
+            .get(&cie.return_address_register)
+            .copied()
+            .map(|pc| FrameRegisters { pc, registers }))
+    }
+
+    /// Computes the registers of the caller from the frame record pointed to by the frame pointer.
+    fn step_with_frame_pointer(
+        &mut self,
+        frame: &FrameRegisters,
+    ) -> Result<Option<FrameRegisters>> {
+        let fp = match frame.registers.get(&DWARF_FP) {
+            Some(fp) if *fp != 0 => *fp,
+            _ => return Ok(None),
+        };
+        let (fp_slot, return_address_slot) = FRAME_POINTER_LAYOUT;
+        let saved_fp =
+            self.read_word(fp.wrapping_add((fp_slot as i64 * WORD_SIZE as i64) as u64))?;
+        let return_address = self
+            .read_word(fp.wrapping_add((return_address_slot as i64 * WORD_SIZE as i64) as u64))?;
+
+        let mut registers = frame.registers.clone();
+        registers.insert(DWARF_FP, saved_fp);
+        registers.insert(
+            DWARF_SP,
+            fp.wrapping_add((return_address_slot as i64 + 1) as u64 * WORD_SIZE) & ADDRESS_MASK,
+        );
+        registers.insert(DWARF_RETURN_ADDRESS, return_address);
+        Ok(Some(FrameRegisters {
+            pc: return_address,
+            registers,
+        }))
+    }
+
+    /// Binary searches the `.eh_frame_hdr` table for the FDE of `pc`.
+    fn find_fde(&mut self, eh_frame_hdr: u64, pc: u64) -> Result<u64> {
+        // version, eh_frame_ptr_enc, fde_count_enc, table_enc, then both encoded values
+        let header = self.read_bytes(eh_frame_hdr, 4 + 2 * 8)?;
+        if header[0] != 1 {
+            return Err(eyre!("Unsupported .eh_frame_hdr version {}", header[0]));
+        }
+        let (eh_frame_ptr_encoding, fde_count_encoding, table_encoding) =
+            (header[1], header[2], header[3]);
+        // Only the encoding emitted by the linkers is supported: 32-bit offsets from the header.
+        if table_encoding != DW_EH_PE_DATAREL | DW_EH_PE_SDATA4 {
+            return Err(eyre!("Unsupported .eh_frame_hdr table encoding"));
+        }
+        let mut offset = 4;
+        read_encoded_pointer(
+            &header,
+            &mut offset,
+            eh_frame_ptr_encoding,
+            eh_frame_hdr,
+            eh_frame_hdr,
+        )?;
+        let fde_count = read_encoded_pointer(
+            &header,
+            &mut offset,
+            fde_count_encoding,
+            eh_frame_hdr,
+            eh_frame_hdr,
+        )?;
+        let table = eh_frame_hdr + offset as u64;
+
+        let (mut low, mut high) = (0, fde_count);
+        let mut fde_address = None;
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let entry = self.read_bytes(table + middle * 8, 8)?;
+            let initial_location =
+                eh_frame_hdr.wrapping_add(entry.pread::<i32>(0)? as i64 as u64) & ADDRESS_MASK;
+            if initial_location <= pc {
+                fde_address = Some(
+                    eh_frame_hdr.wrapping_add(entry.pread::<i32>(4)? as i64 as u64) & ADDRESS_MASK,
+                );
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        fde_address.ok_or_else(|| eyre!("No FDE for pc {:#x}", pc))
+    }
+
+    fn read_fde(&mut self, fde_address: u64, eh_frame_hdr: u64) -> Result<(Cie, Fde)> {
+        let (data, data_address) = self.read_cfi_entry(fde_address)?;
+        let cie_pointer = data.pread::<u32>(0)? as u64;
+        if cie_pointer == 0 {
+            return Err(eyre!("Expected an FDE at {:#x}, found a CIE", fde_address));
+        }
+        let (cie_data, cie_address) =
+            self.read_cfi_entry(data_address.wrapping_sub(cie_pointer))?;
+        let cie = Cie::parse(&cie_data, cie_address, eh_frame_hdr)?;
+        let fde = Fde::parse(&data, data_address, &cie, eh_frame_hdr)?;
+        Ok((cie, fde))
+    }
+
+    /// Reads the CIE or FDE at `address`, returning its contents after the length field and
+    /// their address.
+    fn read_cfi_entry(&mut self, address: u64) -> Result<(Vec<u8>, u64)> {
+        let (length, data_address) = match self.read_bytes(address, 4)?.pread::<u32>(0)? {
+            0 => return Err(eyre!("Empty CFI entry at {:#x}", address)),
+            0xffff_ffff => (
+                self.read_bytes(address + 4, 8)?.pread::<u64>(0)?,
+                address + 12,
+            ),
+            length => (length as u64, address + 4),
+        };
+        if length > MAX_CFI_ENTRY_SIZE {
+            return Err(eyre!("CFI entry at {:#x} is too large", address));
+        }
+        Ok((self.read_bytes(data_address, length)?, data_address))
+    }
+
+    fn read_word(&mut self, address: u64) -> Result<u64> {
+        Ok(self
+            .read_bytes(address & ADDRESS_MASK, WORD_SIZE)?
+            .pread::<ElfPtrSize>(0)? as u64)
+    }
+
+    fn read_bytes(&mut self, address: u64, size: u64) -> Result<Vec<u8>> {
+        self.proc_mem_stream.seek(SeekFrom::Start(address))?;
+        let mut buf = vec![0; size as usize];
+        self.proc_mem_stream.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// The stack grows down: the frame of the caller must be above the frame of the callee.
+fn is_caller_stack(callee: &FrameRegisters, caller: &FrameRegisters) -> bool {
+    match (callee.sp(), caller.sp()) {
+        (Some(callee_sp), Some(caller_sp)) => {
+            caller_sp > callee_sp || (caller_sp == callee_sp && caller.pc != callee.pc)
+        }
+        _ => false,
+    }
+}
+
+/// Common Information Entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cie {
+    code_alignment_factor: u64,
+    data_alignment_factor: i64,
+    return_address_register: u16,
+    fde_pointer_encoding: u8,
+    has_augmentation_data: bool,
+    instructions: Vec<u8>,
+}
+
+impl Cie {
+    fn parse(data: &[u8], address: u64, eh_frame_hdr: u64) -> Result<Self> {
+        let mut offset = 0;
+        if data.gread::<u32>(&mut offset)? != 0 {
+            return Err(eyre!("Expected a CIE at {:#x}", address));
+        }
+        let version = data.gread::<u8>(&mut offset)?;
+        let augmentation = data.gread::<&str>(&mut offset)?;
+        if version >= 4 {
+            // address_size and segment_selector_size
+            offset += 2;
+        }
+        let code_alignment_factor = Uleb128::read(data, &mut offset)?;
+        let data_alignment_factor = Sleb128::read(data, &mut offset)?;
+        let return_address_register = if version == 1 {
+            data.gread::<u8>(&mut offset)? as u16
+        } else {
+            Uleb128::read(data, &mut offset)? as u16
+        };
+
+        let mut fde_pointer_encoding = DW_EH_PE_ABSPTR;
+        let has_augmentation_data = augmentation.starts_with('z');
+        if has_augmentation_data {
+            let augmentation_length = Uleb128::read(data, &mut offset)? as usize;
+            let instructions_offset = offset + augmentation_length;
+            for c in augmentation.chars().skip(1) {
+                match c {
+                    'L' => offset += 1,
+                    'P' => {
+                        let encoding = data.gread::<u8>(&mut offset)?;
+                        read_encoded_pointer(data, &mut offset, encoding, address, eh_frame_hdr)?;
+                    }
+                    'R' => fde_pointer_encoding = data.gread::<u8>(&mut offset)?,
+                    'S' | 'B' => {}
+                    _ => break,
+                }
+            }
+            offset = instructions_offset;
+        } else if !augmentation.is_empty() {
+            return Err(eyre!("Unsupported CIE augmentation {:?}", augmentation));
+        }
+
+        Ok(Self {
+            code_alignment_factor,
+            data_alignment_factor,
+            return_address_register,
+            fde_pointer_encoding,
+            has_augmentation_data,
+            instructions: data
+                .get(offset..)
+                .ok_or_else(|| eyre!("Truncated CIE"))?
+                .to_vec(),
+        })
+    }
+}
+
+/// Frame Description Entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Fde {
+    pc_begin: u64,
+    pc_range: u64,
+    instructions: Vec<u8>,
+}
+
+impl Fde {
+    fn parse(data: &[u8], address: u64, cie: &Cie, eh_frame_hdr: u64) -> Result<Self> {
+        // Skip the CIE pointer
+        let mut offset = 4;
+        let pc_begin = read_encoded_pointer(
+            data,
+            &mut offset,
+            cie.fde_pointer_encoding,
+            address,
+            eh_frame_hdr,
+        )?;
+        // The range is encoded with the same format, but is never relative.
+        let pc_range = read_encoded_pointer(
+            data,
+            &mut offset,
+            cie.fde_pointer_encoding & 0x0f,
+            address,
+            eh_frame_hdr,
+        )?;
+        if cie.has_augmentation_data {
+            offset += Uleb128::read(data, &mut offset)? as usize;
+        }
+        Ok(Self {
+            pc_begin,
+            pc_range,
+            instructions: data
+                .get(offset..)
+                .ok_or_else(|| eyre!("Truncated FDE"))?
+                .to_vec(),
+        })
+    }
+}
+
+/// Reads a pointer of `data` (located at `address`) encoded with the `DW_EH_PE_*` `encoding`.
+fn read_encoded_pointer(
+    data: &[u8],
+    offset: &mut usize,
+    encoding: u8,
+    address: u64,
+    eh_frame_hdr: u64,
+) -> Result<u64> {
+    if encoding == DW_EH_PE_OMIT {
+        return Err(eyre!("Omitted pointer"));
+    }
+    let field_address = address + *offset as u64;
+    let value = match encoding & 0x0f {
+        DW_EH_PE_ABSPTR => data.gread::<ElfPtrSize>(offset)? as u64,
+        DW_EH_PE_ULEB128 => Uleb128::read(data, offset)?,
+        DW_EH_PE_UDATA2 => data.gread::<u16>(offset)? as u64,
+        DW_EH_PE_UDATA4 => data.gread::<u32>(offset)? as u64,
+        DW_EH_PE_UDATA8 => data.gread::<u64>(offset)?,
+        DW_EH_PE_SLEB128 => Sleb128::read(data, offset)? as u64,
+        DW_EH_PE_SDATA2 => data.gread::<i16>(offset)? as i64 as u64,
+        DW_EH_PE_SDATA4 => data.gread::<i32>(offset)? as i64 as u64,
+        DW_EH_PE_SDATA8 => data.gread::<i64>(offset)? as u64,
+        _ => return Err(eyre!("Unsupported pointer encoding {:#x}", encoding)),
+    };
+    // Indirect pointers are only used for personality routines, which are not needed to unwind.
+    let base = match encoding & 0x70 & !DW_EH_PE_INDIRECT {
+        0 => 0,
+        DW_EH_PE_PCREL => field_address,
+        DW_EH_PE_DATAREL => eh_frame_hdr,
+        _ => return Err(eyre!("Unsupported pointer encoding {:#x}", encoding)),
+    };
+    Ok(base.wrapping_add(value) & ADDRESS_MASK)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RegisterRule {
+    Undefined,
+    SameValue,
+    /// Saved at CFA + offset
+    Offset(i64),
+    /// Value is CFA + offset
+    ValOffset(i64),
+    /// Saved in another register
+    Register(u16),
+}
+
+/// The rules to compute the CFA (Canonical Frame Address) and the registers of the caller.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct UnwindRow {
+    cfa_register: u16,
+    cfa_offset: i64,
+    rules: HashMap<u16, RegisterRule>,
+}
+
+/// Executes the CFA instructions of the CIE and FDE up to `pc`.
+fn execute_cfi(cie: &Cie, fde: &Fde, pc: u64, eh_frame_hdr: u64) -> Result<UnwindRow> {
+    let mut row = UnwindRow::default();
+    let mut location = fde.pc_begin;
+    execute_cfa_instructions(
+        &cie.instructions,
+        cie,
+        None,
+        &mut row,
+        &mut location,
+        u64::MAX,
+        eh_frame_hdr,
+    )?;
+    let initial_rules = row.rules.clone();
+    execute_cfa_instructions(
+        &fde.instructions,
+        cie,
+        Some(&initial_rules),
+        &mut row,
+        &mut location,
+        pc,
+        eh_frame_hdr,
+    )?;
+    Ok(row)
+}
+
+fn execute_cfa_instructions(
+    instructions: &[u8],
+    cie: &Cie,
+    initial_rules: Option<&HashMap<u16, RegisterRule>>,
+    row: &mut UnwindRow,
+    location: &mut u64,
+    pc: u64,
+    eh_frame_hdr: u64,
+) -> Result<()> {
+    let mut remembered_rows = Vec::new();
+    let mut offset = 0;
+    let caf = cie.code_alignment_factor;
+    let daf = cie.data_alignment_factor;
+
+    let uleb = |offset: &mut usize| -> Result<u64> { Ok(Uleb128::read(instructions, offset)?) };
+    let sleb = |offset: &mut usize| -> Result<i64> { Ok(Sleb128::read(instructions, offset)?) };
+    let register = |offset: &mut usize| -> Result<u16> { Ok(uleb(offset)? as u16) };
+
+    while offset < instructions.len() {
+        let opcode = instructions.gread::<u8>(&mut offset)?;
+        let operand = opcode & 0x3f;
+        let advance = match opcode & 0xc0 {
+            // DW_CFA_advance_loc
+            0x40 => Some(operand as u64 * caf),
+            // DW_CFA_offset
+            0x80 => {
+                let factored_offset = uleb(&mut offset)? as i64;
+                row.rules
+                    .insert(operand as u16, RegisterRule::Offset(factored_offset * daf));
+                None
+            }
+            // DW_CFA_restore
+            0xc0 => {
+                restore_rule(row, initial_rules, operand as u16);
+                None
+            }
+            _ => match opcode {
+                // DW_CFA_nop
+                0x00 => None,
+                // DW_CFA_set_loc
+                0x01 => {
+                    let new_location = read_encoded_pointer(
+                        instructions,
+                        &mut offset,
+                        cie.fde_pointer_encoding,
+                        0,
+                        eh_frame_hdr,
+                    )?;
+                    Some(new_location.wrapping_sub(*location))
+                }
+                // DW_CFA_advance_loc1, 2 and 4
+                0x02 => Some(instructions.gread::<u8>(&mut offset)? as u64 * caf),
+                0x03 => Some(instructions.gread::<u16>(&mut offset)? as u64 * caf),
+                0x04 => Some(instructions.gread::<u32>(&mut offset)? as u64 * caf),
+                // DW_CFA_offset_extended
+                0x05 => {
+                    let reg = register(&mut offset)?;
+                    let factored_offset = uleb(&mut offset)? as i64;
+                    row.rules
+                        .insert(reg, RegisterRule::Offset(factored_offset * daf));
+                    None
+                }
+                // DW_CFA_restore_extended
+                0x06 => {
+                    let reg = register(&mut offset)?;
+                    restore_rule(row, initial_rules, reg);
+                    None
+                }
+                // DW_CFA_undefined
+                0x07 => {
+                    row.rules
+                        .insert(register(&mut offset)?, RegisterRule::Undefined);
+                    None
+                }
+                // DW_CFA_same_value
+                0x08 => {
+                    row.rules
+                        .insert(register(&mut offset)?, RegisterRule::SameValue);
+                    None
+                }
+                // DW_CFA_register
+                0x09 => {
+                    let reg = register(&mut offset)?;
+                    let other = register(&mut offset)?;
+                    row.rules.insert(reg, RegisterRule::Register(other));
+                    None
+                }
+                // DW_CFA_remember_state
+                0x0a => {
+                    remembered_rows.push(row.clone());
+                    None
+                }
+                // DW_CFA_restore_state
+                0x0b => {
+                    *row = remembered_rows
+                        .pop()
+                        .ok_or_else(|| eyre!("DW_CFA_restore_state without remembered state"))?;
+                    None
+                }
+                // DW_CFA_def_cfa
+                0x0c => {
+                    row.cfa_register = register(&mut offset)?;
+                    row.cfa_offset = uleb(&mut offset)? as i64;
+                    None
+                }
+                // DW_CFA_def_cfa_register
+                0x0d => {
+                    row.cfa_register = register(&mut offset)?;
+                    None
+                }
+                // DW_CFA_def_cfa_offset
+                0x0e => {
+                    row.cfa_offset = uleb(&mut offset)? as i64;
+                    None
+                }
+                // DW_CFA_offset_extended_sf
+                0x11 => {
+                    let reg = register(&mut offset)?;
+                    let factored_offset = sleb(&mut offset)?;
+                    row.rules
+                        .insert(reg, RegisterRule::Offset(factored_offset * daf));
+                    None
+                }
+                // DW_CFA_def_cfa_sf
+                0x12 => {
+                    row.cfa_register = register(&mut offset)?;
+                    row.cfa_offset = sleb(&mut offset)? * daf;
+                    None
+                }
+                // DW_CFA_def_cfa_offset_sf
+                0x13 => {
+                    row.cfa_offset = sleb(&mut offset)? * daf;
+                    None
+                }
+                // DW_CFA_val_offset
+                0x14 => {
+                    let reg = register(&mut offset)?;
+                    let factored_offset = uleb(&mut offset)? as i64;
+                    row.rules
+                        .insert(reg, RegisterRule::ValOffset(factored_offset * daf));
+                    None
+                }
+                // DW_CFA_val_offset_sf
+                0x15 => {
+                    let reg = register(&mut offset)?;
+                    let factored_offset = sleb(&mut offset)?;
+                    row.rules
+                        .insert(reg, RegisterRule::ValOffset(factored_offset * daf));
+                    None
+                }
+                // DW_CFA_AARCH64_negate_ra_state: return addresses are signed with pointer
+                // authentication, which does not change where they are saved.
+                0x2d => None,
+                // DW_CFA_GNU_args_size
+                0x2e => {
+                    uleb(&mut offset)?;
+                    None
+                }
+                // DW_CFA_GNU_negative_offset_extended
+                0x2f => {
+                    let reg = register(&mut offset)?;
+                    let factored_offset = uleb(&mut offset)? as i64;
+                    row.rules
+                        .insert(reg, RegisterRule::Offset(-factored_offset * daf));
+                    None
+                }
+                // DW_CFA_def_cfa_expression, DW_CFA_expression and DW_CFA_val_expression
+                0x0f | 0x10 | 0x16 => {
+                    return Err(eyre!("DWARF expressions are not supported"));
+                }
+                _ => return Err(eyre!("Unknown CFA instruction {:#x}", opcode)),
+            },
+        };
+
+        if let Some(delta) = advance {
+            *location = location.wrapping_add(delta);
+            if *location > pc {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn restore_rule(
+    row: &mut UnwindRow,
+    initial_rules: Option<&HashMap<u16, RegisterRule>>,
+    register: u16,
+) {
+    match initial_rules.and_then(|rules| rules.get(&register)) {
+        Some(rule) => row.rules.insert(register, *rule),
+        None => row.rules.remove(&register),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::{Error, ErrorKind};
+    use std::path::PathBuf;
+
+    use insta::assert_debug_snapshot;
+    use procfs::process::Process;
+    use rstest::rstest;
+
+    use crate::cli::memfault_core_handler::arch::{
+        ELF_TARGET_CLASS, ELF_TARGET_ENDIANNESS, ELF_TARGET_MACHINE,
+    };
+    use crate::cli::memfault_core_handler::core_reader::CachedCoreReader;
+    use crate::cli::memfault_core_handler::elf::header::SIZEOF_EHDR;
+    use crate::cli::memfault_core_handler::elf::program_header::{ProgramHeader, SIZEOF_PHDR};
+    use crate::cli::memfault_core_handler::procfs::ProcMaps;
+    use crate::cli::memfault_core_handler::test_utils::{
+        build_test_header, FakeProcMaps, FakeProcMem,
+    };
+    use scroll::Pwrite;
+
+    use super::*;
+
+    /// Memory made of a few regions at arbitrary addresses.
+    struct SparseMemory {
+        regions: Vec<(u64, Vec<u8>)>,
+        position: u64,
+    }
+
+    impl SparseMemory {
+        fn new(regions: Vec<(u64, Vec<u8>)>) -> Self {
+            Self {
+                regions,
+                position: 0,
+            }
+        }
+    }
+
+    impl Read for SparseMemory {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let position = self.position;
+            let (start, data) = self
+                .regions
+                .iter()
+                .find(|(start, data)| *start <= position && position < start + data.len() as u64)
+                .ok_or_else(|| Error::new(ErrorKind::Other, "Unmapped address"))?;
+            let available = &data[(position - start) as usize..];
+            let size = buf.len().min(available.len());
+            buf[..size].copy_from_slice(&available[..size]);
+            self.position += size as u64;
+            Ok(size)
+        }
+    }
+
+    impl Seek for SparseMemory {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            match pos {
+                SeekFrom::Start(position) => self.position = position,
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "only SeekFrom::Start is supported",
+                    ))
+                }
+            }
+            Ok(self.position)
+        }
+    }
+
+    fn words(values: &[u64]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|v| (*v as ElfPtrSize).to_ne_bytes())
+            .collect()
+    }
+
+    fn cie(instructions: &[u8]) -> Cie {
+        Cie {
+            code_alignment_factor: 1,
+            data_alignment_factor: -8,
+            return_address_register: 16,
+            fde_pointer_encoding: DW_EH_PE_PCREL | DW_EH_PE_SDATA4,
+            has_augmentation_data: true,
+            instructions: instructions.to_vec(),
+        }
+    }
+
+    fn fde(instructions: &[u8]) -> Fde {
+        Fde {
+            pc_begin: 0x1000,
+            pc_range: 0x100,
+            instructions: instructions.to_vec(),
+        }
+    }
+
+    #[rstest]
+    // Typical x86_64 prologue: push %rbp; mov %rsp,%rbp
+    #[case::before_prologue(0x1000, 7, 8, &[(16, RegisterRule::Offset(-8))])]
+    #[case::after_push(0x1001, 7, 16, &[(16, RegisterRule::Offset(-8)), (6, RegisterRule::Offset(-16))])]
+    #[case::after_mov(0x1004, 6, 16, &[(16, RegisterRule::Offset(-8)), (6, RegisterRule::Offset(-16))])]
+    #[case::epilogue(0x1014, 7, 8, &[(16, RegisterRule::Offset(-8))])]
+    #[case::after_epilogue(0x1020, 6, 16, &[(16, RegisterRule::Offset(-8)), (6, RegisterRule::Offset(-16))])]
+    fn executes_cfa_instructions(
+        #[case] pc: u64,
+        #[case] cfa_register: u16,
+        #[case] cfa_offset: i64,
+        #[case] rules: &[(u16, RegisterRule)],
+    ) {
+        // DW_CFA_def_cfa: r7 (rsp) ofs 8; DW_CFA_offset: r16 (rip) at cfa-8
+        let cie = cie(&[0x0c, 0x07, 0x08, 0x90, 0x01]);
+        let fde = fde(&[
+            // DW_CFA_advance_loc: 1; DW_CFA_def_cfa_offset: 16; DW_CFA_offset: r6 (rbp) at cfa-16
+            0x41, 0x0e, 0x10, 0x86, 0x02,
+            // DW_CFA_advance_loc: 3; DW_CFA_def_cfa_register: r6 (rbp)
+            0x43, 0x0d, 0x06,
+            // DW_CFA_advance_loc: 0x10; DW_CFA_remember_state; DW_CFA_def_cfa: r7 (rsp) ofs 8;
+            // DW_CFA_restore: r6 (rbp)
+            0x50, 0x0a, 0x0c, 0x07, 0x08, 0xc6,
+            // DW_CFA_advance_loc: 1; DW_CFA_restore_state
+            0x41, 0x0b,
+        ]);
+
+        let row = execute_cfi(&cie, &fde, pc, 0).unwrap();
+
+        assert_eq!(
+            row,
+            UnwindRow {
+                cfa_register,
+                cfa_offset,
+                rules: rules.iter().copied().collect(),
+            }
+        );
+    }
+
+    #[rstest]
+    fn rejects_dwarf_expressions() {
+        // DW_CFA_def_cfa_expression
+        let cie = cie(&[0x0f, 0x01, 0x00]);
+        assert!(execute_cfi(&cie, &fde(&[]), 0x1000, 0).is_err());
+    }
+
+    #[rstest]
+    #[case::sdata4_pcrel(DW_EH_PE_PCREL | DW_EH_PE_SDATA4, &(-0x10i32).to_ne_bytes(), 0x2000 + 0x4 - 0x10)]
+    #[case::sdata4_datarel(DW_EH_PE_DATAREL | DW_EH_PE_SDATA4, &0x20i32.to_ne_bytes(), 0x3000 + 0x20)]
+    #[case::udata2(DW_EH_PE_UDATA2, &0x1234u16.to_ne_bytes(), 0x1234)]
+    #[case::uleb128(DW_EH_PE_ULEB128, &[0xe5, 0x8e, 0x26], 624485)]
+    fn reads_encoded_pointers(#[case] encoding: u8, #[case] bytes: &[u8], #[case] expected: u64) {
+        let mut data = vec![0xaa; 4];
+        data.extend_from_slice(bytes);
+        let mut offset = 4;
+        assert_eq!(
+            read_encoded_pointer(&data, &mut offset, encoding, 0x2000, 0x3000).unwrap(),
+            expected
+        );
+        assert_eq!(offset, data.len());
+    }
+
+    /// A frame record at `address` (see FRAME_POINTER_LAYOUT), and the frame pointer to it.
+    fn frame_record(address: u64, saved_fp: u64, return_address: u64) -> ((u64, Vec<u8>), u64) {
+        let (fp_slot, return_address_slot) = FRAME_POINTER_LAYOUT;
+        let first_slot = fp_slot.min(return_address_slot);
+        let mut record = [0; 2];
+        record[(fp_slot - first_slot) as usize] = saved_fp;
+        record[(return_address_slot - first_slot) as usize] = return_address;
+        (
+            (address, words(&record)),
+            address + (-first_slot) as u64 * WORD_SIZE,
+        )
+    }
+
+    #[rstest]
+    #[case::all_frames(16, vec![0x4000, 0x4010, 0x4020])]
+    #[case::max_frames(2, vec![0x4000, 0x4010])]
+    fn unwinds_frame_pointer_chain(#[case] max_frames: usize, #[case] expected_pcs: Vec<u64>) {
+        let (caller_record, caller_fp) = frame_record(0x8060, 0, 0x4020);
+        let (record, fp) = frame_record(0x8010, caller_fp, 0x4010);
+        let mut memory = SparseMemory::new(vec![record, caller_record]);
+
+        let mut unwinder = StackUnwinder::new(&mut memory, &[], max_frames);
+        let frames = unwinder.unwind(UnwindRegisters {
+            pc: 0x4000,
+            sp: 0x8008,
+            fp,
+            lr: None,
+        });
+
+        assert_eq!(
+            frames.iter().map(|f| f.pc).collect::<Vec<_>>(),
+            expected_pcs
+        );
+        assert!(frames.iter().all(|f| f.module.is_none()));
+    }
+
+    #[rstest]
+    fn stops_on_frame_pointer_loops() {
+        // A frame record pointing to itself:
+        let (_, fp) = frame_record(0x8000, 0, 0);
+        let (record, _) = frame_record(0x8000, fp, 0x4010);
+        let mut memory = SparseMemory::new(vec![record]);
+
+        let mut unwinder = StackUnwinder::new(&mut memory, &[], 16);
+        let frames = unwinder.unwind(UnwindRegisters {
+            pc: 0x4000,
+            sp: 0x7ff0,
+            fp,
+            lr: None,
+        });
+
+        assert_eq!(
+            frames.iter().map(|f| f.pc).collect::<Vec<_>>(),
+            vec![0x4000, 0x4010]
+        );
+    }
+
+    #[rstest]
+    fn skips_oversized_note_segments() {
+        let mut elf_header =
+            build_test_header(ELF_TARGET_CLASS, ELF_TARGET_ENDIANNESS, ELF_TARGET_MACHINE);
+        elf_header.e_phnum = 1;
+        elf_header.e_phoff = SIZEOF_EHDR as _;
+        let note_program_header = ProgramHeader {
+            p_type: PT_NOTE,
+            p_vaddr: 0x1000,
+            p_filesz: ElfPtrSize::MAX,
+            ..Default::default()
+        };
+        let mut image = vec![0; SIZEOF_EHDR + SIZEOF_PHDR];
+        image.pwrite(elf_header, 0).unwrap();
+        image.pwrite(note_program_header, SIZEOF_EHDR).unwrap();
+        let mut memory = SparseMemory::new(vec![(0x10000, image)]);
+
+        let mut unwinder = StackUnwinder::new(&mut memory, &[], 16);
+        let module = unwinder
+            .read_module("module".to_string(), 0x10000, 0x20000)
+            .unwrap();
+
+        assert_eq!(module.build_id, None);
+    }
+
+    #[rstest]
+    fn unwinds_threads_of_core() {
+        let input_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/cli/memfault_core_handler/fixtures/elf-core-runtime-ld-paths.elf");
+        let mut core_reader = CoreReaderImpl::new(File::open(&input_path).unwrap()).unwrap();
+        let core_reader = CachedCoreReader::new(&mut core_reader).unwrap();
+        let mut proc_mem_stream = FakeProcMem::new_from_path(&input_path).unwrap();
+        let memory_maps = FakeProcMaps::new_from_path(&input_path)
+            .unwrap()
+            .get_process_maps()
+            .unwrap();
+
+        let stack_trace = unwind_stack_trace(
+            &mut proc_mem_stream,
+            &memory_maps,
+            &core_reader.notes(),
+            "binary\0-a\0",
+            16,
+        );
+
+        assert_debug_snapshot!(stack_trace);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(never)]
+    fn current_registers() -> UnwindRegisters {
+        let (pc, sp, fp): (u64, u64, u64);
+        // SAFETY: only reads registers.
+        unsafe {
+            std::arch::asm!(
+                "lea {pc}, [rip]",
+                "mov {sp}, rsp",
+                "mov {fp}, rbp",
+                pc = out(reg) pc,
+                sp = out(reg) sp,
+                fp = out(reg) fp,
+            );
+        }
+        UnwindRegisters {
+            pc,
+            sp,
+            fp,
+            lr: None,
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn unwinds_own_stack_with_cfi() {
+        let registers = current_registers();
+        let memory_maps = Process::myself().unwrap().maps().unwrap().memory_maps;
+        let mut proc_mem_stream = File::open("/proc/self/mem").unwrap();
+
+        let mut unwinder = StackUnwinder::new(&mut proc_mem_stream, &memory_maps, 64);
+        let frames = unwinder.unwind(registers);
+
+        let this_function = unwinds_own_stack_with_cfi as *const () as u64;
+        let test_binary = std::env::current_exe().unwrap();
+        let test_binary = unwinder
+            .modules
+            .iter()
+            .position(|m| m.path == test_binary.to_string_lossy())
+            .expect("test binary should be a module");
+        assert!(unwinder.modules[test_binary].build_id.is_some());
+        assert_eq!(frames[0].module, Some(test_binary));
+        // current_registers() returns into this function:
+        assert_eq!(frames[1].module, Some(test_binary));
+        assert!(frames[1].pc > this_function && frames[1].pc - this_function < 0x2000);
+        // ...which is called by the test harness.
+        assert!(frames.len() > 3);
+    }
+}
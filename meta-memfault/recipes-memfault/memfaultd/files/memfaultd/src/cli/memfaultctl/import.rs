@@ -0,0 +1,39 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use eyre::Result;
+
+use crate::cli::memfaultctl::ImportArgs;
+use crate::config::Config;
+
+pub fn import(config: &Config, args: &ImportArgs) -> Result<()> {
+    import_inner(config, args)
+}
+
+#[cfg(feature = "mar-import")]
+fn import_inner(config: &Config, args: &ImportArgs) -> Result<()> {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use eyre::Context;
+    use log::info;
+
+    use crate::mar::import_mar_file;
+
+    let file = File::open(&args.file)
+        .wrap_err_with(|| format!("Unable to open {}", args.file.display()))?;
+    let entries = import_mar_file(BufReader::new(file), &config.mar_staging_path())?;
+    info!(
+        "Imported {} MAR entries. They will be uploaded with the next sync.",
+        entries.len()
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "mar-import"))]
+fn import_inner(_config: &Config, args: &ImportArgs) -> Result<()> {
+    Err(eyre::eyre!(
+        "Unable to import {}: memfaultctl must be built with the mar-import feature",
+        args.file.display()
+    ))
+}
@@ -2,16 +2,21 @@
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
 use argh::{FromArgs, TopLevelCommand};
-use std::{path::Path, str::FromStr};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 mod add_battery_reading;
 mod config_file;
 mod coredump;
 mod export;
+mod import;
 mod report_sync;
 mod session;
 mod sync;
 mod write_attributes;
+mod write_metrics;
 
 use crate::{
     cli::version::format_version,
@@ -27,8 +32,10 @@ use crate::cli::memfaultctl::add_battery_reading::add_battery_reading;
 use crate::cli::memfaultctl::config_file::{set_data_collection, set_developer_mode};
 use crate::cli::memfaultctl::coredump::{trigger_coredump, ErrorStrategy};
 use crate::cli::memfaultctl::export::export;
+use crate::cli::memfaultctl::import::import;
 use crate::cli::memfaultctl::report_sync::report_sync;
 use crate::cli::memfaultctl::sync::sync;
+use crate::cli::memfaultctl::write_metrics::{write_metrics, WriteMetricsType};
 use crate::cli::show_settings::show_settings;
 use crate::config::Config;
 use crate::network::NetworkConfig;
@@ -95,12 +102,14 @@ enum MemfaultctlCommand {
     EnableDevMode(EnableDevModeArgs),
     DisableDevMode(DisableDevModeArgs),
     Export(ExportArgs),
+    Import(ImportArgs),
     Reboot(RebootArgs),
     RequestMetrics(RequestMetricsArgs),
     ShowSettings(ShowSettingsArgs),
     Synchronize(SyncArgs),
     TriggerCoredump(TriggerCoredumpArgs),
     WriteAttributes(WriteAttributesArgs),
+    WriteMetrics(WriteMetricsArgs),
     AddBatteryReading(AddBatteryReadingArgs),
     ReportSyncSuccess(ReportSyncSuccessArgs),
     ReportSyncFailure(ReportSyncFailureArgs),
@@ -144,6 +153,15 @@ pub struct ExportArgs {
     format: ExportFormat,
 }
 
+#[derive(FromArgs)]
+/// import a MAR file (from 'memfaultctl export' on another device) to upload it with the next sync
+#[argh(subcommand, name = "import")]
+pub struct ImportArgs {
+    #[argh(positional)]
+    /// MAR file to import
+    file: PathBuf,
+}
+
 #[derive(FromArgs)]
 /// register reboot reason and call 'reboot'
 #[argh(subcommand, name = "reboot")]
@@ -186,6 +204,24 @@ struct WriteAttributesArgs {
     attributes: Vec<DeviceAttribute>,
 }
 
+#[derive(FromArgs)]
+/// write metric reading(s) to memfaultd's ongoing heartbeat and sessions
+#[argh(subcommand, name = "write-metrics")]
+struct WriteMetricsArgs {
+    /// type of the readings: gauge (default), counter, string or state
+    #[argh(
+        option,
+        short = 't',
+        long = "type",
+        default = "WriteMetricsType::Gauge"
+    )]
+    metric_type: WriteMetricsType,
+
+    /// readings to write, in the format <KEY1=VALUE1 ...>
+    #[argh(positional)]
+    readings: Vec<String>,
+}
+
 #[derive(FromArgs)]
 /// add a reading to memfaultd's battery metrics in format "[status string]:[0.0-100.0]".
 #[argh(subcommand, name = "add-battery-reading")]
@@ -269,6 +305,10 @@ pub fn main() -> Result<()> {
             set_developer_mode(&mut config, &service_manager, false)
         }
         MemfaultctlCommand::Export(args) => export(&config, &args).wrap_err("Error exporting data"),
+        MemfaultctlCommand::Import(args) => {
+            check_data_collection_enabled(&config, "import data")?;
+            import(&config, &args).wrap_err("Error importing data")
+        }
         MemfaultctlCommand::Reboot(args) => {
             let reason = RebootReason::from_str(&args.reason)
                 .wrap_err(eyre!("Failed to parse {}", args.reason))?;
@@ -298,6 +338,13 @@ pub fn main() -> Result<()> {
                     .map(|_entry| ())
             }
         }
+        MemfaultctlCommand::WriteMetrics(WriteMetricsArgs {
+            metric_type,
+            readings,
+        }) => {
+            check_data_collection_enabled(&config, "write metrics")?;
+            write_metrics(&config, metric_type, &readings)
+        }
         MemfaultctlCommand::AddBatteryReading(AddBatteryReadingArgs { reading_string }) => {
             add_battery_reading(&config, &reading_string)
         }
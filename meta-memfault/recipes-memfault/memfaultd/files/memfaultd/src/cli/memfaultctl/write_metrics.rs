@@ -0,0 +1,150 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use argh::FromArgValue;
+use eyre::{eyre, Result};
+use std::str::FromStr;
+
+use crate::cli::memfaultd_client::MemfaultdClient;
+use crate::config::Config;
+use crate::metrics::{MetricStringKey, PushedMetricReading, PushedMetricValue};
+
+/// Type of the readings passed to `memfaultctl write-metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMetricsType {
+    Gauge,
+    Counter,
+    String,
+    State,
+}
+
+impl FromArgValue for WriteMetricsType {
+    fn from_arg_value(value: &str) -> Result<Self, String> {
+        match value {
+            "gauge" => Ok(WriteMetricsType::Gauge),
+            "counter" => Ok(WriteMetricsType::Counter),
+            "string" => Ok(WriteMetricsType::String),
+            "state" => Ok(WriteMetricsType::State),
+            _ => Err("valid types are 'gauge', 'counter', 'string' and 'state'".to_string()),
+        }
+    }
+}
+
+/// Parse a KEY=VALUE argument into a reading of the given type.
+fn parse_reading(
+    metric_type: WriteMetricsType,
+    reading: &str,
+) -> Result<PushedMetricReading, String> {
+    let (key, value_str) = reading
+        .split_once('=')
+        .ok_or("Each reading should be specified as KEY=VALUE")?;
+
+    let name = MetricStringKey::from_str(key)?;
+    let parse_number = || {
+        value_str
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| format!("Invalid value for {}: must be a number", name))
+    };
+    let value = match metric_type {
+        WriteMetricsType::Gauge => PushedMetricValue::Gauge {
+            value: parse_number()?,
+        },
+        WriteMetricsType::Counter => PushedMetricValue::Counter {
+            value: parse_number()?,
+        },
+        WriteMetricsType::String => PushedMetricValue::String {
+            value: value_str.to_string(),
+        },
+        WriteMetricsType::State => PushedMetricValue::State {
+            value: value_str.to_string(),
+        },
+    };
+    Ok(PushedMetricReading {
+        name,
+        value,
+        timestamp: None,
+    })
+}
+
+pub fn write_metrics(
+    config: &Config,
+    metric_type: WriteMetricsType,
+    readings: &[String],
+) -> Result<()> {
+    // argh does not have a way to specify the minimum number of repeating arguments, so check here:
+    // https://github.com/google/argh/issues/110
+    if readings.is_empty() {
+        return Err(eyre!(
+            "No readings given. Please specify them as KEY=VALUE pairs."
+        ));
+    }
+    let readings = readings
+        .iter()
+        .map(|r| parse_reading(metric_type, r))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| eyre!(e))?;
+
+    let client = MemfaultdClient::from_config(config)?;
+    match client.write_metrics(&readings) {
+        Ok(()) => {
+            eprintln!(
+                "Successfully published {} reading(s) to memfaultd",
+                readings.len()
+            );
+            Ok(())
+        }
+        Err(e) => Err(eyre!("write-metrics failed: {:#}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("")]
+    #[case("KEY")]
+    #[case("KEY:1")]
+    fn split_failed(#[case] input: &str) {
+        assert_eq!(
+            parse_reading(WriteMetricsType::Gauge, input).unwrap_err(),
+            "Each reading should be specified as KEY=VALUE"
+        );
+    }
+
+    #[rstest]
+    #[case(WriteMetricsType::Gauge, "key=")]
+    #[case(WriteMetricsType::Gauge, "key=abc")]
+    #[case(WriteMetricsType::Counter, "key=NaN")]
+    #[case(WriteMetricsType::Counter, "key=inf")]
+    fn invalid_number(#[case] metric_type: WriteMetricsType, #[case] input: &str) {
+        assert_eq!(
+            parse_reading(metric_type, input).unwrap_err(),
+            "Invalid value for key: must be a number"
+        );
+    }
+
+    #[rstest]
+    #[case(WriteMetricsType::Gauge, "key=12.5", PushedMetricValue::Gauge { value: 12.5 })]
+    #[case(WriteMetricsType::Counter, "key=3", PushedMetricValue::Counter { value: 3.0 })]
+    #[case(WriteMetricsType::String, "key=1.2.3", PushedMetricValue::String { value: "1.2.3".to_string() })]
+    #[case(WriteMetricsType::State, "key=a=b", PushedMetricValue::State { value: "a=b".to_string() })]
+    fn parsed_ok(
+        #[case] metric_type: WriteMetricsType,
+        #[case] input: &str,
+        #[case] expected: PushedMetricValue,
+    ) {
+        assert_eq!(
+            parse_reading(metric_type, input),
+            Ok(PushedMetricReading {
+                name: "key".parse().unwrap(),
+                value: expected,
+                timestamp: None,
+            })
+        );
+    }
+}
@@ -9,7 +9,7 @@ use reqwest::{blocking::Client, header::ACCEPT, StatusCode};
 use crate::{
     config::Config,
     mar::{ExportFormat, EXPORT_MAR_URL},
-    metrics::SessionName,
+    metrics::{PushedMetricReading, SessionName, WRITE_METRICS_URL},
 };
 
 /// Client to Memfaultd localhost HTTP API
@@ -106,6 +106,22 @@ impl MemfaultdClient {
         }
     }
 
+    pub fn write_metrics(&self, readings: &[PushedMetricReading]) -> Result<()> {
+        let r = self
+            .client
+            .post(format!("{}{}", self.base_url, WRITE_METRICS_URL))
+            .json(readings)
+            .send()?;
+        match r.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(eyre!(
+                "Unexpected status code {}: {}",
+                r.status().as_u16(),
+                from_utf8(&r.bytes()?)?
+            )),
+        }
+    }
+
     pub fn notify_crash(&self) -> Result<()> {
         self.client
             .post(format!("{}{}", self.base_url, "/v1/crash/report"))
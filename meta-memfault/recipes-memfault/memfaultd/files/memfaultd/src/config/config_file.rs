@@ -5,10 +5,13 @@ use eyre::{eyre, Context};
 use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 use std::time::Duration;
-use std::{collections::HashMap, num::NonZeroU32};
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroU32,
+};
 use std::{net::SocketAddr, path::PathBuf};
 
-use crate::metrics::{MetricStringKey, SessionName};
+use crate::metrics::{HistogramStatistics, MetricStringKey, SessionName};
 use crate::util::*;
 use crate::util::{path::AbsolutePath, serialization::*};
 
@@ -31,6 +34,7 @@ pub struct MemfaultdConfig {
     pub software_type: String,
     pub project_key: String,
     pub base_url: String,
+    pub network: NetworkConnectionConfig,
     pub swupdate: SwUpdateConfig,
     pub reboot: RebootConfig,
     pub coredump: CoredumpConfig,
@@ -41,7 +45,43 @@ pub struct MemfaultdConfig {
     pub http_server: HttpServerConfig,
     pub battery_monitor: Option<BatteryMonitorConfig>,
     pub connectivity_monitor: Option<ConnectivityMonitorConfig>,
+    pub network_policy: Option<NetworkPolicyConfig>,
+    pub upload_backend: UploadBackendConfig,
+    pub gateway_server: Option<GatewayServerConfig>,
+    pub statsd: Option<StatsdConfig>,
+    pub system_metrics: Option<SystemMetricsConfig>,
     pub sessions: Option<Vec<SessionConfig>>,
+    pub metrics: MetricsConfig,
+}
+
+/// Settings of the HTTP clients talking to Memfault.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NetworkConnectionConfig {
+    pub proxy: Option<ProxyConfig>,
+    /// PEM file with the certificates of additional certificate authorities.
+    /// Requires the rust-tls or openssl-tls feature.
+    pub ca_bundle_path: Option<PathBuf>,
+    pub pinned_certificates: PinnedCertificatesConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProxyConfig {
+    /// `http://`, `https://`, `socks5://` or `socks5h://` URL of the proxy.
+    /// SOCKS proxies require the `socks-proxy` feature.
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// PEM files with the only certificates trusted to connect to a host. When set, neither the
+/// built-in certificate authorities nor `ca_bundle_path` are used for that host.
+/// Requires the rust-tls or openssl-tls feature.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PinnedCertificatesConfig {
+    /// Certificates of `base_url`
+    pub api: Option<PathBuf>,
+    /// Certificates of the host MAR files are uploaded to
+    pub file_upload: Option<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -61,6 +101,12 @@ pub enum CoredumpCompression {
     Gzip,
     #[serde(rename = "none")]
     None,
+    /// Requires the zstd feature
+    #[serde(rename = "zstd")]
+    Zstd,
+    /// Requires the xz feature
+    #[serde(rename = "xz")]
+    Xz,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -87,6 +133,113 @@ pub struct CoredumpConfig {
     #[serde(rename = "rate_limit_duration_seconds", with = "seconds_to_duration")]
     pub rate_limit_duration: Duration,
     pub capture_strategy: CoredumpCaptureStrategy,
+    pub stack_trace: Option<StackTraceConfig>,
+    pub deduplication: Option<CoredumpDeduplicationConfig>,
+    pub redaction: Option<CoredumpRedactionConfig>,
+    /// Overrides of the settings above for some processes. The first matching policy applies.
+    pub policies: Vec<CoredumpPolicyConfig>,
+}
+
+/// Coredump settings of the processes matching `executable` and `cmdline`. A policy without
+/// patterns matches all the processes. Unset settings are the ones of `CoredumpConfig`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoredumpPolicyConfig {
+    /// Name of the policy, shown in `show-settings`.
+    pub name: String,
+    /// Pattern (`*` and `?` wildcards) of the path of the executable.
+    pub executable: Option<String>,
+    /// Pattern (`*` and `?` wildcards) of the command line, its arguments separated by spaces.
+    pub cmdline: Option<String>,
+    /// Do not process the coredumps of the matching processes at all.
+    #[serde(default)]
+    pub ignore: bool,
+    pub capture_strategy: Option<CoredumpCaptureStrategy>,
+    #[serde(
+        rename = "coredump_max_size_kib",
+        with = "optional_kib_to_usize",
+        default
+    )]
+    pub coredump_max_size: Option<usize>,
+    /// Rate limit of the matching processes, counted separately from the global one.
+    pub rate_limit: Option<CoredumpRateLimitConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoredumpRateLimitConfig {
+    pub count: u32,
+    #[serde(rename = "duration_seconds", with = "seconds_to_duration")]
+    pub duration: Duration,
+}
+
+/// Settings of the stack traces computed on the device when a process crashes.
+///
+/// Stack traces are uploaded in their own MAR entry, even when the coredump itself is not
+/// (rate limit reached or debugging fleet sampling disabled). They have their own rate limit,
+/// which is not applied in developer mode.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StackTraceConfig {
+    /// Maximum number of frames unwound for each thread.
+    pub max_frames: usize,
+    #[serde(default = "default_stack_trace_rate_limit_count")]
+    pub rate_limit_count: u32,
+    #[serde(
+        rename = "rate_limit_duration_seconds",
+        with = "seconds_to_duration",
+        default = "default_stack_trace_rate_limit_duration"
+    )]
+    pub rate_limit_duration: Duration,
+}
+
+fn default_stack_trace_rate_limit_count() -> u32 {
+    30
+}
+
+fn default_stack_trace_rate_limit_duration() -> Duration {
+    Duration::from_secs(3600)
+}
+
+/// Settings of the deduplication of crashes.
+///
+/// A crash with the same fingerprint (executable, signal and top frames of the crashed thread) as
+/// a crash seen less than `window` ago only increments the `coredumps_deduplicated` metric.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CoredumpDeduplicationConfig {
+    #[serde(rename = "window_seconds", with = "seconds_to_duration")]
+    pub window: Duration,
+    /// Number of frames of the crashed thread included in the fingerprint.
+    pub frames: usize,
+}
+
+/// Settings of the redaction of sensitive memory from coredumps.
+///
+/// A region is redacted if it matches any of the criteria. The redacted regions are listed in the
+/// Memfault debug data note of the coredump.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CoredumpRedactionConfig {
+    /// Patterns (`*` and `?` wildcards) of the paths of the mappings to redact, for example
+    /// `/dev/shm/secret*`.
+    #[serde(default)]
+    pub paths: Vec<String>,
+    /// Redact anonymous mappings and the heap. The stacks of the threads are kept.
+    #[serde(default)]
+    pub anonymous: bool,
+    /// Directory in which a process can write a denylist file named after its PID. Each line of
+    /// the file holds the address and length of a region to redact, like the arguments of
+    /// `madvise(2)`.
+    pub denylist_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub mode: CoredumpRedactionMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CoredumpRedactionMode {
+    #[serde(rename = "zero")]
+    /// Replace the contents of the redacted regions with zeroes.
+    #[default]
+    Zero,
+    #[serde(rename = "omit")]
+    /// Leave the redacted regions out of the coredump.
+    Omit,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -110,12 +263,103 @@ pub struct LogsConfig {
     #[serde(rename = "rotate_after_seconds", with = "seconds_to_duration")]
     pub rotate_after: Duration,
 
+    pub compression: LogCompression,
+
     #[serde(with = "number_to_compression")]
     pub compression_level: Compression,
 
     pub max_lines_per_minute: NonZeroU32,
 
+    pub source_rate_limits: Option<LogSourceRateLimitsConfig>,
+
     pub log_to_metrics: Option<LogToMetricsConfig>,
+
+    pub filtering: Option<LogFilteringConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub enum LogCompression {
+    #[serde(rename = "zlib")]
+    Zlib,
+    /// Requires the zstd feature
+    #[serde(rename = "zstd")]
+    Zstd,
+    /// Requires the xz feature
+    #[serde(rename = "xz")]
+    Xz,
+}
+
+/// Rate limits applied to each source of logs, in addition to `max_lines_per_minute`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogSourceRateLimitsConfig {
+    /// Field identifying the source of a log line, e.g. _SYSTEMD_UNIT or SYSLOG_IDENTIFIER
+    pub key_field: String,
+    pub default_lines_per_minute: NonZeroU32,
+    /// Limits for specific sources, by value of `key_field`
+    #[serde(default)]
+    pub overrides: HashMap<String, NonZeroU32>,
+}
+
+/// Rules applied to each log line before it is written to disk.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LogFilteringConfig {
+    /// Lines matching any of these rules are dropped
+    #[serde(default)]
+    pub drop_rules: Vec<LogDropRule>,
+    /// Fields to keep (MESSAGE is always kept). All fields are kept when not set.
+    #[serde(default)]
+    pub field_allowlist: Option<Vec<String>>,
+    /// Replacements applied to the remaining fields
+    #[serde(default)]
+    pub redaction_rules: Vec<LogRedactionRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogDropRule {
+    /// All the conditions must be true for the line to be dropped
+    pub conditions: Vec<LogFieldCondition>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogFieldCondition {
+    pub field: String,
+    pub operator: LogFieldOperator,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFieldOperator {
+    #[serde(rename = "==")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+    /// Numerical comparisons (the condition is false when the field is not a number)
+    #[serde(rename = ">")]
+    GreaterThan,
+    #[serde(rename = ">=")]
+    GreaterThanOrEqual,
+    #[serde(rename = "<")]
+    LessThan,
+    #[serde(rename = "<=")]
+    LessThanOrEqual,
+    /// The value is a regex which must match the field
+    #[serde(rename = "matches")]
+    Matches,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogRedactionRule {
+    /// Regex to replace
+    pub pattern: String,
+    /// Replacement text, which can refer to capture groups with $1 or ${name}
+    pub replacement: String,
+    /// Fields on which the rule is applied
+    #[serde(default = "default_redaction_fields")]
+    pub fields: Vec<String>,
+}
+
+fn default_redaction_fields() -> Vec<String> {
+    vec!["MESSAGE".to_string()]
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -135,6 +379,46 @@ pub enum LogToMetricRule {
         #[serde(default)]
         filter: HashMap<String, String>,
     },
+    /// Records a value captured from the MESSAGE field as a gauge
+    #[serde(rename = "extract_gauge")]
+    ExtractGauge {
+        /// Regex applied on the MESSAGE field
+        pattern: String,
+        metric_name: String,
+        /// Name or index of the capture group holding the value (defaults to
+        /// the group named `value`, or the first group)
+        #[serde(default)]
+        value_group: Option<String>,
+        /// List of key-value that must exactly match before the regexp is applied
+        #[serde(default)]
+        filter: HashMap<String, String>,
+    },
+    /// Adds a value captured from the MESSAGE field to a counter
+    #[serde(rename = "extract_counter")]
+    ExtractCounter {
+        /// Regex applied on the MESSAGE field
+        pattern: String,
+        metric_name: String,
+        /// Name or index of the capture group holding the value (defaults to
+        /// the group named `value`, or the first group)
+        #[serde(default)]
+        value_group: Option<String>,
+        /// List of key-value that must exactly match before the regexp is applied
+        #[serde(default)]
+        filter: HashMap<String, String>,
+    },
+    /// Sets a state metric when the MESSAGE field matches
+    #[serde(rename = "set_state")]
+    SetState {
+        /// Regex applied on the MESSAGE field
+        pattern: String,
+        metric_name: String,
+        /// State to enter, which can use the capture groups like the metric name
+        state: String,
+        /// List of key-value that must exactly match before the regexp is applied
+        #[serde(default)]
+        filter: HashMap<String, String>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -143,6 +427,47 @@ pub struct MarConfig {
     pub mar_file_max_size: usize,
     #[serde(rename = "mar_entry_max_age_seconds", with = "seconds_to_duration")]
     pub mar_entry_max_age: Duration,
+    /// When set, MAR files are uploaded in chunks of this size, and interrupted
+    /// uploads are resumed instead of restarted.
+    #[serde(
+        rename = "upload_chunk_size_kib",
+        with = "optional_kib_to_usize",
+        default
+    )]
+    pub upload_chunk_size: Option<usize>,
+    /// Maximum size of the MAR files uploaded during one sync. Lower priority
+    /// entries which do not fit are deferred to the next syncs.
+    #[serde(
+        rename = "upload_budget_per_sync_kib",
+        with = "optional_kib_to_usize",
+        default
+    )]
+    pub upload_budget_per_sync: Option<usize>,
+    /// Maximum size of the MAR files uploaded during the last 24 hours.
+    #[serde(
+        rename = "upload_budget_per_day_kib",
+        with = "optional_kib_to_usize",
+        default
+    )]
+    pub upload_budget_per_day: Option<usize>,
+    /// Maximum size of the MAR files uploaded during the last 30 days.
+    #[serde(
+        rename = "upload_budget_per_month_kib",
+        with = "optional_kib_to_usize",
+        default
+    )]
+    pub upload_budget_per_month: Option<usize>,
+    /// Compression of the MAR files on the wire. The server receives them with
+    /// a `Content-Encoding` header and stores the MAR file uncompressed.
+    pub upload_compression: UploadCompression,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UploadCompression {
+    #[serde(rename = "gzip")]
+    Gzip,
+    #[serde(rename = "none")]
+    None,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -167,6 +492,40 @@ fn default_connection_check_timeout() -> Duration {
     Duration::from_secs(10)
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NetworkPolicyConfig {
+    /// Command printing the name of the current link (for example `wlan0`),
+    /// or nothing when offline. Defaults to the interface of the default route.
+    pub link_command: Option<String>,
+    /// Target which must be reachable before uploading.
+    pub connection_check: Option<ConnectivityMonitorTarget>,
+    #[serde(
+        with = "seconds_to_duration",
+        default = "default_connection_check_timeout"
+    )]
+    pub timeout_seconds: Duration,
+    /// Links on which some types of data can be uploaded. The types of data
+    /// without a rule are uploaded on any link.
+    #[serde(default)]
+    pub link_rules: Vec<LinkRule>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LinkRule {
+    pub data_types: Vec<UploadDataType>,
+    pub links: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadDataType {
+    Attributes,
+    Coredumps,
+    Logs,
+    Metrics,
+    Reboots,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConnectivityMonitorTarget {
     #[serde(default = "default_connection_check_protocol")]
@@ -181,22 +540,134 @@ pub enum ConnectionCheckProtocol {
     Tcp,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum UploadBackendConfig {
+    #[serde(rename = "memfault")]
+    /// Upload MAR files to Memfault (`base_url`).
+    Memfault,
+    #[serde(rename = "gateway")]
+    /// Upload MAR files to the gateway server of another device (see `GatewayServerConfig`),
+    /// which forwards their entries to Memfault. Chunked uploads are not supported.
+    Gateway { url: String, auth_token: String },
+    #[serde(rename = "directory")]
+    /// Write MAR files to a directory (for example a removable drive), for devices which are
+    /// never online. The oldest MAR files are deleted to keep at most `max_files` of them and
+    /// `min_headroom` of free space. Chunked uploads are not supported.
+    Directory {
+        path: PathBuf,
+        #[serde(default = "default_directory_max_files")]
+        max_files: usize,
+        #[serde(
+            rename = "min_headroom_kib",
+            with = "kib_to_usize",
+            default = "default_directory_min_headroom"
+        )]
+        min_headroom: usize,
+    },
+}
+
+fn default_directory_max_files() -> usize {
+    100
+}
+
+fn default_directory_min_headroom() -> usize {
+    10 * 1024 * 1024
+}
+
+/// Accept MAR files from other devices on the network (see `UploadBackendConfig::Gateway`).
+/// Requires the `mar-import` feature.
+///
+/// Uploads are plain HTTP: the server only binds to local network addresses and only accepts
+/// uploads from local network addresses.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GatewayServerConfig {
+    pub bind_address: SocketAddr,
+    /// Devices must send it in an `Authorization: Bearer <auth_token>` header.
+    pub auth_token: String,
+    /// MAR data (uncompressed) accepted from each device per hour.
+    #[serde(
+        rename = "client_quota_kib",
+        with = "kib_to_usize",
+        default = "default_gateway_client_quota"
+    )]
+    pub client_quota: usize,
+}
+
+fn default_gateway_client_quota() -> usize {
+    10 * 1024 * 1024
+}
+
 fn default_connection_check_protocol() -> ConnectionCheckProtocol {
     ConnectionCheckProtocol::Tcp
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatsdConfig {
+    pub bind_address: SocketAddr,
+    /// Prepended to the name of each StatsD metric to build the Memfault metric key.
+    #[serde(default)]
+    pub key_prefix: String,
+    /// StatsD metric names (before prefixing) to accept. A trailing `*` matches
+    /// any suffix. All metrics are accepted when not set.
+    #[serde(default)]
+    pub allowlist: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SystemMetricsConfig {
+    #[serde(
+        rename = "poll_interval_seconds",
+        with = "seconds_to_duration",
+        default = "default_system_metrics_poll_interval"
+    )]
+    pub poll_interval: Duration,
+    /// Names of the processes to monitor, as in /proc/<pid>/comm.
+    #[serde(default)]
+    pub processes: Vec<String>,
+    /// Names of the systemd units to monitor, e.g. collectd.service.
+    #[serde(default)]
+    pub systemd_units: Vec<String>,
+    /// Network interfaces to monitor. All interfaces except loopback when not set.
+    #[serde(default)]
+    pub network_interfaces: Option<Vec<String>>,
+    /// Disks to monitor. All disks except loop and RAM devices when not set.
+    #[serde(default)]
+    pub disks: Option<Vec<String>>,
+}
+fn default_system_metrics_poll_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
 #[derive(Serialize, Clone, Deserialize, Debug)]
 pub struct SessionConfig {
     pub name: SessionName,
     pub captured_metrics: Vec<MetricStringKey>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MetricsConfig {
+    /// Statistics to report for each gauge metric. Gauges which are not listed
+    /// here only report their mean.
+    #[serde(serialize_with = "sorted_map::sorted_map")]
+    pub histogram_statistics: HistogramStatistics,
+    /// How often ongoing metric reports are saved to disk, so that they can be
+    /// resumed after a restart. Zero only saves them on shutdown.
+    #[serde(rename = "checkpoint_interval_seconds", with = "seconds_to_duration")]
+    pub checkpoint_interval: Duration,
+}
+
 use flate2::Compression;
 use serde_json::Value;
 use std::fs;
 use std::path::Path;
 
-use crate::config::utils::{software_type_is_valid, software_version_is_valid};
+use crate::config::utils::{
+    compression_feature_is_enabled, gateway_bind_address_is_valid, software_type_is_valid,
+    software_version_is_valid, tls_is_enabled,
+};
+use crate::mar::CompressionAlgorithm;
+use crate::util::patterns::alphanum_slug_is_valid;
 
 pub struct JsonConfigs {
     /// Built-in configuration and System configuration
@@ -205,6 +676,16 @@ pub struct JsonConfigs {
     pub runtime: Value,
 }
 
+/// The first name used by more than one coredump policy. Policies with their own rate limit
+/// store its state in a file named after the policy.
+fn duplicate_policy_name(policies: &[CoredumpPolicyConfig]) -> Option<&str> {
+    let mut names = HashSet::new();
+    policies
+        .iter()
+        .map(|policy| policy.name.as_str())
+        .find(|name| !names.insert(*name))
+}
+
 impl MemfaultdConfig {
     pub fn load(config_path: &Path) -> eyre::Result<MemfaultdConfig> {
         let JsonConfigs {
@@ -225,8 +706,76 @@ impl MemfaultdConfig {
                 "\"software_type\"",
                 software_type_is_valid(&config.software_type),
             ),
+            (
+                "\"mar.upload_chunk_size_kib\"",
+                match config.mar.upload_chunk_size {
+                    Some(0) => Err(eyre!("Chunks cannot be empty")),
+                    _ => Ok(()),
+                },
+            ),
+            (
+                "\"coredump.compression\"",
+                compression_feature_is_enabled(
+                    CompressionAlgorithm::from(config.coredump.compression).missing_feature(),
+                ),
+            ),
+            (
+                "\"logs.compression\"",
+                compression_feature_is_enabled(
+                    CompressionAlgorithm::from(config.logs.compression).missing_feature(),
+                ),
+            ),
+            (
+                "\"gateway_server.bind_address\"",
+                match &config.gateway_server {
+                    Some(gateway_server) => {
+                        gateway_bind_address_is_valid(&gateway_server.bind_address)
+                    }
+                    None => Ok(()),
+                },
+            ),
+            (
+                "\"network.ca_bundle_path\"",
+                match config.network.ca_bundle_path {
+                    Some(_) => tls_is_enabled(),
+                    None => Ok(()),
+                },
+            ),
+            (
+                "\"network.pinned_certificates\"",
+                match config.network.pinned_certificates {
+                    PinnedCertificatesConfig {
+                        api: None,
+                        file_upload: None,
+                    } => Ok(()),
+                    _ => tls_is_enabled(),
+                },
+            ),
+            (
+                "\"upload_backend.max_files\"",
+                match config.upload_backend {
+                    UploadBackendConfig::Directory { max_files: 0, .. } => {
+                        Err(eyre!("At least one file must be kept"))
+                    }
+                    _ => Ok(()),
+                },
+            ),
         ]
-        .iter()
+        .into_iter()
+        .chain(
+            duplicate_policy_name(&config.coredump.policies).map(|name| {
+                (
+                    "\"coredump.policies.name\"",
+                    Err(eyre!("{} is used by more than one policy", name)),
+                )
+            }),
+        )
+        .chain(config.coredump.policies.iter().map(|policy| {
+            (
+                "\"coredump.policies.name\"",
+                alphanum_slug_is_valid(&policy.name, 128),
+            )
+        }))
         .filter_map(|(key, result)| match result {
             Err(e) => Some(format!("  Invalid value for {}: {}", key, e)),
             _ => None,
@@ -416,10 +965,28 @@ mod test {
     #[case("empty_object")]
     #[case("with_partial_logs")]
     #[case("without_coredump_compression")]
+    #[cfg_attr(feature = "zstd", case("with_zstd_compression"))]
+    #[cfg_attr(feature = "xz", case("with_xz_compression"))]
     #[case("with_coredump_capture_strategy_threads")]
+    #[case("with_coredump_stack_trace")]
+    #[case("with_coredump_deduplication")]
+    #[case("with_coredump_redaction")]
+    #[case("with_coredump_policies")]
     #[case("with_log_to_metrics_rules")]
+    #[case("with_log_filtering")]
+    #[case("with_log_source_rate_limits")]
     #[case("with_connectivity_monitor")]
+    #[case("with_network_policy")]
+    #[case("with_gateway")]
+    #[cfg_attr(
+        any(feature = "rust-tls", feature = "openssl-tls"),
+        case("with_network_proxy")
+    )]
+    #[case("with_directory_upload_backend")]
     #[case("with_sessions")]
+    #[case("with_histogram_statistics")]
+    #[case("with_statsd")]
+    #[case("with_system_metrics")]
     fn can_parse_test_files(#[case] name: &str) {
         let input_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("src/config/test-config")
@@ -437,6 +1004,19 @@ mod test {
     #[case("with_invalid_swt_swv")]
     #[case("with_sessions_invalid_metric_name")]
     #[case("with_sessions_invalid_session_name")]
+    #[case("with_invalid_histogram_statistic")]
+    #[case("with_statsd_invalid_bind_address")]
+    #[case("with_log_filtering_invalid_operator")]
+    #[case("with_zero_upload_chunk_size")]
+    #[case("with_public_gateway_bind_address")]
+    #[case("with_zero_directory_max_files")]
+    #[case("with_duplicate_coredump_policy_names")]
+    #[cfg_attr(
+        not(any(feature = "rust-tls", feature = "openssl-tls")),
+        case("with_network_proxy")
+    )]
+    #[cfg_attr(not(feature = "zstd"), case("with_zstd_compression"))]
+    #[cfg_attr(not(feature = "xz"), case("with_xz_compression"))]
     fn will_reject_bad_config(#[case] name: &str) {
         let input_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
             .join("src/config/test-config")
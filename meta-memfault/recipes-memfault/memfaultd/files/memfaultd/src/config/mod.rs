@@ -2,6 +2,7 @@
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
 use eyre::eyre;
+use std::iter::once;
 use std::time::Duration;
 use std::{
     path::{Path, PathBuf},
@@ -9,17 +10,25 @@ use std::{
 };
 
 use crate::{
+    metrics::HistogramStatistics,
     network::{NetworkClient, NetworkConfig},
     util::{DiskBacked, UnwrapOrDie, UpdateStatus},
 };
 
 use crate::util::disk_size::DiskSize;
+use crate::util::patterns::wildcard_match;
 
+#[cfg(test)]
+pub use self::config_file::ProxyConfig;
 pub use self::{
     config_file::{
         ConnectionCheckProtocol, ConnectivityMonitorConfig, ConnectivityMonitorTarget,
-        CoredumpCaptureStrategy, CoredumpCompression, JsonConfigs, LogToMetricRule,
-        MemfaultdConfig, SessionConfig,
+        CoredumpCaptureStrategy, CoredumpCompression, CoredumpPolicyConfig,
+        CoredumpRedactionConfig, CoredumpRedactionMode, GatewayServerConfig, JsonConfigs,
+        LogCompression, LogDropRule, LogFieldCondition, LogFieldOperator, LogFilteringConfig,
+        LogRedactionRule, LogSourceRateLimitsConfig, LogToMetricRule, MemfaultdConfig,
+        NetworkConnectionConfig, NetworkPolicyConfig, SessionConfig, StackTraceConfig,
+        StatsdConfig, SystemMetricsConfig, UploadBackendConfig, UploadCompression, UploadDataType,
     },
     device_config::{DeviceConfig, Resolution, Sampling},
     device_info::{DeviceInfo, DeviceInfoWarning},
@@ -42,10 +51,32 @@ pub struct Config {
     cached_device_config: Arc<RwLock<DiskBacked<DeviceConfig>>>,
 }
 
+/// Coredump settings of a process: those of the first `CoredumpPolicyConfig` matching it, or
+/// the global ones.
+#[derive(Debug, Clone)]
+pub struct CoredumpPolicy {
+    /// Name of the matching policy, `None` for the global settings.
+    pub name: Option<String>,
+    pub executable: Option<String>,
+    pub cmdline: Option<String>,
+    pub ignore: bool,
+    pub capture_strategy: CoredumpCaptureStrategy,
+    pub coredump_max_size: usize,
+    pub rate_limit_count: u32,
+    pub rate_limit_duration: Duration,
+    /// State of the rate limit: policies with their own rate limit have their own file.
+    pub rate_limiter_file_path: PathBuf,
+}
+
 const LOGS_SUBDIRECTORY: &str = "logs";
 const MAR_STAGING_SUBDIRECTORY: &str = "mar";
 const DEVICE_CONFIG_FILE: &str = "device_config.json";
 const COREDUMP_RATE_LIMITER_FILENAME: &str = "coredump_rate_limit";
+const STACK_TRACE_RATE_LIMITER_FILENAME: &str = "stack_trace_rate_limit";
+const COREDUMP_FINGERPRINTS_FILENAME: &str = "coredump_fingerprints.json";
+const METRICS_CHECKPOINT_FILE: &str = "metrics_checkpoint.json";
+const MAR_UPLOAD_PROGRESS_FILE: &str = "mar_upload_progress.json";
+const UPLOAD_BUDGET_FILENAME: &str = "upload_budget";
 
 impl Config {
     pub const DEFAULT_CONFIG_PATH: &'static str = "/etc/memfaultd.conf";
@@ -124,6 +155,81 @@ impl Config {
         self.tmp_dir().join(COREDUMP_RATE_LIMITER_FILENAME)
     }
 
+    pub fn stack_trace_rate_limiter_file_path(&self) -> PathBuf {
+        self.tmp_dir().join(STACK_TRACE_RATE_LIMITER_FILENAME)
+    }
+
+    pub fn coredump_fingerprints_file_path(&self) -> PathBuf {
+        self.tmp_dir().join(COREDUMP_FINGERPRINTS_FILENAME)
+    }
+
+    /// Resolves the coredump settings of the process running `executable` with `cmd_line` (as
+    /// read from `/proc/<pid>/cmdline`).
+    pub fn coredump_policy(&self, executable: &str, cmd_line: &str) -> CoredumpPolicy {
+        let cmd_line = cmd_line.trim_end_matches('\0').replace('\0', " ");
+        let matches = |pattern: &Option<String>, text: &str| {
+            pattern
+                .as_ref()
+                .map_or(true, |pattern| wildcard_match(pattern, text))
+        };
+        let policy_config = self.config_file.coredump.policies.iter().find(|policy| {
+            matches(&policy.executable, executable) && matches(&policy.cmdline, &cmd_line)
+        });
+        self.resolve_coredump_policy(policy_config)
+    }
+
+    /// Effective settings of all the coredump policies, in matching order, followed by the
+    /// global settings.
+    pub fn coredump_policies(&self) -> Vec<CoredumpPolicy> {
+        self.config_file
+            .coredump
+            .policies
+            .iter()
+            .map(Some)
+            .chain(once(None))
+            .map(|policy_config| self.resolve_coredump_policy(policy_config))
+            .collect()
+    }
+
+    fn resolve_coredump_policy(
+        &self,
+        policy_config: Option<&CoredumpPolicyConfig>,
+    ) -> CoredumpPolicy {
+        let coredump_config = &self.config_file.coredump;
+        let mut policy = CoredumpPolicy {
+            name: None,
+            executable: None,
+            cmdline: None,
+            ignore: false,
+            capture_strategy: coredump_config.capture_strategy,
+            coredump_max_size: coredump_config.coredump_max_size,
+            rate_limit_count: coredump_config.rate_limit_count,
+            rate_limit_duration: coredump_config.rate_limit_duration,
+            rate_limiter_file_path: self.coredump_rate_limiter_file_path(),
+        };
+        if let Some(policy_config) = policy_config {
+            policy.name = Some(policy_config.name.clone());
+            policy.executable = policy_config.executable.clone();
+            policy.cmdline = policy_config.cmdline.clone();
+            policy.ignore = policy_config.ignore;
+            if let Some(capture_strategy) = policy_config.capture_strategy {
+                policy.capture_strategy = capture_strategy;
+            }
+            if let Some(coredump_max_size) = policy_config.coredump_max_size {
+                policy.coredump_max_size = coredump_max_size;
+            }
+            if let Some(rate_limit) = &policy_config.rate_limit {
+                policy.rate_limit_count = rate_limit.count;
+                policy.rate_limit_duration = rate_limit.duration;
+                policy.rate_limiter_file_path = self.tmp_dir().join(format!(
+                    "{}_{}",
+                    COREDUMP_RATE_LIMITER_FILENAME, policy_config.name
+                ));
+            }
+        }
+        policy
+    }
+
     pub fn logs_path(&self) -> PathBuf {
         self.tmp_dir().join(LOGS_SUBDIRECTORY)
     }
@@ -200,9 +306,53 @@ impl Config {
         self.config_file.connectivity_monitor.as_ref()
     }
 
+    pub fn network_connection_config(&self) -> &NetworkConnectionConfig {
+        &self.config_file.network
+    }
+
+    pub fn network_policy_config(&self) -> Option<&NetworkPolicyConfig> {
+        self.config_file.network_policy.as_ref()
+    }
+
+    pub fn upload_backend(&self) -> &UploadBackendConfig {
+        &self.config_file.upload_backend
+    }
+
+    pub fn gateway_server_config(&self) -> Option<&GatewayServerConfig> {
+        self.config_file.gateway_server.as_ref()
+    }
+
+    pub fn statsd_config(&self) -> Option<&StatsdConfig> {
+        self.config_file.statsd.as_ref()
+    }
+
+    pub fn system_metrics_config(&self) -> Option<&SystemMetricsConfig> {
+        self.config_file.system_metrics.as_ref()
+    }
+
     pub fn session_configs(&self) -> Option<&Vec<SessionConfig>> {
         self.config_file.sessions.as_ref()
     }
+
+    pub fn histogram_statistics(&self) -> &HistogramStatistics {
+        &self.config_file.metrics.histogram_statistics
+    }
+
+    pub fn metrics_checkpoint_interval(&self) -> Duration {
+        self.config_file.metrics.checkpoint_interval
+    }
+
+    pub fn metrics_checkpoint_path(&self) -> PathBuf {
+        self.config_file.persist_dir.join(METRICS_CHECKPOINT_FILE)
+    }
+
+    pub fn mar_upload_progress_path(&self) -> PathBuf {
+        self.config_file.persist_dir.join(MAR_UPLOAD_PROGRESS_FILE)
+    }
+
+    pub fn upload_budget_file_path(&self) -> PathBuf {
+        self.config_file.persist_dir.join(UPLOAD_BUDGET_FILENAME)
+    }
 }
 
 #[cfg(test)]
@@ -237,7 +387,7 @@ mod tests {
     use rstest::{fixture, rstest};
 
     use crate::{
-        config::Config,
+        config::{Config, CoredumpCaptureStrategy},
         mar::MarEntry,
         network::{
             DeviceConfigResponse, DeviceConfigResponseConfig, DeviceConfigResponseData,
@@ -262,6 +412,81 @@ mod tests {
         assert_eq!(config.tmp_dir(), abs_path);
     }
 
+    #[rstest]
+    #[case::executable("/usr/bin/ssh-agent", "ssh-agent\0-D\0", Some("ssh-agent"), true)]
+    #[case::executable_pattern("/usr/sbin/nginx-debug", "nginx\0", Some("web-server"), false)]
+    #[case::cmdline(
+        "/usr/bin/python3.11",
+        "python3\0/opt/app/main.py\0",
+        Some("python-scripts"),
+        false
+    )]
+    #[case::cmdline_mismatch("/usr/bin/python3.11", "python3\0/tmp/main.py\0", None, false)]
+    #[case::no_match("/usr/bin/app", "app\0", None, false)]
+    fn resolves_coredump_policy(
+        #[case] executable: &str,
+        #[case] cmd_line: &str,
+        #[case] name: Option<&str>,
+        #[case] ignore: bool,
+    ) {
+        let config = policies_fixture();
+
+        let policy = config.coredump_policy(executable, cmd_line);
+        assert_eq!(policy.name.as_deref(), name);
+        assert_eq!(policy.ignore, ignore);
+    }
+
+    #[test]
+    fn coredump_policies_override_global_settings() {
+        let config = policies_fixture();
+
+        let policies = config.coredump_policies();
+        assert_eq!(policies.len(), 4);
+
+        let web_server = &policies[1];
+        assert!(matches!(
+            web_server.capture_strategy,
+            CoredumpCaptureStrategy::Threads {
+                max_thread_size: 65536
+            }
+        ));
+        assert_eq!(web_server.coredump_max_size, 4096 * 1024);
+        assert_eq!(web_server.rate_limit_count, 2);
+        assert_ne!(
+            web_server.rate_limiter_file_path,
+            config.coredump_rate_limiter_file_path()
+        );
+
+        // Policies without their own rate limit share the global one:
+        let python_scripts = &policies[2];
+        assert!(matches!(
+            python_scripts.capture_strategy,
+            CoredumpCaptureStrategy::KernelSelection
+        ));
+        assert_eq!(
+            python_scripts.coredump_max_size,
+            config.config_file.coredump.coredump_max_size
+        );
+        assert_eq!(
+            python_scripts.rate_limiter_file_path,
+            config.coredump_rate_limiter_file_path()
+        );
+
+        assert!(policies[3].name.is_none());
+    }
+
+    fn policies_fixture() -> Config {
+        let mut config = Config::test_fixture();
+        config.config_file.coredump.capture_strategy = CoredumpCaptureStrategy::Threads {
+            max_thread_size: 32 * 1024,
+        };
+        let test_config: serde_json::Value =
+            serde_json::from_str(include_str!("test-config/with_coredump_policies.json")).unwrap();
+        config.config_file.coredump.policies =
+            serde_json::from_value(test_config["coredump"]["policies"].clone()).unwrap();
+        config
+    }
+
     #[test]
     fn test_info_overrides_file() {
         let config = Config::test_fixture_with_info_overrides("1.0.0-overriden", "overriden-type");
@@ -1,9 +1,16 @@
 //
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
-use crate::util::patterns::{
-    alphanum_slug_dots_colon_is_valid, alphanum_slug_dots_colon_spaces_parens_slash_is_valid,
-    alphanum_slug_is_valid,
+use std::net::SocketAddr;
+
+use eyre::eyre;
+
+use crate::util::{
+    ip::is_local_network_address,
+    patterns::{
+        alphanum_slug_dots_colon_is_valid, alphanum_slug_dots_colon_spaces_parens_slash_is_valid,
+        alphanum_slug_is_valid,
+    },
 };
 
 pub fn software_type_is_valid(s: &str) -> eyre::Result<()> {
@@ -22,6 +29,38 @@ pub fn device_id_is_valid(id: &str) -> eyre::Result<()> {
     alphanum_slug_is_valid(id, 128)
 }
 
+/// The gateway server accepts a bearer token over plain HTTP: it must not listen on the internet.
+pub fn gateway_bind_address_is_valid(address: &SocketAddr) -> eyre::Result<()> {
+    let ip = address.ip();
+    if ip.is_unspecified() || is_local_network_address(&ip) {
+        Ok(())
+    } else {
+        Err(eyre!("{} is not a local network address", ip))
+    }
+}
+
+/// zstd and xz compression are only available when memfaultd is built with their feature.
+pub fn compression_feature_is_enabled(missing_feature: Option<&str>) -> eyre::Result<()> {
+    match missing_feature {
+        Some(feature) => Err(eyre!(
+            "Requires memfaultd to be built with the {} feature",
+            feature
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Certificates can only be configured when memfaultd is built with a TLS backend.
+pub fn tls_is_enabled() -> eyre::Result<()> {
+    if cfg!(any(feature = "rust-tls", feature = "openssl-tls")) {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "Requires memfaultd to be built with the rust-tls or openssl-tls feature"
+        ))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -45,4 +84,16 @@ mod tests {
     fn device_id_is_valid_works(#[case] device_id: &str, #[case] expected: bool) {
         assert_eq!(device_id_is_valid(device_id).is_ok(), expected);
     }
+
+    #[rstest]
+    #[case("0.0.0.0:8788", true)]
+    #[case("[::]:8788", true)]
+    #[case("192.168.1.10:8788", true)]
+    #[case("203.0.113.7:8788", false)]
+    fn gateway_bind_address_is_valid_works(#[case] address: &str, #[case] expected: bool) {
+        assert_eq!(
+            gateway_bind_address_is_valid(&address.parse().unwrap()).is_ok(),
+            expected
+        );
+    }
 }
@@ -20,6 +20,8 @@ mod network;
 mod reboot;
 mod retriable_error;
 mod service_manager;
+#[cfg(feature = "statsd")]
+mod statsd;
 #[cfg(feature = "swupdate")]
 mod swupdate;
 #[cfg(test)]
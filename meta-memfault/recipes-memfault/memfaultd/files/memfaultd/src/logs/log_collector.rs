@@ -3,6 +3,7 @@
 // See License.txt for details
 //! Collect logs into log files and save them as MAR entries.
 //!
+use std::collections::HashSet;
 use std::num::NonZeroU32;
 use std::sync::Arc;
 use std::time::Duration;
@@ -12,18 +13,32 @@ use std::{path::PathBuf, sync::Mutex};
 use eyre::{eyre, Context, Result};
 use flate2::Compression;
 use log::{error, trace, warn};
-use serde_json::Value;
+use serde_json::{json, Value};
 
 use crate::logs::headroom::HeadroomCheck;
 use crate::logs::log_file::{LogFile, LogFileControl, LogFileControlImpl};
+use crate::logs::log_filter::LogFilter;
 use crate::logs::recovery::recover_old_logs;
+use crate::logs::source_rate_limiter::{
+    DroppedLines, SourceRateLimit, SourceRateLimiter, OTHER_SOURCES,
+};
+use crate::mar::CompressionAlgorithm;
 use crate::util::rate_limiter::RateLimiter;
-use crate::{config::Config, metrics::MetricReportManager};
 use crate::{config::LogToMetricRule, logs::completed_log::CompletedLog};
+use crate::{
+    config::{Config, LogFilteringConfig, LogSourceRateLimitsConfig},
+    metrics::MetricReportManager,
+};
 
 #[cfg(feature = "log-to-metrics")]
 use super::log_to_metrics::LogToMetrics;
 
+/// Counts the log lines dropped by the rate limit of their source.
+const METRIC_RATE_LIMITED_LOG_LINES_PREFIX: &str = "log_lines_rate_limited_";
+/// Maximum number of sources with their own metric. The lines of the other sources are counted
+/// together, as `OTHER_SOURCES`.
+const MAX_RATE_LIMITED_SOURCE_METRICS: usize = 32;
+
 pub struct LogCollector<H: HeadroomCheck + Send + 'static> {
     inner: Arc<Mutex<Option<Inner<H>>>>,
 }
@@ -36,7 +51,6 @@ impl<H: HeadroomCheck + Send + 'static> LogCollector<H> {
         log_config: LogCollectorConfig,
         mut on_log_completion: R,
         headroom_limiter: H,
-        #[cfg_attr(not(feature = "log-to-metrics"), allow(unused_variables))]
         heartbeat_manager: Arc<Mutex<MetricReportManager>>,
     ) -> Result<Self> {
         fs::create_dir_all(&log_config.log_tmp_path).wrap_err_with(|| {
@@ -46,6 +60,9 @@ impl<H: HeadroomCheck + Send + 'static> LogCollector<H> {
             )
         })?;
 
+        let log_filter =
+            LogFilter::new(&log_config.filtering).wrap_err("Invalid log filtering rules")?;
+
         // Collect any leftover logfiles in the tmp folder
         let next_cid = recover_old_logs(&log_config.log_tmp_path, &mut on_log_completion)?;
 
@@ -56,16 +73,24 @@ impl<H: HeadroomCheck + Send + 'static> LogCollector<H> {
                     next_cid,
                     log_config.log_max_size,
                     log_config.log_max_duration,
+                    log_config.log_compression,
                     log_config.log_compression_level,
                     on_log_completion,
                 )?,
                 rate_limiter: RateLimiter::new(log_config.max_lines_per_minute),
+                source_rate_limiter: log_config
+                    .source_rate_limits
+                    .as_ref()
+                    .map(SourceRateLimiter::new),
+                rate_limited_sources: HashSet::new(),
+                log_filter,
                 headroom_limiter,
                 #[cfg(feature = "log-to-metrics")]
                 log_to_metrics: LogToMetrics::new(
                     log_config.log_to_metrics_rules,
-                    heartbeat_manager,
+                    heartbeat_manager.clone(),
                 ),
+                heartbeat_manager,
             }))),
         })
     }
@@ -159,10 +184,15 @@ struct Inner<H: HeadroomCheck> {
     // We use an Option<Value> here because we have no typed-guarantee that every
     // log message will include a `ts` key.
     rate_limiter: RateLimiter<Option<Value>>,
+    source_rate_limiter: Option<SourceRateLimiter>,
+    /// Sources which have their own rate limited lines metric
+    rate_limited_sources: HashSet<String>,
+    log_filter: LogFilter,
     log_file_control: LogFileControlImpl,
     headroom_limiter: H,
     #[cfg(feature = "log-to-metrics")]
     log_to_metrics: LogToMetrics,
+    heartbeat_manager: Arc<Mutex<MetricReportManager>>,
 }
 
 impl<H: HeadroomCheck> Inner<H> {
@@ -171,13 +201,18 @@ impl<H: HeadroomCheck> Inner<H> {
     // Be careful to not try to acquire other mutexes here to avoid a
     // dead-lock. Everything we need should be in Inner.
     fn process_log_record(&mut self, log: Value) -> Result<()> {
-        let log_timestamp = log.get("ts");
-
         #[cfg(feature = "log-to-metrics")]
         if let Err(e) = self.log_to_metrics.process(&log) {
             warn!("Error processing log to metrics: {:?}", e);
         }
 
+        // Dropped lines do not count against the rate limit.
+        let log = match self.log_filter.apply(log) {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let log_timestamp = log.get("ts");
+
         if !self
             .headroom_limiter
             .check(log_timestamp, &mut self.log_file_control)?
@@ -185,10 +220,37 @@ impl<H: HeadroomCheck> Inner<H> {
             return Ok(());
         }
 
+        let dropped_lines = match self.source_rate_limiter.as_mut().map(|l| l.check(&log)) {
+            Some(SourceRateLimit::Limited { source }) => {
+                self.count_rate_limited_line(&source);
+                return Ok(());
+            }
+            Some(SourceRateLimit::Allowed(dropped_lines)) => dropped_lines,
+            None => None,
+        };
+
         // Rotate before writing (in case log file is now too old)
         self.log_file_control.rotate_if_needed()?;
 
         let logfile = self.log_file_control.current_log();
+        // Print a message if some previous lines of this source were rate limited.
+        if let (
+            Some(DroppedLines {
+                source,
+                count,
+                latest_ts,
+            }),
+            Some(source_rate_limiter),
+        ) = (dropped_lines, &self.source_rate_limiter)
+        {
+            logfile.write_json_line(json!({
+                "ts": latest_ts,
+                "data": {
+                    "MESSAGE": format!("Memfaultd rate limited {} messages from {}.", count, source),
+                    source_rate_limiter.key_field(): source,
+                }
+            }))?;
+        }
         self.rate_limiter
             .run_within_limits(log_timestamp.cloned(), |rate_limited_calls| {
                 // Print a message if some previous calls were rate limited.
@@ -210,6 +272,27 @@ impl<H: HeadroomCheck> Inner<H> {
     fn rotate_if_needed(&mut self) -> Result<bool> {
         self.log_file_control.rotate_if_needed()
     }
+
+    fn count_rate_limited_line(&mut self, source: &str) {
+        let source = match self.rate_limited_sources.contains(source)
+            || self.rate_limited_sources.len() < MAX_RATE_LIMITED_SOURCE_METRICS
+        {
+            true => {
+                self.rate_limited_sources.insert(source.to_string());
+                source
+            }
+            false => OTHER_SOURCES,
+        };
+        let metric_name = format!("{}{}", METRIC_RATE_LIMITED_LOG_LINES_PREFIX, source);
+        if let Err(e) = self
+            .heartbeat_manager
+            .lock()
+            .unwrap()
+            .increment_counter(&metric_name)
+        {
+            warn!("Failed to increment {}: {}", metric_name, e);
+        }
+    }
 }
 
 pub struct LogCollectorConfig {
@@ -222,15 +305,24 @@ pub struct LogCollectorConfig {
     /// MAR entry will be rotated when they get this old.
     log_max_duration: Duration,
 
+    /// Algorithm used to compress the logs.
+    log_compression: CompressionAlgorithm,
+
     /// Compression level to use for compressing the logs.
     log_compression_level: Compression,
 
     /// Maximum number of lines written per second continuously
     max_lines_per_minute: NonZeroU32,
 
+    /// Maximum number of lines written per minute by each source
+    source_rate_limits: Option<LogSourceRateLimitsConfig>,
+
     /// Rules to convert logs to metrics
     #[cfg_attr(not(feature = "log-to-metrics"), allow(dead_code))]
     log_to_metrics_rules: Vec<LogToMetricRule>,
+
+    /// Rules to drop, strip and redact logs before they are written
+    filtering: LogFilteringConfig,
 }
 
 impl From<&Config> for LogCollectorConfig {
@@ -239,8 +331,10 @@ impl From<&Config> for LogCollectorConfig {
             log_tmp_path: config.logs_path(),
             log_max_size: config.config_file.logs.rotate_size,
             log_max_duration: config.config_file.logs.rotate_after,
+            log_compression: config.config_file.logs.compression.into(),
             log_compression_level: config.config_file.logs.compression_level,
             max_lines_per_minute: config.config_file.logs.max_lines_per_minute,
+            source_rate_limits: config.config_file.logs.source_rate_limits.clone(),
             log_to_metrics_rules: config
                 .config_file
                 .logs
@@ -248,22 +342,35 @@ impl From<&Config> for LogCollectorConfig {
                 .as_ref()
                 .map(|c| c.rules.clone())
                 .unwrap_or_default(),
+            filtering: config
+                .config_file
+                .logs
+                .filtering
+                .clone()
+                .unwrap_or_default(),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::num::NonZeroU32;
+    use std::str::FromStr;
     use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::mpsc::{channel, Receiver};
     use std::sync::Arc;
     use std::{fs::remove_file, sync::Mutex};
     use std::{io::Write, path::PathBuf, time::Duration};
 
+    use crate::config::{LogFilteringConfig, LogSourceRateLimitsConfig};
     use crate::logs::headroom::HeadroomCheck;
     use crate::logs::log_file::{LogFile, LogFileControl};
-    use crate::{logs::completed_log::CompletedLog, metrics::MetricReportManager};
+    use crate::mar::CompressionAlgorithm;
+    use crate::{
+        logs::completed_log::CompletedLog,
+        metrics::{MetricReportManager, MetricStringKey, MetricValue},
+    };
     use eyre::Context;
     use flate2::Compression;
     use rstest::{fixture, rstest};
@@ -271,7 +378,7 @@ mod tests {
     use tempfile::{tempdir, TempDir};
     use uuid::Uuid;
 
-    use super::{LogCollector, LogCollectorConfig};
+    use super::{LogCollector, LogCollectorConfig, MAX_RATE_LIMITED_SOURCE_METRICS};
 
     #[rstest]
     fn write_logs_to_disk(mut fixture: LogFixture) {
@@ -323,6 +430,80 @@ mod tests {
         assert_eq!(fixture.on_log_completion_calls(), 0);
     }
 
+    #[rstest]
+    fn rate_limits_each_source() {
+        let mut fixture = collector_with_source_rate_limits(
+            tempdir().unwrap(),
+            Some(LogSourceRateLimitsConfig {
+                key_field: "_SYSTEMD_UNIT".to_string(),
+                default_lines_per_minute: NonZeroU32::new(2).unwrap(),
+                overrides: HashMap::new(),
+            }),
+        );
+
+        for unit in ["noisy.service"; 5].iter().chain(&["quiet.service"]) {
+            fixture
+                .collector
+                .with_mut_inner(|inner| {
+                    inner.process_log_record(
+                        json!({"ts": 0, "data": {"MESSAGE": "xxx", "_SYSTEMD_UNIT": unit}}),
+                    )
+                })
+                .unwrap();
+        }
+
+        let metrics = fixture
+            .heartbeat_manager
+            .lock()
+            .unwrap()
+            .take_heartbeat_metrics();
+        assert_eq!(
+            metrics
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect::<Vec<_>>(),
+            vec![(
+                "log_lines_rate_limited_noisy.service".to_string(),
+                MetricValue::Number(3.0)
+            )]
+        );
+    }
+
+    #[rstest]
+    fn counts_rate_limited_lines_of_many_sources_together() {
+        let mut fixture = collector_with_source_rate_limits(
+            tempdir().unwrap(),
+            Some(LogSourceRateLimitsConfig {
+                key_field: "_PID".to_string(),
+                default_lines_per_minute: NonZeroU32::new(1).unwrap(),
+                overrides: HashMap::new(),
+            }),
+        );
+
+        let sources = MAX_RATE_LIMITED_SOURCE_METRICS + 8;
+        for pid in (0..sources).flat_map(|pid| [pid, pid]) {
+            fixture
+                .collector
+                .with_mut_inner(|inner| {
+                    inner.process_log_record(
+                        json!({"ts": 0, "data": {"MESSAGE": "xxx", "_PID": pid.to_string()}}),
+                    )
+                })
+                .unwrap();
+        }
+
+        let metrics = fixture
+            .heartbeat_manager
+            .lock()
+            .unwrap()
+            .take_heartbeat_metrics();
+        assert_eq!(metrics.len(), MAX_RATE_LIMITED_SOURCE_METRICS + 1);
+        assert_eq!(
+            metrics.get(&MetricStringKey::from_str("log_lines_rate_limited_other").unwrap()),
+            Some(&MetricValue::Number(8.0))
+        );
+    }
+
     #[rstest]
     fn recover_old_logfiles() {
         let (tmp_logs, _old_file_path) = existing_tmplogs_with_log(&(Uuid::new_v4().to_string()));
@@ -365,6 +546,7 @@ mod tests {
         logs_dir: TempDir,
         on_log_completion_receiver: Receiver<(PathBuf, Uuid)>,
         on_completion_should_fail: Arc<AtomicBool>,
+        heartbeat_manager: Arc<Mutex<MetricReportManager>>,
     }
     impl LogFixture {
         fn count_log_files(&self) -> usize {
@@ -400,13 +582,23 @@ mod tests {
     }
 
     fn collector_with_logs_dir(logs_dir: TempDir) -> LogFixture {
+        collector_with_source_rate_limits(logs_dir, None)
+    }
+
+    fn collector_with_source_rate_limits(
+        logs_dir: TempDir,
+        source_rate_limits: Option<LogSourceRateLimitsConfig>,
+    ) -> LogFixture {
         let config = LogCollectorConfig {
             log_tmp_path: logs_dir.path().to_owned(),
             log_max_size: 1024,
             log_max_duration: Duration::from_secs(3600),
+            log_compression: CompressionAlgorithm::Zlib,
             log_compression_level: Compression::default(),
             max_lines_per_minute: NonZeroU32::new(1_000).unwrap(),
+            source_rate_limits,
             log_to_metrics_rules: vec![],
+            filtering: LogFilteringConfig::default(),
         };
 
         let (on_log_completion_sender, on_log_completion_receiver) = channel();
@@ -435,7 +627,7 @@ mod tests {
                 config,
                 on_log_completion,
                 StubHeadroomLimiter,
-                heartbeat_manager,
+                heartbeat_manager.clone(),
             )
             .unwrap()
         };
@@ -445,6 +637,7 @@ mod tests {
             collector,
             on_log_completion_receiver,
             on_completion_should_fail,
+            heartbeat_manager,
         }
     }
 }
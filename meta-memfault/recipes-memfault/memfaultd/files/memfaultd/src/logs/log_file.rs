@@ -6,7 +6,6 @@
 use crate::logs::completed_log::CompletedLog;
 use crate::mar::CompressionAlgorithm;
 use eyre::{Result, WrapErr};
-use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use log::{trace, warn};
 use serde_json::{json, Value};
@@ -32,22 +31,32 @@ pub trait LogFile {
 pub struct LogFileImpl {
     cid: Uuid,
     path: PathBuf,
-    writer: ZlibEncoder<BufWriter<File>>,
+    compression: CompressionAlgorithm,
+    writer: Box<dyn Write + Send>,
     bytes_written: usize,
     since: Instant,
 }
 
 impl LogFileImpl {
-    fn open(log_tmp_path: &Path, cid: Uuid, compression_level: Compression) -> Result<LogFileImpl> {
-        let filename = cid.to_string() + ".log.zlib";
+    fn open(
+        log_tmp_path: &Path,
+        cid: Uuid,
+        compression: CompressionAlgorithm,
+        compression_level: Compression,
+    ) -> Result<LogFileImpl> {
+        let filename = match compression.file_extension() {
+            Some(extension) => format!("{}.log.{}", cid, extension),
+            None => format!("{}.log", cid),
+        };
         let path = log_tmp_path.join(filename);
         let file = File::create(&path)?;
-        let writer = ZlibEncoder::new(BufWriter::new(file), compression_level);
+        let writer = compression.encoder(BufWriter::new(file), compression_level)?;
 
         trace!("Now writing logs to: {}", path.display());
         Ok(LogFileImpl {
             cid,
             path,
+            compression,
             writer,
             bytes_written: 0,
             since: Instant::now(),
@@ -82,6 +91,7 @@ pub struct LogFileControlImpl {
     tmp_path: PathBuf,
     max_size: usize,
     max_duration: Duration,
+    compression: CompressionAlgorithm,
     compression_level: Compression,
     on_log_completion: Box<(dyn FnMut(CompletedLog) -> Result<()> + Send)>,
 }
@@ -92,14 +102,16 @@ impl LogFileControlImpl {
         next_cid: Uuid,
         max_size: usize,
         max_duration: Duration,
+        compression: CompressionAlgorithm,
         compression_level: Compression,
         on_log_completion: R,
     ) -> Result<Self> {
         Ok(LogFileControlImpl {
-            current_log: LogFileImpl::open(&tmp_path, next_cid, compression_level)?,
+            current_log: LogFileImpl::open(&tmp_path, next_cid, compression, compression_level)?,
             tmp_path,
             max_size,
             max_duration,
+            compression,
             compression_level,
             on_log_completion: Box::new(on_log_completion),
         })
@@ -110,7 +122,12 @@ impl LogFileControlImpl {
         // Start a new log and make it the current one. We are now writing there.
         let closed_log = replace(
             &mut self.current_log,
-            LogFileImpl::open(&self.tmp_path, Uuid::new_v4(), self.compression_level)?,
+            LogFileImpl::open(
+                &self.tmp_path,
+                Uuid::new_v4(),
+                self.compression,
+                self.compression_level,
+            )?,
         );
 
         Self::dispatch_on_log_completion(
@@ -132,14 +149,19 @@ impl LogFileControlImpl {
             warn!("Failed to flush logs: {}", e);
         });
 
-        let LogFileImpl { path, cid, .. } = log;
+        let LogFileImpl {
+            path,
+            cid,
+            compression,
+            ..
+        } = log;
 
         // The callback is responsible for moving the file to its final location (or deleting it):
         (on_log_completion)(CompletedLog {
             path: path.clone(),
             cid,
             next_cid,
-            compression: CompressionAlgorithm::Zlib,
+            compression,
         })
         .unwrap_or_else(|e| {
             warn!(
@@ -205,8 +227,13 @@ mod tests {
         let tmp = tempdir().expect("tmpdir");
 
         // Generate a logfile with lots of bogus data
-        let mut log = LogFileImpl::open(tmp.path(), Uuid::new_v4(), Compression::fast())
-            .expect("open log error");
+        let mut log = LogFileImpl::open(
+            tmp.path(),
+            Uuid::new_v4(),
+            CompressionAlgorithm::Zlib,
+            Compression::fast(),
+        )
+        .expect("open log error");
         let mut count_lines = 0;
         while log.bytes_written < 1024 * 1024 {
             let message = format!(
@@ -0,0 +1,236 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! Drop, strip and redact log lines before they are written to disk.
+//!
+use std::borrow::Cow;
+
+use eyre::{Context, Result};
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use crate::config::{
+    LogDropRule, LogFieldCondition, LogFieldOperator, LogFilteringConfig, LogRedactionRule,
+};
+
+/// Field which is always kept by the field allowlist.
+const MESSAGE_FIELD: &str = "MESSAGE";
+
+pub struct LogFilter {
+    drop_rules: Vec<Vec<Condition>>,
+    field_allowlist: Option<Vec<String>>,
+    redaction_rules: Vec<Redaction>,
+}
+
+struct Condition {
+    field: String,
+    operator: LogFieldOperator,
+    value: String,
+    /// Compiled value of `matches` conditions
+    regex: Option<Regex>,
+}
+
+struct Redaction {
+    regex: Regex,
+    replacement: String,
+    fields: Vec<String>,
+}
+
+impl LogFilter {
+    /// Compiles the rules. Fails if one of the regexes is invalid.
+    pub fn new(config: &LogFilteringConfig) -> Result<Self> {
+        Ok(Self {
+            drop_rules: config
+                .drop_rules
+                .iter()
+                .map(|LogDropRule { conditions }| conditions.iter().map(Condition::new).collect())
+                .collect::<Result<_>>()?,
+            field_allowlist: config.field_allowlist.clone(),
+            redaction_rules: config
+                .redaction_rules
+                .iter()
+                .map(Redaction::new)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Applies the rules on a log line. Returns None when the line should be dropped.
+    pub fn apply(&self, mut log: Value) -> Option<Value> {
+        let data = match log.get_mut("data").and_then(Value::as_object_mut) {
+            Some(data) => data,
+            None => return Some(log),
+        };
+
+        if self
+            .drop_rules
+            .iter()
+            .any(|conditions| conditions.iter().all(|c| c.is_true(data)))
+        {
+            return None;
+        }
+
+        if let Some(allowlist) = &self.field_allowlist {
+            data.retain(|k, _| k == MESSAGE_FIELD || allowlist.contains(k));
+        }
+
+        for redaction in &self.redaction_rules {
+            redaction.apply(data);
+        }
+        Some(log)
+    }
+}
+
+impl Condition {
+    fn new(condition: &LogFieldCondition) -> Result<Self> {
+        let regex = match condition.operator {
+            LogFieldOperator::Matches => Some(
+                Regex::new(&condition.value)
+                    .wrap_err_with(|| format!("Invalid log drop rule for {}", condition.field))?,
+            ),
+            _ => None,
+        };
+        Ok(Self {
+            field: condition.field.clone(),
+            operator: condition.operator,
+            value: condition.value.clone(),
+            regex,
+        })
+    }
+
+    /// Whether the condition is true for a log line. Conditions on missing fields are false.
+    fn is_true(&self, data: &Map<String, Value>) -> bool {
+        let field = match data.get(&self.field).map(field_as_str) {
+            Some(field) => field,
+            None => return false,
+        };
+        let compare = |check: fn(f64, f64) -> bool| match (
+            field.trim().parse::<f64>(),
+            self.value.trim().parse::<f64>(),
+        ) {
+            (Ok(field), Ok(value)) => check(field, value),
+            _ => false,
+        };
+        match self.operator {
+            LogFieldOperator::Equal => field == self.value,
+            LogFieldOperator::NotEqual => field != self.value,
+            LogFieldOperator::GreaterThan => compare(|f, v| f > v),
+            LogFieldOperator::GreaterThanOrEqual => compare(|f, v| f >= v),
+            LogFieldOperator::LessThan => compare(|f, v| f < v),
+            LogFieldOperator::LessThanOrEqual => compare(|f, v| f <= v),
+            LogFieldOperator::Matches => self.regex.as_ref().map_or(false, |r| r.is_match(&field)),
+        }
+    }
+}
+
+impl Redaction {
+    fn new(rule: &LogRedactionRule) -> Result<Self> {
+        Ok(Self {
+            regex: Regex::new(&rule.pattern)
+                .wrap_err_with(|| format!("Invalid log redaction pattern {}", rule.pattern))?,
+            replacement: rule.replacement.clone(),
+            fields: rule.fields.clone(),
+        })
+    }
+
+    /// Replaces the matches in the string fields of the rule.
+    fn apply(&self, data: &mut Map<String, Value>) {
+        for field in &self.fields {
+            if let Some(Value::String(value)) = data.get_mut(field) {
+                if let Cow::Owned(redacted) =
+                    self.regex.replace_all(value, self.replacement.as_str())
+                {
+                    *value = redacted;
+                }
+            }
+        }
+    }
+}
+
+/// Journald fields are strings, but other sources may send numbers.
+fn field_as_str(value: &Value) -> Cow<'_, str> {
+    match value {
+        Value::String(s) => Cow::Borrowed(s),
+        v => Cow::Owned(v.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use serde_json::json;
+
+    use super::*;
+
+    fn filter(config: Value) -> LogFilter {
+        LogFilter::new(&serde_json::from_value(config).unwrap()).unwrap()
+    }
+
+    #[rstest]
+    #[case::priority_above(json!({"MESSAGE": "debug", "PRIORITY": "7"}), true)]
+    #[case::priority_below(json!({"MESSAGE": "error", "PRIORITY": "3"}), false)]
+    #[case::numeric_priority(json!({"MESSAGE": "debug", "PRIORITY": 7}), true)]
+    #[case::all_conditions(json!({"MESSAGE": "heartbeat 42", "_SYSTEMD_UNIT": "noisy.service"}), true)]
+    #[case::some_conditions(json!({"MESSAGE": "crashed", "_SYSTEMD_UNIT": "noisy.service"}), false)]
+    #[case::missing_field(json!({"MESSAGE": "heartbeat 42"}), false)]
+    fn drops_matching_lines(#[case] data: Value, #[case] dropped: bool) {
+        let filter = filter(json!({
+            "drop_rules": [
+                {"conditions": [{"field": "PRIORITY", "operator": ">", "value": "6"}]},
+                {"conditions": [
+                    {"field": "_SYSTEMD_UNIT", "operator": "==", "value": "noisy.service"},
+                    {"field": "MESSAGE", "operator": "matches", "value": "^heartbeat"}
+                ]}
+            ]
+        }));
+
+        assert_eq!(
+            filter.apply(json!({"ts": 0, "data": data})).is_none(),
+            dropped
+        );
+    }
+
+    #[rstest]
+    fn strips_fields_not_in_allowlist() {
+        let filter = filter(json!({"field_allowlist": ["PRIORITY"]}));
+
+        assert_eq!(
+            filter.apply(
+                json!({"ts": 0, "data": {"MESSAGE": "hello", "PRIORITY": "6", "_PID": "42"}})
+            ),
+            Some(json!({"ts": 0, "data": {"MESSAGE": "hello", "PRIORITY": "6"}}))
+        );
+    }
+
+    #[rstest]
+    fn redacts_fields() {
+        let filter = filter(json!({
+            "redaction_rules": [
+                {"pattern": "[\\w.+-]+@[\\w-]+\\.[\\w.]+", "replacement": "<email>"},
+                {"pattern": "token=\\w+", "replacement": "token=<redacted>", "fields": ["MESSAGE", "URL"]}
+            ]
+        }));
+
+        assert_eq!(
+            filter.apply(json!({"ts": 0, "data": {
+                "MESSAGE": "login of jane.doe@example.com with token=abc123",
+                "URL": "https://example.com/?token=abc123",
+                "USER": "jane.doe@example.com"
+            }})),
+            Some(json!({"ts": 0, "data": {
+                "MESSAGE": "login of <email> with token=<redacted>",
+                "URL": "https://example.com/?token=<redacted>",
+                "USER": "jane.doe@example.com"
+            }}))
+        );
+    }
+
+    #[rstest]
+    fn rejects_invalid_regex() {
+        let config = serde_json::from_value(json!({
+            "redaction_rules": [{"pattern": "(", "replacement": ""}]
+        }))
+        .unwrap();
+
+        assert!(LogFilter::new(&config).is_err());
+    }
+}
@@ -6,12 +6,16 @@ use std::{
     sync::{Arc, Mutex},
 };
 
-use eyre::Result;
+use chrono::{Duration, Utc};
+use eyre::{eyre, Result};
 use log::{debug, warn};
-use regex::Regex;
+use regex::{Captures, Regex};
 use serde_json::{Map, Value};
 
-use crate::{config::LogToMetricRule, metrics::MetricReportManager};
+use crate::{
+    config::LogToMetricRule,
+    metrics::{KeyedMetricReading, MetricReading, MetricReportManager, MetricStringKey},
+};
 
 const SEARCH_FIELD: &str = "MESSAGE";
 
@@ -38,19 +42,87 @@ impl LogToMetrics {
             if !self.rules.is_empty() {
                 debug!("LogToMetrics: Processing log: {:?}", data);
                 for rule in &self.rules {
-                    match rule {
+                    let (pattern, metric_name, filter) = match rule {
                         LogToMetricRule::CountMatching {
                             pattern,
                             metric_name,
                             filter,
-                        } => Self::apply_count_matching(
-                            data,
+                        }
+                        | LogToMetricRule::ExtractGauge {
+                            pattern,
+                            metric_name,
+                            filter,
+                            ..
+                        }
+                        | LogToMetricRule::ExtractCounter {
                             pattern,
-                            &mut self.regex_cache,
                             metric_name,
                             filter,
-                            self.heartbeat_manager.clone(),
+                            ..
+                        }
+                        | LogToMetricRule::SetState {
+                            pattern,
+                            metric_name,
+                            filter,
+                            ..
+                        } => (pattern, metric_name, filter),
+                    };
+                    let regex = self
+                        .regex_cache
+                        .entry(pattern.to_string())
+                        .or_insert_with(|| Regex::new(pattern).unwrap());
+                    let captures = match Self::match_log(data, regex, filter) {
+                        Some(captures) => captures,
+                        None => continue,
+                    };
+                    let metric_name_with_captures =
+                        Self::get_metric_name_with_captures(metric_name, regex, &captures);
+
+                    let result = match rule {
+                        LogToMetricRule::CountMatching { .. } => self
+                            .heartbeat_manager
+                            .lock()
+                            .unwrap()
+                            .increment_counter(&metric_name_with_captures),
+                        LogToMetricRule::ExtractGauge { value_group, .. } => {
+                            Self::captured_value(&captures, value_group).and_then(|value| {
+                                Self::add_reading(
+                                    &self.heartbeat_manager,
+                                    &metric_name_with_captures,
+                                    MetricReading::Gauge {
+                                        value,
+                                        timestamp: Utc::now(),
+                                        interval: Duration::zero(),
+                                    },
+                                )
+                            })
+                        }
+                        LogToMetricRule::ExtractCounter { value_group, .. } => {
+                            Self::captured_value(&captures, value_group).and_then(|value| {
+                                Self::add_reading(
+                                    &self.heartbeat_manager,
+                                    &metric_name_with_captures,
+                                    MetricReading::Counter {
+                                        value,
+                                        timestamp: Utc::now(),
+                                    },
+                                )
+                            })
+                        }
+                        LogToMetricRule::SetState { state, .. } => Self::add_reading(
+                            &self.heartbeat_manager,
+                            &metric_name_with_captures,
+                            MetricReading::State {
+                                value: Self::get_metric_name_with_captures(state, regex, &captures),
+                                timestamp: Utc::now(),
+                            },
                         ),
+                    };
+                    if let Err(e) = result {
+                        warn!(
+                            "Failed to update metric {}: {}",
+                            metric_name_with_captures, e
+                        )
                     }
                 }
             }
@@ -58,61 +130,87 @@ impl LogToMetrics {
         Ok(())
     }
 
-    fn get_metric_name_with_captures(metric_name: &str, captures: regex::Captures) -> String {
+    /// Replaces `${name}` and `${n}` (or `$n`) in a template with the captured groups.
+    fn get_metric_name_with_captures(
+        metric_name: &str,
+        regex: &Regex,
+        captures: &Captures,
+    ) -> String {
         let mut metric_name_with_captures = metric_name.to_string();
-        for (i, capture) in captures.iter().enumerate() {
-            if let Some(capture) = capture {
-                metric_name_with_captures =
-                    metric_name_with_captures.replace(&format!("${}", i), capture.as_str());
+        // Iterate in reverse order so that $1 does not replace the beginning of $10.
+        for (i, name) in regex
+            .capture_names()
+            .enumerate()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            if let Some(capture) = captures.get(i) {
+                if let Some(name) = name {
+                    metric_name_with_captures = metric_name_with_captures
+                        .replace(&format!("${{{}}}", name), capture.as_str());
+                }
+                metric_name_with_captures = metric_name_with_captures
+                    .replace(&format!("${{{}}}", i), capture.as_str())
+                    .replace(&format!("${}", i), capture.as_str());
             }
         }
         metric_name_with_captures
     }
 
-    fn apply_count_matching(
-        data: &Map<String, Value>,
-        pattern: &str,
-        regex_cache: &mut HashMap<String, Regex>,
+    /// Parses the value captured by `value_group` (a group name or index). Without
+    /// `value_group`, uses the group named `value`, or the first group.
+    fn captured_value(captures: &Captures, value_group: &Option<String>) -> Result<f64> {
+        let capture = match value_group {
+            Some(group) => match group.parse::<usize>() {
+                Ok(i) => captures.get(i),
+                Err(_) => captures.name(group),
+            },
+            None => captures.name("value").or_else(|| captures.get(1)),
+        };
+        let capture = capture.ok_or_else(|| eyre!("No value captured"))?;
+        capture
+            .as_str()
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| eyre!("Invalid value '{}'", capture.as_str()))
+    }
+
+    fn add_reading(
+        heartbeat_manager: &Arc<Mutex<MetricReportManager>>,
         metric_name: &str,
+        reading: MetricReading,
+    ) -> Result<()> {
+        let name: MetricStringKey = metric_name.parse().map_err(|e: &str| eyre!(e))?;
+        heartbeat_manager
+            .lock()
+            .unwrap()
+            .add_metric(KeyedMetricReading::new(name, reading))
+    }
+
+    /// Applies the filter and the regex on a log entry.
+    fn match_log<'a>(
+        data: &'a Map<String, Value>,
+        regex: &Regex,
         filter: &HashMap<String, String>,
-        heartbeat_manager: Arc<Mutex<MetricReportManager>>,
-    ) {
+    ) -> Option<Captures<'a>> {
         // Use filter to quickly disqualify a log entry
         for (key, value) in filter {
-            if let Some(log_value) = data.get(key) {
-                if log_value != value {
-                    return;
-                }
-            } else {
-                return;
+            if data.get(key)? != value {
+                return None;
             }
         }
 
-        let regex = regex_cache
-            .entry(pattern.to_string())
-            .or_insert_with(|| Regex::new(pattern).unwrap());
-        if let Some(search_value) = data[SEARCH_FIELD].as_str() {
-            let captures = regex.captures(search_value);
-            debug!(
-                "LogToMetrics Pattern '{}'=> MATCH={} Captures={:?}",
-                &pattern,
-                captures.is_some(),
-                captures
-            );
-
-            if let Some(captures) = captures {
-                let metric_name_with_captures =
-                    Self::get_metric_name_with_captures(metric_name, captures);
-
-                if let Err(e) = heartbeat_manager
-                    .lock()
-                    .unwrap()
-                    .increment_counter(&metric_name_with_captures)
-                {
-                    warn!("Failed to increment metric: {}", e)
-                }
-            }
-        }
+        let search_value = data.get(SEARCH_FIELD)?.as_str()?;
+        let captures = regex.captures(search_value);
+        debug!(
+            "LogToMetrics Pattern '{}'=> MATCH={} Captures={:?}",
+            regex.as_str(),
+            captures.is_some(),
+            captures
+        );
+        captures
     }
 }
 
@@ -166,6 +264,39 @@ mod tests {
         metric_name: "oomkill_$1".to_string(),
         filter: HashMap::default()
     }], vec![json!({"MESSAGE": "Out of memory: Killed process 423 (wefaultd) total-vm:553448kB, anon-rss:284496kB, file-rss:0kB, shmem-rss:0kB, UID:0 pgtables:624kB oom_score_adj:0"})], "oomkill_wefaultd", 1.0)]
+    #[case(vec![LogToMetricRule::ExtractGauge {
+        pattern: "(?P<iface>\\w+): rssi=(?P<value>-?\\d+)".to_string(),
+        metric_name: "wifi_${iface}_rssi".to_string(),
+        value_group: None,
+        filter: HashMap::default()
+    }],
+    vec![
+        json!({"MESSAGE": "wlan0: rssi=-60"}),
+        json!({"MESSAGE": "wlan1: rssi=-90"}),
+        json!({"MESSAGE": "wlan0: rssi=-70"}),
+    ], "wifi_wlan0_rssi", -65.0)]
+    #[case(vec![LogToMetricRule::ExtractGauge {
+        pattern: "latency of (\\w+) is ([\\d.]+)ms".to_string(),
+        metric_name: "${1}_latency_ms".to_string(),
+        value_group: Some("2".to_string()),
+        filter: HashMap::default()
+    }], vec![json!({"MESSAGE": "latency of upload is 12.5ms"})], "upload_latency_ms", 12.5)]
+    #[case(vec![LogToMetricRule::ExtractCounter {
+        pattern: "bytes_sent=(\\d+)".to_string(),
+        metric_name: "bytes_sent".to_string(),
+        value_group: None,
+        filter: HashMap::default()
+    }],
+    vec![
+        json!({"MESSAGE": "upload done bytes_sent=100"}),
+        json!({"MESSAGE": "upload done bytes_sent=50"}),
+    ], "bytes_sent", 150.0)]
+    #[case(vec![LogToMetricRule::ExtractCounter {
+        pattern: "bytes_sent=(\\w+)".to_string(),
+        metric_name: "bytes_sent".to_string(),
+        value_group: None,
+        filter: HashMap::default()
+    }], vec![json!({"MESSAGE": "upload done bytes_sent=unknown"})], "bytes_sent", 0.0)]
 
     fn test_log_to_metrics(
         #[case] rules: Vec<LogToMetricRule>,
@@ -197,7 +328,41 @@ mod tests {
 
             match m.1 {
                 MetricValue::Number(v) => assert_eq!(*v, expected_value),
+                _ => panic!("Unexpected metric value"),
             }
         }
     }
+
+    #[rstest]
+    fn test_set_state(_setup_logger: ()) {
+        let metric_report_manager = Arc::new(Mutex::new(MetricReportManager::new()));
+        let mut log_to_metrics = LogToMetrics::new(
+            vec![LogToMetricRule::SetState {
+                pattern: "(?P<iface>\\w+): link (?P<state>up|down)".to_string(),
+                metric_name: "${iface}_link".to_string(),
+                state: "${state}".to_string(),
+                filter: HashMap::default(),
+            }],
+            metric_report_manager.clone(),
+        );
+
+        for message in ["eth0: link up", "eth0: link down", "eth1: link up"] {
+            log_to_metrics
+                .process(&json!({ "data": { "MESSAGE": message } }))
+                .expect("process error");
+        }
+        let mut metrics = metric_report_manager
+            .lock()
+            .unwrap()
+            .take_heartbeat_metrics()
+            .into_keys()
+            .map(|k| k.to_string())
+            .collect::<Vec<_>>();
+        metrics.sort();
+
+        assert_eq!(
+            metrics,
+            vec!["eth0_link.down_ms", "eth0_link.up_ms", "eth1_link.up_ms"]
+        );
+    }
 }
@@ -10,7 +10,9 @@ pub use log_collector::{LogCollector, LogCollectorConfig};
 pub mod headroom;
 pub use headroom::HeadroomLimiter;
 mod log_file;
+mod log_filter;
 mod recovery;
+mod source_rate_limiter;
 
 #[cfg(feature = "log-to-metrics")]
 mod log_to_metrics;
@@ -135,11 +135,14 @@ pub fn recover_old_logs<R: FnMut(CompletedLog) -> Result<()> + Send + 'static>(
         // Write the MAR entry which will move the logfile
         debug!("Recovering logfile: {:?}", path.display());
 
+        // Logs are compressed with the algorithm configured when they were written:
+        let compression =
+            CompressionAlgorithm::from_file_extension(&path).unwrap_or(CompressionAlgorithm::Zlib);
         if let Err(e) = (on_log_recovery)(CompletedLog {
             path,
             cid,
             next_cid,
-            compression: CompressionAlgorithm::Zlib,
+            compression,
         }) {
             warn!("Unable to recover log file: {}", e);
         }
@@ -0,0 +1,247 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! Rate limit logs separately for each source, so that one chatty service
+//! does not starve the logs of the others.
+//!
+use std::{collections::HashMap, num::NonZeroU32, time::Duration};
+
+use governor::clock::{self, Reference};
+use serde_json::Value;
+
+use crate::{config::LogSourceRateLimitsConfig, util::rate_limiter::RateLimiter};
+
+/// Source of the lines which do not have the key field.
+const UNKNOWN_SOURCE: &str = "unknown";
+
+/// Source of the lines of all the sources which are not tracked, once `MAX_TRACKED_SOURCES`
+/// sources are tracked. They share one rate limit.
+pub const OTHER_SOURCES: &str = "other";
+
+/// Maximum number of sources with their own rate limit, so that a key field with many values
+/// (e.g. _PID) does not grow the rate limiters forever.
+const MAX_TRACKED_SOURCES: usize = 128;
+
+/// Sources which have not logged for this long have a full rate limit and can be forgotten.
+const IDLE_SOURCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Lines of a source which were dropped since its previous line was written.
+#[derive(Debug, PartialEq)]
+pub struct DroppedLines {
+    pub source: String,
+    pub count: usize,
+    /// Timestamp of the last dropped line
+    pub latest_ts: Option<Value>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SourceRateLimit {
+    /// The line can be written. Some of the previous lines of the source may have been dropped.
+    Allowed(Option<DroppedLines>),
+    /// The line should be dropped.
+    Limited { source: String },
+}
+
+pub struct SourceRateLimiter<C: clock::Clock = clock::MonotonicClock> {
+    key_field: String,
+    default_lines_per_minute: NonZeroU32,
+    overrides: HashMap<String, NonZeroU32>,
+    rate_limiters: HashMap<String, TrackedSource<C>>,
+    clock: C,
+}
+
+struct TrackedSource<C: clock::Clock> {
+    rate_limiter: RateLimiter<Option<Value>, C>,
+    last_line: C::Instant,
+}
+
+impl SourceRateLimiter {
+    pub fn new(config: &LogSourceRateLimitsConfig) -> Self {
+        Self::new_with_clock(config, clock::MonotonicClock)
+    }
+}
+
+impl<C: clock::Clock> SourceRateLimiter<C> {
+    pub fn new_with_clock(config: &LogSourceRateLimitsConfig, clock: C) -> Self {
+        Self {
+            key_field: config.key_field.clone(),
+            default_lines_per_minute: config.default_lines_per_minute,
+            overrides: config.overrides.clone(),
+            rate_limiters: HashMap::new(),
+            clock,
+        }
+    }
+
+    /// Field identifying the source of a log line.
+    pub fn key_field(&self) -> &str {
+        &self.key_field
+    }
+
+    /// Checks the limit of the source of a log line.
+    pub fn check(&mut self, log: &Value) -> SourceRateLimit {
+        let now = self.clock.now();
+        let mut source = log["data"][&self.key_field]
+            .as_str()
+            .unwrap_or(UNKNOWN_SOURCE)
+            .to_string();
+        if !self.rate_limiters.contains_key(&source)
+            && self.rate_limiters.len() >= MAX_TRACKED_SOURCES
+        {
+            self.forget_idle_sources(now);
+            if self.rate_limiters.len() >= MAX_TRACKED_SOURCES {
+                source = OTHER_SOURCES.to_string();
+            }
+        }
+
+        let lines_per_minute = *self
+            .overrides
+            .get(&source)
+            .unwrap_or(&self.default_lines_per_minute);
+        let clock = &self.clock;
+        let tracked_source =
+            self.rate_limiters
+                .entry(source.clone())
+                .or_insert_with(|| TrackedSource {
+                    rate_limiter: RateLimiter::new_with_clock(lines_per_minute, clock),
+                    last_line: now,
+                });
+        tracked_source.last_line = now;
+
+        let mut result = SourceRateLimit::Limited {
+            source: source.clone(),
+        };
+        // The work function never fails.
+        let _ = tracked_source
+            .rate_limiter
+            .run_within_limits(log.get("ts").cloned(), |limited| {
+                result = SourceRateLimit::Allowed(limited.map(|l| DroppedLines {
+                    source,
+                    count: l.count,
+                    latest_ts: l.latest_call,
+                }));
+                Ok(())
+            });
+        result
+    }
+
+    /// Forgets the sources which are idle, unless some of their lines were dropped and not
+    /// reported yet.
+    fn forget_idle_sources(&mut self, now: C::Instant) {
+        self.rate_limiters.retain(|_, tracked_source| {
+            tracked_source.rate_limiter.has_limited_calls()
+                || Duration::from(now.duration_since(tracked_source.last_line))
+                    < IDLE_SOURCE_TIMEOUT
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use governor::clock::FakeRelativeClock;
+    use rstest::rstest;
+    use serde_json::json;
+
+    use super::*;
+
+    fn log(ts: u64, unit: Option<&str>) -> Value {
+        match unit {
+            Some(unit) => json!({"ts": ts, "data": {"MESSAGE": "hello", "_SYSTEMD_UNIT": unit}}),
+            None => json!({"ts": ts, "data": {"MESSAGE": "hello"}}),
+        }
+    }
+
+    fn count_allowed<C: clock::Clock>(
+        limiter: &mut SourceRateLimiter<C>,
+        unit: Option<&str>,
+        count: u64,
+    ) -> usize {
+        (0..count)
+            .filter(|i| matches!(limiter.check(&log(*i, unit)), SourceRateLimit::Allowed(_)))
+            .count()
+    }
+
+    #[rstest]
+    fn limits_each_source() {
+        let config = LogSourceRateLimitsConfig {
+            key_field: "_SYSTEMD_UNIT".to_string(),
+            default_lines_per_minute: NonZeroU32::new(10).unwrap(),
+            overrides: HashMap::from([("noisy.service".to_string(), NonZeroU32::new(2).unwrap())]),
+        };
+        let mut limiter = SourceRateLimiter::new_with_clock(&config, FakeRelativeClock::default());
+
+        assert_eq!(count_allowed(&mut limiter, Some("noisy.service"), 20), 2);
+        assert_eq!(count_allowed(&mut limiter, Some("quiet.service"), 20), 10);
+        assert_eq!(count_allowed(&mut limiter, None, 20), 10);
+    }
+
+    #[rstest]
+    fn reports_dropped_lines() {
+        let config = LogSourceRateLimitsConfig {
+            key_field: "_SYSTEMD_UNIT".to_string(),
+            default_lines_per_minute: NonZeroU32::new(1).unwrap(),
+            overrides: HashMap::new(),
+        };
+        let clock = FakeRelativeClock::default();
+        let mut limiter = SourceRateLimiter::new_with_clock(&config, clock.clone());
+
+        assert_eq!(
+            limiter.check(&log(1, Some("a.service"))),
+            SourceRateLimit::Allowed(None)
+        );
+        assert_eq!(
+            limiter.check(&log(2, Some("a.service"))),
+            SourceRateLimit::Limited {
+                source: "a.service".to_string()
+            }
+        );
+        limiter.check(&log(3, Some("a.service")));
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(
+            limiter.check(&log(4, Some("a.service"))),
+            SourceRateLimit::Allowed(Some(DroppedLines {
+                source: "a.service".to_string(),
+                count: 2,
+                latest_ts: Some(json!(3)),
+            }))
+        );
+    }
+
+    #[rstest]
+    fn limits_the_number_of_tracked_sources() {
+        let config = LogSourceRateLimitsConfig {
+            key_field: "_SYSTEMD_UNIT".to_string(),
+            default_lines_per_minute: NonZeroU32::new(1).unwrap(),
+            overrides: HashMap::new(),
+        };
+        let clock = FakeRelativeClock::default();
+        let mut limiter = SourceRateLimiter::new_with_clock(&config, clock.clone());
+
+        for pid in 0..MAX_TRACKED_SOURCES {
+            limiter.check(&log(0, Some(&pid.to_string())));
+        }
+        // New sources share the limit of the other sources
+        assert_eq!(
+            limiter.check(&log(1, Some("new-1"))),
+            SourceRateLimit::Allowed(None)
+        );
+        assert_eq!(
+            limiter.check(&log(2, Some("new-2"))),
+            SourceRateLimit::Limited {
+                source: OTHER_SOURCES.to_string()
+            }
+        );
+        assert_eq!(limiter.rate_limiters.len(), MAX_TRACKED_SOURCES + 1);
+
+        // Idle sources are forgotten
+        clock.advance(IDLE_SOURCE_TIMEOUT);
+        assert_eq!(
+            limiter.check(&log(3, Some("new-3"))),
+            SourceRateLimit::Allowed(None)
+        );
+        assert!(limiter.rate_limiters.contains_key("new-3"));
+        // The dropped line of the other sources is still reported
+        assert!(limiter.rate_limiters.contains_key(OTHER_SOURCES));
+        assert_eq!(limiter.rate_limiters.len(), 2);
+    }
+}
@@ -2,7 +2,7 @@
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
 use crate::{
-    mar::MarEntry,
+    mar::{MarEntry, MarEntryPriority},
     util::disk_size::{get_disk_space, get_size, DiskSize},
 };
 use eyre::{eyre, Result, WrapErr};
@@ -73,6 +73,7 @@ struct AgeSizePath {
     age: Duration,
     size: DiskSize,
     path: PathBuf,
+    priority: MarEntryPriority,
 }
 
 impl AgeSizePath {
@@ -86,8 +87,13 @@ impl AgeSizePath {
             age: (reference_date.duration_since(timestamp)).unwrap_or(Duration::ZERO),
             size,
             path: path.to_owned(),
+            priority: MarEntryPriority::Low,
         }
     }
+
+    fn with_priority(self, priority: MarEntryPriority) -> AgeSizePath {
+        Self { priority, ..self }
+    }
 }
 
 fn clean_mar_staging(
@@ -136,7 +142,9 @@ fn collect_mar_entries(
                 get_size(&entry.path).unwrap_or(DiskSize::ZERO),
                 entry.manifest.collection_time.timestamp.into(),
                 reference_date,
-            ),
+            )
+            .with_priority(entry.manifest.metadata.priority()),
+            // Invalid entries are deleted first, like the lowest priority entries.
             Err(_) => {
                 let path = dir_entry.path();
                 let timestamp = path
@@ -166,9 +174,9 @@ fn mark_entries_for_deletion(
     min_space: DiskSize,
     max_age: Duration,
 ) -> Vec<(AgeSizePath, DeletionReason)> {
-    // Sort entries with oldest first
+    // Sort entries with lowest priority first, then oldest first
     let mut entries_by_age = entries;
-    entries_by_age.sort_by(|a, b| b.age.cmp(&a.age));
+    entries_by_age.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.age.cmp(&a.age)));
 
     // Note that (total_space_used).exceeds(max_total_size) would not
     // work here because it would be false when only one of bytes/inode is
@@ -195,8 +203,8 @@ fn mark_entries_for_deletion(
     // Ignore max_age if it is configured to 0
     let delete_expired_entries = !max_age.is_zero();
 
-    // Since the vector is sorted from oldest to newest,
-    // older entries will be marked for deletion first
+    // Since the vector is sorted from lowest priority and oldest to newest,
+    // low priority and older entries will be marked for deletion first
     entries_by_age
         .into_iter()
         .filter_map(|entry| {
@@ -451,6 +459,53 @@ mod test {
         }
     }
 
+    #[rstest]
+    fn test_lowest_priority_marked_before_oldest() {
+        let now = SystemTime::now();
+        let size = DiskSize {
+            bytes: 1000,
+            inodes: 2,
+        };
+        let entries = vec![
+            AgeSizePath::new(
+                Path::new("/mock/reboot"),
+                size,
+                now - Duration::from_secs(15),
+                now,
+            )
+            .with_priority(MarEntryPriority::High),
+            AgeSizePath::new(
+                Path::new("/mock/metrics"),
+                size,
+                now - Duration::from_secs(10),
+                now,
+            )
+            .with_priority(MarEntryPriority::Normal),
+            AgeSizePath::new(
+                Path::new("/mock/log"),
+                size,
+                now - Duration::from_secs(1),
+                now,
+            )
+            .with_priority(MarEntryPriority::Low),
+        ];
+
+        let marked_entries = mark_entries_for_deletion(
+            entries,
+            DiskSize::new_capacity(3000),
+            DiskSize::new_capacity(1500), // 2 entries over quota
+            DiskSize::new_capacity(10000),
+            DiskSize::ZERO,         // No min headroom quota for this test
+            Duration::from_secs(0), // No max age for this test
+        );
+
+        let marked_paths: Vec<_> = marked_entries
+            .iter()
+            .map(|(entry, _reason)| entry.path.to_str().unwrap())
+            .collect();
+        assert_eq!(marked_paths, vec!["/mock/log", "/mock/metrics"]);
+    }
+
     #[rstest]
     fn expired_entries_marked() {
         let now = SystemTime::now();
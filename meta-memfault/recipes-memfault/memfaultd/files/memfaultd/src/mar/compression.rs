@@ -0,0 +1,210 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! Compression of the files attached to MAR entries.
+//!
+//! zstd and xz are only available when memfaultd is built with the `zstd` and `xz` features, as
+//! they require additional C libraries.
+//!
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::Path;
+
+use eyre::Result;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+use crate::config::{CoredumpCompression, LogCompression};
+use crate::mar::CompressionAlgorithm;
+
+impl CompressionAlgorithm {
+    /// Feature memfaultd must be built with to use this algorithm, when it was not.
+    pub fn missing_feature(&self) -> Option<&'static str> {
+        match self {
+            CompressionAlgorithm::Zstd if !cfg!(feature = "zstd") => Some("zstd"),
+            CompressionAlgorithm::Xz if !cfg!(feature = "xz") => Some("xz"),
+            _ => None,
+        }
+    }
+
+    /// Extension of the files compressed with this algorithm (without the dot).
+    pub fn file_extension(&self) -> Option<&'static str> {
+        match self {
+            CompressionAlgorithm::None => None,
+            CompressionAlgorithm::Zlib => Some("zlib"),
+            CompressionAlgorithm::Gzip => Some("gz"),
+            CompressionAlgorithm::Zstd => Some("zst"),
+            CompressionAlgorithm::Xz => Some("xz"),
+        }
+    }
+
+    /// Guess the algorithm used to compress a file from its extension.
+    pub fn from_file_extension(path: &Path) -> Option<Self> {
+        match path.extension().and_then(OsStr::to_str)? {
+            "zlib" => Some(CompressionAlgorithm::Zlib),
+            "gz" => Some(CompressionAlgorithm::Gzip),
+            "zst" => Some(CompressionAlgorithm::Zstd),
+            "xz" => Some(CompressionAlgorithm::Xz),
+            _ => None,
+        }
+    }
+
+    /// Wraps `writer` in an encoder for this algorithm. The stream is finished when the encoder
+    /// is dropped.
+    ///
+    /// The level (0-9) is used as is for zlib and gzip, as the preset for xz and as the level
+    /// for zstd (where 0 selects zstd's default level).
+    pub fn encoder<W: Write + Send + 'static>(
+        &self,
+        writer: W,
+        level: Compression,
+    ) -> Result<Box<dyn Write + Send>> {
+        match self {
+            CompressionAlgorithm::None => Ok(Box::new(writer)),
+            CompressionAlgorithm::Zlib => Ok(Box::new(ZlibEncoder::new(writer, level))),
+            CompressionAlgorithm::Gzip => Ok(Box::new(GzEncoder::new(writer, level))),
+            CompressionAlgorithm::Zstd => zstd_encoder(writer, level),
+            CompressionAlgorithm::Xz => xz_encoder(writer, level),
+        }
+    }
+}
+
+impl From<CoredumpCompression> for CompressionAlgorithm {
+    fn from(compression: CoredumpCompression) -> Self {
+        match compression {
+            CoredumpCompression::Gzip => CompressionAlgorithm::Gzip,
+            CoredumpCompression::None => CompressionAlgorithm::None,
+            CoredumpCompression::Zstd => CompressionAlgorithm::Zstd,
+            CoredumpCompression::Xz => CompressionAlgorithm::Xz,
+        }
+    }
+}
+
+impl From<LogCompression> for CompressionAlgorithm {
+    fn from(compression: LogCompression) -> Self {
+        match compression {
+            LogCompression::Zlib => CompressionAlgorithm::Zlib,
+            LogCompression::Zstd => CompressionAlgorithm::Zstd,
+            LogCompression::Xz => CompressionAlgorithm::Xz,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_encoder<W: Write + Send + 'static>(
+    writer: W,
+    level: Compression,
+) -> Result<Box<dyn Write + Send>> {
+    let encoder = zstd::Encoder::new(writer, level.level() as i32)?;
+    // The default callback of auto_finish() is not Send.
+    Ok(Box::new(encoder.on_finish(|_| ())))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_encoder<W: Write + Send + 'static>(
+    _writer: W,
+    _level: Compression,
+) -> Result<Box<dyn Write + Send>> {
+    Err(eyre::eyre!(
+        "zstd compression requires memfaultd to be built with the zstd feature"
+    ))
+}
+
+#[cfg(feature = "xz")]
+fn xz_encoder<W: Write + Send + 'static>(
+    writer: W,
+    level: Compression,
+) -> Result<Box<dyn Write + Send>> {
+    Ok(Box::new(xz2::write::XzEncoder::new(writer, level.level())))
+}
+
+#[cfg(not(feature = "xz"))]
+fn xz_encoder<W: Write + Send + 'static>(
+    _writer: W,
+    _level: Compression,
+) -> Result<Box<dyn Write + Send>> {
+    Err(eyre::eyre!(
+        "xz compression requires memfaultd to be built with the xz feature"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read, File};
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn decompress(algorithm: CompressionAlgorithm, bytes: &[u8]) -> Vec<u8> {
+        let mut decoder: Box<dyn Read> = match algorithm {
+            CompressionAlgorithm::None => Box::new(bytes),
+            CompressionAlgorithm::Zlib => Box::new(ZlibDecoder::new(bytes)),
+            CompressionAlgorithm::Gzip => Box::new(GzDecoder::new(bytes)),
+            #[cfg(feature = "zstd")]
+            CompressionAlgorithm::Zstd => Box::new(zstd::Decoder::new(bytes).unwrap()),
+            #[cfg(feature = "xz")]
+            CompressionAlgorithm::Xz => Box::new(xz2::read::XzDecoder::new(bytes)),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        };
+        let mut output = vec![];
+        decoder.read_to_end(&mut output).unwrap();
+        output
+    }
+
+    #[rstest]
+    #[case(CompressionAlgorithm::None)]
+    #[case(CompressionAlgorithm::Zlib)]
+    #[case(CompressionAlgorithm::Gzip)]
+    #[cfg_attr(feature = "zstd", case(CompressionAlgorithm::Zstd))]
+    #[cfg_attr(feature = "xz", case(CompressionAlgorithm::Xz))]
+    fn finishes_stream_on_drop(#[case] algorithm: CompressionAlgorithm) {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("file");
+        let data = "memfault ".repeat(1000);
+
+        let mut encoder = algorithm
+            .encoder(File::create(&path).unwrap(), Compression::fast())
+            .unwrap();
+        encoder.write_all(data.as_bytes()).unwrap();
+        drop(encoder);
+
+        assert_eq!(
+            decompress(algorithm, &read(&path).unwrap()),
+            data.as_bytes()
+        );
+    }
+
+    #[rstest]
+    #[case(CompressionAlgorithm::Zlib)]
+    #[case(CompressionAlgorithm::Gzip)]
+    #[case(CompressionAlgorithm::Zstd)]
+    #[case(CompressionAlgorithm::Xz)]
+    fn guesses_algorithm_from_extension(#[case] algorithm: CompressionAlgorithm) {
+        let path = format!("core.elf.{}", algorithm.file_extension().unwrap());
+        assert_eq!(
+            CompressionAlgorithm::from_file_extension(Path::new(&path)),
+            Some(algorithm)
+        );
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[rstest]
+    fn rejects_zstd_without_feature() {
+        assert!(CompressionAlgorithm::Zstd
+            .encoder(Vec::new(), Compression::default())
+            .is_err());
+    }
+
+    #[cfg(not(feature = "xz"))]
+    #[rstest]
+    fn rejects_xz_without_feature() {
+        assert!(CompressionAlgorithm::Xz
+            .encoder(Vec::new(), Compression::default())
+            .is_err());
+    }
+}
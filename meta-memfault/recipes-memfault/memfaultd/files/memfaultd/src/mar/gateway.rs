@@ -0,0 +1,427 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! MAR Gateway
+//!
+//! Receives MAR files uploaded by other devices of the local network (see `GatewayServerConfig`)
+//! and imports their entries into the MAR staging area, so that they are forwarded to Memfault
+//! with the next sync.
+//!
+//! Uploads are plain HTTP, so they are only accepted from the local network. Each device can
+//! upload `client_quota` bytes per hour, and only when the MAR staging area has room for them.
+//!
+use std::collections::HashMap;
+use std::io::{copy, BufReader, Read, Seek};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use eyre::Result;
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use tiny_http::{Method, Request, Response, ResponseBox};
+
+use crate::{
+    http_server::{HttpHandler, HttpHandlerResult},
+    mar::{import_mar_file, MarStagingCleaner, GATEWAY_UPLOAD_URL},
+    util::{disk_size::DiskSize, ip::is_local_network_address},
+};
+
+const CLIENT_QUOTA_PERIOD: Duration = Duration::from_secs(3600);
+
+/// An `HttpHandler` accepting MAR files POSTed by other devices.
+///
+/// Requests must be authenticated with an `Authorization: Bearer <auth_token>` header and
+/// can be sent with `Content-Encoding: gzip`.
+pub struct GatewayUploadHandler {
+    mar_staging: PathBuf,
+    mar_cleaner: Arc<MarStagingCleaner>,
+    tmp_dir: PathBuf,
+    authorization: String,
+    /// Largest MAR file accepted (uncompressed)
+    max_size: u64,
+    /// MAR data (uncompressed) accepted from each device per `CLIENT_QUOTA_PERIOD`
+    client_quota: u64,
+    /// Start of the current quota period and bytes accepted since, for each device
+    client_usage: Mutex<HashMap<IpAddr, (Instant, u64)>>,
+}
+
+impl GatewayUploadHandler {
+    pub fn new(
+        mar_staging: PathBuf,
+        mar_cleaner: Arc<MarStagingCleaner>,
+        tmp_dir: PathBuf,
+        auth_token: &str,
+        max_size: u64,
+        client_quota: u64,
+    ) -> Self {
+        GatewayUploadHandler {
+            mar_staging,
+            mar_cleaner,
+            tmp_dir,
+            authorization: format!("Bearer {}", auth_token),
+            max_size,
+            client_quota,
+            client_usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_authorized(&self, request: &Request) -> bool {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map_or(false, |h| {
+                constant_time_eq(h.value.as_bytes(), self.authorization.as_bytes())
+            })
+    }
+
+    /// Bytes accepted from `client` in its current quota period.
+    fn client_quota_used(&self, client: IpAddr) -> u64 {
+        let mut client_usage = self.client_usage.lock().unwrap();
+        let now = Instant::now();
+        client_usage.retain(|_, (since, _)| now.duration_since(*since) < CLIENT_QUOTA_PERIOD);
+        client_usage.get(&client).map_or(0, |(_, used)| *used)
+    }
+
+    /// Counts `size` imported bytes against the quota of `client`.
+    fn charge_client_quota(&self, client: IpAddr, size: u64) {
+        let mut client_usage = self.client_usage.lock().unwrap();
+        let (_, used) = client_usage.entry(client).or_insert((Instant::now(), 0));
+        *used += size;
+    }
+
+    fn handle_upload(&self, request: &mut Request) -> Result<ResponseBox> {
+        let client = match request.remote_addr() {
+            Some(addr) if is_local_network_address(&addr.ip()) => addr.ip(),
+            _ => {
+                return Ok(Response::from_string(
+                    "Uploads are only accepted from the local network",
+                )
+                .with_status_code(403)
+                .boxed())
+            }
+        };
+        if !self.is_authorized(request) {
+            return Ok(Response::from_string("Unauthorized")
+                .with_status_code(401)
+                .boxed());
+        }
+
+        let gzipped = request
+            .headers()
+            .iter()
+            .any(|h| h.field.equiv("Content-Encoding") && h.value == "gzip");
+        let body: Box<dyn Read> = if gzipped {
+            Box::new(GzDecoder::new(request.as_reader()))
+        } else {
+            Box::new(request.as_reader())
+        };
+
+        // The MAR file is read from the end, it must be stored before it can be imported.
+        let mut mar_file = tempfile::tempfile_in(&self.tmp_dir)?;
+        let size = match copy(&mut body.take(self.max_size + 1), &mut mar_file) {
+            Ok(size) => size,
+            Err(e) => {
+                return Ok(
+                    Response::from_string(format!("Error reading MAR file: {}", e))
+                        .with_status_code(400)
+                        .boxed(),
+                )
+            }
+        };
+        if size > self.max_size {
+            return Ok(Response::from_string("MAR file too large")
+                .with_status_code(413)
+                .boxed());
+        }
+        // Do not make room for the MAR file: that would delete older entries, which could be
+        // this device's own data.
+        let available_space = self.mar_cleaner.clean(DiskSize::ZERO)?;
+        if size >= available_space.bytes || available_space.inodes == 0 {
+            warn!("Not enough room in the MAR staging area for a MAR file from the gateway.");
+            return Ok(Response::from_string("Not enough storage")
+                .with_status_code(507)
+                .boxed());
+        }
+        if self.client_quota_used(client) + size > self.client_quota {
+            return Ok(Response::from_string("Upload quota exceeded")
+                .with_status_code(429)
+                .boxed());
+        }
+        mar_file.rewind()?;
+
+        match import_mar_file(BufReader::new(mar_file), &self.mar_staging) {
+            Ok(entries) => {
+                self.charge_client_quota(client, size);
+                info!("Imported {} MAR entries from the gateway.", entries.len());
+                Ok(Response::empty(200).boxed())
+            }
+            Err(e) => {
+                warn!("Unable to import MAR file from the gateway: {:#}", e);
+                Ok(Response::from_string(format!("{:#}", e))
+                    .with_status_code(400)
+                    .boxed())
+            }
+        }
+    }
+}
+
+impl HttpHandler for GatewayUploadHandler {
+    fn handle_request(&self, request: &mut Request) -> HttpHandlerResult {
+        if request.url() == GATEWAY_UPLOAD_URL {
+            match *request.method() {
+                Method::Post => self.handle_upload(request).into(),
+                _ => HttpHandlerResult::Response(Response::empty(405).boxed()),
+            }
+        } else {
+            HttpHandlerResult::NotHandled
+        }
+    }
+}
+
+/// Compares in constant time, so that the token cannot be guessed from the response time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rstest::{fixture, rstest};
+    use tempfile::TempDir;
+    use tiny_http::{Server, StatusCode, TestRequest};
+
+    use crate::{
+        mar::{gather_mar_entries_to_zip, test_utils::MarCollectorFixture, MarEntry},
+        network::{GatewayClient, NetworkClient},
+        util::{io::MeasuredGzEncoder, zip::ZipEncoder},
+    };
+
+    use super::*;
+
+    #[rstest]
+    #[case::missing_token(None)]
+    #[case::wrong_token(Some("Bearer wrong-token"))]
+    fn rejects_unauthorized_uploads(fixture: Fixture, #[case] authorization: Option<&str>) {
+        let mut request = TestRequest::new()
+            .with_method(Method::Post)
+            .with_path(GATEWAY_UPLOAD_URL);
+        if let Some(authorization) = authorization {
+            request = request.with_header(
+                tiny_http::Header::from_bytes("Authorization", authorization).unwrap(),
+            );
+        }
+        let response = fixture
+            .handler
+            .handle_request(&mut request.into())
+            .expect("should respond");
+        assert_eq!(response.status_code(), StatusCode(401));
+    }
+
+    #[rstest]
+    fn only_accepts_post(fixture: Fixture) {
+        let response = fixture
+            .handler
+            .handle_request(
+                &mut TestRequest::new()
+                    .with_method(Method::Get)
+                    .with_path(GATEWAY_UPLOAD_URL)
+                    .into(),
+            )
+            .expect("should respond");
+        assert_eq!(response.status_code(), StatusCode(405));
+    }
+
+    #[rstest]
+    #[case::plain(false)]
+    #[case::gzipped(true)]
+    fn imports_uploaded_mar_files(fixture: Fixture, #[case] gzipped: bool) {
+        let mut satellite = MarCollectorFixture::new();
+        let log = satellite.create_logentry_with_size(512);
+        satellite.create_reboot_entry();
+
+        fixture.upload(&satellite, TOKEN, gzipped).unwrap();
+
+        assert_eq!(count_entries(&fixture.hub), 2);
+        let imported_log = fixture.hub.mar_staging.join(log.file_name().unwrap());
+        assert_eq!(
+            std::fs::read(imported_log.join("manifest.json")).unwrap(),
+            std::fs::read(log.join("manifest.json")).unwrap()
+        );
+    }
+
+    #[rstest]
+    fn rejects_uploads_with_wrong_token(fixture: Fixture) {
+        let mut satellite = MarCollectorFixture::new();
+        satellite.create_reboot_entry();
+
+        assert!(fixture.upload(&satellite, "wrong-token", false).is_err());
+        assert_eq!(count_entries(&fixture.hub), 0);
+    }
+
+    #[rstest]
+    fn rejects_uploads_from_outside_the_local_network(fixture: Fixture) {
+        let response = fixture
+            .handler
+            .handle_request(
+                &mut TestRequest::new()
+                    .with_method(Method::Post)
+                    .with_path(GATEWAY_UPLOAD_URL)
+                    .with_remote_addr("203.0.113.7:40000".parse().unwrap())
+                    .with_header(
+                        tiny_http::Header::from_bytes("Authorization", format!("Bearer {}", TOKEN))
+                            .unwrap(),
+                    )
+                    .into(),
+            )
+            .expect("should respond");
+        assert_eq!(response.status_code(), StatusCode(403));
+    }
+
+    #[rstest]
+    fn rejects_uploads_exceeding_the_client_quota() {
+        let fixture = Fixture::new(4096, u64::MAX);
+        let mut satellite = MarCollectorFixture::new();
+        satellite.create_logentry_with_size(3000);
+
+        fixture.upload(&satellite, TOKEN, false).unwrap();
+        assert!(fixture.upload(&satellite, TOKEN, false).is_err());
+        assert_eq!(count_entries(&fixture.hub), 1);
+    }
+
+    #[rstest]
+    fn does_not_charge_the_client_quota_for_failed_imports() {
+        let fixture = Fixture::new(4096, u64::MAX);
+        // Larger than what the quota leaves for the MAR file below, if it was charged.
+        let body: &'static str = Box::leak("not a MAR file".repeat(200).into_boxed_str());
+        let mut request = TestRequest::new()
+            .with_method(Method::Post)
+            .with_path(GATEWAY_UPLOAD_URL)
+            .with_header(
+                tiny_http::Header::from_bytes("Authorization", format!("Bearer {}", TOKEN))
+                    .unwrap(),
+            )
+            .with_body(body)
+            .into();
+        let response = fixture
+            .handler
+            .handle_request(&mut request)
+            .expect("should respond");
+        assert_eq!(response.status_code(), StatusCode(400));
+
+        let mut satellite = MarCollectorFixture::new();
+        satellite.create_logentry_with_size(3000);
+        fixture.upload(&satellite, TOKEN, false).unwrap();
+        assert_eq!(count_entries(&fixture.hub), 1);
+    }
+
+    #[rstest]
+    fn rejects_uploads_without_room_in_mar_staging() {
+        let fixture = Fixture::new(u64::MAX, 1024);
+        let mut satellite = MarCollectorFixture::new();
+        satellite.create_logentry_with_size(2048);
+
+        assert!(fixture.upload(&satellite, TOKEN, false).is_err());
+        assert_eq!(count_entries(&fixture.hub), 0);
+    }
+
+    #[rstest]
+    fn rejects_invalid_mar_files(fixture: Fixture) {
+        let mut request = TestRequest::new()
+            .with_method(Method::Post)
+            .with_path(GATEWAY_UPLOAD_URL)
+            .with_header(
+                tiny_http::Header::from_bytes("Authorization", format!("Bearer {}", TOKEN))
+                    .unwrap(),
+            )
+            .with_body("not a MAR file")
+            .into();
+        let response = fixture
+            .handler
+            .handle_request(&mut request)
+            .expect("should respond");
+        assert_eq!(response.status_code(), StatusCode(400));
+        assert_eq!(count_entries(&fixture.hub), 0);
+    }
+
+    const TOKEN: &str = "hub-token";
+
+    struct Fixture {
+        hub: MarCollectorFixture,
+        handler: Arc<GatewayUploadHandler>,
+        _tmp_dir: TempDir,
+    }
+
+    impl Fixture {
+        fn new(client_quota: u64, mar_staging_max_size: u64) -> Self {
+            let hub = MarCollectorFixture::new();
+            let tmp_dir = TempDir::new().unwrap();
+            let mar_cleaner = Arc::new(MarStagingCleaner::new(
+                &hub.mar_staging,
+                DiskSize::new_capacity(mar_staging_max_size),
+                DiskSize::ZERO,
+                Duration::from_secs(3600),
+            ));
+            let handler = Arc::new(GatewayUploadHandler::new(
+                hub.mar_staging.clone(),
+                mar_cleaner,
+                tmp_dir.path().to_owned(),
+                TOKEN,
+                1024 * 1024,
+                client_quota,
+            ));
+            Fixture {
+                hub,
+                handler,
+                _tmp_dir: tmp_dir,
+            }
+        }
+
+        /// Uploads all the entries of `satellite` to the handler with a `GatewayClient`.
+        fn upload(
+            &self,
+            satellite: &MarCollectorFixture,
+            token: &str,
+            gzipped: bool,
+        ) -> Result<()> {
+            let server = Server::http("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", server.server_addr().to_ip().unwrap());
+            let handler = self.handler.clone();
+            let hub = thread::spawn(move || {
+                let mut request = server.recv().unwrap();
+                let response = handler
+                    .handle_request(&mut request)
+                    .expect("should respond");
+                request.respond(response).unwrap();
+            });
+
+            let mut entries = MarEntry::iterate_from_container(&satellite.mar_staging)?;
+            let mars = gather_mar_entries_to_zip(&mut entries, usize::MAX);
+            let zip_infos = mars[0].zip_infos.clone();
+            let client = GatewayClient::new(&url, token)?;
+            let result = if gzipped {
+                let zip = MeasuredGzEncoder::new(|| ZipEncoder::new(zip_infos.clone()))?;
+                client.upload_mar_file(zip, true)
+            } else {
+                client.upload_mar_file(ZipEncoder::new(zip_infos), false)
+            };
+            hub.join().unwrap();
+            result
+        }
+    }
+
+    fn count_entries(fixture: &MarCollectorFixture) -> usize {
+        MarEntry::iterate_from_container(&fixture.mar_staging)
+            .unwrap()
+            .filter(|e| e.is_ok())
+            .count()
+    }
+
+    #[fixture]
+    fn fixture() -> Fixture {
+        Fixture::new(u64::MAX, u64::MAX)
+    }
+}
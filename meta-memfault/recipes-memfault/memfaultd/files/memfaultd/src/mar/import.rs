@@ -0,0 +1,254 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! MAR Import
+//!
+//! Unpacks the MAR entries of a MAR file (for example one made by `memfaultctl export` on another
+//! device) into the MAR staging area, so that they are uploaded with the next sync.
+//!
+use std::collections::BTreeMap;
+use std::fs::{rename, File};
+use std::io::{copy, Read, Seek};
+use std::path::Path;
+use std::str::FromStr;
+
+use eyre::{eyre, Context, Result};
+use log::{debug, warn};
+use uuid::Uuid;
+use zip::ZipArchive;
+
+use super::mar_entry_builder::MarEntryDir;
+use super::{Manifest, MarEntry};
+
+/// A MAR entry of the MAR file, validated but not unpacked yet.
+struct ImportedEntry {
+    uuid: Uuid,
+    manifest: Manifest,
+    manifest_json: Vec<u8>,
+    /// Index of each attachment in the zip archive
+    attachments: Vec<(String, usize)>,
+}
+
+/// Unpacks the MAR entries of `mar_file` into `mar_staging` and returns them.
+///
+/// The whole MAR file is validated before anything is written: each entry must have a manifest
+/// which can be parsed as a `Manifest`, and all the attachments it lists. The manifests are kept
+/// as is, so the entries keep the device serial of the device which recorded them.
+/// Entries which are already in `mar_staging` (same UUID) are skipped.
+pub fn import_mar_file<R: Read + Seek>(mar_file: R, mar_staging: &Path) -> Result<Vec<MarEntry>> {
+    let mut archive = ZipArchive::new(mar_file).wrap_err("Invalid MAR file")?;
+    let entries = read_entries(&mut archive)?;
+
+    let mut imported = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let path = mar_staging.join(entry.uuid.to_string());
+        if path.exists() {
+            warn!("MAR entry {} was already imported, skipping.", entry.uuid);
+            continue;
+        }
+        imported.push(unpack_entry(&mut archive, entry, mar_staging)?);
+    }
+    Ok(imported)
+}
+
+fn read_entries<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Vec<ImportedEntry>> {
+    // Files of each entry directory, by name, with their index in the archive
+    let mut directories: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+    for index in 0..archive.len() {
+        let file = archive.by_index(index)?;
+        if file.is_dir() {
+            continue;
+        }
+        let (directory, filename) = file
+            .name()
+            .split_once('/')
+            .filter(|(_, filename)| !filename.contains('/') && !filename.starts_with('.'))
+            .ok_or_else(|| eyre!("Unexpected file in MAR file: {}", file.name()))?;
+        directories
+            .entry(directory.to_owned())
+            .or_default()
+            .insert(filename.to_owned(), index);
+    }
+
+    directories
+        .into_iter()
+        .map(|(directory, mut files)| {
+            let uuid = Uuid::from_str(&directory)
+                .wrap_err_with(|| format!("Invalid MAR entry name: {}", directory))?;
+            let manifest_index = files
+                .remove("manifest.json")
+                .ok_or_else(|| eyre!("MAR entry {} does not contain a manifest file.", uuid))?;
+            let mut manifest_json = vec![];
+            archive
+                .by_index(manifest_index)?
+                .read_to_end(&mut manifest_json)?;
+            let manifest: Manifest = serde_json::from_slice(&manifest_json)
+                .wrap_err_with(|| format!("Error parsing manifest of MAR entry {}", uuid))?;
+            let attachments = manifest
+                .attachments()
+                .into_iter()
+                .map(|filename| match files.remove(&filename) {
+                    Some(index) => Ok((filename, index)),
+                    None => Err(eyre!("MAR entry {} is missing {}", uuid, filename)),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if !files.is_empty() {
+                debug!(
+                    "Ignoring files of MAR entry {} not listed in its manifest: {:?}",
+                    uuid,
+                    files.keys()
+                );
+            }
+            Ok(ImportedEntry {
+                uuid,
+                manifest,
+                manifest_json,
+                attachments,
+            })
+        })
+        .collect()
+}
+
+/// Writes the attachments, then the manifest, so that the uploader ignores the entry
+/// until it is complete (see `MarEntry::iterate_from_container()`).
+fn unpack_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    entry: ImportedEntry,
+    mar_staging: &Path,
+) -> Result<MarEntry> {
+    let entry_dir = MarEntryDir::new(mar_staging.join(entry.uuid.to_string()));
+    std::fs::create_dir(&entry_dir.path)?;
+
+    for (filename, index) in entry.attachments {
+        let path = entry_dir.path.join(&filename);
+        let mut file =
+            File::create(&path).wrap_err_with(|| format!("Error creating {}", path.display()))?;
+        copy(&mut archive.by_index(index)?, &mut file)
+            .wrap_err_with(|| format!("Error unpacking {}", path.display()))?;
+    }
+
+    let manifest_path = entry_dir.path.join("manifest.tmp");
+    std::fs::write(&manifest_path, &entry.manifest_json)
+        .wrap_err_with(|| format!("Error writing manifest {}", manifest_path.display()))?;
+    rename(&manifest_path, manifest_path.with_extension("json"))?;
+
+    Ok(MarEntry {
+        path: entry_dir.mark_saved(),
+        uuid: entry.uuid,
+        manifest: entry.manifest,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use rstest::{fixture, rstest};
+    use zip::{write::FileOptions, ZipWriter};
+
+    use crate::mar::{gather_mar_entries_to_zip, test_utils::MarCollectorFixture};
+    use crate::util::zip::ZipEncoder;
+
+    use super::*;
+
+    #[rstest]
+    fn imports_exported_entries(mut source: MarCollectorFixture, target: MarCollectorFixture) {
+        let log = source.create_logentry_with_size(512);
+        source.create_reboot_entry();
+
+        let imported = import_mar_file(export(&source), &target.mar_staging).unwrap();
+
+        assert_eq!(imported.len(), 2);
+        assert_eq!(count_entries(&target), 2);
+        let imported_log = target.mar_staging.join(log.file_name().unwrap());
+        assert_eq!(
+            std::fs::read(imported_log.join("system.log")).unwrap(),
+            std::fs::read(log.join("system.log")).unwrap()
+        );
+        assert_eq!(
+            std::fs::read(imported_log.join("manifest.json")).unwrap(),
+            std::fs::read(log.join("manifest.json")).unwrap()
+        );
+    }
+
+    #[rstest]
+    fn skips_entries_already_imported(
+        mut source: MarCollectorFixture,
+        target: MarCollectorFixture,
+    ) {
+        source.create_reboot_entry();
+
+        assert_eq!(
+            import_mar_file(export(&source), &target.mar_staging)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            import_mar_file(export(&source), &target.mar_staging)
+                .unwrap()
+                .len(),
+            0
+        );
+        assert_eq!(count_entries(&target), 1);
+    }
+
+    #[rstest]
+    #[case::bogus_manifest(&[("manifest.json", b"{\"schema_version\": 1}".as_slice())])]
+    #[case::missing_manifest(&[("system.log", b"Hello".as_slice())])]
+    #[case::missing_attachment(&[("manifest.json", LOG_MANIFEST.as_bytes())])]
+    #[case::nested_file(&[("manifest.json", LOG_MANIFEST.as_bytes()), ("logs/logs.txt", b"Hello".as_slice())])]
+    fn rejects_invalid_entries(
+        mut source: MarCollectorFixture,
+        target: MarCollectorFixture,
+        #[case] files: &[(&str, &[u8])],
+    ) {
+        // A valid entry comes first, it must not be imported either:
+        source.create_reboot_entry();
+        let mut mar_file = export(&source).into_inner();
+        let mut zip = ZipWriter::new_append(Cursor::new(&mut mar_file)).unwrap();
+        for (name, contents) in files {
+            zip.start_file(
+                format!("00000000-0000-0000-0000-000000000001/{}", name),
+                FileOptions::default(),
+            )
+            .unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap();
+        drop(zip);
+
+        assert!(import_mar_file(Cursor::new(mar_file), &target.mar_staging).is_err());
+        assert_eq!(count_entries(&target), 0);
+    }
+
+    const LOG_MANIFEST: &str = include_str!("test-manifests/log.json");
+
+    /// Makes a MAR file with all the entries of `fixture`.
+    fn export(fixture: &MarCollectorFixture) -> Cursor<Vec<u8>> {
+        let mut entries = MarEntry::iterate_from_container(&fixture.mar_staging).unwrap();
+        let mars = gather_mar_entries_to_zip(&mut entries, usize::MAX);
+        let mut mar_file = vec![];
+        ZipEncoder::new(mars[0].zip_infos.clone())
+            .read_to_end(&mut mar_file)
+            .unwrap();
+        Cursor::new(mar_file)
+    }
+
+    fn count_entries(fixture: &MarCollectorFixture) -> usize {
+        MarEntry::iterate_from_container(&fixture.mar_staging)
+            .unwrap()
+            .filter(|e| e.is_ok())
+            .count()
+    }
+
+    #[fixture]
+    fn source() -> MarCollectorFixture {
+        MarCollectorFixture::new()
+    }
+
+    #[fixture]
+    fn target() -> MarCollectorFixture {
+        MarCollectorFixture::new()
+    }
+}
@@ -67,13 +67,17 @@ impl Default for Producer {
     }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum CompressionAlgorithm {
     None,
     #[serde(rename = "zlib")]
     Zlib,
     #[serde(rename = "gzip")]
     Gzip,
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "xz")]
+    Xz,
 }
 
 impl CompressionAlgorithm {
@@ -130,6 +134,48 @@ pub enum Metadata {
         duration: Duration,
         report_type: MetricReportType,
     },
+    #[serde(rename = "linux-stack-trace")]
+    LinuxStackTrace { stack_trace: StackTrace },
+}
+
+/// Backtraces of the threads of a crashed process, unwound on the device.
+///
+/// Program counters are not symbolicated: they are resolved with the symbol files matching the
+/// build IDs of `modules`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StackTrace {
+    pub cmdline: String,
+    pub signal: Option<u32>,
+    pub modules: Vec<StackTraceModule>,
+    /// The thread which received the signal comes first.
+    pub threads: Vec<ThreadStackTrace>,
+}
+
+/// An ELF file (executable or shared library) mapped in the crashed process.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StackTraceModule {
+    pub path: String,
+    /// GNU build ID, hex encoded.
+    pub build_id: Option<String>,
+    pub load_address: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ThreadStackTrace {
+    pub tid: u32,
+    pub crashed: bool,
+    pub frames: Vec<StackFrame>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StackFrame {
+    pub pc: u64,
+    /// Index of the module containing `pc` in `StackTrace::modules`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<usize>,
+    /// `pc` translated to the virtual addresses of the module's ELF file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module_offset: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -204,6 +250,33 @@ impl Metadata {
     pub fn new_reboot(reason: RebootReason) -> Self {
         Self::LinuxReboot { reason }
     }
+
+    pub fn new_stack_trace(stack_trace: StackTrace) -> Self {
+        Self::LinuxStackTrace { stack_trace }
+    }
+
+    /// Priority of the MAR entry when uploading it and when cleaning the MAR staging area.
+    pub fn priority(&self) -> MarEntryPriority {
+        match self {
+            Metadata::LinuxReboot { .. } => MarEntryPriority::High,
+            Metadata::ElfCoredump { .. } => MarEntryPriority::High,
+            Metadata::LinuxStackTrace { .. } => MarEntryPriority::High,
+            Metadata::DeviceConfig { .. } => MarEntryPriority::High,
+            Metadata::DeviceAttributes { .. } => MarEntryPriority::Normal,
+            Metadata::LinuxHeartbeat { .. } => MarEntryPriority::Normal,
+            Metadata::LinuxMetricReport { .. } => MarEntryPriority::Normal,
+            Metadata::LinuxLogs { .. } => MarEntryPriority::Low,
+        }
+    }
+}
+
+/// Higher priority MAR entries are uploaded first, and deleted last when the
+/// MAR staging area is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MarEntryPriority {
+    Low,
+    Normal,
+    High,
 }
 
 impl CollectionTime {
@@ -272,6 +345,7 @@ impl Manifest {
             Metadata::LinuxHeartbeat { .. } => vec![],
             Metadata::LinuxMetricReport { .. } => vec![],
             Metadata::LinuxReboot { .. } => vec![],
+            Metadata::LinuxStackTrace { .. } => vec![],
         }
     }
 }
@@ -298,11 +372,15 @@ mod tests {
     use crate::network::NetworkConfig;
     use crate::reboot::RebootReason;
 
-    use super::{CollectionTime, Manifest};
+    use super::{
+        CollectionTime, Manifest, StackFrame, StackTrace, StackTraceModule, ThreadStackTrace,
+    };
 
     #[rstest]
     #[case("coredump-gzip", CompressionAlgorithm::Gzip)]
     #[case("coredump-none", CompressionAlgorithm::None)]
+    #[case("coredump-zstd", CompressionAlgorithm::Zstd)]
+    #[case("coredump-xz", CompressionAlgorithm::Xz)]
     fn serialization_of_coredump(#[case] name: &str, #[case] compression: CompressionAlgorithm) {
         let config = NetworkConfig::test_fixture();
 
@@ -317,6 +395,8 @@ mod tests {
     #[rstest]
     #[case("log-zlib", CompressionAlgorithm::Zlib)]
     #[case("log-none", CompressionAlgorithm::None)]
+    #[case("log-zstd", CompressionAlgorithm::Zstd)]
+    #[case("log-xz", CompressionAlgorithm::Xz)]
     fn serialization_of_log(#[case] name: &str, #[case] compression: CompressionAlgorithm) {
         let config = NetworkConfig::test_fixture();
 
@@ -395,6 +475,41 @@ mod tests {
         insta::assert_json_snapshot!(manifest, { ".producer.version" => "tests"});
     }
 
+    #[rstest]
+    fn serialization_of_stack_trace() {
+        let config = NetworkConfig::test_fixture();
+        let manifest = Manifest::new(
+            &config,
+            CollectionTime::test_fixture(),
+            super::Metadata::new_stack_trace(StackTrace {
+                cmdline: "/usr/bin/crasher --now".into(),
+                signal: Some(11),
+                modules: vec![StackTraceModule {
+                    path: "/usr/bin/crasher".into(),
+                    build_id: Some("9a5bd1d5e8c4b2d1a0f5a4c7d6e3b2a1f0e9d8c7".into()),
+                    load_address: 0x5587ae8bd000,
+                }],
+                threads: vec![ThreadStackTrace {
+                    tid: 1234,
+                    crashed: true,
+                    frames: vec![
+                        StackFrame {
+                            pc: 0x5587ae8be139,
+                            module: Some(0),
+                            module_offset: Some(0x1139),
+                        },
+                        StackFrame {
+                            pc: 0x7f2b1c229d90,
+                            module: None,
+                            module_offset: None,
+                        },
+                    ],
+                }],
+            }),
+        );
+        insta::assert_json_snapshot!(manifest, { ".producer.version" => "tests"});
+    }
+
     #[rstest]
     fn serialization_of_linux_heartbeat() {
         let config = NetworkConfig::test_fixture();
@@ -413,6 +528,30 @@ mod tests {
         insta::assert_json_snapshot!(manifest, { ".producer.version" => "tests"});
     }
 
+    #[rstest]
+    fn serialization_of_session_with_string_and_state_metrics() {
+        let config = NetworkConfig::test_fixture();
+        let manifest = Manifest::new(
+            &config,
+            CollectionTime::test_fixture(),
+            super::Metadata::LinuxMetricReport {
+                metrics: HashMap::from([
+                    (
+                        "modem_technology".parse().unwrap(),
+                        MetricValue::String("lte".to_string()),
+                    ),
+                    (
+                        "wifi_state.connected_ms".parse().unwrap(),
+                        MetricValue::Number(30000.0),
+                    ),
+                ]),
+                duration: std::time::Duration::from_secs(30),
+                report_type: MetricReportType::Session("connected".parse().unwrap()),
+            },
+        );
+        insta::assert_json_snapshot!(manifest, { ".producer.version" => "tests"});
+    }
+
     #[rstest]
     #[case("heartbeat")]
     #[case("heartbeat_with_duration")]
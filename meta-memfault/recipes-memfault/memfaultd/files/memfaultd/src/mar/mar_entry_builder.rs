@@ -139,17 +139,17 @@ impl MarEntryBuilder<Metadata> {
 }
 
 /// Helper structure that will clean up the entry directory on Drop if mark_saved() was not called.
-struct MarEntryDir {
-    path: PathBuf,
+pub(super) struct MarEntryDir {
+    pub(super) path: PathBuf,
     saved: bool,
 }
 
 impl MarEntryDir {
-    fn new(path: PathBuf) -> Self {
+    pub(super) fn new(path: PathBuf) -> Self {
         Self { path, saved: false }
     }
 
-    fn mark_saved(mut self) -> PathBuf {
+    pub(super) fn mark_saved(mut self) -> PathBuf {
         self.saved = true;
         take(&mut self.path)
     }
@@ -2,24 +2,38 @@
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
 pub mod clean;
+pub mod compression;
+#[cfg(feature = "mar-import")]
+pub mod import;
 pub mod manifest;
 pub mod mar_entry;
 pub mod mar_entry_builder;
 pub mod upload;
+pub mod upload_budget;
 
 pub use clean::*;
+#[cfg(feature = "mar-import")]
+pub use import::*;
 pub use manifest::*;
 pub use mar_entry::*;
 pub use mar_entry_builder::*;
 pub use upload::*;
+pub use upload_budget::*;
 
 mod chunks;
 mod export;
 mod export_format;
+#[cfg(feature = "mar-import")]
+mod gateway;
 
 pub use chunks::{Chunk, ChunkMessage, ChunkMessageType, ChunkWrapper};
 pub use export::{MarExportHandler, EXPORT_MAR_URL};
 pub use export_format::ExportFormat;
+#[cfg(feature = "mar-import")]
+pub use gateway::GatewayUploadHandler;
+
+/// Path to which `GatewayClient` uploads MAR files (see `GatewayUploadHandler`).
+pub const GATEWAY_UPLOAD_URL: &str = "/v1/gateway/upload";
 
 #[cfg(test)]
 mod test_utils;
@@ -14,6 +14,7 @@ use tempfile::{tempdir, TempDir};
 use uuid::Uuid;
 
 use crate::network::NetworkConfig;
+use crate::reboot::{RebootReason, RebootReasonCode};
 use crate::test_utils::create_file_with_size;
 use crate::util::zip::ZipEncoder;
 
@@ -69,6 +70,20 @@ impl MarCollectorFixture {
         path
     }
 
+    pub fn create_reboot_entry(&mut self) -> PathBuf {
+        let path = self.create_empty_entry();
+        let manifest_file = File::create(path.join("manifest.json")).unwrap();
+
+        let manifest = Manifest::new(
+            &self.config,
+            CollectionTime::test_fixture(),
+            Metadata::new_reboot(RebootReason::from(RebootReasonCode::UserReset)),
+        );
+        serde_json::to_writer(BufWriter::new(manifest_file), &manifest).unwrap();
+
+        path
+    }
+
     pub fn create_logentry_with_size(&mut self, size: u64) -> PathBuf {
         self.create_logentry_with_size_and_age(size, SystemTime::now())
     }
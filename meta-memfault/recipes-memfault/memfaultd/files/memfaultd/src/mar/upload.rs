@@ -1,31 +1,96 @@
 //
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
+use std::cmp::Reverse;
 use std::fs::{remove_dir_all, File};
-use std::io::BufReader;
+use std::io::{copy, sink, BufReader, Read};
 use std::path::{Path, PathBuf};
 
-use eyre::{Context, Result};
+use eyre::{eyre, Context, Result};
+use flate2::{read::GzEncoder, Compression};
 use itertools::Itertools;
-use log::{trace, warn};
+use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    config::{Resolution, Sampling},
-    mar::{MarEntry, Metadata},
-    network::NetworkClient,
-    util::zip::{zip_stream_len_empty, zip_stream_len_for_file, ZipEncoder, ZipEntryInfo},
+    config::{Config, Resolution, Sampling, UploadBackendConfig, UploadCompression},
+    mar::{MarEntry, Metadata, UploadBudget},
+    network::{LinkPolicy, NetworkClient, ResumableUpload},
+    retriable_error::RetriableError,
+    util::{
+        io::{MeasuredGzEncoder, StreamLen},
+        zip::{zip_stream_len, ZipEncoder, ZipEntryInfo, ZipStreamLen},
+        DiskBacked,
+    },
 };
 
+/// How MAR files are built and sent (see `MarConfig`).
+pub struct UploadOptions {
+    pub max_zip_size: usize,
+    pub chunked_upload: Option<ChunkedUpload>,
+    pub compression: UploadCompression,
+}
+
+impl UploadOptions {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_zip_size: config.config_file.mar.mar_file_max_size,
+            // Resumable uploads are only supported by Memfault
+            chunked_upload: match config.upload_backend() {
+                UploadBackendConfig::Memfault => {
+                    config
+                        .config_file
+                        .mar
+                        .upload_chunk_size
+                        .map(|chunk_size| ChunkedUpload {
+                            chunk_size,
+                            progress_path: config.mar_upload_progress_path(),
+                        })
+                }
+                UploadBackendConfig::Gateway { .. } | UploadBackendConfig::Directory { .. } => None,
+            },
+            compression: config.config_file.mar.upload_compression,
+        }
+    }
+}
+
+/// Settings of chunked uploads (see `MarConfig::upload_chunk_size`).
+#[derive(Clone)]
+pub struct ChunkedUpload {
+    pub chunk_size: usize,
+    /// File where the progress of the current upload is saved
+    pub progress_path: PathBuf,
+}
+
+/// Progress of a chunked upload. It is saved after each chunk, so that the
+/// upload can be resumed after a network error or a restart of memfaultd.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct UploadProgress {
+    upload: ResumableUpload,
+    /// MAR entries included in the file, in order
+    entry_paths: Vec<PathBuf>,
+    /// Number of bytes received by the server
+    offset: u64,
+}
+
 /// Collect all valid MAR entries, upload them and delete them on success.
 ///
+/// Entries are uploaded by priority (see `Metadata::priority()`), then from oldest to newest.
+/// With an `upload_budget`, the entries which do not fit are left for the next call, and the
+/// uploaded bytes are recorded in the budget. With a `link_policy`, the entries which are not
+/// allowed on the current network link are left for the next call too. The budget
+/// records the bytes sent on the wire, after compression.
+///
 /// Returns the number of MAR entries that were uploaded.
 ///
 /// This function will not do anything with invalid MAR entries (we assume they are "under construction").
 pub fn collect_and_upload(
     mar_staging: &Path,
     client: &impl NetworkClient,
-    max_zip_size: usize,
+    options: &UploadOptions,
     sampling: Sampling,
+    upload_budget: Option<&mut UploadBudget>,
+    link_policy: Option<&LinkPolicy>,
 ) -> Result<usize> {
     let mut entries = MarEntry::iterate_from_container(mar_staging)?
         // Apply fleet sampling to the MAR entries
@@ -34,14 +99,34 @@ pub fn collect_and_upload(
                 applicable_resolution(&entry.manifest.metadata, &sampling) == Resolution::On
             }
             _ => true,
+        })
+        // Keep the entries which cannot be uploaded on the current link for later
+        .filter(|entry_result| match (entry_result, link_policy) {
+            (Ok(entry), Some(link_policy)) => link_policy.allows(&entry.manifest.metadata),
+            _ => true,
+        })
+        // Stable sort: entries of the same priority remain sorted by age
+        .sorted_by_key(|entry_result| {
+            Reverse(
+                entry_result
+                    .as_ref()
+                    .ok()
+                    .map(|entry| entry.manifest.metadata.priority()),
+            )
         });
 
-    upload_mar_entries(&mut entries, client, max_zip_size, |included_entries| {
-        trace!("Uploaded {:?} - deleting...", included_entries);
-        included_entries.iter().for_each(|f| {
-            let _ = remove_dir_all(f);
-        })
-    })
+    upload_mar_entries(
+        &mut entries,
+        client,
+        options,
+        upload_budget,
+        |included_entries| {
+            trace!("Uploaded {:?} - deleting...", included_entries);
+            included_entries.iter().for_each(|f| {
+                let _ = remove_dir_all(f);
+            })
+        },
+    )
 }
 
 /// Given the Metadata of a MAR entry and the fleet sampling configuration, return the applicable
@@ -56,6 +141,8 @@ fn applicable_resolution(metadata: &Metadata, sampling: &Sampling) -> Resolution
         Metadata::LinuxMetricReport { .. } => sampling.monitoring_resolution,
         Metadata::LinuxLogs { .. } => sampling.logging_resolution,
         Metadata::LinuxReboot { .. } => Resolution::On, // Always upload reboots
+        // Unlike coredumps, stack traces are small enough to be collected for the whole fleet.
+        Metadata::LinuxStackTrace { .. } => sampling.monitoring_resolution,
     }
 }
 
@@ -93,15 +180,17 @@ pub fn gather_mar_entries_to_zip(
         }
     });
 
-    let mut zip_size = zip_stream_len_empty();
+    let mut zip_len = ZipStreamLen::default();
     let mut zip_file_index: usize = 0;
     let grouper = entry_paths_with_zip_infos.group_by(|(_, zip_infos)| {
-        let entry_zipped_size = zip_infos.iter().map(zip_stream_len_for_file).sum::<usize>();
-        if zip_size + entry_zipped_size > max_zip_size {
-            zip_size = zip_stream_len_empty() + entry_zipped_size;
+        let mut new_zip_len = zip_len.clone();
+        zip_infos.iter().for_each(|info| new_zip_len.add(info));
+        if new_zip_len.total_len() > max_zip_size as u64 {
+            zip_len = ZipStreamLen::default();
+            zip_infos.iter().for_each(|info| zip_len.add(info));
             zip_file_index += 1;
         } else {
-            zip_size += entry_zipped_size;
+            zip_len = new_zip_len;
         }
         zip_file_index
     });
@@ -152,27 +241,263 @@ impl TryFrom<&MarEntry> for Vec<ZipEntryInfo> {
 fn upload_mar_entries(
     entries: &mut impl Iterator<Item = Result<MarEntry>>,
     client: &impl NetworkClient,
-    max_zip_size: usize,
+    options: &UploadOptions,
+    mut upload_budget: Option<&mut UploadBudget>,
     callback: fn(entries: Vec<PathBuf>) -> (),
 ) -> Result<usize> {
-    let zip_files = gather_mar_entries_to_zip(entries, max_zip_size);
+    let available_bytes = upload_budget.as_ref().and_then(|b| b.available());
+    if available_bytes == Some(0) {
+        info!("Upload budget exhausted, deferring MAR entries to a later sync.");
+        return Ok(0);
+    }
+    // Smaller MAR files make a better use of the budget.
+    let max_zip_size =
+        available_bytes.map_or(options.max_zip_size, |b| options.max_zip_size.min(b));
+    let chunked_upload = options.chunked_upload.as_ref().map(|c| {
+        (
+            c.chunk_size,
+            DiskBacked::<Option<UploadProgress>>::from_path(&c.progress_path),
+        )
+    });
+    let zip_files = match chunked_upload.as_ref().and_then(|(_, p)| p.get().as_ref()) {
+        Some(progress) => {
+            gather_mar_entries_with_interrupted_upload(entries, progress, max_zip_size)
+        }
+        None => gather_mar_entries_to_zip(entries, max_zip_size),
+    };
+    let zip_files = match available_bytes {
+        Some(budget) => within_upload_budget(
+            zip_files,
+            budget,
+            upload_budget
+                .as_ref()
+                .and_then(|b| b.available_in_periods()),
+        ),
+        None => zip_files,
+    };
     let count = zip_files.len();
 
-    for MarZipContents {
-        entry_paths,
-        zip_infos,
-    } in zip_files.into_iter()
-    {
-        client.upload_mar_file(BufReader::new(ZipEncoder::new(zip_infos)))?;
-        callback(entry_paths);
+    match chunked_upload {
+        Some((chunk_size, mut progress_store)) => {
+            for contents in zip_files.into_iter() {
+                let size = upload_in_chunks(
+                    client,
+                    &contents,
+                    chunk_size,
+                    options.compression,
+                    &mut progress_store,
+                )?;
+                if let Some(budget) = upload_budget.as_deref_mut() {
+                    budget.record(size as usize);
+                }
+                callback(contents.entry_paths);
+            }
+        }
+        None => {
+            for MarZipContents {
+                entry_paths,
+                zip_infos,
+            } in zip_files.into_iter()
+            {
+                let size = upload_mar_file(client, zip_infos, options.compression)?;
+                if let Some(budget) = upload_budget.as_deref_mut() {
+                    budget.record(size as usize);
+                }
+                callback(entry_paths);
+            }
+        }
     }
     Ok(count)
 }
 
+/// Upload a MAR file in one request. Returns the number of bytes sent.
+fn upload_mar_file(
+    client: &impl NetworkClient,
+    zip_infos: Vec<ZipEntryInfo>,
+    compression: UploadCompression,
+) -> Result<u64> {
+    match compression {
+        UploadCompression::None => {
+            let size = zip_stream_len(&zip_infos);
+            client.upload_mar_file(BufReader::new(ZipEncoder::new(zip_infos)), false)?;
+            Ok(size)
+        }
+        UploadCompression::Gzip => {
+            let file = MeasuredGzEncoder::new(|| ZipEncoder::new(zip_infos.clone()))?;
+            let size = file.stream_len();
+            client.upload_mar_file(file, true)?;
+            Ok(size)
+        }
+    }
+}
+
+/// Keep the MAR files which fit in the upload budget, in order. The first file is
+/// always kept if it fits in the daily and monthly budgets (`period_budget`), so that an entry
+/// larger than the budget of the sync does not block the uploads.
+fn within_upload_budget(
+    zip_files: Vec<MarZipContents>,
+    sync_budget: usize,
+    period_budget: Option<usize>,
+) -> Vec<MarZipContents> {
+    let total_count = zip_files.len();
+    let mut total_size = 0;
+    let zip_files: Vec<MarZipContents> = zip_files
+        .into_iter()
+        .enumerate()
+        .take_while(|(index, contents)| {
+            total_size += zip_stream_len(&contents.zip_infos);
+            period_budget.map_or(true, |budget| total_size <= budget as u64)
+                && (*index == 0 || total_size <= sync_budget as u64)
+        })
+        .map(|(_, contents)| contents)
+        .collect();
+
+    if zip_files.len() < total_count {
+        info!(
+            "Upload budget reached, deferring {} MAR file(s) to the next sync.",
+            total_count - zip_files.len()
+        );
+    }
+    zip_files
+}
+
+/// Gather MAR entries like `gather_mar_entries_to_zip`, putting the entries of an
+/// interrupted upload first, in the same order, so that the same MAR file is generated again.
+fn gather_mar_entries_with_interrupted_upload(
+    entries: &mut impl Iterator<Item = Result<MarEntry>>,
+    progress: &UploadProgress,
+    max_zip_size: usize,
+) -> Vec<MarZipContents> {
+    let (mut interrupted, others): (Vec<_>, Vec<_>) = entries.partition(
+        |entry| matches!(entry, Ok(entry) if progress.entry_paths.contains(&entry.path)),
+    );
+    interrupted.sort_by_key(|entry| {
+        entry
+            .as_ref()
+            .ok()
+            .and_then(|entry| progress.entry_paths.iter().position(|p| p == &entry.path))
+    });
+
+    if interrupted.len() != progress.entry_paths.len() {
+        // Some entries were deleted. The upload will be restarted.
+        return gather_mar_entries_to_zip(&mut interrupted.into_iter().chain(others), max_zip_size);
+    }
+    gather_mar_entries_to_zip(&mut interrupted.into_iter(), usize::MAX)
+        .into_iter()
+        .chain(gather_mar_entries_to_zip(
+            &mut others.into_iter(),
+            max_zip_size,
+        ))
+        .collect()
+}
+
+/// Upload a MAR file in chunks, resuming the saved upload if it was for the same MAR file.
+/// Returns the number of bytes of the (compressed) MAR file.
+fn upload_in_chunks(
+    client: &impl NetworkClient,
+    contents: &MarZipContents,
+    chunk_size: usize,
+    compression: UploadCompression,
+    progress_store: &mut DiskBacked<Option<UploadProgress>>,
+) -> Result<u64> {
+    let gzipped = compression == UploadCompression::Gzip;
+    let size = match compression {
+        UploadCompression::None => zip_stream_len(&contents.zip_infos),
+        UploadCompression::Gzip => copy(
+            &mut mar_stream(&contents.zip_infos, compression),
+            &mut sink(),
+        )?,
+    };
+    let resumed_progress = match progress_store.get() {
+        Some(progress)
+            if progress.entry_paths == contents.entry_paths
+                && progress.upload.size == size
+                && progress.upload.gzipped == gzipped =>
+        {
+            match client.resumable_upload_offset(&progress.upload) {
+                Ok(offset) => {
+                    info!("Resuming MAR upload at {}/{} bytes", offset, size);
+                    Some(UploadProgress {
+                        offset,
+                        ..progress.clone()
+                    })
+                }
+                Err(e) if e.downcast_ref::<RetriableError>().is_some() => return Err(e),
+                Err(e) => {
+                    warn!("Unable to resume MAR upload, restarting it: {:?}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let mut progress = match resumed_progress {
+        Some(progress) => progress,
+        None => UploadProgress {
+            upload: client.prepare_resumable_mar_upload(size, gzipped)?,
+            entry_paths: contents.entry_paths.clone(),
+            offset: 0,
+        },
+    };
+    progress_store.set(Some(progress.clone()))?;
+
+    let mut zip = mar_stream_at(&contents.zip_infos, compression, progress.offset)?;
+    let mut position = progress.offset;
+    while progress.offset < size {
+        if position != progress.offset {
+            // The server did not receive the whole chunk: send the rest again.
+            zip = mar_stream_at(&contents.zip_infos, compression, progress.offset)?;
+        }
+        let mut chunk = Vec::with_capacity(chunk_size);
+        (&mut zip).take(chunk_size as u64).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            return Err(eyre!("MAR file is shorter than expected"));
+        }
+        position = progress.offset + chunk.len() as u64;
+        let offset = client.upload_mar_chunk(&progress.upload, progress.offset, chunk)?;
+        if offset <= progress.offset {
+            return Err(eyre!(
+                "MAR upload is not progressing (server received {} bytes, after {})",
+                offset,
+                progress.offset
+            ));
+        }
+        progress.offset = offset;
+        progress_store.set(Some(progress.clone()))?;
+    }
+
+    client.commit_resumable_mar_upload(&progress.upload)?;
+    progress_store.set(None)?;
+    Ok(size)
+}
+
+/// The MAR file of `zip_infos`, compressed with `compression`.
+fn mar_stream(zip_infos: &[ZipEntryInfo], compression: UploadCompression) -> Box<dyn Read> {
+    let zip = ZipEncoder::new(zip_infos.to_vec());
+    match compression {
+        UploadCompression::None => Box::new(zip),
+        UploadCompression::Gzip => Box::new(GzEncoder::new(zip, Compression::default())),
+    }
+}
+
+/// Returns the MAR file stream which already skipped `offset` bytes.
+fn mar_stream_at(
+    zip_infos: &[ZipEntryInfo],
+    compression: UploadCompression,
+    offset: u64,
+) -> Result<Box<dyn Read>> {
+    let mut stream = mar_stream(zip_infos, compression);
+    copy(&mut (&mut stream).take(offset), &mut sink())?;
+    Ok(stream)
+}
+
 #[cfg(test)]
 mod tests {
+    use eyre::eyre;
     use rstest::{fixture, rstest};
+    use std::sync::{Arc, Mutex};
     use std::time::SystemTime;
+    use tempfile::{tempdir, TempDir};
 
     use crate::test_utils::setup_logger;
     use crate::{
@@ -294,12 +619,14 @@ mod tests {
         collect_and_upload(
             &mar_fixture.mar_staging,
             &client,
-            usize::MAX,
+            &options(None, UploadCompression::None),
             Sampling {
                 debugging_resolution: Resolution::On,
                 logging_resolution: Resolution::On,
                 monitoring_resolution: Resolution::On,
             },
+            None,
+            None,
         )
         .unwrap();
     }
@@ -313,7 +640,7 @@ mod tests {
         mar_fixture.create_logentry();
         client
             .expect_upload_mar_file::<BufReader<ZipEncoder>>()
-            .withf(|buf_reader| {
+            .withf(|buf_reader, _gzipped| {
                 let zip_encoder = buf_reader.get_ref();
                 assert_mar_content_matches(
                     zip_encoder,
@@ -321,16 +648,18 @@ mod tests {
                 )
             })
             .once()
-            .returning(|_| Ok(()));
+            .returning(|_, _| Ok(()));
         collect_and_upload(
             &mar_fixture.mar_staging,
             &client,
-            usize::MAX,
+            &options(None, UploadCompression::None),
             Sampling {
                 debugging_resolution: Resolution::Off,
                 logging_resolution: Resolution::On,
                 monitoring_resolution: Resolution::Off,
             },
+            None,
+            None,
         )
         .unwrap();
     }
@@ -348,12 +677,14 @@ mod tests {
         collect_and_upload(
             &mar_fixture.mar_staging,
             &client,
-            usize::MAX,
+            &options(None, UploadCompression::None),
             Sampling {
                 debugging_resolution: Resolution::Off,
                 logging_resolution: Resolution::Off,
                 monitoring_resolution: Resolution::Off,
             },
+            None,
+            None,
         )
         .unwrap();
     }
@@ -367,21 +698,23 @@ mod tests {
         mar_fixture.create_device_attributes_entry(vec![], SystemTime::now());
         client
             .expect_upload_mar_file::<BufReader<ZipEncoder>>()
-            .withf(|buf_reader| {
+            .withf(|buf_reader, _gzipped| {
                 let zip_encoder = buf_reader.get_ref();
                 assert_mar_content_matches(zip_encoder, vec!["<entry>/manifest.json"])
             })
             .once()
-            .returning(|_| Ok(()));
+            .returning(|_, _| Ok(()));
         collect_and_upload(
             &mar_fixture.mar_staging,
             &client,
-            usize::MAX,
+            &options(None, UploadCompression::None),
             Sampling {
                 debugging_resolution: Resolution::Off,
                 logging_resolution: Resolution::Off,
                 monitoring_resolution: Resolution::On,
             },
+            None,
+            None,
         )
         .unwrap();
     }
@@ -399,16 +732,579 @@ mod tests {
         collect_and_upload(
             &mar_fixture.mar_staging,
             &client,
-            usize::MAX,
+            &options(None, UploadCompression::None),
             Sampling {
                 debugging_resolution: Resolution::Off,
                 logging_resolution: Resolution::Off,
                 monitoring_resolution: Resolution::Off,
             },
+            None,
+            None,
         )
         .unwrap();
     }
 
+    #[rstest]
+    fn deferring_low_priority_entries_over_budget(
+        _setup_logger: (),
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        let budget_path = progress_dir.path().join("upload_budget");
+        mar_fixture.create_logentry_with_size(2048);
+        mar_fixture.create_reboot_entry();
+        let sampling = Sampling {
+            debugging_resolution: Resolution::On,
+            logging_resolution: Resolution::On,
+            monitoring_resolution: Resolution::On,
+        };
+
+        // The reboot is uploaded first, the log does not fit in the budget:
+        let mut client = MockNetworkClient::default();
+        client
+            .expect_upload_mar_file::<BufReader<ZipEncoder>>()
+            .withf(|buf_reader, _gzipped| {
+                assert_mar_content_matches(buf_reader.get_ref(), vec!["<entry>/manifest.json"])
+            })
+            .once()
+            .returning(|_, _| Ok(()));
+        let uploaded = collect_and_upload(
+            &mar_fixture.mar_staging,
+            &client,
+            &options(None, UploadCompression::None),
+            sampling,
+            Some(&mut UploadBudget::load(&budget_path, Some(1024), None, None).unwrap()),
+            None,
+        )
+        .unwrap();
+        assert_eq!(uploaded, 1);
+        assert_eq!(count_entries(&mar_fixture), 1);
+
+        // The log is uploaded during the next sync, even though it is larger than the budget:
+        let mut client = MockNetworkClient::default();
+        client
+            .expect_upload_mar_file::<BufReader<ZipEncoder>>()
+            .withf(|buf_reader, _gzipped| {
+                assert_mar_content_matches(
+                    buf_reader.get_ref(),
+                    vec!["<entry>/manifest.json", "<entry>/system.log"],
+                )
+            })
+            .once()
+            .returning(|_, _| Ok(()));
+        collect_and_upload(
+            &mar_fixture.mar_staging,
+            &client,
+            &options(None, UploadCompression::None),
+            sampling,
+            Some(&mut UploadBudget::load(&budget_path, Some(1024), None, None).unwrap()),
+            None,
+        )
+        .unwrap();
+        assert_eq!(count_entries(&mar_fixture), 0);
+    }
+
+    #[rstest]
+    fn never_exceeding_the_daily_budget(
+        _setup_logger: (),
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        let budget_path = progress_dir.path().join("upload_budget");
+        mar_fixture.create_logentry_with_size(2048);
+        let sampling = Sampling {
+            debugging_resolution: Resolution::On,
+            logging_resolution: Resolution::On,
+            monitoring_resolution: Resolution::On,
+        };
+
+        // Unlike the budget of the sync, the first file cannot exceed the daily budget:
+        let mut client = MockNetworkClient::default();
+        client
+            .expect_upload_mar_file::<BufReader<ZipEncoder>>()
+            .never();
+        let uploaded = collect_and_upload(
+            &mar_fixture.mar_staging,
+            &client,
+            &options(None, UploadCompression::None),
+            sampling,
+            Some(&mut UploadBudget::load(&budget_path, None, Some(1024), None).unwrap()),
+            None,
+        )
+        .unwrap();
+        assert_eq!(uploaded, 0);
+        assert_eq!(count_entries(&mar_fixture), 1);
+    }
+
+    #[rstest]
+    fn deferring_entries_not_allowed_on_link(
+        _setup_logger: (),
+        mut client: MockNetworkClient,
+        mut mar_fixture: MarCollectorFixture,
+    ) {
+        mar_fixture.create_logentry();
+        mar_fixture.create_reboot_entry();
+        let network_policy = serde_json::from_value(serde_json::json!({
+            "link_rules": [{"data_types": ["logs"], "links": ["wlan0"]}]
+        }))
+        .unwrap();
+
+        client
+            .expect_upload_mar_file::<BufReader<ZipEncoder>>()
+            .withf(|buf_reader, _gzipped| {
+                assert_mar_content_matches(buf_reader.get_ref(), vec!["<entry>/manifest.json"])
+            })
+            .once()
+            .returning(|_, _| Ok(()));
+        collect_and_upload(
+            &mar_fixture.mar_staging,
+            &client,
+            &options(None, UploadCompression::None),
+            Sampling {
+                debugging_resolution: Resolution::On,
+                logging_resolution: Resolution::On,
+                monitoring_resolution: Resolution::On,
+            },
+            None,
+            Some(&LinkPolicy::new(&network_policy, Some("wwan0".to_string()))),
+        )
+        .unwrap();
+        assert_eq!(count_entries(&mar_fixture), 1);
+    }
+
+    #[rstest]
+    fn recording_uploads_in_budget(
+        _setup_logger: (),
+        mut client: MockNetworkClient,
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        mar_fixture.create_logentry_with_size(512);
+        let expected_size = mar_file_bytes(&mar_fixture.mar_staging).len();
+        let mut budget = UploadBudget::load(
+            progress_dir.path().join("upload_budget"),
+            None,
+            Some(4096),
+            None,
+        )
+        .unwrap();
+        client
+            .expect_upload_mar_file::<BufReader<ZipEncoder>>()
+            .once()
+            .returning(|_, _| Ok(()));
+
+        upload(
+            &mar_fixture,
+            &client,
+            None,
+            UploadCompression::None,
+            Some(&mut budget),
+        )
+        .unwrap();
+
+        assert_eq!(budget.recorded(), expected_size);
+        assert_eq!(budget.available(), Some(4096 - expected_size));
+    }
+
+    #[rstest]
+    fn skipping_uploads_when_budget_is_exhausted(
+        _setup_logger: (),
+        client: MockNetworkClient,
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        mar_fixture.create_reboot_entry();
+        let mut budget = UploadBudget::load(
+            progress_dir.path().join("upload_budget"),
+            None,
+            Some(1024),
+            None,
+        )
+        .unwrap();
+        budget.record(1024);
+
+        // We do not set an expectation on client => it will panic if client.upload_mar is called
+        assert_eq!(
+            upload(
+                &mar_fixture,
+                &client,
+                None,
+                UploadCompression::None,
+                Some(&mut budget)
+            )
+            .unwrap(),
+            0
+        );
+        assert_eq!(count_entries(&mar_fixture), 1);
+    }
+
+    #[rstest]
+    fn uploading_mar_in_chunks(
+        _setup_logger: (),
+        mut client: MockNetworkClient,
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        mar_fixture.create_logentry();
+        let expected = mar_file_bytes(&mar_fixture.mar_staging);
+        let chunked_upload = chunked_upload(&progress_dir);
+
+        client
+            .expect_prepare_resumable_mar_upload()
+            .once()
+            .returning(|size, _| Ok(resumable_upload(size)));
+        let received = expect_chunks(&mut client, (expected.len() + 99) / 100);
+        client
+            .expect_commit_resumable_mar_upload()
+            .once()
+            .returning(|_| Ok(()));
+
+        assert_eq!(
+            upload(
+                &mar_fixture,
+                &client,
+                Some(&chunked_upload),
+                UploadCompression::None,
+                None
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(*received.lock().unwrap(), expected);
+        assert_eq!(saved_progress(&chunked_upload), None);
+        assert_eq!(count_entries(&mar_fixture), 0);
+    }
+
+    #[rstest]
+    fn stops_upload_which_does_not_progress(
+        _setup_logger: (),
+        mut client: MockNetworkClient,
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        mar_fixture.create_logentry();
+        let chunked_upload = chunked_upload(&progress_dir);
+
+        client
+            .expect_prepare_resumable_mar_upload()
+            .once()
+            .returning(|size, _| Ok(resumable_upload(size)));
+        // e.g. a 308 response without a Range header
+        client
+            .expect_upload_mar_chunk()
+            .once()
+            .returning(|_, _, _| Ok(0));
+
+        assert!(upload(
+            &mar_fixture,
+            &client,
+            Some(&chunked_upload),
+            UploadCompression::None,
+            None
+        )
+        .is_err());
+        assert_eq!(count_entries(&mar_fixture), 1);
+    }
+
+    #[rstest]
+    fn resuming_interrupted_upload(
+        _setup_logger: (),
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        mar_fixture.create_logentry();
+        let expected = mar_file_bytes(&mar_fixture.mar_staging);
+        let chunked_upload = chunked_upload(&progress_dir);
+
+        // The connection is lost after the first chunk:
+        let mut client = MockNetworkClient::default();
+        client
+            .expect_prepare_resumable_mar_upload()
+            .once()
+            .returning(|size, _| Ok(resumable_upload(size)));
+        client
+            .expect_upload_mar_chunk()
+            .times(2)
+            .returning(|_, offset, chunk| match offset {
+                0 => Ok(chunk.len() as u64),
+                _ => Err(eyre!("Connection reset")),
+            });
+        assert!(upload(
+            &mar_fixture,
+            &client,
+            Some(&chunked_upload),
+            UploadCompression::None,
+            None
+        )
+        .is_err());
+        assert_eq!(saved_progress(&chunked_upload).map(|p| p.offset), Some(100));
+        assert_eq!(count_entries(&mar_fixture), 1);
+
+        // After a restart, the upload continues where the server left off:
+        let mut client = MockNetworkClient::default();
+        client.expect_prepare_resumable_mar_upload().never();
+        client
+            .expect_resumable_upload_offset()
+            .once()
+            .returning(|_| Ok(100));
+        let received = expect_chunks(&mut client, (expected.len() - 100 + 99) / 100);
+        received.lock().unwrap().extend_from_slice(&expected[..100]);
+        client
+            .expect_commit_resumable_mar_upload()
+            .once()
+            .returning(|_| Ok(()));
+
+        assert_eq!(
+            upload(
+                &mar_fixture,
+                &client,
+                Some(&chunked_upload),
+                UploadCompression::None,
+                None
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(*received.lock().unwrap(), expected);
+        assert_eq!(saved_progress(&chunked_upload), None);
+        assert_eq!(count_entries(&mar_fixture), 0);
+    }
+
+    #[rstest]
+    fn restarting_upload_of_deleted_entries(
+        _setup_logger: (),
+        mut client: MockNetworkClient,
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        mar_fixture.create_logentry();
+        let expected = mar_file_bytes(&mar_fixture.mar_staging);
+        let chunked_upload = chunked_upload(&progress_dir);
+        DiskBacked::from_path(&chunked_upload.progress_path)
+            .set(Some(UploadProgress {
+                upload: resumable_upload(1234),
+                entry_paths: vec![mar_fixture.mar_staging.join("deleted")],
+                offset: 100,
+            }))
+            .unwrap();
+
+        client.expect_resumable_upload_offset().never();
+        client
+            .expect_prepare_resumable_mar_upload()
+            .once()
+            .returning(|size, _| Ok(resumable_upload(size)));
+        let received = expect_chunks(&mut client, (expected.len() + 99) / 100);
+        client
+            .expect_commit_resumable_mar_upload()
+            .once()
+            .returning(|_| Ok(()));
+
+        assert_eq!(
+            upload(
+                &mar_fixture,
+                &client,
+                Some(&chunked_upload),
+                UploadCompression::None,
+                None
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(*received.lock().unwrap(), expected);
+    }
+
+    #[rstest]
+    fn uploading_gzipped_mar(
+        _setup_logger: (),
+        mut client: MockNetworkClient,
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        mar_fixture.create_logentry_with_size(4096);
+        let expected = mar_file_bytes(&mar_fixture.mar_staging);
+        let mut budget =
+            UploadBudget::load(progress_dir.path().join("upload_budget"), None, None, None)
+                .unwrap();
+        let sent = Arc::new(Mutex::new(vec![]));
+        let s = sent.clone();
+        client
+            .expect_upload_mar_file::<MeasuredGzEncoder<ZipEncoder>>()
+            .withf(|_, gzipped| *gzipped)
+            .once()
+            .returning(move |mut file, _| {
+                let mut sent = s.lock().unwrap();
+                file.read_to_end(&mut sent).unwrap();
+                assert_eq!(file.stream_len(), sent.len() as u64);
+                Ok(())
+            });
+
+        upload(
+            &mar_fixture,
+            &client,
+            None,
+            UploadCompression::Gzip,
+            Some(&mut budget),
+        )
+        .unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(gunzip(&sent), expected);
+        assert!(sent.len() < expected.len());
+        assert_eq!(budget.recorded(), sent.len());
+    }
+
+    #[rstest]
+    fn uploading_gzipped_mar_in_chunks(
+        _setup_logger: (),
+        mut client: MockNetworkClient,
+        mut mar_fixture: MarCollectorFixture,
+        progress_dir: TempDir,
+    ) {
+        mar_fixture.create_logentry_with_size(4096);
+        let expected = mar_file_bytes(&mar_fixture.mar_staging);
+        let chunked_upload = chunked_upload(&progress_dir);
+
+        client
+            .expect_prepare_resumable_mar_upload()
+            .withf(|_, gzipped| *gzipped)
+            .once()
+            .returning(|size, gzipped| {
+                Ok(ResumableUpload {
+                    gzipped,
+                    ..resumable_upload(size)
+                })
+            });
+        let received = Arc::new(Mutex::new(vec![]));
+        let r = received.clone();
+        client
+            .expect_upload_mar_chunk()
+            .returning(move |upload, offset, chunk| {
+                let mut received = r.lock().unwrap();
+                assert!(upload.gzipped);
+                assert_eq!(offset, received.len() as u64);
+                received.extend(chunk);
+                Ok(received.len() as u64)
+            });
+        client
+            .expect_commit_resumable_mar_upload()
+            .once()
+            .returning(|_| Ok(()));
+
+        assert_eq!(
+            upload(
+                &mar_fixture,
+                &client,
+                Some(&chunked_upload),
+                UploadCompression::Gzip,
+                None
+            )
+            .unwrap(),
+            1
+        );
+        assert_eq!(gunzip(&received.lock().unwrap()), expected);
+        assert_eq!(saved_progress(&chunked_upload), None);
+    }
+
+    fn upload(
+        mar_fixture: &MarCollectorFixture,
+        client: &MockNetworkClient,
+        chunked_upload: Option<&ChunkedUpload>,
+        compression: UploadCompression,
+        upload_budget: Option<&mut UploadBudget>,
+    ) -> Result<usize> {
+        collect_and_upload(
+            &mar_fixture.mar_staging,
+            client,
+            &options(chunked_upload, compression),
+            Sampling {
+                debugging_resolution: Resolution::On,
+                logging_resolution: Resolution::On,
+                monitoring_resolution: Resolution::On,
+            },
+            upload_budget,
+            None,
+        )
+    }
+
+    fn options(
+        chunked_upload: Option<&ChunkedUpload>,
+        compression: UploadCompression,
+    ) -> UploadOptions {
+        UploadOptions {
+            max_zip_size: usize::MAX,
+            chunked_upload: chunked_upload.cloned(),
+            compression,
+        }
+    }
+
+    fn chunked_upload(progress_dir: &TempDir) -> ChunkedUpload {
+        ChunkedUpload {
+            chunk_size: 100,
+            progress_path: progress_dir.path().join("mar_upload_progress.json"),
+        }
+    }
+
+    fn resumable_upload(size: u64) -> ResumableUpload {
+        ResumableUpload {
+            upload_url: "https://example.com/upload/1".to_string(),
+            token: "token".to_string(),
+            size,
+            gzipped: false,
+        }
+    }
+
+    /// Expects the chunks to be sent in order and returns the bytes received.
+    fn expect_chunks(client: &mut MockNetworkClient, count: usize) -> Arc<Mutex<Vec<u8>>> {
+        let received = Arc::new(Mutex::new(vec![]));
+        let r = received.clone();
+        client
+            .expect_upload_mar_chunk()
+            .times(count)
+            .returning(move |_, offset, chunk| {
+                let mut received = r.lock().unwrap();
+                assert_eq!(offset, received.len() as u64);
+                received.extend(chunk);
+                Ok(received.len() as u64)
+            });
+        received
+    }
+
+    /// Content of the MAR file of all the entries in the staging area.
+    fn mar_file_bytes(mar_staging: &Path) -> Vec<u8> {
+        let mut entries = MarEntry::iterate_from_container(mar_staging).unwrap();
+        let mars = gather_mar_entries_to_zip(&mut entries, usize::MAX);
+        let mut bytes = vec![];
+        ZipEncoder::new(mars[0].zip_infos.clone())
+            .read_to_end(&mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    fn gunzip(bytes: &[u8]) -> Vec<u8> {
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(bytes)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    fn saved_progress(chunked_upload: &ChunkedUpload) -> Option<UploadProgress> {
+        DiskBacked::<Option<UploadProgress>>::from_path(&chunked_upload.progress_path)
+            .get()
+            .clone()
+    }
+
+    fn count_entries(mar_fixture: &MarCollectorFixture) -> usize {
+        MarEntry::iterate_from_container(&mar_fixture.mar_staging)
+            .unwrap()
+            .count()
+    }
+
+    #[fixture]
+    fn progress_dir() -> TempDir {
+        tempdir().unwrap()
+    }
+
     #[fixture]
     fn client() -> MockNetworkClient {
         MockNetworkClient::default()
@@ -0,0 +1,262 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use eyre::Result;
+use itertools::Itertools;
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use crate::config::Config;
+use crate::util::fs::write_atomically;
+
+/// An `UploadBudget` limits the amount of data uploaded per sync, per day and
+/// per month (30 days), for devices on metered connections.
+///
+/// - Periods are rolling windows, with a resolution of one hour.
+/// - It stores the bytes uploaded during the last month in a file `path`.
+pub struct UploadBudget {
+    path: PathBuf,
+    per_sync: Option<usize>,
+    per_day: Option<usize>,
+    per_month: Option<usize>,
+    /// Bytes uploaded during each hour of the last month. We keep them sorted
+    /// from most recent to oldest.
+    history: VecDeque<(DateTime<Utc>, usize)>,
+    /// Bytes recorded since the budget was loaded.
+    recorded: usize,
+}
+
+fn one_day() -> Duration {
+    Duration::days(1)
+}
+
+fn one_month() -> Duration {
+    Duration::days(30)
+}
+
+impl UploadBudget {
+    /// Load the budget state from disk. A `None` limit is unlimited.
+    ///
+    /// Non-existent file is not considered an error. Garbage in the file will be skipped over.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        per_sync: Option<usize>,
+        per_day: Option<usize>,
+        per_month: Option<usize>,
+    ) -> Result<Self> {
+        // Each line is "<timestamp> <bytes>". Discard anything that is not parseable.
+        let history = match File::open(&path) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .filter_map(|line| {
+                    let line = line.ok()?;
+                    let (ts, bytes) = line.split_once(' ')?;
+                    let ts = Utc.timestamp_opt(ts.parse().ok()?, 0).single()?;
+                    Some((ts, bytes.parse().ok()?))
+                })
+                .sorted_by(|a, b| b.0.cmp(&a.0))
+                .collect::<VecDeque<_>>(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: path.as_ref().to_owned(),
+            per_sync,
+            per_day,
+            per_month,
+            history,
+            recorded: 0,
+        })
+    }
+
+    /// Load the budget configured in the `mar` section of the configuration.
+    pub fn load_from_config(config: &Config) -> Result<Self> {
+        Self::load(
+            config.upload_budget_file_path(),
+            config.config_file.mar.upload_budget_per_sync,
+            config.config_file.mar.upload_budget_per_day,
+            config.config_file.mar.upload_budget_per_month,
+        )
+    }
+
+    fn used_with_time(&self, now: DateTime<Utc>, period: Duration) -> usize {
+        self.history
+            .iter()
+            .take_while(|(time, _)| now.signed_duration_since(*time) < period)
+            .map(|(_, bytes)| bytes)
+            .sum()
+    }
+
+    fn available_with_time(&self, now: DateTime<Utc>) -> Option<usize> {
+        [self.per_sync, self.available_in_periods_with_time(now)]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn available_in_periods_with_time(&self, now: DateTime<Utc>) -> Option<usize> {
+        [
+            self.per_day
+                .map(|b| b.saturating_sub(self.used_with_time(now, one_day()))),
+            self.per_month
+                .map(|b| b.saturating_sub(self.used_with_time(now, one_month()))),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    fn record_with_time(&mut self, bytes: usize, now: DateTime<Utc>) {
+        let hour = Utc
+            .timestamp_opt(now.timestamp() - now.timestamp().rem_euclid(3600), 0)
+            .single()
+            .unwrap_or(now);
+        match self.history.front_mut() {
+            Some((time, used)) if *time == hour => *used += bytes,
+            _ => self.history.push_front((hour, bytes)),
+        }
+        while matches!(self.history.back(), Some((time, _)) if now.signed_duration_since(*time) >= one_month())
+        {
+            self.history.pop_back();
+        }
+        self.recorded += bytes;
+    }
+
+    /// Number of bytes which can be uploaded during the next sync, or None when unlimited.
+    pub fn available(&self) -> Option<usize> {
+        self.available_with_time(Utc::now())
+    }
+
+    /// Number of bytes which can be uploaded within the daily and monthly budgets, ignoring the
+    /// budget of the sync, or None when unlimited.
+    pub fn available_in_periods(&self) -> Option<usize> {
+        self.available_in_periods_with_time(Utc::now())
+    }
+
+    /// Record bytes uploaded now. The state is updated but not written to
+    /// disk. Call `save()` to persist the budget.
+    pub fn record(&mut self, bytes: usize) {
+        self.record_with_time(bytes, Utc::now())
+    }
+
+    /// Bytes recorded since the budget was loaded.
+    pub fn recorded(&self) -> usize {
+        self.recorded
+    }
+
+    pub fn per_sync(&self) -> Option<usize> {
+        self.per_sync
+    }
+
+    pub fn per_day(&self) -> Option<usize> {
+        self.per_day
+    }
+
+    pub fn per_month(&self) -> Option<usize> {
+        self.per_month
+    }
+
+    /// Bytes uploaded during the last 24 hours.
+    pub fn used_in_last_day(&self) -> usize {
+        self.used_with_time(Utc::now(), one_day())
+    }
+
+    /// Bytes uploaded during the last 30 days.
+    pub fn used_in_last_month(&self) -> usize {
+        self.used_with_time(Utc::now(), one_month())
+    }
+
+    /// Writes the budget state to disk.
+    pub fn save(&self) -> Result<()> {
+        let mut contents = String::new();
+        for (time, bytes) in self.history.iter() {
+            writeln!(contents, "{} {}", time.timestamp(), bytes)?;
+        }
+        write_atomically(&self.path, contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn time(hours: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_700_000_000, 0).single().unwrap() + Duration::hours(hours)
+    }
+
+    #[rstest]
+    #[case::unlimited(None, None, None, None)]
+    #[case::per_sync(Some(500), None, None, Some(500))]
+    #[case::per_day(Some(500), Some(1000), None, Some(100))]
+    #[case::per_month(None, Some(10000), Some(1000), Some(0))]
+    fn available_bytes(
+        #[case] per_sync: Option<usize>,
+        #[case] per_day: Option<usize>,
+        #[case] per_month: Option<usize>,
+        #[case] expected: Option<usize>,
+    ) {
+        let tmpdir = tempdir().unwrap();
+        let mut budget =
+            UploadBudget::load(tmpdir.path().join("budget"), per_sync, per_day, per_month).unwrap();
+
+        // Outside of the last day, but within the last month:
+        budget.record_with_time(100, time(0));
+        // Within the last day:
+        budget.record_with_time(600, time(20));
+        budget.record_with_time(300, time(30));
+
+        assert_eq!(budget.available_with_time(time(30)), expected);
+    }
+
+    #[rstest]
+    fn forgets_uploads_older_than_a_month() {
+        let tmpdir = tempdir().unwrap();
+        let mut budget =
+            UploadBudget::load(tmpdir.path().join("budget"), None, Some(1000), Some(1000)).unwrap();
+
+        budget.record_with_time(1000, time(0));
+        assert_eq!(budget.available_with_time(time(1)), Some(0));
+        assert_eq!(budget.available_with_time(time(25)), Some(0));
+
+        budget.record_with_time(100, time(24 * 30));
+        assert_eq!(budget.history.len(), 1);
+        assert_eq!(budget.available_with_time(time(24 * 30)), Some(900));
+    }
+
+    #[rstest]
+    fn persists_usage() {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("budget");
+
+        let mut budget = UploadBudget::load(&path, None, Some(1000), None).unwrap();
+        budget.record(100);
+        budget.record(200);
+        assert_eq!(budget.recorded(), 300);
+        budget.save().unwrap();
+
+        let budget = UploadBudget::load(&path, None, Some(1000), None).unwrap();
+        assert_eq!(budget.recorded(), 0);
+        assert_eq!(budget.used_in_last_day(), 300);
+        assert_eq!(budget.available(), Some(700));
+    }
+
+    #[rstest]
+    fn skips_garbage() {
+        let tmpdir = tempdir().unwrap();
+        let path = tmpdir.path().join("budget");
+        let now = Utc::now().timestamp();
+        std::fs::write(&path, format!("garbage\n{} 100\n{} xyz\n", now, now)).unwrap();
+
+        let budget = UploadBudget::load(&path, None, None, Some(1000)).unwrap();
+        assert_eq!(budget.used_in_last_month(), 100);
+    }
+}
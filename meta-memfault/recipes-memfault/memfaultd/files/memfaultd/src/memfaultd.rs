@@ -2,6 +2,7 @@
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
 use std::process::Command;
+use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
@@ -11,24 +12,27 @@ use std::thread::{sleep, spawn};
 use std::time::Duration;
 use std::{fs::create_dir_all, time::Instant};
 
+use chrono::Utc;
 use eyre::Result;
 use eyre::{eyre, Context};
 use log::{error, info, trace, warn};
 
 use crate::metrics::{
-    BatteryMonitor, BatteryReadingHandler, ConnectivityMonitor, MetricReportType,
-    ReportSyncEventHandler, SessionEventHandler,
+    BatteryMonitor, BatteryReadingHandler, ConnectivityMonitor, KeyedMetricReading, MetricReading,
+    MetricReadingHandler, MetricReportType, MetricStringKey, ReportSyncEventHandler,
+    SessionEventHandler,
 };
 
 use crate::{
-    config::Config,
-    mar::upload::collect_and_upload,
+    config::{Config, GatewayServerConfig},
+    mar::upload::{collect_and_upload, UploadOptions},
+    mar::UploadBudget,
     metrics::{CrashFreeIntervalTracker, MetricReportManager},
 };
 use crate::{http_server::HttpHandler, util::UpdateStatus};
 use crate::{
     http_server::HttpServer,
-    network::{NetworkClientImpl, NetworkConfig},
+    network::{LinkPolicy, NetworkConfig, UploadClient},
 };
 use crate::{
     mar::MarExportHandler,
@@ -43,6 +47,12 @@ use crate::{reboot::RebootReasonTracker, util::disk_size::DiskSize};
 #[cfg(feature = "collectd")]
 use crate::collectd::CollectdHandler;
 
+#[cfg(feature = "statsd")]
+use crate::statsd::StatsdServer;
+
+#[cfg(feature = "system-metrics")]
+use crate::metrics::SystemMetricsCollector;
+
 #[cfg(feature = "logging")]
 use crate::{
     fluent_bit::{FluentBitConfig, FluentBitConnectionHandler},
@@ -54,6 +64,9 @@ use crate::{
 const CONFIG_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 120);
 const METRIC_MF_SYNC_SUCCESS: &str = "sync_memfault_successful";
 const METRIC_MF_SYNC_FAILURE: &str = "sync_memfault_failure";
+const METRIC_MF_SYNC_UPLOAD_BYTES: &str = "sync_memfault_upload_bytes";
+const METRIC_UPLOAD_BUDGET_DAY_USED: &str = "upload_budget_day_used_bytes";
+const METRIC_UPLOAD_BUDGET_MONTH_USED: &str = "upload_budget_month_used_bytes";
 
 #[derive(PartialEq, Eq)]
 pub enum MemfaultLoopResult {
@@ -82,8 +95,7 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
 
     // Load configuration and device information. This has already been done by the C code but
     // we are preparing for a future where there is no more C code.
-    let client = NetworkClientImpl::new(NetworkConfig::from(&config))
-        .wrap_err(eyre!("Unable to prepare network client"))?;
+    let client = UploadClient::new(&config).wrap_err(eyre!("Unable to prepare network client"))?;
 
     let service_manager = get_service_manager();
 
@@ -113,12 +125,35 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
         vec![Box::new(MarExportHandler::new(config.mar_staging_path()))];
 
     // Metric store
-    let metric_report_manager = match config.session_configs() {
-        Some(session_configs) => Arc::new(Mutex::new(
-            MetricReportManager::new_with_session_configs(session_configs),
-        )),
-        None => Arc::new(Mutex::new(MetricReportManager::new())),
-    };
+    let metric_report_manager = Arc::new(Mutex::new(MetricReportManager::new_with_configs(
+        config.session_configs().map_or(&[], |c| c.as_slice()),
+        config.histogram_statistics(),
+    )));
+
+    // Resume the metric reports which were ongoing when memfaultd stopped
+    {
+        let previous_boot_reports = metric_report_manager
+            .lock()
+            .unwrap()
+            .restore_checkpoint(&config.metrics_checkpoint_path());
+        match previous_boot_reports {
+            Ok(reports) => {
+                let net_config = NetworkConfig::from(&config);
+                for mut report in reports {
+                    let saved = report
+                        .prepare_metric_report(&config.mar_staging_path())
+                        .and_then(|mar_builder| match mar_builder {
+                            Some(mar_builder) => mar_builder.save(&net_config).map(|_| ()),
+                            None => Ok(()),
+                        });
+                    if let Err(e) = saved {
+                        warn!("Unable to save metrics of the previous boot: {}", e);
+                    }
+                }
+            }
+            Err(e) => warn!("Unable to restore metrics checkpoint: {}", e),
+        }
+    }
 
     let battery_monitor = Arc::new(Mutex::new(BatteryMonitor::<Instant>::new(
         metric_report_manager.clone(),
@@ -143,6 +178,12 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
     );
     http_handlers.push(Box::new(session_event_handler));
 
+    let metric_reading_handler = MetricReadingHandler::new(
+        config.config_file.enable_data_collection,
+        metric_report_manager.clone(),
+    );
+    http_handlers.push(Box::new(metric_reading_handler));
+
     #[cfg(feature = "collectd")]
     {
         let collectd_handler = CollectdHandler::new(
@@ -152,14 +193,24 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
         http_handlers.push(Box::new(collectd_handler));
     }
 
+    #[cfg(feature = "statsd")]
+    if let Some(statsd_config) = config.statsd_config() {
+        if config.config_file.enable_data_collection {
+            StatsdServer::bind(statsd_config, metric_report_manager.clone())?.spawn();
+        }
+    }
+
     // Start a thread to dump the metrics precisely every heartbeat interval
     {
         let net_config = NetworkConfig::from(&config);
         let mar_staging_path = config.mar_staging_path();
         let heartbeat_interval = config.config_file.heartbeat_interval;
         let metric_report_manager = metric_report_manager.clone();
+        // The heartbeat may have been resumed from a checkpoint
+        let heartbeat_elapsed = metric_report_manager.lock().unwrap().heartbeat_elapsed();
         spawn(move || {
-            let mut next_heartbeat = Instant::now() + heartbeat_interval;
+            let mut next_heartbeat =
+                Instant::now() + heartbeat_interval.saturating_sub(heartbeat_elapsed);
             loop {
                 while Instant::now() < next_heartbeat {
                     sleep(next_heartbeat - Instant::now());
@@ -176,6 +227,23 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
             }
         });
     }
+    // Start a thread to checkpoint the metrics periodically
+    if !config.metrics_checkpoint_interval().is_zero() {
+        let checkpoint_interval = config.metrics_checkpoint_interval();
+        let metric_report_manager = metric_report_manager.clone();
+        spawn(move || {
+            let mut next_checkpoint = Instant::now() + checkpoint_interval;
+            loop {
+                while Instant::now() < next_checkpoint {
+                    sleep(next_checkpoint - Instant::now());
+                }
+                next_checkpoint += checkpoint_interval;
+                if let Err(e) = metric_report_manager.lock().unwrap().checkpoint() {
+                    warn!("Unable to checkpoint metrics: {}", e);
+                }
+            }
+        });
+    }
     // Start a thread to update battery metrics
     // periodically if enabled by configuration
     if config.battery_monitor_periodic_update_enabled() {
@@ -199,6 +267,30 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
             }
         });
     }
+    // System metrics are only sampled if config is defined
+    #[cfg(feature = "system-metrics")]
+    if let Some(system_metrics_config) = config.system_metrics_config() {
+        let mut collector =
+            SystemMetricsCollector::new(system_metrics_config, Box::new(get_service_manager()));
+        let metric_report_manager = metric_report_manager.clone();
+        spawn(move || {
+            let poll_interval = collector.poll_interval();
+            let mut next_poll = Instant::now();
+            loop {
+                while Instant::now() < next_poll {
+                    sleep(next_poll - Instant::now());
+                }
+                next_poll += poll_interval;
+                let readings = collector.collect();
+                let mut store = metric_report_manager.lock().unwrap();
+                for reading in readings {
+                    if let Err(e) = store.add_metric(reading) {
+                        warn!("Unable to add system metric: {}", e);
+                    }
+                }
+            }
+        });
+    }
     // Connected time monitor is only enabled if config is defined
     if let Some(connectivity_monitor_config) = config.connectivity_monitor_config() {
         let mut connectivity_monitor = ConnectivityMonitor::<Instant, TcpConnectionChecker>::new(
@@ -235,19 +327,25 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
             false => Ok(()),
         }));
     }
-    // Schedule a task to dump the metrics when we are shutting down
+    // Schedule a task to dump the metrics when we are shutting down. When
+    // reloading, the ongoing reports are only checkpointed and will be resumed.
     {
         let net_config = NetworkConfig::from(&config);
         let mar_staging_path = config.mar_staging_path();
+        let reload = reload.clone();
 
         let heartbeat_manager = metric_report_manager.clone();
         shutdown_tasks.push(Box::new(move || {
-            MetricReportManager::dump_report_to_mar_entry(
-                &heartbeat_manager,
-                &mar_staging_path,
-                &net_config,
-                MetricReportType::Heartbeat,
-            )
+            if reload.load(Ordering::Relaxed) {
+                heartbeat_manager.lock().unwrap().checkpoint()
+            } else {
+                MetricReportManager::dump_report_to_mar_entry(
+                    &heartbeat_manager,
+                    &mar_staging_path,
+                    &net_config,
+                    MetricReportType::Heartbeat,
+                )
+            }
         }));
     }
     // Schedule a task to compute operational and crashfree hours
@@ -362,6 +460,15 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
     let mut http_server = HttpServer::new(http_handlers);
     http_server.start(config.config_file.http_server.bind_address)?;
 
+    // Start the gateway server, which accepts MAR files from other devices of the network
+    if let Some(gateway_server) = config.gateway_server_config() {
+        if config.config_file.enable_data_collection {
+            start_gateway_server(&config, gateway_server, mar_cleaner.clone())?;
+        } else {
+            info!("Data collection is disabled, not starting the gateway server.");
+        }
+    }
+
     // Run the ready callback (creates the PID file)
     ready_callback()?;
 
@@ -384,14 +491,32 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
             let enable_data_collection = config.config_file.enable_data_collection;
 
             // Refresh device config if needed. In cases where we are only syncing on demand, we
-            // short-circuit this check.
-            if enable_data_collection
+            // short-circuit this check. It can only be fetched from Memfault (see `UploadBackendConfig`).
+            let should_refresh_device_config = enable_data_collection
+                && client.can_fetch_device_config()
                 && (!forced_sync_only
                     && (last_device_config_refresh.is_none()
                         || last_device_config_refresh.unwrap() + CONFIG_REFRESH_INTERVAL
                             < Instant::now())
-                    || forced)
-            {
+                    || forced);
+            let should_upload = enable_data_collection && !forced_sync_only || forced;
+
+            // Detect the network link first: None when there is no network policy, Some(None)
+            // when the device is offline.
+            let link_policy = match config.network_policy_config() {
+                Some(network_policy) if should_refresh_device_config || should_upload => {
+                    Some(LinkPolicy::detect::<TcpConnectionChecker>(network_policy))
+                }
+                _ => None,
+            };
+            // Do not request the device config when nothing can be uploaded on the current link.
+            let link_allows_uploads = match &link_policy {
+                Some(Some(link_policy)) => link_policy.allows_uploads(),
+                Some(None) => false,
+                None => true,
+            };
+
+            if should_refresh_device_config && link_allows_uploads {
                 // Refresh device config from the server
                 match config.refresh_device_config(&client) {
                     Err(e) => {
@@ -418,14 +543,35 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
 
             mar_cleaner.clean(DiskSize::ZERO).unwrap();
 
-            if enable_data_collection && !forced_sync_only || forced {
+            if should_upload {
+                let link_policy = match link_policy {
+                    Some(Some(link_policy)) => Some(link_policy),
+                    Some(None) => {
+                        info!("Network is offline, skipping upload.");
+                        return Ok(());
+                    }
+                    None => None,
+                };
+                if let Some(link) = link_policy.as_ref().and_then(LinkPolicy::link) {
+                    trace!("Current network link: {}", link);
+                }
+
                 trace!("Collect MAR entries...");
+                let mut upload_budget = UploadBudget::load_from_config(&config)?;
                 let result = collect_and_upload(
                     &config.mar_staging_path(),
                     &client,
-                    config.config_file.mar.mar_file_max_size,
+                    &UploadOptions::from_config(&config),
                     config.sampling(),
+                    Some(&mut upload_budget),
+                    link_policy.as_ref(),
                 );
+                if let Err(e) = upload_budget.save() {
+                    warn!("Unable to save upload budget: {:#}", e);
+                }
+                if let Err(e) = report_upload_budget(&upload_budget, &metric_report_manager) {
+                    warn!("Unable to report upload budget metrics: {:#}", e);
+                }
                 let _metric_result = match result {
                     Ok(0) => Ok(()),
                     Ok(_count) => metric_report_manager
@@ -468,3 +614,64 @@ pub fn memfaultd_loop<C: Fn() -> Result<()>>(
         Ok(MemfaultLoopResult::Terminate)
     }
 }
+
+#[cfg(feature = "mar-import")]
+fn start_gateway_server(
+    config: &Config,
+    gateway_server: &GatewayServerConfig,
+    mar_cleaner: Arc<MarStagingCleaner>,
+) -> Result<()> {
+    use crate::mar::GatewayUploadHandler;
+
+    let mut gateway_http_server = HttpServer::new(vec![Box::new(GatewayUploadHandler::new(
+        config.mar_staging_path(),
+        mar_cleaner,
+        config.tmp_dir(),
+        &gateway_server.auth_token,
+        config.config_file.mar.mar_file_max_size as u64,
+        gateway_server.client_quota as u64,
+    ))]);
+    gateway_http_server.start(gateway_server.bind_address)
+}
+
+#[cfg(not(feature = "mar-import"))]
+fn start_gateway_server(
+    _config: &Config,
+    _gateway_server: &GatewayServerConfig,
+    _mar_cleaner: Arc<MarStagingCleaner>,
+) -> Result<()> {
+    Err(eyre!(
+        "The gateway server requires memfaultd to be built with the mar-import feature"
+    ))
+}
+
+/// Report the bytes uploaded during the sync and the usage of the upload budget.
+fn report_upload_budget(
+    upload_budget: &UploadBudget,
+    metric_report_manager: &Mutex<MetricReportManager>,
+) -> Result<()> {
+    let mut metric_report_manager = metric_report_manager.lock().unwrap();
+    metric_report_manager
+        .add_to_counter(METRIC_MF_SYNC_UPLOAD_BYTES, upload_budget.recorded() as f64)?;
+
+    for (name, used) in [
+        (
+            METRIC_UPLOAD_BUDGET_DAY_USED,
+            upload_budget.used_in_last_day(),
+        ),
+        (
+            METRIC_UPLOAD_BUDGET_MONTH_USED,
+            upload_budget.used_in_last_month(),
+        ),
+    ] {
+        metric_report_manager.add_metric(KeyedMetricReading::new(
+            MetricStringKey::from_str(name).map_err(|e| eyre!(e))?,
+            MetricReading::Gauge {
+                value: used as f64,
+                timestamp: Utc::now(),
+                interval: chrono::Duration::zero(),
+            },
+        ))?;
+    }
+    Ok(())
+}
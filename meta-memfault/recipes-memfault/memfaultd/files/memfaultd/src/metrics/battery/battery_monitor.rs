@@ -328,6 +328,7 @@ mod tests {
                     assert!(e.is_nan());
                 }
             }
+            _ => panic!("Unexpected metric value"),
         }
 
         let soc_pct_discharge_key = METRIC_BATTERY_SOC_PCT_DROP
@@ -335,6 +336,7 @@ mod tests {
             .unwrap();
         match metrics.get(&soc_pct_discharge_key).unwrap() {
             MetricValue::Number(e) => assert_eq!(*e, expected_soc_pct_discharge),
+            _ => panic!("Unexpected metric value"),
         }
 
         let soc_discharge_duration_key = METRIC_BATTERY_DISCHARGE_DURATION_MS
@@ -342,6 +344,7 @@ mod tests {
             .unwrap();
         match metrics.get(&soc_discharge_duration_key).unwrap() {
             MetricValue::Number(e) => assert_eq!(*e, expected_discharge_duration),
+            _ => panic!("Unexpected metric value"),
         }
     }
 }
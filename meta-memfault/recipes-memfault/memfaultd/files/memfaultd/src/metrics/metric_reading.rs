@@ -8,7 +8,7 @@ use super::{MetricStringKey, MetricTimestamp};
 
 use crate::util::serialization::float_to_duration;
 
-#[derive(Copy, Clone, Debug, Serialize)]
+#[derive(Clone, Debug, Serialize)]
 pub enum MetricReading {
     /// Gauges are absolute values. We keep the latest value collected during a heartbeat.
     Gauge {
@@ -30,6 +30,18 @@ pub enum MetricReading {
         value: f64,
         timestamp: MetricTimestamp,
     },
+    /// A string value. We keep the latest value collected, and carry it over to the next reports.
+    String {
+        value: String,
+        timestamp: MetricTimestamp,
+    },
+    /// The state the device (or one of its components) entered at `timestamp`.
+    /// We report the time spent in each state during a report, as `<name>.<state>_ms`.
+    /// The current state is carried over to the next reports.
+    State {
+        value: String,
+        timestamp: MetricTimestamp,
+    },
 }
 
 #[derive(Debug, Serialize, Clone)]
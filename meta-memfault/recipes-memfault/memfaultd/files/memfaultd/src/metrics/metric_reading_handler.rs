@@ -0,0 +1,219 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use std::{
+    io::Read,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{Duration, Utc};
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use tiny_http::{Method, Request, Response};
+
+use crate::http_server::{HttpHandler, HttpHandlerResult};
+
+use super::{
+    KeyedMetricReading, MetricReading, MetricReportManager, MetricStringKey, MetricTimestamp,
+};
+
+pub const WRITE_METRICS_URL: &str = "/v1/metrics";
+
+/// A metric reading pushed by another program, typically with `memfaultctl write-metrics`.
+///
+/// The HTTP API accepts a JSON array of readings:
+/// ```json
+/// [
+///   { "name": "app.requests", "type": "counter", "value": 3 },
+///   { "name": "app.queue_size", "type": "gauge", "value": 12.5, "timestamp": "2024-01-01T00:00:00Z" }
+/// ]
+/// ```
+/// When the timestamp is omitted, the time of reception is used.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PushedMetricReading {
+    pub name: MetricStringKey,
+    #[serde(flatten)]
+    pub value: PushedMetricValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<MetricTimestamp>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PushedMetricValue {
+    Gauge { value: f64 },
+    Counter { value: f64 },
+    String { value: String },
+    State { value: String },
+}
+
+impl PushedMetricReading {
+    fn into_keyed_reading(self, received_at: MetricTimestamp) -> KeyedMetricReading {
+        let timestamp = self.timestamp.unwrap_or(received_at);
+        let value = match self.value {
+            PushedMetricValue::Gauge { value } => MetricReading::Gauge {
+                value,
+                timestamp,
+                interval: Duration::zero(),
+            },
+            PushedMetricValue::Counter { value } => MetricReading::Counter { value, timestamp },
+            PushedMetricValue::String { value } => MetricReading::String { value, timestamp },
+            PushedMetricValue::State { value } => MetricReading::State { value, timestamp },
+        };
+        KeyedMetricReading::new(self.name, value)
+    }
+}
+
+/// A server that listens for metric readings pushed as JSON and adds them to the ongoing reports.
+#[derive(Clone)]
+pub struct MetricReadingHandler {
+    data_collection_enabled: bool,
+    metrics_store: Arc<Mutex<MetricReportManager>>,
+}
+
+impl MetricReadingHandler {
+    pub fn new(
+        data_collection_enabled: bool,
+        metrics_store: Arc<Mutex<MetricReportManager>>,
+    ) -> Self {
+        Self {
+            data_collection_enabled,
+            metrics_store,
+        }
+    }
+
+    fn parse_request(stream: &mut dyn Read) -> Result<Vec<KeyedMetricReading>> {
+        let readings: Vec<PushedMetricReading> =
+            serde_json::from_reader(stream).map_err(|e| eyre!("Invalid metric readings: {}", e))?;
+        let received_at = Utc::now();
+        Ok(readings
+            .into_iter()
+            .map(|r| r.into_keyed_reading(received_at))
+            .collect())
+    }
+}
+
+impl HttpHandler for MetricReadingHandler {
+    fn handle_request(&self, request: &mut Request) -> HttpHandlerResult {
+        if request.url() != WRITE_METRICS_URL || *request.method() != Method::Post {
+            return HttpHandlerResult::NotHandled;
+        }
+        if self.data_collection_enabled {
+            let readings = match Self::parse_request(request.as_reader()) {
+                Ok(readings) => readings,
+                Err(e) => {
+                    return HttpHandlerResult::Response(
+                        Response::from_string(format!("{:#}", e))
+                            .with_status_code(400)
+                            .boxed(),
+                    )
+                }
+            };
+            let mut metrics_store = self.metrics_store.lock().unwrap();
+            for reading in readings {
+                let name = reading.name.clone();
+                if let Err(e) = metrics_store.add_metric(reading) {
+                    return HttpHandlerResult::Error(format!(
+                        "Failed to add metric reading {}: {:#}",
+                        name, e
+                    ));
+                }
+            }
+        }
+        HttpHandlerResult::Response(Response::empty(200).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use insta::assert_json_snapshot;
+    use rstest::{fixture, rstest};
+    use tiny_http::{Method, StatusCode, TestRequest};
+
+    use crate::{
+        http_server::{HttpHandler, HttpHandlerResult},
+        metrics::MetricReportManager,
+    };
+
+    use super::{MetricReadingHandler, WRITE_METRICS_URL};
+
+    #[rstest]
+    fn handle_push(handler: MetricReadingHandler) {
+        let r = TestRequest::new()
+            .with_method(Method::Post)
+            .with_path(WRITE_METRICS_URL)
+            .with_body(
+                r#"[
+                    {"name": "app.requests", "type": "counter", "value": 3},
+                    {"name": "app.requests", "type": "counter", "value": 2, "timestamp": "2024-01-01T00:00:00Z"},
+                    {"name": "app.queue_size", "type": "gauge", "value": 10},
+                    {"name": "app.queue_size", "type": "gauge", "value": 20.0},
+                    {"name": "app.version", "type": "string", "value": "1.2.3"}
+                ]"#,
+            );
+        let response = handler.handle_request(&mut r.into()).expect("response");
+        assert_eq!(response.status_code(), StatusCode(200));
+
+        let metrics: BTreeMap<_, _> = handler
+            .metrics_store
+            .lock()
+            .unwrap()
+            .take_heartbeat_metrics()
+            .into_iter()
+            .collect();
+        assert_json_snapshot!(metrics);
+    }
+
+    #[rstest]
+    #[case::not_an_array(r#"{"name": "foo", "type": "gauge", "value": 1}"#)]
+    #[case::unknown_type(r#"[{"name": "foo", "type": "histogram", "value": 1}]"#)]
+    #[case::invalid_value(r#"[{"name": "foo", "type": "gauge", "value": "one"}]"#)]
+    #[case::invalid_name(r#"[{"name": "", "type": "gauge", "value": 1}]"#)]
+    #[case::invalid_timestamp(r#"[{"name": "foo", "type": "gauge", "value": 1, "timestamp": 0}]"#)]
+    fn rejects_invalid_readings(handler: MetricReadingHandler, #[case] body: &'static str) {
+        let r = TestRequest::new()
+            .with_method(Method::Post)
+            .with_path(WRITE_METRICS_URL)
+            .with_body(body);
+        let response = handler.handle_request(&mut r.into()).expect("response");
+        assert_eq!(response.status_code(), StatusCode(400));
+
+        assert!(handler
+            .metrics_store
+            .lock()
+            .unwrap()
+            .take_heartbeat_metrics()
+            .is_empty());
+    }
+
+    #[rstest]
+    fn ignores_data_when_data_collection_is_off() {
+        let handler =
+            MetricReadingHandler::new(false, Arc::new(Mutex::new(MetricReportManager::new())));
+        let r = TestRequest::new()
+            .with_method(Method::Post)
+            .with_path(WRITE_METRICS_URL)
+            .with_body(r#"[{"name": "foo", "type": "gauge", "value": 1}]"#);
+        assert!(matches!(
+            handler.handle_request(&mut r.into()),
+            HttpHandlerResult::Response(_)
+        ));
+
+        assert!(handler
+            .metrics_store
+            .lock()
+            .unwrap()
+            .take_heartbeat_metrics()
+            .is_empty());
+    }
+
+    #[fixture]
+    fn handler() -> MetricReadingHandler {
+        MetricReadingHandler::new(true, Arc::new(Mutex::new(MetricReportManager::new())))
+    }
+}
@@ -1,7 +1,7 @@
 //
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use eyre::{eyre, Result};
 use serde::{Deserialize, Serialize};
 use std::{
@@ -18,8 +18,15 @@ use crate::{
 use super::{
     battery::METRIC_BATTERY_SOC_PCT,
     metric_reading::KeyedMetricReading,
-    timeseries::{Counter, Histogram, TimeSeries, TimeWeightedAverage},
+    timeseries::{
+        Counter, Histogram, HistogramStatistic, LatestString, StateTracker, TimeSeries,
+        TimeSeriesCheckpoint, TimeWeightedAverage,
+    },
 };
+use crate::util::serialization::milliseconds_to_duration;
+
+/// Statistics to report for gauge metrics aggregated as histograms, by metric name
+pub type HistogramStatistics = HashMap<MetricStringKey, Vec<HistogramStatistic>>;
 
 pub enum CapturedMetrics {
     All,
@@ -45,6 +52,8 @@ pub struct MetricReport {
     /// Indicates whether this is a heartbeat metric report or
     /// session metric report (with session name)
     report_type: MetricReportType,
+    /// Statistics to report for histogram metrics
+    histogram_statistics: HistogramStatistics,
 }
 
 struct MetricReportSnapshot {
@@ -52,19 +61,87 @@ struct MetricReportSnapshot {
     metrics: HashMap<MetricStringKey, MetricValue>,
 }
 
+/// Serializable state of a metric report, used to resume it after memfaultd restarts.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct MetricReportCheckpoint {
+    /// Value of the boot clock when the report started
+    #[serde(rename = "start_boottime_ms", with = "milliseconds_to_duration")]
+    start_boottime: Duration,
+    metrics: HashMap<MetricStringKey, TimeSeriesCheckpoint>,
+}
+
 impl MetricReport {
-    pub fn new(report_type: MetricReportType, captured_metrics: CapturedMetrics) -> Self {
+    pub fn new(
+        report_type: MetricReportType,
+        captured_metrics: CapturedMetrics,
+        histogram_statistics: HistogramStatistics,
+    ) -> Self {
         Self {
             metrics: HashMap::new(),
             start: Instant::now(),
             captured_metrics,
             report_type,
+            histogram_statistics,
         }
     }
 
     // Creates a heartbeat report that captures all metrics
-    pub fn new_heartbeat() -> Self {
-        MetricReport::new(MetricReportType::Heartbeat, CapturedMetrics::All)
+    pub fn new_heartbeat(histogram_statistics: HistogramStatistics) -> Self {
+        MetricReport::new(
+            MetricReportType::Heartbeat,
+            CapturedMetrics::All,
+            histogram_statistics,
+        )
+    }
+
+    /// Resumes a report from a checkpoint. `boottime` is the current value of
+    /// the boot clock, used to compute how long ago the report started, and
+    /// `resume_at` is when the time spent in the current state of state
+    /// metrics is counted from again.
+    pub fn restore(
+        report_type: MetricReportType,
+        captured_metrics: CapturedMetrics,
+        histogram_statistics: HistogramStatistics,
+        checkpoint: MetricReportCheckpoint,
+        boottime: Duration,
+        resume_at: DateTime<Utc>,
+    ) -> Self {
+        let elapsed = boottime.saturating_sub(checkpoint.start_boottime);
+        let now = Instant::now();
+        let metrics = checkpoint
+            .metrics
+            .into_iter()
+            .map(|(name, state)| {
+                let state = state.restore(resume_at, histogram_statistics.get(&name));
+                (name, state)
+            })
+            .collect();
+
+        Self {
+            metrics,
+            start: now.checked_sub(elapsed).unwrap_or(now),
+            captured_metrics,
+            report_type,
+            histogram_statistics,
+        }
+    }
+
+    /// Serializable state of this report. `boottime` is the current value of
+    /// the boot clock and `at` the current time.
+    pub fn checkpoint(&self, boottime: Duration, at: DateTime<Utc>) -> MetricReportCheckpoint {
+        MetricReportCheckpoint {
+            start_boottime: boottime.saturating_sub(self.start.elapsed()),
+            metrics: self
+                .metrics
+                .iter()
+                .map(|(name, state)| (name.clone(), state.checkpoint(at)))
+                .collect(),
+        }
+    }
+
+    /// Time elapsed since this report started
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
     }
 
     fn is_captured(&self, metric_key: &MetricStringKey) -> bool {
@@ -84,7 +161,8 @@ impl MetricReport {
                     let key = o.key().clone();
                     let state = o.get_mut();
                     if let Err(e) = (*state).aggregate(&m.value) {
-                        *state = Self::select_aggregate_for(&key, &m.value)?;
+                        *state =
+                            Self::select_aggregate_for(&key, &m.value, &self.histogram_statistics)?;
                         log::warn!(
                             "New value for metric {} is incompatible ({}). Resetting timeseries.",
                             o.key(),
@@ -93,7 +171,8 @@ impl MetricReport {
                     }
                 }
                 std::collections::hash_map::Entry::Vacant(v) => {
-                    let timeseries = Self::select_aggregate_for(v.key(), &m.value)?;
+                    let timeseries =
+                        Self::select_aggregate_for(v.key(), &m.value, &self.histogram_statistics)?;
                     v.insert(timeseries);
                 }
             };
@@ -124,11 +203,31 @@ impl MetricReport {
         self.take_metric_report_snapshot().metrics
     }
 
+    /// Start tracking the current state of the device (string and state
+    /// metrics) known by another report.
+    pub fn carry_over_from(&mut self, other: &MetricReport) {
+        let now = Utc::now();
+        for (name, state) in other.metrics.iter() {
+            if self.is_captured(name) {
+                if let Some(carried_over) = state.carry_over(now) {
+                    self.metrics.insert(name.clone(), carried_over);
+                }
+            }
+        }
+    }
+
     fn take_metric_report_snapshot(&mut self) -> MetricReportSnapshot {
         let duration = std::mem::replace(&mut self.start, Instant::now()).elapsed();
-        let metrics = std::mem::take(&mut self.metrics)
+        let end = Utc::now();
+        let previous_metrics = std::mem::take(&mut self.metrics);
+
+        self.metrics = previous_metrics
+            .iter()
+            .filter_map(|(name, state)| state.carry_over(end).map(|c| (name.clone(), c)))
+            .collect();
+        let metrics = previous_metrics
             .into_iter()
-            .map(|(name, state)| (name, state.value()))
+            .flat_map(|(name, state)| state.values(&name, end))
             .collect();
 
         MetricReportSnapshot { duration, metrics }
@@ -159,6 +258,7 @@ impl MetricReport {
     fn select_aggregate_for(
         key: &MetricStringKey,
         event: &MetricReading,
+        histogram_statistics: &HistogramStatistics,
     ) -> Result<Box<dyn TimeSeries + Send>> {
         match event {
             MetricReading::Gauge { .. } => {
@@ -166,10 +266,16 @@ impl MetricReport {
                 if key.as_str().eq(METRIC_BATTERY_SOC_PCT) {
                     Ok(Box::new(TimeWeightedAverage::new(event)?))
                 } else {
-                    Ok(Box::new(Histogram::new(event)?))
+                    let histogram = Histogram::new(event)?;
+                    match histogram_statistics.get(key) {
+                        Some(statistics) => Ok(Box::new(histogram.with_statistics(statistics))),
+                        None => Ok(Box::new(histogram)),
+                    }
                 }
             }
             MetricReading::Counter { .. } => Ok(Box::new(Counter::new(event)?)),
+            MetricReading::String { .. } => Ok(Box::new(LatestString::new(event)?)),
+            MetricReading::State { .. } => Ok(Box::new(StateTracker::new(event)?)),
         }
     }
 }
@@ -182,7 +288,6 @@ mod tests {
 
     use super::*;
     use crate::metrics::MetricTimestamp;
-    use chrono::Duration;
     use std::str::FromStr;
 
     use insta::assert_json_snapshot;
@@ -198,7 +303,7 @@ mod tests {
         #[case] metrics: impl Iterator<Item = KeyedMetricReading>,
         #[case] test_name: &str,
     ) {
-        let mut metric_report = MetricReport::new_heartbeat();
+        let mut metric_report = MetricReport::new_heartbeat(HistogramStatistics::new());
 
         for m in metrics {
             metric_report.add_metric(m).unwrap();
@@ -223,6 +328,7 @@ mod tests {
                 MetricStringKey::from_str("foo").unwrap(),
                 MetricStringKey::from_str("baz").unwrap(),
             ]),
+            HistogramStatistics::new(),
         );
 
         for m in metrics {
@@ -232,9 +338,118 @@ mod tests {
         assert_json_snapshot!(test_name, sorted_metrics);
     }
 
+    #[rstest]
+    fn test_histogram_statistics() {
+        let mut metric_report = MetricReport::new_heartbeat(HistogramStatistics::from([(
+            MetricStringKey::from_str("foo").unwrap(),
+            vec![
+                HistogramStatistic::Min,
+                HistogramStatistic::Max,
+                HistogramStatistic::P50,
+            ],
+        )]));
+
+        for m in in_gauges(vec![
+            ("foo", 1000, 1.0),
+            ("foo", 1000, 2.0),
+            ("foo", 1000, 3.0),
+            ("bar", 1000, 2.0),
+        ]) {
+            metric_report.add_metric(m).unwrap();
+        }
+        let sorted_metrics: BTreeMap<_, _> = metric_report.take_metrics().into_iter().collect();
+        assert_json_snapshot!(sorted_metrics);
+    }
+
+    #[rstest]
+    fn test_string_metrics_are_carried_over() {
+        let mut metric_report = MetricReport::new_heartbeat(HistogramStatistics::new());
+        metric_report
+            .add_metric(KeyedMetricReading::new(
+                MetricStringKey::from_str("firmware_slot").unwrap(),
+                MetricReading::String {
+                    value: "b".to_string(),
+                    timestamp: MetricTimestamp::from_str("2021-01-01T00:00:00Z").unwrap(),
+                },
+            ))
+            .unwrap();
+        for m in in_gauges(vec![("foo", 1000, 1.0)]) {
+            metric_report.add_metric(m).unwrap();
+        }
+
+        let first: BTreeMap<_, _> = metric_report.take_metrics().into_iter().collect();
+        assert_json_snapshot!("string_report_1", first);
+
+        // Gauges are reset but the latest string value is still reported
+        let second: BTreeMap<_, _> = metric_report.take_metrics().into_iter().collect();
+        assert_json_snapshot!("string_report_2", second);
+    }
+
+    #[rstest]
+    fn test_state_metrics() {
+        let mut metric_report = MetricReport::new_heartbeat(HistogramStatistics::new());
+        let now = Utc::now();
+        for (state, seconds_ago) in [("scanning", 30), ("connected", 20)] {
+            metric_report
+                .add_metric(KeyedMetricReading::new(
+                    MetricStringKey::from_str("wifi_state").unwrap(),
+                    MetricReading::State {
+                        value: state.to_string(),
+                        timestamp: now - chrono::Duration::seconds(seconds_ago),
+                    },
+                ))
+                .unwrap();
+        }
+
+        let metrics = metric_report.take_metrics();
+        assert_eq!(
+            metrics.get(&MetricStringKey::from_str("wifi_state.scanning_ms").unwrap()),
+            Some(&MetricValue::Number(10_000.0))
+        );
+        assert!(matches!(
+            metrics.get(&MetricStringKey::from_str("wifi_state.connected_ms").unwrap()),
+            Some(MetricValue::Number(ms)) if *ms >= 20_000.0
+        ));
+        assert!(!metrics.contains_key(&MetricStringKey::from_str("wifi_state").unwrap()));
+
+        // The next report only contains the current state
+        let metrics = metric_report.take_metrics();
+        assert_eq!(
+            metrics.keys().collect::<Vec<_>>(),
+            vec![&MetricStringKey::from_str("wifi_state.connected_ms").unwrap()]
+        );
+    }
+
+    #[rstest]
+    fn test_restore_from_checkpoint() {
+        let mut metric_report = MetricReport::new_heartbeat(HistogramStatistics::new());
+        for m in in_gauges(vec![("foo", 1000, 1.0), ("foo", 1000, 3.0)]) {
+            metric_report.add_metric(m).unwrap();
+        }
+        metric_report.add_to_counter("bar", 2.0).unwrap();
+
+        let checkpoint = metric_report.checkpoint(Duration::from_secs(100), Utc::now());
+        let serialized = serde_json::to_string(&checkpoint).unwrap();
+
+        // Restore 20 seconds later (on the boot clock)
+        let mut restored = MetricReport::restore(
+            MetricReportType::Heartbeat,
+            CapturedMetrics::All,
+            HistogramStatistics::new(),
+            serde_json::from_str(&serialized).unwrap(),
+            Duration::from_secs(120),
+            Utc::now(),
+        );
+        assert!(restored.elapsed() >= Duration::from_secs(20));
+
+        restored.add_to_counter("bar", 1.0).unwrap();
+        let sorted_metrics: BTreeMap<_, _> = restored.take_metrics().into_iter().collect();
+        assert_json_snapshot!(sorted_metrics);
+    }
+
     #[rstest]
     fn test_empty_after_write() {
-        let mut metric_report = MetricReport::new_heartbeat();
+        let mut metric_report = MetricReport::new_heartbeat(HistogramStatistics::new());
         for m in in_gauges(vec![
             ("foo", 1000, 1.0),
             ("bar", 1000, 2.0),
@@ -258,7 +473,7 @@ mod tests {
                 name: MetricStringKey::from_str(name).unwrap(),
                 value: MetricReading::Gauge {
                     value,
-                    interval: Duration::milliseconds(interval),
+                    interval: chrono::Duration::milliseconds(interval),
                     timestamp: MetricTimestamp::from_str("2021-01-01T00:00:00Z").unwrap()
                         + chrono::Duration::seconds(i as i64),
                 },
@@ -1,44 +1,195 @@
 //
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
+use chrono::{DateTime, Utc};
 use eyre::{eyre, Result};
-use log::debug;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     path::Path,
     sync::{Arc, Mutex},
+    time::Duration,
 };
+use uuid::Uuid;
 
-use super::{metric_reading::KeyedMetricReading, metric_report::CapturedMetrics, SessionName};
+use super::{
+    metric_reading::KeyedMetricReading,
+    metric_report::{CapturedMetrics, MetricReportCheckpoint},
+    HistogramStatistics, SessionName,
+};
 use crate::{
     config::SessionConfig,
     metrics::{MetricReport, MetricReportType, MetricStringKey, MetricValue},
     network::NetworkConfig,
+    util::{
+        serialization::milliseconds_to_duration,
+        system::{get_system_clock, read_system_boot_id, Clock},
+        DiskBacked,
+    },
 };
 
 pub struct MetricReportManager {
     heartbeat: MetricReport,
     sessions: HashMap<SessionName, MetricReport>,
     session_configs: Vec<SessionConfig>,
+    histogram_statistics: HistogramStatistics,
+    /// Where the ongoing reports are saved, when checkpointing is enabled
+    checkpoint_store: Option<DiskBacked<Option<MetricStoreCheckpoint>>>,
+}
+
+/// Serializable state of all ongoing metric reports
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct MetricStoreCheckpoint {
+    boot_id: Uuid,
+    /// Value of the boot clock when the checkpoint was taken
+    #[serde(rename = "boottime_ms", with = "milliseconds_to_duration")]
+    boottime: Duration,
+    timestamp: DateTime<Utc>,
+    heartbeat: MetricReportCheckpoint,
+    sessions: HashMap<SessionName, MetricReportCheckpoint>,
 }
 
 impl MetricReportManager {
     /// Creates a MetricReportManager with no sessions
     /// configured
     pub fn new() -> Self {
-        Self {
-            heartbeat: MetricReport::new_heartbeat(),
-            sessions: HashMap::new(),
-            session_configs: vec![],
-        }
+        Self::new_with_session_configs(&[])
     }
 
     pub fn new_with_session_configs(session_configs: &[SessionConfig]) -> Self {
+        Self::new_with_configs(session_configs, &HistogramStatistics::new())
+    }
+
+    /// Creates a MetricReportManager with the provided sessions and the
+    /// statistics to report for histogram metrics in every report.
+    pub fn new_with_configs(
+        session_configs: &[SessionConfig],
+        histogram_statistics: &HistogramStatistics,
+    ) -> Self {
         Self {
-            heartbeat: MetricReport::new_heartbeat(),
+            heartbeat: MetricReport::new_heartbeat(histogram_statistics.clone()),
             sessions: HashMap::new(),
             session_configs: session_configs.to_vec(),
+            histogram_statistics: histogram_statistics.clone(),
+            checkpoint_store: None,
+        }
+    }
+
+    /// Resumes the reports saved in the checkpoint file at `path`, and enables
+    /// saving checkpoints to it. This should be called before any metric is added.
+    /// Reports which were started during a previous boot cannot be resumed.
+    /// They are returned so that they can be written to MAR entries.
+    pub fn restore_checkpoint(&mut self, path: &Path) -> Result<Vec<MetricReport>> {
+        Ok(self.restore_checkpoint_with_boot(
+            path,
+            read_system_boot_id()?,
+            get_system_clock(Clock::Boottime)?,
+        ))
+    }
+
+    fn restore_checkpoint_with_boot(
+        &mut self,
+        path: &Path,
+        boot_id: Uuid,
+        boottime: Duration,
+    ) -> Vec<MetricReport> {
+        let store = DiskBacked::<Option<MetricStoreCheckpoint>>::from_path(path);
+        let checkpoint = store.get().clone();
+        self.checkpoint_store = Some(store);
+
+        let checkpoint = match checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => return vec![],
+        };
+        let same_boot = checkpoint.boot_id == boot_id;
+        // Reports from a previous boot ended when the checkpoint was taken.
+        let (boottime, resume_at) = match same_boot {
+            true => (boottime, checkpoint.timestamp),
+            false => (checkpoint.boottime, Utc::now()),
+        };
+
+        let mut previous_boot_reports = vec![];
+        let heartbeat = MetricReport::restore(
+            MetricReportType::Heartbeat,
+            CapturedMetrics::All,
+            self.histogram_statistics.clone(),
+            checkpoint.heartbeat,
+            boottime,
+            resume_at,
+        );
+        match same_boot {
+            true => self.heartbeat = heartbeat,
+            false => previous_boot_reports.push(heartbeat),
+        }
+
+        for (session_name, session_checkpoint) in checkpoint.sessions {
+            let report_type = MetricReportType::Session(session_name.clone());
+            let captured_metrics = self.captured_metric_keys_for_report(&report_type);
+            if same_boot && captured_metrics.is_err() {
+                warn!(
+                    "Session {} is no longer configured and will end now.",
+                    session_name
+                );
+            }
+            let resumable = same_boot && captured_metrics.is_ok();
+            let session_report = MetricReport::restore(
+                report_type,
+                captured_metrics.unwrap_or(CapturedMetrics::All),
+                self.histogram_statistics.clone(),
+                session_checkpoint,
+                boottime,
+                resume_at,
+            );
+            match resumable {
+                true => {
+                    self.sessions.insert(session_name, session_report);
+                }
+                false => previous_boot_reports.push(session_report),
+            }
+        }
+
+        // The reports of the previous boot are handed off to the caller, they must not be
+        // restored again if memfaultd restarts before the next checkpoint.
+        if !same_boot {
+            if let Err(e) = self.checkpoint_with_boot(boot_id, boottime) {
+                warn!("Unable to reset metrics checkpoint: {}", e);
+            }
+        }
+        previous_boot_reports
+    }
+
+    /// Saves the state of all ongoing reports to the checkpoint file. Does
+    /// nothing if checkpointing was not enabled with `restore_checkpoint()`.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if self.checkpoint_store.is_none() {
+            return Ok(());
+        }
+        self.checkpoint_with_boot(read_system_boot_id()?, get_system_clock(Clock::Boottime)?)
+    }
+
+    fn checkpoint_with_boot(&mut self, boot_id: Uuid, boottime: Duration) -> Result<()> {
+        let timestamp = Utc::now();
+        let checkpoint = MetricStoreCheckpoint {
+            boot_id,
+            boottime,
+            timestamp,
+            heartbeat: self.heartbeat.checkpoint(boottime, timestamp),
+            sessions: self
+                .sessions
+                .iter()
+                .map(|(name, report)| (name.clone(), report.checkpoint(boottime, timestamp)))
+                .collect(),
+        };
+        if let Some(store) = self.checkpoint_store.as_mut() {
+            store.set(Some(checkpoint))?;
         }
+        Ok(())
+    }
+
+    /// Time elapsed since the current heartbeat started
+    pub fn heartbeat_elapsed(&self) -> Duration {
+        self.heartbeat.elapsed()
     }
 
     /// Starts a session of the specified session name.
@@ -50,10 +201,15 @@ impl MetricReportManager {
         let report_type = MetricReportType::Session(session_name.clone());
         let captured_metric_keys = self.captured_metric_keys_for_report(&report_type)?;
 
-        self.sessions.insert(
-            session_name,
-            MetricReport::new(report_type, captured_metric_keys),
+        let mut session_report = MetricReport::new(
+            report_type,
+            captured_metric_keys,
+            self.histogram_statistics.clone(),
         );
+        // Sessions start in the current state of the device
+        session_report.carry_over_from(&self.heartbeat);
+
+        self.sessions.insert(session_name, session_report);
         Ok(())
     }
 
@@ -168,6 +324,14 @@ impl MetricReportManager {
                 report_name
             )
         }
+
+        if let Err(e) = metric_report_manager
+            .lock()
+            .expect("Mutex Poisoned!")
+            .checkpoint()
+        {
+            warn!("Unable to checkpoint metrics: {}", e);
+        }
         Ok(())
     }
 }
@@ -183,9 +347,11 @@ mod tests {
     use tempfile::TempDir;
 
     use super::*;
+    use crate::metrics::MetricReading;
     use crate::test_utils::in_gauges;
     use insta::assert_json_snapshot;
     use rstest::rstest;
+    use std::collections::BTreeMap;
     use std::str::FromStr;
 
     #[rstest]
@@ -221,6 +387,128 @@ mod tests {
             .is_err())
     }
 
+    #[rstest]
+    fn test_restore_checkpoint_in_same_boot() {
+        let tempdir = TempDir::new().unwrap();
+        let checkpoint_path = tempdir.path().join("metrics.json");
+        let boot_id = Uuid::new_v4();
+        let session_name = SessionName::from_str("test-session").unwrap();
+        let session_configs = [SessionConfig {
+            name: session_name.clone(),
+            captured_metrics: vec![MetricStringKey::from_str("foo").unwrap()],
+        }];
+
+        let mut metric_report_manager =
+            MetricReportManager::new_with_session_configs(&session_configs);
+        assert!(metric_report_manager
+            .restore_checkpoint_with_boot(&checkpoint_path, boot_id, Duration::from_secs(10))
+            .is_empty());
+        metric_report_manager
+            .start_session(session_name.clone())
+            .unwrap();
+        for m in in_gauges(vec![("foo", 1000, 1.0), ("bar", 1000, 2.0)]) {
+            metric_report_manager.add_metric(m).unwrap();
+        }
+        metric_report_manager
+            .checkpoint_with_boot(boot_id, Duration::from_secs(20))
+            .unwrap();
+
+        // Restart 30 seconds later
+        let mut metric_report_manager =
+            MetricReportManager::new_with_session_configs(&session_configs);
+        assert!(metric_report_manager
+            .restore_checkpoint_with_boot(&checkpoint_path, boot_id, Duration::from_secs(50))
+            .is_empty());
+        assert!(metric_report_manager.heartbeat_elapsed() >= Duration::from_secs(30));
+
+        let heartbeat: BTreeMap<_, _> = metric_report_manager
+            .take_heartbeat_metrics()
+            .into_iter()
+            .collect();
+        assert_json_snapshot!("restored_heartbeat", heartbeat);
+        let session: BTreeMap<_, _> = metric_report_manager
+            .take_session_metrics(&session_name)
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_json_snapshot!("restored_session", session);
+    }
+
+    #[rstest]
+    fn test_restore_checkpoint_from_previous_boot() {
+        let tempdir = TempDir::new().unwrap();
+        let checkpoint_path = tempdir.path().join("metrics.json");
+
+        let mut metric_report_manager = MetricReportManager::new();
+        metric_report_manager.restore_checkpoint_with_boot(
+            &checkpoint_path,
+            Uuid::new_v4(),
+            Duration::from_secs(10),
+        );
+        for m in in_gauges(vec![("foo", 1000, 1.0)]) {
+            metric_report_manager.add_metric(m).unwrap();
+        }
+        metric_report_manager
+            .checkpoint_with_boot(Uuid::new_v4(), Duration::from_secs(20))
+            .unwrap();
+
+        let boot_id = Uuid::new_v4();
+        let mut metric_report_manager = MetricReportManager::new();
+        let previous_boot_reports = metric_report_manager.restore_checkpoint_with_boot(
+            &checkpoint_path,
+            boot_id,
+            Duration::from_secs(5),
+        );
+
+        assert_eq!(previous_boot_reports.len(), 1);
+        assert!(metric_report_manager.take_heartbeat_metrics().is_empty());
+
+        // Restarting memfaultd does not restore these reports again
+        let mut metric_report_manager = MetricReportManager::new();
+        assert!(metric_report_manager
+            .restore_checkpoint_with_boot(&checkpoint_path, boot_id, Duration::from_secs(6))
+            .is_empty());
+        assert!(metric_report_manager.take_heartbeat_metrics().is_empty());
+
+        let tempdir = TempDir::new().unwrap();
+        let mut previous_heartbeat = previous_boot_reports.into_iter().next().unwrap();
+        let builder = previous_heartbeat
+            .prepare_metric_report(tempdir.path())
+            .unwrap();
+        assert_json_snapshot!(builder.unwrap().get_metadata(), {".metadata.duration_ms" => 0});
+    }
+
+    #[rstest]
+    fn test_session_starts_in_current_state() {
+        let session_name = SessionName::from_str("test-session").unwrap();
+        let mut metric_report_manager =
+            MetricReportManager::new_with_session_configs(&[SessionConfig {
+                name: session_name.clone(),
+                captured_metrics: vec![MetricStringKey::from_str("modem_technology").unwrap()],
+            }]);
+
+        metric_report_manager
+            .add_metric(KeyedMetricReading::new(
+                MetricStringKey::from_str("modem_technology").unwrap(),
+                MetricReading::String {
+                    value: "lte".to_string(),
+                    timestamp: Utc::now(),
+                },
+            ))
+            .unwrap();
+        metric_report_manager
+            .start_session(session_name.clone())
+            .unwrap();
+
+        let metrics = metric_report_manager
+            .take_session_metrics(&session_name)
+            .unwrap();
+        assert_eq!(
+            metrics.get(&MetricStringKey::from_str("modem_technology").unwrap()),
+            Some(&MetricValue::String("lte".to_string()))
+        );
+    }
+
     #[rstest]
     #[case(in_gauges(vec![("foo", 1000, 1.0), ("bar", 1000, 2.0), ("baz", 1000, 3.0)]), "heartbeat_and_sessions_report_1")]
     #[case(in_gauges(vec![("foo", 1000, 1.0), ("foo", 1000, 2.0), ("foo", 1000, 3.0)]), "heartbeat_and_sessions_report_2")]
@@ -17,6 +17,11 @@ impl MetricStringKey {
     pub fn as_str(&self) -> &str {
         &self.inner
     }
+
+    /// Returns a new key named `<self>.<suffix>`.
+    pub fn with_suffix(&self, suffix: &str) -> Result<Self, &'static str> {
+        format!("{}.{}", self.inner, suffix).parse()
+    }
 }
 
 impl Debug for MetricStringKey {
@@ -92,4 +97,14 @@ mod tests {
         let result: Result<MetricStringKey, &str> = str::parse(input);
         assert_eq!(result.ok().unwrap().as_str(), input);
     }
+
+    #[rstest]
+    fn with_suffix() {
+        let key: MetricStringKey = str::parse("foo").unwrap();
+        assert_eq!(key.with_suffix("p99").unwrap().as_str(), "foo.p99");
+        assert!(str::parse::<MetricStringKey>(&"a".repeat(126))
+            .unwrap()
+            .with_suffix("p99")
+            .is_err());
+    }
 }
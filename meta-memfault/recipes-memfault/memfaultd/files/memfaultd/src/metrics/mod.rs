@@ -16,6 +16,7 @@ mod metric_string_key;
 pub use metric_string_key::MetricStringKey;
 
 mod metric_report;
+pub use metric_report::HistogramStatistics;
 pub use metric_report::MetricReport;
 pub use metric_report::MetricReportType;
 
@@ -27,6 +28,7 @@ pub use metric_reading::KeyedMetricReading;
 pub use metric_reading::MetricReading;
 
 mod timeseries;
+pub use timeseries::HistogramStatistic;
 
 mod metric_value;
 pub use metric_value::MetricValue;
@@ -39,5 +41,16 @@ pub use crashfree_interval::CrashFreeIntervalTracker;
 mod session_name;
 pub use session_name::SessionName;
 
+mod metric_reading_handler;
+pub use metric_reading_handler::MetricReadingHandler;
+pub use metric_reading_handler::PushedMetricReading;
+pub use metric_reading_handler::PushedMetricValue;
+pub use metric_reading_handler::WRITE_METRICS_URL;
+
+#[cfg(feature = "system-metrics")]
+mod system;
+#[cfg(feature = "system-metrics")]
+pub use system::SystemMetricsCollector;
+
 mod session_event_handler;
 pub use session_event_handler::SessionEventHandler;
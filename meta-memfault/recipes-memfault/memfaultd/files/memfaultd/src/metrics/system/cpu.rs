@@ -0,0 +1,126 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use eyre::{eyre, Result};
+
+use crate::metrics::KeyedMetricReading;
+
+use super::Sample;
+
+/// States reported on the `cpu` line of /proc/stat, in order.
+/// Guest time is already included in user and nice time.
+const CPU_STATES: [&str; 8] = [
+    "user", "nice", "system", "idle", "iowait", "irq", "softirq", "steal",
+];
+
+type CpuTimes = [u64; CPU_STATES.len()];
+
+/// Reports the share of time spent by all the CPUs in each state since the previous sample.
+pub struct CpuMetrics {
+    previous: Option<CpuTimes>,
+}
+
+impl CpuMetrics {
+    pub fn new() -> Self {
+        Self { previous: None }
+    }
+
+    pub fn collect(&mut self, proc_stat: &str, sample: &Sample) -> Result<Vec<KeyedMetricReading>> {
+        let times = parse_cpu_times(proc_stat)?;
+        let previous = match self.previous.replace(times) {
+            Some(previous) => previous,
+            None => return Ok(vec![]),
+        };
+
+        let deltas: Vec<u64> = times
+            .iter()
+            .zip(previous.iter())
+            .map(|(t, p)| t.saturating_sub(*p))
+            .collect();
+        let total: u64 = deltas.iter().sum();
+        if total == 0 {
+            return Ok(vec![]);
+        }
+        CPU_STATES
+            .iter()
+            .zip(deltas)
+            .map(|(state, delta)| {
+                sample.gauge(
+                    format!("cpu/sum/percent/{}", state),
+                    delta as f64 * 100.0 / total as f64,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Parses the aggregated `cpu` line of /proc/stat.
+/// Older kernels do not report all the states: they are counted as zero.
+fn parse_cpu_times(proc_stat: &str) -> Result<CpuTimes> {
+    let line = proc_stat
+        .lines()
+        .find(|l| l.starts_with("cpu "))
+        .ok_or_else(|| eyre!("No cpu line in /proc/stat"))?;
+
+    let mut times = CpuTimes::default();
+    for (time, value) in times.iter_mut().zip(line.split_whitespace().skip(1)) {
+        *time = value.parse()?;
+    }
+    Ok(times)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use chrono::Utc;
+    use insta::assert_json_snapshot;
+    use rstest::rstest;
+
+    use crate::metrics::MetricReading;
+
+    use super::*;
+
+    #[rstest]
+    fn parses_cpu_line() {
+        let proc_stat =
+            "cpu  10 20 30 40 50 60 70 80 90 100\ncpu0 1 2 3 4 5 6 7 8 9 10\nintr 1234\n";
+        assert_eq!(
+            parse_cpu_times(proc_stat).unwrap(),
+            [10, 20, 30, 40, 50, 60, 70, 80]
+        );
+    }
+
+    #[rstest]
+    fn parses_old_kernel_cpu_line() {
+        assert_eq!(
+            parse_cpu_times("cpu 1 2 3 4\n").unwrap(),
+            [1, 2, 3, 4, 0, 0, 0, 0]
+        );
+    }
+
+    #[rstest]
+    fn computes_percentages_between_samples() {
+        let sample = Sample {
+            timestamp: Utc::now(),
+            interval: Duration::from_secs(10),
+        };
+        let mut cpu = CpuMetrics::new();
+        assert!(cpu
+            .collect("cpu 100 0 100 800 0 0 0 0\n", &sample)
+            .unwrap()
+            .is_empty());
+
+        let readings = cpu
+            .collect("cpu 150 0 125 1000 25 0 0 0\n", &sample)
+            .unwrap();
+        let percentages: BTreeMap<_, _> = readings
+            .into_iter()
+            .map(|r| match r.value {
+                MetricReading::Gauge { value, .. } => (r.name, value),
+                _ => panic!("Unexpected metric type"),
+            })
+            .collect();
+        assert_json_snapshot!(percentages);
+    }
+}
@@ -0,0 +1,118 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use eyre::{eyre, Result};
+
+use crate::metrics::KeyedMetricReading;
+
+use super::{is_selected, CounterDeltas, Sample};
+
+/// /proc/diskstats always counts sectors of 512 bytes.
+const SECTOR_SIZE: u64 = 512;
+
+/// Reports the bytes and operations read and written on each disk since the previous sample.
+pub struct DiskMetrics {
+    disks: Option<Vec<String>>,
+    deltas: CounterDeltas,
+}
+
+impl DiskMetrics {
+    /// Monitors the given disks, or all the disks except loop and RAM devices.
+    pub fn new(disks: Option<Vec<String>>) -> Self {
+        Self {
+            disks,
+            deltas: CounterDeltas::default(),
+        }
+    }
+
+    pub fn collect(&mut self, diskstats: &str, sample: &Sample) -> Result<Vec<KeyedMetricReading>> {
+        let mut readings = vec![];
+        for line in diskstats.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                continue;
+            }
+            let device = fields[2];
+            if !is_selected(&self.disks, device, |d| {
+                !d.starts_with("loop") && !d.starts_with("ram")
+            }) {
+                continue;
+            }
+
+            let field = |i: usize| -> Result<u64> {
+                fields[i]
+                    .parse()
+                    .map_err(|e| eyre!("Invalid /proc/diskstats for {}: {}", device, e))
+            };
+            let counters = [
+                ("disk_ops/read", field(3)?),
+                ("disk_octets/read", field(5)? * SECTOR_SIZE),
+                ("disk_ops/write", field(7)?),
+                ("disk_octets/write", field(9)? * SECTOR_SIZE),
+            ];
+            for (name, value) in counters {
+                let name = format!("disk/{}/{}", device, name);
+                if let Some(delta) = self.deltas.delta(&name, value) {
+                    readings.push(sample.counter(name, delta as f64)?);
+                }
+            }
+        }
+        Ok(readings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use chrono::Utc;
+    use insta::assert_json_snapshot;
+    use rstest::rstest;
+
+    use crate::metrics::MetricReading;
+
+    use super::*;
+
+    fn diskstats(sda_reads: u64, sda_sectors_written: u64) -> String {
+        format!(
+            "   7       0 loop0 10 0 20 0 0 0 0 0 0 0 0 0 0 0 0 0 0\n\
+             \x20  8       0 sda {} 5 800 30 12 3 {} 40 0 50 70 0 0 0 0 0 0\n\
+             \x20  8       1 sda1 4 0 8 1 0 0 0 0 0 1 1\n",
+            sda_reads, sda_sectors_written
+        )
+    }
+
+    fn counters(readings: Vec<KeyedMetricReading>) -> BTreeMap<String, f64> {
+        readings
+            .into_iter()
+            .map(|r| match r.value {
+                MetricReading::Counter { value, .. } => (r.name.to_string(), value),
+                _ => panic!("Unexpected metric type"),
+            })
+            .collect()
+    }
+
+    #[rstest]
+    #[case::all_disks(None)]
+    #[case::selected_disks(Some(vec!["sda1".to_string()]))]
+    fn reports_disk_traffic(#[case] disks: Option<Vec<String>>) {
+        let sample = Sample {
+            timestamp: Utc::now(),
+            interval: Duration::from_secs(10),
+        };
+        let mut metrics = DiskMetrics::new(disks.clone());
+        assert!(metrics
+            .collect(&diskstats(100, 1000), &sample)
+            .unwrap()
+            .is_empty());
+
+        let readings = counters(metrics.collect(&diskstats(110, 1064), &sample).unwrap());
+        assert_json_snapshot!(
+            format!(
+                "reports_disk_traffic_{}",
+                disks.map_or("all", |_| "selected")
+            ),
+            readings
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use std::collections::HashMap;
+
+use eyre::{eyre, Result};
+
+use crate::metrics::KeyedMetricReading;
+
+use super::Sample;
+
+/// Reports memory usage in bytes, computed like the collectd memory plugin.
+pub fn collect(meminfo: &str, sample: &Sample) -> Result<Vec<KeyedMetricReading>> {
+    let meminfo = parse_meminfo(meminfo)?;
+    let get = |field: &str| {
+        meminfo
+            .get(field)
+            .copied()
+            .ok_or_else(|| eyre!("No {} in /proc/meminfo", field))
+    };
+
+    let total = get("MemTotal")?;
+    let free = get("MemFree")?;
+    let buffered = get("Buffers")?;
+    // Reclaimable slab memory is used as a cache by the kernel.
+    let cached = get("Cached")? + get("SReclaimable").unwrap_or(0);
+    let used = total.saturating_sub(free + buffered + cached);
+
+    [
+        ("used", used),
+        ("free", free),
+        ("buffered", buffered),
+        ("cached", cached),
+    ]
+    .into_iter()
+    .map(|(name, value)| sample.gauge(format!("memory/memory/{}", name), value as f64))
+    .collect()
+}
+
+/// Parses /proc/meminfo into a map of field name to bytes.
+fn parse_meminfo(meminfo: &str) -> Result<HashMap<&str, u64>> {
+    meminfo
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| {
+            let mut parts = value.split_whitespace();
+            let value: u64 = parts
+                .next()
+                .ok_or_else(|| eyre!("No value for {} in /proc/meminfo", name))?
+                .parse()?;
+            let multiplier = match parts.next() {
+                Some("kB") => 1024,
+                _ => 1,
+            };
+            Ok((name, value * multiplier))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use chrono::Utc;
+    use insta::assert_json_snapshot;
+    use rstest::rstest;
+
+    use crate::metrics::MetricReading;
+
+    use super::*;
+
+    const MEMINFO: &str = "MemTotal:        1000 kB
+MemFree:          100 kB
+MemAvailable:     500 kB
+Buffers:           10 kB
+Cached:           200 kB
+SReclaimable:      40 kB
+HugePages_Total:    0
+";
+
+    #[rstest]
+    fn reports_memory_usage() {
+        let sample = Sample {
+            timestamp: Utc::now(),
+            interval: Duration::from_secs(10),
+        };
+        let readings: BTreeMap<_, _> = collect(MEMINFO, &sample)
+            .unwrap()
+            .into_iter()
+            .map(|r| match r.value {
+                MetricReading::Gauge { value, .. } => (r.name, value),
+                _ => panic!("Unexpected metric type"),
+            })
+            .collect();
+        assert_json_snapshot!(readings);
+    }
+
+    #[rstest]
+    fn fails_on_missing_field() {
+        let sample = Sample {
+            timestamp: Utc::now(),
+            interval: Duration::from_secs(10),
+        };
+        assert!(collect("MemTotal: 1000 kB\n", &sample).is_err());
+    }
+}
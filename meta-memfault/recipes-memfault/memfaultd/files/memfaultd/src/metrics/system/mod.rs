@@ -0,0 +1,281 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! System metrics
+//!
+//! Samples /proc and /sys periodically and reports CPU, memory, disk, network,
+//! thermal and per-process metrics, without requiring collectd.
+//!
+//! Metric names follow the naming of the equivalent collectd plugins where
+//! there is one, so that existing charts keep working when collectd is removed:
+//! - `cpu/sum/percent/<state>`
+//! - `memory/memory/<used|free|buffered|cached>` (bytes)
+//! - `disk/<device>/disk_octets/<read|write>` and `disk/<device>/disk_ops/<read|write>`
+//! - `interface/<interface>/<if_octets|if_packets|if_errors>/<rx|tx>`
+//! - `thermal/<zone>/temperature` (degrees Celsius)
+//! - `processes/<name>/<rss_bytes|cpu_percent|num_threads|num_fds|restarts>`
+//! - `systemd/<unit>/<rss_bytes|cpu_percent|num_threads|num_fds|restarts>`
+//!
+use std::{
+    collections::{HashMap, HashSet},
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use eyre::{eyre, Result};
+use log::{debug, warn};
+
+use crate::{config::SystemMetricsConfig, service_manager::MemfaultdServiceManager};
+
+use super::{KeyedMetricReading, MetricReading, MetricStringKey, MetricTimestamp};
+
+mod cpu;
+mod disk;
+mod memory;
+mod network;
+mod process;
+mod thermal;
+
+use cpu::CpuMetrics;
+use disk::DiskMetrics;
+use network::NetworkMetrics;
+use process::ProcessMetrics;
+
+/// Samples system metrics from /proc and /sys.
+pub struct SystemMetricsCollector {
+    proc_path: PathBuf,
+    sys_path: PathBuf,
+    poll_interval: Duration,
+    last_sample: Option<Instant>,
+    cpu: CpuMetrics,
+    disks: DiskMetrics,
+    network: NetworkMetrics,
+    processes: ProcessMetrics,
+    /// Sources which could not be collected already, to warn only once about them.
+    failed_sources: HashSet<&'static str>,
+}
+
+impl SystemMetricsCollector {
+    /// The service manager is used to find the main process of the monitored units.
+    pub fn new(
+        config: &SystemMetricsConfig,
+        service_manager: Box<dyn MemfaultdServiceManager + Send>,
+    ) -> Self {
+        Self::new_with_paths(
+            config,
+            service_manager,
+            Path::new("/proc"),
+            Path::new("/sys"),
+        )
+    }
+
+    fn new_with_paths(
+        config: &SystemMetricsConfig,
+        service_manager: Box<dyn MemfaultdServiceManager + Send>,
+        proc_path: &Path,
+        sys_path: &Path,
+    ) -> Self {
+        Self {
+            proc_path: proc_path.to_path_buf(),
+            sys_path: sys_path.to_path_buf(),
+            poll_interval: config.poll_interval,
+            last_sample: None,
+            cpu: CpuMetrics::new(),
+            disks: DiskMetrics::new(config.disks.clone()),
+            network: NetworkMetrics::new(config.network_interfaces.clone()),
+            processes: ProcessMetrics::new(
+                config.processes.clone(),
+                config.systemd_units.clone(),
+                service_manager,
+            ),
+            failed_sources: HashSet::new(),
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    /// Samples all the sources. Sources which cannot be read are skipped (with a warning the
+    /// first time).
+    ///
+    /// Rates (CPU usage, disk and network traffic) are computed from the
+    /// difference with the previous sample, so the first call does not report them.
+    pub fn collect(&mut self) -> Vec<KeyedMetricReading> {
+        let now = Instant::now();
+        let elapsed = self.last_sample.map(|last| now.duration_since(last));
+        self.last_sample = Some(now);
+
+        let sample = Sample {
+            timestamp: Utc::now(),
+            interval: self.poll_interval,
+        };
+        let mut readings = vec![];
+        let failed_sources = &mut self.failed_sources;
+        let mut add = |source: &'static str, result: Result<Vec<KeyedMetricReading>>| match result {
+            Ok(r) => readings.extend(r),
+            // Sources which do not exist on the device fail on every poll:
+            Err(e) if failed_sources.insert(source) => {
+                warn!("Unable to collect {} metrics: {}", source, e)
+            }
+            Err(e) => debug!("Unable to collect {} metrics: {}", source, e),
+        };
+
+        add(
+            "cpu",
+            read_to_string(self.proc_path.join("stat"))
+                .map_err(|e| e.into())
+                .and_then(|s| self.cpu.collect(&s, &sample)),
+        );
+        add(
+            "memory",
+            read_to_string(self.proc_path.join("meminfo"))
+                .map_err(|e| e.into())
+                .and_then(|s| memory::collect(&s, &sample)),
+        );
+        add(
+            "disk",
+            read_to_string(self.proc_path.join("diskstats"))
+                .map_err(|e| e.into())
+                .and_then(|s| self.disks.collect(&s, &sample)),
+        );
+        add(
+            "network",
+            read_to_string(self.proc_path.join("net/dev"))
+                .map_err(|e| e.into())
+                .and_then(|s| self.network.collect(&s, &sample)),
+        );
+        add(
+            "thermal",
+            thermal::collect(&self.sys_path.join("class/thermal"), &sample),
+        );
+        add(
+            "process",
+            self.processes.collect(&self.proc_path, elapsed, &sample),
+        );
+        readings
+    }
+}
+
+/// Timestamp and interval shared by all the readings of a sample.
+struct Sample {
+    timestamp: MetricTimestamp,
+    interval: Duration,
+}
+
+impl Sample {
+    fn gauge(&self, name: String, value: f64) -> Result<KeyedMetricReading> {
+        Ok(KeyedMetricReading::new(
+            metric_key(name)?,
+            MetricReading::Gauge {
+                value,
+                timestamp: self.timestamp,
+                interval: chrono::Duration::from_std(self.interval)?,
+            },
+        ))
+    }
+
+    fn counter(&self, name: String, value: f64) -> Result<KeyedMetricReading> {
+        Ok(KeyedMetricReading::new(
+            metric_key(name)?,
+            MetricReading::Counter {
+                value,
+                timestamp: self.timestamp,
+            },
+        ))
+    }
+}
+
+fn metric_key(name: String) -> Result<MetricStringKey> {
+    name.parse()
+        .map_err(|e| eyre!("Invalid metric name {}: {}", name, e))
+}
+
+/// Keeps the last value of monotonic kernel counters, to report their increase.
+#[derive(Default)]
+struct CounterDeltas {
+    previous: HashMap<String, u64>,
+}
+
+impl CounterDeltas {
+    /// Returns the increase since the previous value of the counter, or None
+    /// on the first value and when the counter was reset.
+    fn delta(&mut self, name: &str, value: u64) -> Option<u64> {
+        match self.previous.insert(name.to_string(), value) {
+            Some(previous) if value >= previous => Some(value - previous),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `name` is selected by an optional list of names, or by the default
+/// filter when there is no list.
+fn is_selected(list: &Option<Vec<String>>, name: &str, default: impl Fn(&str) -> bool) -> bool {
+    match list {
+        Some(names) => names.iter().any(|n| n == name),
+        None => default(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use insta::assert_json_snapshot;
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    use crate::service_manager::MockMemfaultdServiceManager;
+
+    use super::*;
+
+    #[rstest]
+    fn counter_deltas() {
+        let mut deltas = CounterDeltas::default();
+        assert_eq!(deltas.delta("a", 10), None);
+        assert_eq!(deltas.delta("a", 15), Some(5));
+        assert_eq!(deltas.delta("b", 1), None);
+        // Counter was reset
+        assert_eq!(deltas.delta("a", 3), None);
+        assert_eq!(deltas.delta("a", 4), Some(1));
+    }
+
+    #[rstest]
+    fn skips_missing_sources() {
+        let root = tempdir().unwrap();
+        let proc_path = root.path().join("proc");
+        create_dir_all(&proc_path).unwrap();
+        write(
+            proc_path.join("meminfo"),
+            "MemTotal: 1000 kB\nMemFree: 100 kB\nBuffers: 10 kB\nCached: 200 kB\nSReclaimable: 40 kB\n",
+        )
+        .unwrap();
+
+        let config = SystemMetricsConfig {
+            poll_interval: Duration::from_secs(10),
+            processes: vec![],
+            systemd_units: vec![],
+            network_interfaces: None,
+            disks: None,
+        };
+        let mut collector = SystemMetricsCollector::new_with_paths(
+            &config,
+            Box::new(MockMemfaultdServiceManager::new()),
+            &proc_path,
+            &root.path().join("sys"),
+        );
+
+        let readings = collector
+            .collect()
+            .into_iter()
+            .map(|r| r.name.to_string())
+            .collect::<Vec<_>>();
+        assert_json_snapshot!(readings);
+
+        // Missing sources are only warned about once
+        assert!(collector.failed_sources.contains("cpu"));
+        assert!(!collector.failed_sources.contains("memory"));
+    }
+}
@@ -0,0 +1,128 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use eyre::{eyre, Result};
+
+use crate::metrics::KeyedMetricReading;
+
+use super::{is_selected, CounterDeltas, Sample};
+
+/// Columns of /proc/net/dev which are reported, with their metric name.
+/// The first 8 columns are for received traffic, the next 8 for transmitted traffic.
+const COLUMNS: [(usize, &str); 6] = [
+    (0, "if_octets/rx"),
+    (1, "if_packets/rx"),
+    (2, "if_errors/rx"),
+    (8, "if_octets/tx"),
+    (9, "if_packets/tx"),
+    (10, "if_errors/tx"),
+];
+
+/// Reports the traffic on each network interface since the previous sample.
+pub struct NetworkMetrics {
+    interfaces: Option<Vec<String>>,
+    deltas: CounterDeltas,
+}
+
+impl NetworkMetrics {
+    /// Monitors the given interfaces, or all the interfaces except loopback.
+    pub fn new(interfaces: Option<Vec<String>>) -> Self {
+        Self {
+            interfaces,
+            deltas: CounterDeltas::default(),
+        }
+    }
+
+    pub fn collect(&mut self, net_dev: &str, sample: &Sample) -> Result<Vec<KeyedMetricReading>> {
+        let mut readings = vec![];
+        // The first two lines are headers.
+        for line in net_dev.lines().skip(2) {
+            let (interface, values) = match line.split_once(':') {
+                Some((interface, values)) => (interface.trim(), values),
+                None => continue,
+            };
+            if !is_selected(&self.interfaces, interface, |i| i != "lo") {
+                continue;
+            }
+
+            let values = values
+                .split_whitespace()
+                .map(|v| v.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| eyre!("Invalid /proc/net/dev for {}: {}", interface, e))?;
+            for (column, name) in COLUMNS {
+                let value = *values
+                    .get(column)
+                    .ok_or_else(|| eyre!("Missing columns in /proc/net/dev for {}", interface))?;
+                let name = format!("interface/{}/{}", interface, name);
+                if let Some(delta) = self.deltas.delta(&name, value) {
+                    readings.push(sample.counter(name, delta as f64)?);
+                }
+            }
+        }
+        Ok(readings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use chrono::Utc;
+    use insta::assert_json_snapshot;
+    use rstest::rstest;
+
+    use crate::metrics::MetricReading;
+
+    use super::*;
+
+    fn net_dev(eth0_rx_bytes: u64, eth0_tx_bytes: u64) -> String {
+        format!(
+            "Inter-|   Receive                                                |  Transmit\n \
+             face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed\n    \
+             lo:    1000      10    0    0    0     0          0         0     1000      10    0    0    0     0       0          0\n  \
+             eth0: {}     100    1    0    0     0          0         0 {}      50    0    0    0     0       0          0\n",
+            eth0_rx_bytes, eth0_tx_bytes
+        )
+    }
+
+    #[rstest]
+    fn reports_interface_traffic() {
+        let sample = Sample {
+            timestamp: Utc::now(),
+            interval: Duration::from_secs(10),
+        };
+        let mut metrics = NetworkMetrics::new(None);
+        assert!(metrics
+            .collect(&net_dev(5000, 2000), &sample)
+            .unwrap()
+            .is_empty());
+
+        let readings: BTreeMap<_, _> = metrics
+            .collect(&net_dev(8000, 2500), &sample)
+            .unwrap()
+            .into_iter()
+            .map(|r| match r.value {
+                MetricReading::Counter { value, .. } => (r.name, value),
+                _ => panic!("Unexpected metric type"),
+            })
+            .collect();
+        assert_json_snapshot!(readings);
+    }
+
+    #[rstest]
+    fn only_reports_selected_interfaces() {
+        let sample = Sample {
+            timestamp: Utc::now(),
+            interval: Duration::from_secs(10),
+        };
+        let mut metrics = NetworkMetrics::new(Some(vec!["lo".to_string()]));
+        metrics.collect(&net_dev(5000, 2000), &sample).unwrap();
+        let readings = metrics.collect(&net_dev(8000, 2500), &sample).unwrap();
+
+        assert!(readings
+            .iter()
+            .all(|r| r.name.as_str().starts_with("interface/lo/")));
+        assert_eq!(readings.len(), COLUMNS.len());
+    }
+}
@@ -0,0 +1,451 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{read_dir, read_to_string},
+    path::Path,
+    time::Duration,
+};
+
+use eyre::{eyre, Result};
+use log::debug;
+
+use crate::{
+    metrics::KeyedMetricReading, service_manager::MemfaultdServiceManager,
+    util::system::get_clock_ticks_per_second,
+};
+
+use super::{CounterDeltas, Sample};
+
+/// Resources used by one process.
+#[derive(Debug, PartialEq, Eq)]
+struct ProcessSample {
+    rss_bytes: u64,
+    num_threads: u64,
+    /// Not available when memfaultd is not allowed to list the file descriptors of the process
+    num_fds: Option<u64>,
+    /// User and system time, in clock ticks
+    cpu_ticks: u64,
+}
+
+impl ProcessSample {
+    fn read(process_path: &Path) -> Result<Self> {
+        let (rss_bytes, num_threads) = parse_status(&read_to_string(process_path.join("status"))?)?;
+        let cpu_ticks = parse_stat_cpu_ticks(&read_to_string(process_path.join("stat"))?)?;
+        let num_fds = read_dir(process_path.join("fd"))
+            .map(|fds| fds.count() as u64)
+            .ok();
+        Ok(Self {
+            rss_bytes,
+            num_threads,
+            num_fds,
+            cpu_ticks,
+        })
+    }
+}
+
+/// A set of processes reported under one name.
+struct ProcessGroup {
+    /// Prefix of the metrics of the group
+    prefix: String,
+    pids: HashSet<u32>,
+    /// Number of restarts reported by the service manager
+    n_restarts: Option<u32>,
+}
+
+/// Reports the resources used by the configured processes and systemd units,
+/// and how many times they were restarted.
+///
+/// Processes are selected by name: when several processes have the same name,
+/// their usage is summed up. A restart is counted when one of them exits and a
+/// new one is started. Units report the usage of their main process, and the
+/// restarts counted by systemd (or changes of the main process with older versions).
+pub struct ProcessMetrics {
+    /// Names as found in /proc/<pid>/comm (truncated to 15 characters by the kernel)
+    names: Vec<String>,
+    units: Vec<String>,
+    service_manager: Box<dyn MemfaultdServiceManager + Send>,
+    clock_ticks_per_second: u64,
+    previous_cpu_ticks: HashMap<u32, u64>,
+    previous_pids: HashMap<String, HashSet<u32>>,
+    n_restarts: CounterDeltas,
+}
+
+impl ProcessMetrics {
+    pub fn new(
+        names: Vec<String>,
+        units: Vec<String>,
+        service_manager: Box<dyn MemfaultdServiceManager + Send>,
+    ) -> Self {
+        Self {
+            names,
+            units,
+            service_manager,
+            clock_ticks_per_second: get_clock_ticks_per_second(),
+            previous_cpu_ticks: HashMap::new(),
+            previous_pids: HashMap::new(),
+            n_restarts: CounterDeltas::default(),
+        }
+    }
+
+    /// Collects the metrics of the monitored processes. `elapsed` is the time
+    /// since the previous sample, which is needed to report CPU usage.
+    pub fn collect(
+        &mut self,
+        proc_path: &Path,
+        elapsed: Option<Duration>,
+        sample: &Sample,
+    ) -> Result<Vec<KeyedMetricReading>> {
+        let mut groups = self.find_processes(proc_path)?;
+        groups.extend(self.find_units());
+
+        let mut readings = vec![];
+        let mut cpu_ticks = HashMap::new();
+        for group in groups {
+            // Processes can exit at any time: ignore the ones which cannot be read.
+            let processes: BTreeMap<u32, ProcessSample> = group
+                .pids
+                .iter()
+                .filter_map(|pid| {
+                    ProcessSample::read(&proc_path.join(pid.to_string()))
+                        .ok()
+                        .map(|p| (*pid, p))
+                })
+                .collect();
+
+            if let Some(restarts) = self.count_restarts(&group) {
+                readings
+                    .push(sample.counter(format!("{}/restarts", group.prefix), restarts as f64)?);
+            }
+            self.previous_pids.insert(group.prefix.clone(), group.pids);
+
+            if processes.is_empty() {
+                continue;
+            }
+            let rss_bytes: u64 = processes.values().map(|p| p.rss_bytes).sum();
+            let num_threads: u64 = processes.values().map(|p| p.num_threads).sum();
+            let num_fds: Option<u64> = processes.values().map(|p| p.num_fds).sum();
+            // Processes which were not running at the previous sample used
+            // all their CPU time since then.
+            let cpu_ticks_delta: u64 = processes
+                .iter()
+                .map(|(pid, p)| {
+                    p.cpu_ticks
+                        .saturating_sub(*self.previous_cpu_ticks.get(pid).unwrap_or(&0))
+                })
+                .sum();
+            cpu_ticks.extend(processes.iter().map(|(pid, p)| (*pid, p.cpu_ticks)));
+
+            readings.push(sample.gauge(format!("{}/rss_bytes", group.prefix), rss_bytes as f64)?);
+            readings
+                .push(sample.gauge(format!("{}/num_threads", group.prefix), num_threads as f64)?);
+            if let Some(num_fds) = num_fds {
+                readings.push(sample.gauge(format!("{}/num_fds", group.prefix), num_fds as f64)?);
+            }
+            if let Some(elapsed) = elapsed.filter(|e| !e.is_zero()) {
+                let cpu_seconds = cpu_ticks_delta as f64 / self.clock_ticks_per_second as f64;
+                readings.push(sample.gauge(
+                    format!("{}/cpu_percent", group.prefix),
+                    cpu_seconds * 100.0 / elapsed.as_secs_f64(),
+                )?);
+            }
+        }
+        self.previous_cpu_ticks = cpu_ticks;
+        Ok(readings)
+    }
+
+    /// Finds the processes with the configured names.
+    fn find_processes(&self, proc_path: &Path) -> Result<Vec<ProcessGroup>> {
+        if self.names.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut groups: Vec<ProcessGroup> = self
+            .names
+            .iter()
+            .map(|name| ProcessGroup {
+                prefix: format!("processes/{}", name),
+                pids: HashSet::new(),
+                n_restarts: None,
+            })
+            .collect();
+        for entry in read_dir(proc_path)? {
+            let entry = entry?;
+            let pid = match entry.file_name().to_string_lossy().parse::<u32>() {
+                Ok(pid) => pid,
+                Err(_) => continue,
+            };
+            let comm = match read_to_string(entry.path().join("comm")) {
+                Ok(comm) => comm,
+                Err(_) => continue,
+            };
+            if let Some(i) = self
+                .names
+                .iter()
+                .position(|n| n.as_str() == comm.trim_end())
+            {
+                groups[i].pids.insert(pid);
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Finds the main process of the configured units.
+    fn find_units(&self) -> Vec<ProcessGroup> {
+        self.units
+            .iter()
+            .filter_map(|unit| match self.service_manager.service_unit_info(unit) {
+                Ok(info) => Some(ProcessGroup {
+                    prefix: format!("systemd/{}", unit),
+                    pids: info.main_pid.into_iter().collect(),
+                    n_restarts: info.n_restarts,
+                }),
+                Err(e) => {
+                    debug!("Unable to get info of unit {}: {}", unit, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Number of restarts since the previous sample, or None on the first sample.
+    fn count_restarts(&mut self, group: &ProcessGroup) -> Option<u64> {
+        match group.n_restarts {
+            Some(n_restarts) => self.n_restarts.delta(&group.prefix, n_restarts as u64),
+            None => {
+                let previous_pids = self.previous_pids.get(&group.prefix)?;
+                let exited = previous_pids.difference(&group.pids).count();
+                let started = group.pids.difference(previous_pids).count();
+                Some(exited.min(started) as u64)
+            }
+        }
+    }
+}
+
+/// Parses the resident set size (in bytes) and the number of threads from /proc/<pid>/status.
+fn parse_status(status: &str) -> Result<(u64, u64)> {
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .ok_or_else(|| eyre!("No {} in status", name))
+            .and_then(|value| Ok(value.parse::<u64>()?))
+    };
+    // Kernel threads have no memory: VmRSS is missing.
+    let rss_kib = field("VmRSS:").unwrap_or(0);
+    Ok((rss_kib * 1024, field("Threads:")?))
+}
+
+/// Parses the user and system time (in clock ticks) from /proc/<pid>/stat.
+fn parse_stat_cpu_ticks(stat: &str) -> Result<u64> {
+    // The process name is in parentheses and may contain spaces: skip it.
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .ok_or_else(|| eyre!("Invalid stat"))?
+        .1
+        .split_whitespace()
+        .collect();
+    // utime and stime are fields 14 and 15 of stat, the state (field 3) being the first after the name.
+    let utime: u64 = fields
+        .get(11)
+        .ok_or_else(|| eyre!("No utime in stat"))?
+        .parse()?;
+    let stime: u64 = fields
+        .get(12)
+        .ok_or_else(|| eyre!("No stime in stat"))?
+        .parse()?;
+    Ok(utime + stime)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        fs::{create_dir_all, remove_dir_all, write},
+    };
+
+    use chrono::Utc;
+    use insta::assert_json_snapshot;
+    use mockall::predicate::eq;
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    use crate::{
+        metrics::MetricReading,
+        service_manager::{MockMemfaultdServiceManager, ServiceUnitInfo},
+    };
+
+    use super::*;
+
+    fn write_process(proc_path: &Path, pid: u32, comm: &str, rss_kib: u64, cpu_ticks: u64) {
+        let process_path = proc_path.join(pid.to_string());
+        create_dir_all(process_path.join("fd")).unwrap();
+        for fd in 0..3 {
+            write(process_path.join("fd").join(fd.to_string()), "").unwrap();
+        }
+        write(process_path.join("comm"), format!("{}\n", comm)).unwrap();
+        write(
+            process_path.join("status"),
+            format!("Name:\t{}\nVmRSS:\t{} kB\nThreads:\t2\n", comm, rss_kib),
+        )
+        .unwrap();
+        write(
+            process_path.join("stat"),
+            format!(
+                "{} ({} x) S 1 1 1 0 -1 4194560 100 0 0 0 {} {} 0 0 20 0 2 0 100 1000 10",
+                pid,
+                comm,
+                cpu_ticks / 2,
+                cpu_ticks - cpu_ticks / 2
+            ),
+        )
+        .unwrap();
+    }
+
+    fn sample() -> Sample {
+        Sample {
+            timestamp: Utc::now(),
+            interval: Duration::from_secs(10),
+        }
+    }
+
+    fn values(readings: Vec<KeyedMetricReading>) -> BTreeMap<String, f64> {
+        readings
+            .into_iter()
+            .map(|r| match r.value {
+                MetricReading::Gauge { value, .. } | MetricReading::Counter { value, .. } => {
+                    (r.name.to_string(), value)
+                }
+                _ => panic!("Unexpected metric type"),
+            })
+            .collect()
+    }
+
+    #[rstest]
+    fn parses_status() {
+        assert_eq!(
+            parse_status("Name:\tfoo\nVmRSS:\t   1234 kB\nThreads:\t3\n").unwrap(),
+            (1234 * 1024, 3)
+        );
+        assert_eq!(
+            parse_status("Name:\tkthreadd\nThreads:\t1\n").unwrap(),
+            (0, 1)
+        );
+    }
+
+    #[rstest]
+    fn parses_stat_with_spaces_in_name() {
+        assert_eq!(
+            parse_stat_cpu_ticks(
+                "42 (my (weird) name) S 1 1 1 0 -1 4194560 100 0 0 0 30 12 0 0 20 0 1 0 100"
+            )
+            .unwrap(),
+            42
+        );
+    }
+
+    #[rstest]
+    fn reports_process_usage() {
+        let proc_path = tempdir().unwrap();
+        write_process(proc_path.path(), 10, "memfaultd", 1000, 100);
+        write_process(proc_path.path(), 20, "worker", 200, 50);
+        write_process(proc_path.path(), 21, "worker", 300, 50);
+        write_process(proc_path.path(), 30, "other", 5000, 500);
+
+        let mut metrics = ProcessMetrics::new(
+            vec!["memfaultd".into(), "worker".into()],
+            vec![],
+            Box::new(MockMemfaultdServiceManager::new()),
+        );
+        metrics.clock_ticks_per_second = 100;
+        metrics.collect(proc_path.path(), None, &sample()).unwrap();
+
+        // memfaultd used 1s of CPU and a new worker was started
+        write_process(proc_path.path(), 10, "memfaultd", 1000, 200);
+        write_process(proc_path.path(), 22, "worker", 300, 100);
+
+        let readings = metrics
+            .collect(proc_path.path(), Some(Duration::from_secs(10)), &sample())
+            .unwrap();
+        assert_json_snapshot!(values(readings));
+    }
+
+    #[rstest]
+    fn counts_process_restarts() {
+        let proc_path = tempdir().unwrap();
+        write_process(proc_path.path(), 10, "leaky", 1000, 100);
+
+        let mut metrics = ProcessMetrics::new(
+            vec!["leaky".into(), "crashed".into()],
+            vec![],
+            Box::new(MockMemfaultdServiceManager::new()),
+        );
+        write_process(proc_path.path(), 20, "crashed", 1000, 100);
+        metrics.collect(proc_path.path(), None, &sample()).unwrap();
+
+        // leaky was restarted, crashed exited and was not restarted
+        remove_dir_all(proc_path.path().join("10")).unwrap();
+        remove_dir_all(proc_path.path().join("20")).unwrap();
+        write_process(proc_path.path(), 11, "leaky", 100, 10);
+
+        let readings = metrics
+            .collect(proc_path.path(), Some(Duration::from_secs(10)), &sample())
+            .unwrap();
+        assert_json_snapshot!(values(readings));
+    }
+
+    #[rstest]
+    fn reports_unit_usage_and_restarts() {
+        let proc_path = tempdir().unwrap();
+        write_process(proc_path.path(), 10, "collectd", 1000, 100);
+        write_process(proc_path.path(), 20, "legacy", 1000, 100);
+
+        let mut service_manager = MockMemfaultdServiceManager::new();
+        let mut collectd_restarts = 2;
+        service_manager
+            .expect_service_unit_info()
+            .with(eq("collectd.service"))
+            .returning(move |_| {
+                collectd_restarts += 1;
+                Ok(ServiceUnitInfo {
+                    main_pid: Some(10),
+                    n_restarts: Some(collectd_restarts),
+                })
+            });
+        let mut legacy_pid = 20;
+        service_manager
+            .expect_service_unit_info()
+            .with(eq("legacy.service"))
+            .returning(move |_| {
+                legacy_pid += 1;
+                Ok(ServiceUnitInfo {
+                    main_pid: Some(legacy_pid - 1),
+                    n_restarts: None,
+                })
+            });
+        service_manager
+            .expect_service_unit_info()
+            .with(eq("missing.service"))
+            .returning(|_| Err(eyre!("No such unit")));
+
+        let mut metrics = ProcessMetrics::new(
+            vec![],
+            vec![
+                "collectd.service".into(),
+                "legacy.service".into(),
+                "missing.service".into(),
+            ],
+            Box::new(service_manager),
+        );
+        metrics.clock_ticks_per_second = 100;
+        metrics.collect(proc_path.path(), None, &sample()).unwrap();
+
+        write_process(proc_path.path(), 21, "legacy", 500, 50);
+        let readings = metrics
+            .collect(proc_path.path(), Some(Duration::from_secs(10)), &sample())
+            .unwrap();
+        assert_json_snapshot!(values(readings));
+    }
+}
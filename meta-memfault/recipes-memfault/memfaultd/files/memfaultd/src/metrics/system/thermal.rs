@@ -0,0 +1,88 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use std::{fs::read_dir, fs::read_to_string, path::Path};
+
+use eyre::Result;
+use log::debug;
+
+use crate::metrics::KeyedMetricReading;
+
+use super::Sample;
+
+/// Reports the temperature of each thermal zone in /sys/class/thermal, in degrees Celsius.
+pub fn collect(thermal_path: &Path, sample: &Sample) -> Result<Vec<KeyedMetricReading>> {
+    let mut readings = vec![];
+    for entry in read_dir(thermal_path)? {
+        let entry = entry?;
+        let zone = entry.file_name().to_string_lossy().to_string();
+        if !zone.starts_with("thermal_zone") {
+            continue;
+        }
+        // Disabled zones fail to report their temperature.
+        let millidegrees = match read_to_string(entry.path().join("temp"))
+            .map_err(eyre::Report::from)
+            .and_then(|t| Ok(t.trim().parse::<i64>()?))
+        {
+            Ok(millidegrees) => millidegrees,
+            Err(e) => {
+                debug!("Unable to read temperature of {}: {}", zone, e);
+                continue;
+            }
+        };
+        readings.push(sample.gauge(
+            format!("thermal/{}/temperature", zone),
+            millidegrees as f64 / 1000.0,
+        )?);
+    }
+    Ok(readings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        fs::{create_dir_all, write},
+        time::Duration,
+    };
+
+    use chrono::Utc;
+    use insta::assert_json_snapshot;
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    use crate::metrics::MetricReading;
+
+    use super::*;
+
+    #[rstest]
+    fn reports_zone_temperatures() {
+        let thermal = tempdir().unwrap();
+        for (zone, temp) in [
+            ("thermal_zone0", Some("45000\n")),
+            ("thermal_zone1", Some("-5500\n")),
+            ("thermal_zone2", None),
+            ("cooling_device0", Some("1\n")),
+        ] {
+            let zone_path = thermal.path().join(zone);
+            create_dir_all(&zone_path).unwrap();
+            if let Some(temp) = temp {
+                write(zone_path.join("temp"), temp).unwrap();
+            }
+        }
+
+        let sample = Sample {
+            timestamp: Utc::now(),
+            interval: Duration::from_secs(10),
+        };
+        let readings: BTreeMap<_, _> = collect(thermal.path(), &sample)
+            .unwrap()
+            .into_iter()
+            .map(|r| match r.value {
+                MetricReading::Gauge { value, .. } => (r.name, value),
+                _ => panic!("Unexpected metric type"),
+            })
+            .collect();
+        assert_json_snapshot!(readings);
+    }
+}
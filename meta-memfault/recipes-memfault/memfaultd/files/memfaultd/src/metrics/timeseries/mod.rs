@@ -3,9 +3,14 @@
 // See License.txt for details
 use chrono::{DateTime, Utc};
 use eyre::{eyre, Result};
-use std::cmp;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{cmp, collections::BTreeMap};
 
-use super::{MetricReading, MetricValue};
+use super::{MetricReading, MetricStringKey, MetricValue};
+
+mod quantile_sketch;
+use quantile_sketch::QuantileSketch;
 
 const FINITENESS_ERROR: &str = "Metric values must be finite.";
 
@@ -15,8 +20,108 @@ const FINITENESS_ERROR: &str = "Metric values must be finite.";
 pub trait TimeSeries {
     fn aggregate(&mut self, newer: &MetricReading) -> Result<()>;
     fn value(&self) -> MetricValue;
+
+    /// All the values to include in a report ending at `end` for this time
+    /// series. By default this is the single `value()` reported under the time
+    /// series' own key.
+    fn values(
+        &self,
+        key: &MetricStringKey,
+        _end: DateTime<Utc>,
+    ) -> Vec<(MetricStringKey, MetricValue)> {
+        vec![(key.clone(), self.value())]
+    }
+
+    /// Time series describing the current state of the device are carried over
+    /// to the next report. Returns the time series to start the next report
+    /// with, beginning at `start`.
+    fn carry_over(&self, _start: DateTime<Utc>) -> Option<Box<dyn TimeSeries + Send>> {
+        None
+    }
+
+    /// Serializable state of this time series at time `at`, so that it can be
+    /// written to disk and restored later.
+    fn checkpoint(&self, at: DateTime<Utc>) -> TimeSeriesCheckpoint;
+}
+
+/// Serializable state of a time series.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", content = "state", rename_all = "snake_case")]
+pub enum TimeSeriesCheckpoint {
+    Histogram(Histogram),
+    Counter(Counter),
+    TimeWeightedAverage(TimeWeightedAverage),
+    LatestString(LatestString),
+    State(StateTracker),
 }
 
+impl TimeSeriesCheckpoint {
+    /// Resumes aggregation from this checkpoint. Time spent in the current
+    /// state of state metrics is counted again from `resume_at`.
+    pub fn restore(
+        self,
+        resume_at: DateTime<Utc>,
+        histogram_statistics: Option<&Vec<HistogramStatistic>>,
+    ) -> Box<dyn TimeSeries + Send> {
+        match self {
+            TimeSeriesCheckpoint::Histogram(h) => match histogram_statistics {
+                Some(statistics) => Box::new(h.with_statistics(statistics)),
+                None => Box::new(h),
+            },
+            TimeSeriesCheckpoint::Counter(c) => Box::new(c),
+            TimeSeriesCheckpoint::TimeWeightedAverage(t) => Box::new(t),
+            TimeSeriesCheckpoint::LatestString(l) => Box::new(l),
+            TimeSeriesCheckpoint::State(s) => Box::new(StateTracker {
+                since: resume_at,
+                ..s
+            }),
+        }
+    }
+}
+
+/// A statistic that can be reported for a histogram. Each statistic is
+/// reported as its own metric, named `<metric>.<statistic>`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HistogramStatistic {
+    Mean,
+    Min,
+    Max,
+    Sum,
+    Count,
+    P50,
+    P90,
+    P95,
+    P99,
+}
+
+impl HistogramStatistic {
+    fn suffix(&self) -> &'static str {
+        match self {
+            HistogramStatistic::Mean => "mean",
+            HistogramStatistic::Min => "min",
+            HistogramStatistic::Max => "max",
+            HistogramStatistic::Sum => "sum",
+            HistogramStatistic::Count => "count",
+            HistogramStatistic::P50 => "p50",
+            HistogramStatistic::P90 => "p90",
+            HistogramStatistic::P95 => "p95",
+            HistogramStatistic::P99 => "p99",
+        }
+    }
+
+    fn quantile(&self) -> Option<f64> {
+        match self {
+            HistogramStatistic::P50 => Some(0.50),
+            HistogramStatistic::P90 => Some(0.90),
+            HistogramStatistic::P95 => Some(0.95),
+            HistogramStatistic::P99 => Some(0.99),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Histogram {
     sum: f64,
     count: u64,
@@ -24,6 +129,11 @@ pub struct Histogram {
     end: DateTime<Utc>,
     min: f64,
     max: f64,
+    sketch: QuantileSketch,
+    /// Statistics to report. When empty, only the mean is reported under the
+    /// metric's own key. This comes from the configuration and is not checkpointed.
+    #[serde(skip)]
+    statistics: Vec<HistogramStatistic>,
 }
 
 impl Histogram {
@@ -37,6 +147,8 @@ impl Histogram {
                 if !value.is_finite() {
                     return Err(eyre!(FINITENESS_ERROR));
                 }
+                let mut sketch = QuantileSketch::new();
+                sketch.add(value);
                 Ok(Self {
                     sum: value,
                     count: 1,
@@ -44,11 +156,40 @@ impl Histogram {
                     end: timestamp,
                     min: value,
                     max: value,
+                    sketch,
+                    statistics: vec![],
                 })
             }
             _ => Err(eyre!("Cannot create a histogram from a non-gauge metric")),
         }
     }
+
+    /// Report the given statistics instead of only the mean.
+    pub fn with_statistics(mut self, statistics: &[HistogramStatistic]) -> Self {
+        self.statistics = statistics.to_vec();
+        self
+    }
+
+    fn statistic(&self, statistic: HistogramStatistic) -> MetricValue {
+        if self.count == 0 {
+            return MetricValue::Number(f64::NAN);
+        }
+        match statistic {
+            HistogramStatistic::Mean => self.value(),
+            HistogramStatistic::Min => MetricValue::Number(self.min),
+            HistogramStatistic::Max => MetricValue::Number(self.max),
+            HistogramStatistic::Sum => MetricValue::Number(self.sum),
+            HistogramStatistic::Count => MetricValue::Number(self.count as f64),
+            _ => {
+                let quantile = statistic
+                    .quantile()
+                    .and_then(|q| self.sketch.quantile(q))
+                    .unwrap_or(f64::NAN);
+                // The sketch is approximate. Never report a value outside of what was seen.
+                MetricValue::Number(quantile.clamp(self.min, self.max))
+            }
+        }
+    }
 }
 
 impl TimeSeries for Histogram {
@@ -68,6 +209,7 @@ impl TimeSeries for Histogram {
                 self.end = cmp::max(self.end, *timestamp);
                 self.min = f64::min(self.min, *value);
                 self.max = f64::max(self.max, *value);
+                self.sketch.add(*value);
                 Ok(())
             }
             _ => Err(eyre!(
@@ -83,10 +225,35 @@ impl TimeSeries for Histogram {
             MetricValue::Number(f64::NAN)
         }
     }
+
+    fn values(
+        &self,
+        key: &MetricStringKey,
+        _end: DateTime<Utc>,
+    ) -> Vec<(MetricStringKey, MetricValue)> {
+        if self.statistics.is_empty() {
+            return vec![(key.clone(), self.value())];
+        }
+        self.statistics
+            .iter()
+            .filter_map(|statistic| match key.with_suffix(statistic.suffix()) {
+                Ok(statistic_key) => Some((statistic_key, self.statistic(*statistic))),
+                Err(e) => {
+                    warn!("Unable to report {} of {}: {}", statistic.suffix(), key, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn checkpoint(&self, _at: DateTime<Utc>) -> TimeSeriesCheckpoint {
+        TimeSeriesCheckpoint::Histogram(self.clone())
+    }
 }
 
 /// An aggregation that calculates the sum of all values received. This assumes that all readings will be positive numbers.
 /// Monotonic counter in OpenTelemetry data model.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Counter {
     sum: f64,
     end: DateTime<Utc>,
@@ -127,9 +294,14 @@ impl TimeSeries for Counter {
     fn value(&self) -> MetricValue {
         MetricValue::Number(self.sum)
     }
+
+    fn checkpoint(&self, _at: DateTime<Utc>) -> TimeSeriesCheckpoint {
+        TimeSeriesCheckpoint::Counter(self.clone())
+    }
 }
 
 /// A time-weighted sum of all values received. This is useful to maintain an accurate average measurement when the interval between readings is not constant.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct TimeWeightedAverage {
     weighted_sum: f64,
     duration: u64,
@@ -193,6 +365,147 @@ impl TimeSeries for TimeWeightedAverage {
             MetricValue::Number(f64::NAN)
         }
     }
+
+    fn checkpoint(&self, _at: DateTime<Utc>) -> TimeSeriesCheckpoint {
+        TimeSeriesCheckpoint::TimeWeightedAverage(self.clone())
+    }
+}
+
+/// Keeps the latest string value received.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct LatestString {
+    value: String,
+    end: DateTime<Utc>,
+}
+
+impl LatestString {
+    pub fn new(reading: &MetricReading) -> Result<Self> {
+        match reading {
+            MetricReading::String { value, timestamp } => Ok(Self {
+                value: value.clone(),
+                end: *timestamp,
+            }),
+            _ => Err(eyre!("Cannot create a string from a non-string metric")),
+        }
+    }
+}
+
+impl TimeSeries for LatestString {
+    fn aggregate(&mut self, newer: &MetricReading) -> Result<()> {
+        match newer {
+            MetricReading::String { value, timestamp } => {
+                if *timestamp >= self.end {
+                    self.value = value.clone();
+                    self.end = *timestamp;
+                }
+                Ok(())
+            }
+            _ => Err(eyre!("Cannot aggregate a string with a non-string metric")),
+        }
+    }
+
+    fn value(&self) -> MetricValue {
+        MetricValue::String(self.value.clone())
+    }
+
+    fn carry_over(&self, start: DateTime<Utc>) -> Option<Box<dyn TimeSeries + Send>> {
+        Some(Box::new(Self {
+            value: self.value.clone(),
+            end: start,
+        }))
+    }
+
+    fn checkpoint(&self, _at: DateTime<Utc>) -> TimeSeriesCheckpoint {
+        TimeSeriesCheckpoint::LatestString(self.clone())
+    }
+}
+
+/// Tracks the time spent in each state.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct StateTracker {
+    /// Milliseconds spent in each state before the current one
+    durations: BTreeMap<String, i64>,
+    current: String,
+    since: DateTime<Utc>,
+}
+
+impl StateTracker {
+    pub fn new(reading: &MetricReading) -> Result<Self> {
+        match reading {
+            MetricReading::State { value, timestamp } => Ok(Self {
+                durations: BTreeMap::new(),
+                current: value.clone(),
+                since: *timestamp,
+            }),
+            _ => Err(eyre!("Cannot create a state from a non-state metric")),
+        }
+    }
+
+    /// Milliseconds spent in each state, counting the current state until `end`.
+    fn durations_until(&self, end: DateTime<Utc>) -> BTreeMap<String, i64> {
+        let mut durations = self.durations.clone();
+        *durations.entry(self.current.clone()).or_insert(0) +=
+            cmp::max(end - self.since, chrono::Duration::zero()).num_milliseconds();
+        durations
+    }
+}
+
+impl TimeSeries for StateTracker {
+    fn aggregate(&mut self, newer: &MetricReading) -> Result<()> {
+        match newer {
+            MetricReading::State { value, timestamp } => {
+                // Out-of-order readings are ignored, like for LatestString: the state they
+                // report was already superseded.
+                if *timestamp >= self.since && *value != self.current {
+                    *self.durations.entry(self.current.clone()).or_insert(0) +=
+                        (*timestamp - self.since).num_milliseconds();
+                    self.current = value.clone();
+                    self.since = *timestamp;
+                }
+                Ok(())
+            }
+            _ => Err(eyre!("Cannot aggregate a state with a non-state metric")),
+        }
+    }
+
+    fn value(&self) -> MetricValue {
+        MetricValue::String(self.current.clone())
+    }
+
+    fn values(
+        &self,
+        key: &MetricStringKey,
+        end: DateTime<Utc>,
+    ) -> Vec<(MetricStringKey, MetricValue)> {
+        self.durations_until(end)
+            .into_iter()
+            .filter_map(
+                |(state, ms)| match key.with_suffix(&format!("{}_ms", state)) {
+                    Ok(state_key) => Some((state_key, MetricValue::Number(ms as f64))),
+                    Err(e) => {
+                        warn!("Unable to report time in state {} of {}: {}", state, key, e);
+                        None
+                    }
+                },
+            )
+            .collect()
+    }
+
+    fn carry_over(&self, start: DateTime<Utc>) -> Option<Box<dyn TimeSeries + Send>> {
+        Some(Box::new(Self {
+            durations: BTreeMap::new(),
+            current: self.current.clone(),
+            since: cmp::max(start, self.since),
+        }))
+    }
+
+    fn checkpoint(&self, at: DateTime<Utc>) -> TimeSeriesCheckpoint {
+        TimeSeriesCheckpoint::State(Self {
+            durations: self.durations_until(at),
+            current: self.current.clone(),
+            since: at,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -200,11 +513,14 @@ mod tests {
     use chrono::Duration;
     use rstest::rstest;
 
-    use crate::metrics::{MetricReading, MetricTimestamp, MetricValue};
+    use crate::metrics::{MetricReading, MetricStringKey, MetricTimestamp, MetricValue};
     use std::{f64::INFINITY, f64::NAN, f64::NEG_INFINITY, str::FromStr};
 
     use super::TimeSeries;
-    use super::{Counter, Histogram, TimeWeightedAverage};
+    use super::{
+        Counter, Histogram, HistogramStatistic, LatestString, StateTracker, TimeSeriesCheckpoint,
+        TimeWeightedAverage,
+    };
 
     #[rstest]
     #[case(1.0, 1000, 2.0, 1000, 1.5, 2000)]
@@ -242,6 +558,61 @@ mod tests {
         assert_eq!(h.value(), MetricValue::Number(expected));
     }
 
+    #[rstest]
+    fn test_histogram_statistics() {
+        let t0 = MetricTimestamp::from_str("2021-01-01T00:00:00Z").unwrap();
+        let reading = |i: i64| MetricReading::Gauge {
+            value: i as f64,
+            interval: Duration::milliseconds(1000),
+            timestamp: t0 + Duration::milliseconds(1000 * i),
+        };
+
+        let mut h = Histogram::new(&reading(1)).unwrap().with_statistics(&[
+            HistogramStatistic::Mean,
+            HistogramStatistic::Min,
+            HistogramStatistic::Max,
+            HistogramStatistic::Count,
+            HistogramStatistic::P50,
+            HistogramStatistic::P99,
+        ]);
+        for i in 2..=100 {
+            h.aggregate(&reading(i)).unwrap();
+        }
+
+        let values: Vec<(String, f64)> = h
+            .values(&MetricStringKey::from_str("latency").unwrap(), h.end)
+            .into_iter()
+            .map(|(key, value)| match value {
+                MetricValue::Number(n) => (key.to_string(), n.round()),
+                _ => panic!("Unexpected metric value"),
+            })
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("latency.mean".to_string(), 51.0),
+                ("latency.min".to_string(), 1.0),
+                ("latency.max".to_string(), 100.0),
+                ("latency.count".to_string(), 100.0),
+                ("latency.p50".to_string(), 50.0),
+                ("latency.p99".to_string(), 99.0),
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_histogram_without_statistics_reports_mean() {
+        let reading = MetricReading::Gauge {
+            value: 4.0,
+            interval: Duration::milliseconds(1000),
+            timestamp: MetricTimestamp::from_str("2021-01-01T00:00:00Z").unwrap(),
+        };
+        let key = MetricStringKey::from_str("temperature").unwrap();
+
+        let h = Histogram::new(&reading).unwrap();
+        assert_eq!(h.values(&key, h.end), vec![(key, MetricValue::Number(4.0))]);
+    }
+
     #[rstest]
     #[case(1.0, 1000, 2.0, 1000, 1.5, 2000)]
     #[case(10.0, 10000, 10.0, 1000, 10.0, 11000)]
@@ -349,4 +720,156 @@ mod tests {
         assert_eq!(sum.end, timestamp2);
         assert_eq!(sum.sum, expected);
     }
+
+    #[rstest]
+    fn test_latest_string() {
+        let t0 = MetricTimestamp::from_str("2021-01-01T00:00:00Z").unwrap();
+        let reading = |value: &str, seconds: i64| MetricReading::String {
+            value: value.to_string(),
+            timestamp: t0 + Duration::seconds(seconds),
+        };
+
+        let mut s = LatestString::new(&reading("slot_a", 0)).unwrap();
+        s.aggregate(&reading("slot_b", 2)).unwrap();
+        // Older values are ignored
+        s.aggregate(&reading("slot_c", 1)).unwrap();
+        assert_eq!(s.value(), MetricValue::String("slot_b".to_string()));
+
+        let carried_over = s.carry_over(t0 + Duration::seconds(10)).unwrap();
+        assert_eq!(
+            carried_over.value(),
+            MetricValue::String("slot_b".to_string())
+        );
+    }
+
+    #[rstest]
+    fn test_state_durations() {
+        let t0 = MetricTimestamp::from_str("2021-01-01T00:00:00Z").unwrap();
+        let reading = |value: &str, seconds: i64| MetricReading::State {
+            value: value.to_string(),
+            timestamp: t0 + Duration::seconds(seconds),
+        };
+        let key = MetricStringKey::from_str("wifi_state").unwrap();
+
+        let mut s = StateTracker::new(&reading("scanning", 0)).unwrap();
+        s.aggregate(&reading("connected", 5)).unwrap();
+        s.aggregate(&reading("connected", 8)).unwrap();
+        s.aggregate(&reading("scanning", 30)).unwrap();
+        s.aggregate(&reading("connected", 40)).unwrap();
+
+        let end = t0 + Duration::seconds(60);
+        assert_eq!(
+            s.values(&key, end),
+            vec![
+                (
+                    MetricStringKey::from_str("wifi_state.connected_ms").unwrap(),
+                    MetricValue::Number(45_000.0)
+                ),
+                (
+                    MetricStringKey::from_str("wifi_state.scanning_ms").unwrap(),
+                    MetricValue::Number(15_000.0)
+                ),
+            ]
+        );
+
+        // The next report starts in the current state
+        let carried_over = s.carry_over(end).unwrap();
+        assert_eq!(
+            carried_over.values(&key, end + Duration::seconds(10)),
+            vec![(
+                MetricStringKey::from_str("wifi_state.connected_ms").unwrap(),
+                MetricValue::Number(10_000.0)
+            )]
+        );
+    }
+
+    #[rstest]
+    fn test_state_checkpoint() {
+        let t0 = MetricTimestamp::from_str("2021-01-01T00:00:00Z").unwrap();
+        let reading = |value: &str, seconds: i64| MetricReading::State {
+            value: value.to_string(),
+            timestamp: t0 + Duration::seconds(seconds),
+        };
+        let key = MetricStringKey::from_str("wifi_state").unwrap();
+
+        let mut s = StateTracker::new(&reading("scanning", 0)).unwrap();
+        s.aggregate(&reading("connected", 5)).unwrap();
+
+        let checkpoint = s.checkpoint(t0 + Duration::seconds(10));
+        let serialized = serde_json::to_string(&checkpoint).unwrap();
+        let checkpoint: TimeSeriesCheckpoint = serde_json::from_str(&serialized).unwrap();
+
+        // Time between the checkpoint and the restoration is not counted
+        let restored = checkpoint.restore(t0 + Duration::seconds(100), None);
+        assert_eq!(
+            restored.values(&key, t0 + Duration::seconds(110)),
+            vec![
+                (
+                    MetricStringKey::from_str("wifi_state.connected_ms").unwrap(),
+                    MetricValue::Number(15_000.0)
+                ),
+                (
+                    MetricStringKey::from_str("wifi_state.scanning_ms").unwrap(),
+                    MetricValue::Number(5_000.0)
+                ),
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_histogram_checkpoint() {
+        let t0 = MetricTimestamp::from_str("2021-01-01T00:00:00Z").unwrap();
+        let reading = |value: f64, seconds: i64| MetricReading::Gauge {
+            value,
+            interval: Duration::seconds(1),
+            timestamp: t0 + Duration::seconds(seconds),
+        };
+        let key = MetricStringKey::from_str("latency").unwrap();
+
+        let mut h = Histogram::new(&reading(1.0, 0)).unwrap();
+        h.aggregate(&reading(3.0, 1)).unwrap();
+
+        let serialized = serde_json::to_string(&h.checkpoint(t0)).unwrap();
+        let checkpoint: TimeSeriesCheckpoint = serde_json::from_str(&serialized).unwrap();
+        let mut restored = checkpoint.restore(t0, Some(&vec![HistogramStatistic::Max]));
+        restored.aggregate(&reading(5.0, 2)).unwrap();
+
+        assert_eq!(
+            restored.values(&key, t0),
+            vec![(
+                MetricStringKey::from_str("latency.max").unwrap(),
+                MetricValue::Number(5.0)
+            )]
+        );
+        assert_eq!(restored.value(), MetricValue::Number(3.0));
+    }
+
+    #[rstest]
+    fn test_state_ignores_older_timestamp() {
+        let t0 = MetricTimestamp::from_str("2021-01-01T00:00:00Z").unwrap();
+        let reading = |value: &str, seconds: i64| MetricReading::State {
+            value: value.to_string(),
+            timestamp: t0 + Duration::seconds(seconds),
+        };
+        let key = MetricStringKey::from_str("wifi_state").unwrap();
+
+        let mut s = StateTracker::new(&reading("scanning", 0)).unwrap();
+        s.aggregate(&reading("connected", 10)).unwrap();
+        s.aggregate(&reading("scanning", 5)).unwrap();
+
+        assert_eq!(s.value(), MetricValue::String("connected".to_string()));
+        assert_eq!(
+            s.values(&key, t0 + Duration::seconds(20)),
+            vec![
+                (
+                    MetricStringKey::from_str("wifi_state.connected_ms").unwrap(),
+                    MetricValue::Number(10_000.0)
+                ),
+                (
+                    MetricStringKey::from_str("wifi_state.scanning_ms").unwrap(),
+                    MetricValue::Number(10_000.0)
+                ),
+            ]
+        );
+    }
 }
@@ -0,0 +1,186 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Quantiles returned by the sketch are within this relative error of the exact value.
+const RELATIVE_ACCURACY: f64 = 0.01;
+/// Upper bound on the number of buckets kept by a sketch. When exceeded, the
+/// buckets closest to zero are merged together.
+const MAX_BUCKETS: usize = 2048;
+/// Values smaller than this (in absolute value) are counted as zero.
+const MIN_INDEXABLE_VALUE: f64 = 1e-9;
+
+/// A quantile sketch with relative-error guarantees, after DDSketch:
+/// https://arxiv.org/abs/1908.10693
+///
+/// Values are counted in logarithmically sized buckets so that memory usage
+/// only depends on the range of values received, not on the number of readings.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct QuantileSketch {
+    ln_gamma: f64,
+    /// Buckets for positive values, keyed by logarithmic index
+    positive: BTreeMap<i32, u64>,
+    /// Buckets for negative values, keyed by logarithmic index of the absolute value
+    negative: BTreeMap<i32, u64>,
+    zero_count: u64,
+    count: u64,
+}
+
+impl QuantileSketch {
+    pub fn new() -> Self {
+        let gamma = (1.0 + RELATIVE_ACCURACY) / (1.0 - RELATIVE_ACCURACY);
+        Self {
+            ln_gamma: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zero_count: 0,
+            count: 0,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if value.abs() < MIN_INDEXABLE_VALUE {
+            self.zero_count += 1;
+        } else {
+            let index = self.index(value.abs());
+            let store = if value > 0.0 {
+                &mut self.positive
+            } else {
+                &mut self.negative
+            };
+            *store.entry(index).or_insert(0) += 1;
+        }
+        self.count += 1;
+        self.collapse_if_needed();
+    }
+
+    /// Returns the approximate value at quantile `q` (between 0 and 1), or
+    /// None if the sketch is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = q.clamp(0.0, 1.0) * (self.count - 1) as f64;
+
+        // Walk the buckets in ascending order of value: most negative first.
+        let mut seen = 0;
+        for (index, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen as f64 > rank {
+                return Some(-self.value(*index));
+            }
+        }
+        seen += self.zero_count;
+        if seen as f64 > rank {
+            return Some(0.0);
+        }
+        for (index, count) in self.positive.iter() {
+            seen += count;
+            if seen as f64 > rank {
+                return Some(self.value(*index));
+            }
+        }
+        // Only reachable through rounding errors on the rank.
+        self.positive
+            .keys()
+            .next_back()
+            .map(|index| self.value(*index))
+    }
+
+    fn index(&self, value: f64) -> i32 {
+        (value.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    /// Representative value of a bucket, chosen so that the relative error is
+    /// the same for the lower and upper bounds of the bucket.
+    fn value(&self, index: i32) -> f64 {
+        let gamma = self.ln_gamma.exp();
+        2.0 * (self.ln_gamma * index as f64).exp() / (gamma + 1.0)
+    }
+
+    fn collapse_if_needed(&mut self) {
+        while self.positive.len() + self.negative.len() > MAX_BUCKETS {
+            let store = if self.positive.len() >= self.negative.len() {
+                &mut self.positive
+            } else {
+                &mut self.negative
+            };
+            // Merge the bucket closest to zero into its neighbour.
+            if let Some(first) = store.keys().next().copied() {
+                let count = store.remove(&first).unwrap_or(0);
+                if let Some((_, next)) = store.iter_mut().next() {
+                    *next += count;
+                }
+            }
+        }
+    }
+}
+
+impl Default for QuantileSketch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(0.0, 1.0)]
+    #[case(0.5, 50.0)]
+    #[case(0.9, 90.0)]
+    #[case(0.99, 99.0)]
+    #[case(1.0, 100.0)]
+    fn test_quantiles(#[case] q: f64, #[case] expected: f64) {
+        let mut sketch = QuantileSketch::new();
+        for v in 1..=100 {
+            sketch.add(v as f64);
+        }
+
+        let value = sketch.quantile(q).unwrap();
+        assert!(
+            (value - expected).abs() <= expected * RELATIVE_ACCURACY,
+            "q{} = {} (expected {})",
+            q,
+            value,
+            expected
+        );
+    }
+
+    #[rstest]
+    fn test_negative_and_zero_values() {
+        let mut sketch = QuantileSketch::new();
+        for v in [-10.0, -5.0, 0.0, 5.0, 10.0] {
+            sketch.add(v);
+        }
+
+        assert!((sketch.quantile(0.0).unwrap() + 10.0).abs() <= 10.0 * RELATIVE_ACCURACY);
+        assert_eq!(sketch.quantile(0.5), Some(0.0));
+        assert!((sketch.quantile(1.0).unwrap() - 10.0).abs() <= 10.0 * RELATIVE_ACCURACY);
+    }
+
+    #[rstest]
+    fn test_empty_sketch() {
+        assert_eq!(QuantileSketch::new().quantile(0.5), None);
+    }
+
+    #[rstest]
+    fn test_bucket_count_is_bounded() {
+        let mut sketch = QuantileSketch::new();
+        for exponent in -300..300 {
+            for step in 1..10 {
+                sketch.add(step as f64 * 10f64.powi(exponent));
+            }
+        }
+
+        assert!(sketch.positive.len() <= MAX_BUCKETS);
+        // The largest values are not affected by collapsing.
+        let max = 9.0 * 10f64.powi(299);
+        assert!((sketch.quantile(1.0).unwrap() - max).abs() <= max * RELATIVE_ACCURACY);
+    }
+}
@@ -17,12 +17,14 @@ use crate::retriable_error::RetriableError;
 use crate::util::io::StreamLen;
 use crate::util::string::Ellipsis;
 
+use super::client_builder::configure_client;
 use super::requests::DeviceConfigResponse;
 use super::requests::UploadPrepareRequest;
 use super::requests::UploadPrepareResponse;
 use super::requests::{DeviceConfigRequest, MarUploadMetadata};
 use super::NetworkClient;
 use super::NetworkConfig;
+use super::ResumableUpload;
 
 /// Memfault Network client
 pub struct NetworkClientImpl {
@@ -61,18 +63,30 @@ impl NetworkClientImpl {
         .into_iter()
         .collect();
 
-        let client = blocking::ClientBuilder::new()
-            .default_headers(headers)
-            .build()?;
+        let connection = &config.connection;
+        let client = configure_client(
+            blocking::ClientBuilder::new().default_headers(headers),
+            connection,
+            connection.pinned_certificates.api.as_deref(),
+        )?
+        .build()?;
+        let file_upload_client = configure_client(
+            blocking::ClientBuilder::new(),
+            connection,
+            connection.pinned_certificates.file_upload.as_deref(),
+        )?
+        .build()?;
 
         Ok(NetworkClientImpl {
             client,
-            file_upload_client: blocking::Client::new(),
+            file_upload_client,
             config,
         })
     }
 
-    fn good_response_or_error(response: blocking::Response) -> Result<blocking::Response> {
+    pub(super) fn good_response_or_error(
+        response: blocking::Response,
+    ) -> Result<blocking::Response> {
         // Map status code to an error
         let status = response.status();
         match status.as_u16() {
@@ -136,29 +150,83 @@ impl NetworkClientImpl {
         file: BodyAdapter<R>,
         gzipped: bool,
     ) -> Result<String> {
-        let prepare_request =
-            UploadPrepareRequest::prepare(&self.config, file.size as usize, gzipped);
+        let prepare_response = self.prepare_upload(UploadPrepareRequest::prepare(
+            &self.config,
+            file.size as usize,
+            gzipped,
+        ))?;
+
+        self.put_file(
+            &prepare_response.data.upload_url,
+            file,
+            if gzipped { Some("gzip") } else { None },
+        )
+        .wrap_err("Storage upload error")?;
+        debug!("Successfully transmitted file");
 
+        Ok(prepare_response.data.token)
+    }
+
+    fn prepare_upload(&self, request: UploadPrepareRequest) -> Result<UploadPrepareResponse> {
         let prepare_response = self
             .fetch(
                 Method::POST,
                 "/api/v0/upload",
-                &serde_json::to_string(&prepare_request)?,
+                &serde_json::to_string(&request)?,
             )?
             .json::<UploadPrepareResponse>()
             .wrap_err("Prepare upload error")?;
 
         trace!("Upload prepare response: {:?}", prepare_response);
+        Ok(prepare_response)
+    }
 
-        self.put_file(
-            &prepare_response.data.upload_url,
-            file,
-            if gzipped { Some("gzip") } else { None },
+    fn commit_mar_upload(&self, token: &str) -> Result<()> {
+        let mar_upload = MarUploadMetadata::prepare(&self.config, token);
+        self.fetch(
+            Method::POST,
+            "/api/v0/upload/mar",
+            &serde_json::to_string(&mar_upload)?,
         )
-        .wrap_err("Storage upload error")?;
-        debug!("Successfully transmitted file");
+        .wrap_err("MAR Upload Error")
+        .and(Ok(()))
+    }
 
-        Ok(prepare_response.data.token)
+    /// Send a range of a resumable upload (or only query its progress when `range` is None).
+    /// Returns the number of bytes received by the server.
+    fn put_range(&self, upload: &ResumableUpload, range: Option<(u64, Vec<u8>)>) -> Result<u64> {
+        let mut req = self.file_upload_client.put(&upload.upload_url);
+        if upload.gzipped {
+            req = req.header(header::CONTENT_ENCODING, "gzip");
+        }
+        let req = match range {
+            Some((offset, chunk)) => req
+                .header(
+                    header::CONTENT_RANGE,
+                    format!(
+                        "bytes {}-{}/{}",
+                        offset,
+                        offset + chunk.len() as u64 - 1,
+                        upload.size
+                    ),
+                )
+                .body(chunk),
+            None => req
+                .header(header::CONTENT_RANGE, format!("bytes */{}", upload.size))
+                .body(Vec::new()),
+        };
+        let response = req
+            .send()
+            .map_err(|e| RetriableError::NetworkError { source: e })?;
+
+        // 308 (Resume Incomplete) tells which range was received so far.
+        if response.status().as_u16() == 308 {
+            return match response.headers().get(header::RANGE) {
+                Some(range) => received_bytes_from_range(range.to_str()?),
+                None => Ok(0),
+            };
+        }
+        Self::good_response_or_error(response).map(|_| upload.size)
     }
 
     fn put_file<R: Read + Send + 'static>(
@@ -182,17 +250,50 @@ impl NetworkClientImpl {
 }
 
 impl NetworkClient for NetworkClientImpl {
-    fn upload_mar_file<F: Read + StreamLen + Send + 'static>(&self, file: F) -> Result<()> {
-        let token = self.prepare_and_upload(file.into(), false)?;
+    fn upload_mar_file<F: Read + StreamLen + Send + 'static>(
+        &self,
+        file: F,
+        gzipped: bool,
+    ) -> Result<()> {
+        let token = self.prepare_and_upload(file.into(), gzipped)?;
+        self.commit_mar_upload(&token)
+    }
 
-        let mar_upload = MarUploadMetadata::prepare(&self.config, &token);
-        self.fetch(
-            Method::POST,
-            "/api/v0/upload/mar",
-            &serde_json::to_string(&mar_upload)?,
-        )
-        .wrap_err("MAR Upload Error")
-        .and(Ok(()))
+    fn prepare_resumable_mar_upload(&self, size: u64, gzipped: bool) -> Result<ResumableUpload> {
+        let prepare_response = self.prepare_upload(
+            UploadPrepareRequest::prepare(&self.config, size as usize, gzipped).resumable(),
+        )?;
+        Ok(ResumableUpload {
+            upload_url: prepare_response.data.upload_url,
+            token: prepare_response.data.token,
+            size,
+            gzipped,
+        })
+    }
+
+    fn upload_mar_chunk(
+        &self,
+        upload: &ResumableUpload,
+        offset: u64,
+        chunk: Vec<u8>,
+    ) -> Result<u64> {
+        trace!(
+            "Uploading {} bytes at offset {} to {}",
+            chunk.len(),
+            offset,
+            upload.upload_url
+        );
+        self.put_range(upload, Some((offset, chunk)))
+            .wrap_err("Storage upload error")
+    }
+
+    fn resumable_upload_offset(&self, upload: &ResumableUpload) -> Result<u64> {
+        self.put_range(upload, None)
+            .wrap_err("Storage upload status error")
+    }
+
+    fn commit_resumable_mar_upload(&self, upload: &ResumableUpload) -> Result<()> {
+        self.commit_mar_upload(&upload.token)
     }
 
     fn fetch_device_config(&self) -> Result<super::requests::DeviceConfigResponse> {
@@ -207,6 +308,18 @@ impl NetworkClient for NetworkClientImpl {
     }
 }
 
+/// Parses the `Range` header of a 308 response (`bytes=0-<last byte received>`)
+/// into the number of bytes received.
+fn received_bytes_from_range(range: &str) -> Result<u64> {
+    let last_byte = range
+        .strip_prefix("bytes=0-")
+        .ok_or_else(|| eyre!("Unexpected range {}", range))?;
+    Ok(last_byte
+        .parse::<u64>()
+        .wrap_err_with(|| format!("Unexpected range {}", range))?
+        + 1)
+}
+
 /// Small helper to adapt a Read/File into a Body.
 /// Note it's not possible to directly write: impl<T: Read + ...> From<T> for Body { ... }
 /// because of orphan rules. See https://doc.rust-lang.org/error_codes/E0210.html
@@ -236,3 +349,122 @@ impl<T: Read + Send + 'static> From<BodyAdapter<T>> for Body {
         Body::sized(wrapper.reader, wrapper.size)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rstest::rstest;
+    use tiny_http::{Header, Response, Server};
+
+    use super::*;
+
+    #[rstest]
+    #[case("bytes=0-0", 1)]
+    #[case("bytes=0-1023", 1024)]
+    fn parses_received_range(#[case] range: &str, #[case] expected: u64) {
+        assert_eq!(received_bytes_from_range(range).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case("bytes=10-20")]
+    #[case("bytes=0-")]
+    fn rejects_unexpected_range(#[case] range: &str) {
+        assert!(received_bytes_from_range(range).is_err());
+    }
+
+    #[rstest]
+    fn uploads_mar_in_chunks() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let client = NetworkClientImpl::new(NetworkConfig {
+            base_url: base_url.clone(),
+            ..NetworkConfig::test_fixture()
+        })
+        .unwrap();
+
+        // Mock backend which only keeps the first 2 bytes of the first chunk.
+        let backend = thread::spawn(move || {
+            let mut requests = vec![];
+            let mut received = vec![];
+            for mut request in server.incoming_requests().take(5) {
+                let mut body = vec![];
+                request.as_reader().read_to_end(&mut body).unwrap();
+                let content_range = request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("Content-Range"))
+                    .map(|h| h.value.to_string())
+                    .unwrap_or_default();
+                requests.push(format!(
+                    "{} {} {}",
+                    request.method(),
+                    request.url(),
+                    content_range
+                ));
+
+                let response = match request.url() {
+                    "/api/v0/upload" => {
+                        assert!(str::from_utf8(&body)
+                            .unwrap()
+                            .contains("\"resumable\":true"));
+                        Response::from_string(format!(
+                            r#"{{"data": {{"upload_url": "{}/storage", "token": "abc"}}}}"#,
+                            base_url
+                        ))
+                    }
+                    "/storage" => {
+                        let kept = if received.is_empty() {
+                            body.len().min(2)
+                        } else {
+                            body.len()
+                        };
+                        received.extend_from_slice(&body[..kept]);
+                        match received.len() {
+                            10 => Response::from_string(""),
+                            n => Response::from_string("").with_status_code(308).with_header(
+                                Header::from_bytes("Range", format!("bytes=0-{}", n - 1)).unwrap(),
+                            ),
+                        }
+                    }
+                    "/api/v0/upload/mar" => {
+                        assert!(str::from_utf8(&body).unwrap().contains("\"abc\""));
+                        Response::from_string("")
+                    }
+                    url => panic!("Unexpected request to {}", url),
+                };
+                request.respond(response).unwrap();
+            }
+            (requests, received)
+        });
+
+        let upload = client.prepare_resumable_mar_upload(10, false).unwrap();
+        assert_eq!(
+            client
+                .upload_mar_chunk(&upload, 0, b"0123".to_vec())
+                .unwrap(),
+            2
+        );
+        assert_eq!(client.resumable_upload_offset(&upload).unwrap(), 2);
+        assert_eq!(
+            client
+                .upload_mar_chunk(&upload, 2, b"23456789".to_vec())
+                .unwrap(),
+            10
+        );
+        client.commit_resumable_mar_upload(&upload).unwrap();
+
+        let (requests, received) = backend.join().unwrap();
+        assert_eq!(received, b"0123456789");
+        assert_eq!(
+            requests,
+            vec![
+                "POST /api/v0/upload ",
+                "PUT /storage bytes 0-3/10",
+                "PUT /storage bytes */10",
+                "PUT /storage bytes 2-9/10",
+                "POST /api/v0/upload/mar ",
+            ]
+        );
+    }
+}
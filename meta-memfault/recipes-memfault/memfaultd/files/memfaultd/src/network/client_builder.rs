@@ -0,0 +1,165 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+#[cfg(any(feature = "rust-tls", feature = "openssl-tls"))]
+use std::fs::read_to_string;
+use std::path::Path;
+
+use eyre::{eyre, Context, Result};
+use reqwest::blocking::ClientBuilder;
+#[cfg(any(feature = "rust-tls", feature = "openssl-tls"))]
+use reqwest::Certificate;
+use reqwest::Proxy;
+
+use crate::config::NetworkConnectionConfig;
+
+#[cfg(any(feature = "rust-tls", feature = "openssl-tls"))]
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+/// Applies the proxy and certificate settings of `config` to a client connecting to a host
+/// which trusts only `pinned_certificates`, when set (see `PinnedCertificatesConfig`).
+pub(super) fn configure_client(
+    mut builder: ClientBuilder,
+    config: &NetworkConnectionConfig,
+    pinned_certificates: Option<&Path>,
+) -> Result<ClientBuilder> {
+    if let Some(proxy_config) = &config.proxy {
+        if proxy_config.url.starts_with("socks") && !cfg!(feature = "socks-proxy") {
+            return Err(eyre!(
+                "SOCKS proxies require memfaultd to be built with the socks-proxy feature"
+            ));
+        }
+        let mut proxy = Proxy::all(&proxy_config.url)
+            .wrap_err_with(|| format!("Invalid proxy URL {}", proxy_config.url))?;
+        if let Some(username) = &proxy_config.username {
+            proxy = proxy.basic_auth(username, proxy_config.password.as_deref().unwrap_or(""));
+        }
+        builder = builder.proxy(proxy);
+    }
+
+    configure_certificates(builder, config, pinned_certificates)
+}
+
+#[cfg(any(feature = "rust-tls", feature = "openssl-tls"))]
+fn configure_certificates(
+    mut builder: ClientBuilder,
+    config: &NetworkConnectionConfig,
+    pinned_certificates: Option<&Path>,
+) -> Result<ClientBuilder> {
+    let certificates_path = match pinned_certificates {
+        Some(path) => {
+            builder = builder.tls_built_in_root_certs(false);
+            Some(path)
+        }
+        None => config.ca_bundle_path.as_deref(),
+    };
+    if let Some(path) = certificates_path {
+        for certificate in read_certificates(path)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    Ok(builder)
+}
+
+/// Without TLS there are no certificates to configure: the config validation rejects
+/// `ca_bundle_path` and `pinned_certificates`.
+#[cfg(not(any(feature = "rust-tls", feature = "openssl-tls")))]
+fn configure_certificates(
+    builder: ClientBuilder,
+    _config: &NetworkConnectionConfig,
+    _pinned_certificates: Option<&Path>,
+) -> Result<ClientBuilder> {
+    Ok(builder)
+}
+
+/// Reads all the certificates of a PEM file (a single `Certificate::from_pem()` only reads the
+/// first one with some TLS backends).
+#[cfg(any(feature = "rust-tls", feature = "openssl-tls"))]
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let pem = read_to_string(path)
+        .wrap_err_with(|| format!("Unable to read certificates from {}", path.display()))?;
+    let certificates = split_pem_certificates(&pem)
+        .into_iter()
+        .map(|certificate| Certificate::from_pem(certificate.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err_with(|| format!("Invalid certificate in {}", path.display()))?;
+    if certificates.is_empty() {
+        return Err(eyre!("No certificate found in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+#[cfg(any(feature = "rust-tls", feature = "openssl-tls"))]
+fn split_pem_certificates(pem: &str) -> Vec<&str> {
+    pem.split_inclusive(PEM_CERTIFICATE_END)
+        .filter(|block| block.contains(PEM_CERTIFICATE_END))
+        .map(str::trim)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rstest::rstest;
+
+    use crate::config::ProxyConfig;
+
+    use super::*;
+
+    #[cfg(any(feature = "rust-tls", feature = "openssl-tls"))]
+    #[rstest]
+    fn splits_pem_bundles() {
+        const CERTIFICATE_A: &str = "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----";
+        const CERTIFICATE_B: &str = "-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----";
+        let bundle = format!("# Corporate CA\n{}\n\n{}\n", CERTIFICATE_A, CERTIFICATE_B);
+        assert_eq!(
+            split_pem_certificates(&bundle),
+            vec![
+                format!("# Corporate CA\n{}", CERTIFICATE_A).as_str(),
+                CERTIFICATE_B
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case::http_proxy(Some("http://proxy:3128"), None, true)]
+    #[case::invalid_proxy(Some("not a url"), None, false)]
+    #[cfg_attr(
+        any(feature = "rust-tls", feature = "openssl-tls"),
+        case::missing_ca_bundle(None, Some("/does/not/exist.pem"), false)
+    )]
+    fn configures_clients(
+        #[case] proxy_url: Option<&str>,
+        #[case] ca_bundle_path: Option<&str>,
+        #[case] valid: bool,
+    ) {
+        let config = NetworkConnectionConfig {
+            proxy: proxy_url.map(|url| ProxyConfig {
+                url: url.to_owned(),
+                username: Some("user".to_owned()),
+                password: Some("pass".to_owned()),
+            }),
+            ca_bundle_path: ca_bundle_path.map(PathBuf::from),
+            ..Default::default()
+        };
+        assert_eq!(
+            configure_client(ClientBuilder::new(), &config, None).is_ok(),
+            valid
+        );
+    }
+
+    #[cfg(not(feature = "socks-proxy"))]
+    #[rstest]
+    fn rejects_socks_proxies_without_feature() {
+        let config = NetworkConnectionConfig {
+            proxy: Some(ProxyConfig {
+                url: "socks5://proxy:1080".to_owned(),
+                username: None,
+                password: None,
+            }),
+            ..Default::default()
+        };
+        assert!(configure_client(ClientBuilder::new(), &config, None).is_err());
+    }
+}
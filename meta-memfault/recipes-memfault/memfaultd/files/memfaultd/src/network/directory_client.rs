@@ -0,0 +1,283 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use std::fs::{read_dir, remove_file, rename, File};
+use std::io::{copy, BufWriter, Read, Write};
+use std::path::PathBuf;
+
+use chrono::Utc;
+use eyre::{eyre, Context, Result};
+use log::{debug, trace, warn};
+use uuid::Uuid;
+
+use crate::util::disk_size::get_disk_space;
+use crate::util::io::StreamLen;
+
+use super::{DeviceConfigResponse, NetworkClient, ResumableUpload};
+
+const MAR_FILE_PREFIX: &str = "memfault-";
+const CHUNKED_UPLOADS_UNSUPPORTED: &str =
+    "Chunked uploads are not supported by the directory upload backend";
+
+/// Writes MAR files to a directory instead of uploading them (see `UploadBackendConfig::Directory`).
+///
+/// Files are named `memfault-<timestamp>-<uuid>.mar` (`.mar.gz` when gzipped) so that they sort
+/// from oldest to newest. Only these files are deleted to make room for new ones.
+pub struct DirectoryClient {
+    path: PathBuf,
+    max_files: usize,
+    min_headroom: u64,
+}
+
+impl DirectoryClient {
+    pub fn new(path: PathBuf, max_files: usize, min_headroom: u64) -> Self {
+        let client = DirectoryClient {
+            path,
+            max_files,
+            min_headroom,
+        };
+        // The directory may not be available yet (a drive which is not mounted).
+        if client.path.is_dir() {
+            if let Err(e) = client.remove_partial_files() {
+                warn!(
+                    "Unable to remove partial MAR files from {}: {}",
+                    client.path.display(),
+                    e
+                );
+            }
+        }
+        client
+    }
+
+    /// Deletes the temporary files left behind by writes which were interrupted.
+    fn remove_partial_files(&self) -> Result<()> {
+        for entry in read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(MAR_FILE_PREFIX) && name.ends_with(".tmp") {
+                debug!("Deleting partial MAR file {}", entry.path().display());
+                remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// MAR files previously written to the directory, from oldest to newest, with their size.
+    fn mar_files(&self) -> Result<Vec<(PathBuf, u64)>> {
+        let mut files = read_dir(&self.path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with(MAR_FILE_PREFIX)
+                    && (name.ends_with(".mar") || name.ends_with(".mar.gz"))
+            })
+            .map(|entry| Ok((entry.path(), entry.metadata()?.len())))
+            .collect::<Result<Vec<_>>>()?;
+        files.sort();
+        Ok(files)
+    }
+
+    /// Deletes the oldest MAR files so that a new one of `size` bytes can be written.
+    /// Nothing is deleted when the new file would not fit anyway.
+    fn make_room(&self, size: u64) -> Result<()> {
+        let files = self.mar_files()?;
+        let free = get_disk_space(&self.path)?.bytes;
+        let needed = size + self.min_headroom;
+        let deletable: u64 = files.iter().map(|(_, len)| len).sum();
+        if free + deletable < needed {
+            return Err(eyre!(
+                "Not enough space in {} for a MAR file of {} bytes",
+                self.path.display(),
+                size
+            ));
+        }
+
+        let mut free = free;
+        let mut count = files.len();
+        for (path, len) in files {
+            if count < self.max_files && free >= needed {
+                break;
+            }
+            debug!(
+                "Deleting {} to make room for a new MAR file",
+                path.display()
+            );
+            remove_file(&path)?;
+            free += len;
+            count -= 1;
+        }
+        Ok(())
+    }
+
+    fn write_mar_file(&self, mut file: impl Read, gzipped: bool) -> Result<PathBuf> {
+        let filename = format!(
+            "{}{}-{}.{}",
+            MAR_FILE_PREFIX,
+            Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+            Uuid::new_v4(),
+            if gzipped { "mar.gz" } else { "mar" }
+        );
+        let path = self.path.join(filename);
+        // Written under a temporary name, so that a partial file is never taken for a MAR file.
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        if let Err(e) = copy(&mut file, &mut writer).and_then(|_| writer.flush()) {
+            let _ = remove_file(&tmp_path);
+            return Err(e.into());
+        }
+        writer.into_inner()?.sync_all()?;
+        rename(&tmp_path, &path)?;
+        Ok(path)
+    }
+}
+
+impl NetworkClient for DirectoryClient {
+    fn upload_mar_file<F: Read + StreamLen + Send + 'static>(
+        &self,
+        file: F,
+        gzipped: bool,
+    ) -> Result<()> {
+        if !self.path.is_dir() {
+            return Err(eyre!("{} is not available", self.path.display()));
+        }
+        self.make_room(file.stream_len())?;
+        let path = self
+            .write_mar_file(file, gzipped)
+            .wrap_err_with(|| format!("Error writing MAR file to {}", self.path.display()))?;
+        trace!("Wrote MAR file {}", path.display());
+        Ok(())
+    }
+
+    fn prepare_resumable_mar_upload(&self, _size: u64, _gzipped: bool) -> Result<ResumableUpload> {
+        Err(eyre!(CHUNKED_UPLOADS_UNSUPPORTED))
+    }
+
+    fn upload_mar_chunk(
+        &self,
+        _upload: &ResumableUpload,
+        _offset: u64,
+        _chunk: Vec<u8>,
+    ) -> Result<u64> {
+        Err(eyre!(CHUNKED_UPLOADS_UNSUPPORTED))
+    }
+
+    fn resumable_upload_offset(&self, _upload: &ResumableUpload) -> Result<u64> {
+        Err(eyre!(CHUNKED_UPLOADS_UNSUPPORTED))
+    }
+
+    fn commit_resumable_mar_upload(&self, _upload: &ResumableUpload) -> Result<()> {
+        Err(eyre!(CHUNKED_UPLOADS_UNSUPPORTED))
+    }
+
+    fn fetch_device_config(&self) -> Result<DeviceConfigResponse> {
+        Err(eyre!(
+            "Device config cannot be fetched with the directory upload backend"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use rstest::rstest;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn written_files(dir: &Path) -> Vec<Vec<u8>> {
+        let mut paths: Vec<PathBuf> = read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| {
+                p.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with(MAR_FILE_PREFIX)
+            })
+            .collect();
+        paths.sort();
+        paths.iter().map(|p| std::fs::read(p).unwrap()).collect()
+    }
+
+    #[rstest]
+    fn writes_mar_files() {
+        let dir = tempdir().unwrap();
+        let client = DirectoryClient::new(dir.path().to_owned(), 10, 0);
+
+        client
+            .upload_mar_file(Cursor::new(b"first".to_vec()), false)
+            .unwrap();
+        client
+            .upload_mar_file(Cursor::new(b"second".to_vec()), true)
+            .unwrap();
+
+        assert_eq!(
+            written_files(dir.path()),
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+    }
+
+    #[rstest]
+    fn keeps_the_most_recent_files() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "not a MAR file").unwrap();
+        let client = DirectoryClient::new(dir.path().to_owned(), 2, 0);
+
+        for contents in ["1", "2", "3"] {
+            client
+                .upload_mar_file(Cursor::new(contents.as_bytes().to_vec()), false)
+                .unwrap();
+        }
+
+        assert_eq!(
+            written_files(dir.path()),
+            vec![b"2".to_vec(), b"3".to_vec()]
+        );
+        assert!(dir.path().join("notes.txt").exists());
+    }
+
+    #[rstest]
+    fn keeps_files_when_there_is_not_enough_space() {
+        let dir = tempdir().unwrap();
+        let client = DirectoryClient::new(dir.path().to_owned(), 10, 0);
+        client
+            .upload_mar_file(Cursor::new(b"first".to_vec()), false)
+            .unwrap();
+
+        let client = DirectoryClient::new(dir.path().to_owned(), 10, u64::MAX / 2);
+        assert!(client
+            .upload_mar_file(Cursor::new(b"second".to_vec()), false)
+            .is_err());
+
+        assert_eq!(written_files(dir.path()), vec![b"first".to_vec()]);
+    }
+
+    #[rstest]
+    fn removes_partial_files_at_startup() {
+        let dir = tempdir().unwrap();
+        let partial = dir
+            .path()
+            .join(format!("{}20240101T000000Z.tmp", MAR_FILE_PREFIX));
+        std::fs::write(&partial, "partial").unwrap();
+        std::fs::write(dir.path().join("notes.tmp"), "not a MAR file").unwrap();
+
+        DirectoryClient::new(dir.path().to_owned(), 10, 0);
+
+        assert!(!partial.exists());
+        assert!(dir.path().join("notes.tmp").exists());
+    }
+
+    #[rstest]
+    fn fails_when_directory_is_missing() {
+        let dir = tempdir().unwrap();
+        let client = DirectoryClient::new(dir.path().join("usb"), 10, 0);
+
+        assert!(client
+            .upload_mar_file(Cursor::new(b"first".to_vec()), false)
+            .is_err());
+    }
+}
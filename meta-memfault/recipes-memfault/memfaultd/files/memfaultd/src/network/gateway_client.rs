@@ -0,0 +1,86 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use std::io::Read;
+
+use eyre::{eyre, Context, Result};
+use log::trace;
+use reqwest::blocking::{self, Body};
+use reqwest::header;
+
+use crate::mar::GATEWAY_UPLOAD_URL;
+use crate::retriable_error::RetriableError;
+use crate::util::io::StreamLen;
+
+use super::{DeviceConfigResponse, NetworkClient, NetworkClientImpl, ResumableUpload};
+
+const CHUNKED_UPLOADS_UNSUPPORTED: &str = "Chunked uploads are not supported by the upload gateway";
+
+/// Uploads MAR files to the gateway server of another device (see `UploadBackendConfig::Gateway`),
+/// for devices without a connection to Memfault.
+pub struct GatewayClient {
+    client: blocking::Client,
+    url: String,
+    auth_token: String,
+}
+
+impl GatewayClient {
+    pub fn new(url: &str, auth_token: &str) -> Result<Self> {
+        Ok(GatewayClient {
+            client: blocking::Client::builder().build()?,
+            url: url.trim_end_matches('/').to_owned(),
+            auth_token: auth_token.to_owned(),
+        })
+    }
+}
+
+impl NetworkClient for GatewayClient {
+    fn upload_mar_file<F: Read + StreamLen + Send + 'static>(
+        &self,
+        file: F,
+        gzipped: bool,
+    ) -> Result<()> {
+        let url = format!("{}{}", self.url, GATEWAY_UPLOAD_URL);
+        let mut req = self.client.post(&url).bearer_auth(&self.auth_token);
+        if gzipped {
+            req = req.header(header::CONTENT_ENCODING, "gzip");
+        }
+
+        trace!("Uploading MAR file to {}", url);
+        let size = file.stream_len();
+        let response = req
+            .body(Body::sized(file, size))
+            .send()
+            .map_err(|e| RetriableError::NetworkError { source: e })?;
+        NetworkClientImpl::good_response_or_error(response)
+            .wrap_err("Gateway upload error")
+            .and(Ok(()))
+    }
+
+    fn prepare_resumable_mar_upload(&self, _size: u64, _gzipped: bool) -> Result<ResumableUpload> {
+        Err(eyre!(CHUNKED_UPLOADS_UNSUPPORTED))
+    }
+
+    fn upload_mar_chunk(
+        &self,
+        _upload: &ResumableUpload,
+        _offset: u64,
+        _chunk: Vec<u8>,
+    ) -> Result<u64> {
+        Err(eyre!(CHUNKED_UPLOADS_UNSUPPORTED))
+    }
+
+    fn resumable_upload_offset(&self, _upload: &ResumableUpload) -> Result<u64> {
+        Err(eyre!(CHUNKED_UPLOADS_UNSUPPORTED))
+    }
+
+    fn commit_resumable_mar_upload(&self, _upload: &ResumableUpload) -> Result<()> {
+        Err(eyre!(CHUNKED_UPLOADS_UNSUPPORTED))
+    }
+
+    fn fetch_device_config(&self) -> Result<DeviceConfigResponse> {
+        Err(eyre!(
+            "Device config cannot be fetched through the upload gateway"
+        ))
+    }
+}
@@ -0,0 +1,256 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! Decide what can be uploaded on the current network link (see `NetworkPolicyConfig`).
+//!
+use std::{fs::read_to_string, process::Command};
+
+use eyre::{eyre, Result};
+use log::warn;
+
+use crate::{
+    config::{NetworkPolicyConfig, UploadDataType},
+    mar::Metadata,
+    util::can_connect::CanConnect,
+};
+
+const PROC_NET_ROUTE: &str = "/proc/net/route";
+const UPLOAD_DATA_TYPES: [UploadDataType; 5] = [
+    UploadDataType::Attributes,
+    UploadDataType::Coredumps,
+    UploadDataType::Logs,
+    UploadDataType::Metrics,
+    UploadDataType::Reboots,
+];
+const RTF_UP: u32 = 0x1;
+
+pub struct LinkPolicy<'a> {
+    config: &'a NetworkPolicyConfig,
+    /// Name of the current link, or None if it could not be determined
+    link: Option<String>,
+}
+
+impl<'a> LinkPolicy<'a> {
+    pub fn new(config: &'a NetworkPolicyConfig, link: Option<String>) -> Self {
+        Self { config, link }
+    }
+
+    /// Detects the current link. Returns None when the device is offline.
+    pub fn detect<C: CanConnect>(config: &'a NetworkPolicyConfig) -> Option<Self> {
+        let link = match current_link(config) {
+            Ok(Some(link)) => Some(link),
+            Ok(None) => return None,
+            Err(e) => {
+                warn!("Unable to detect the current network link: {:#}", e);
+                None
+            }
+        };
+
+        if let Some(target) = &config.connection_check {
+            if let Err(e) = C::new(config.timeout_seconds).can_connect(&target.host, target.port) {
+                warn!("{}:{} is not reachable: {}", target.host, target.port, e);
+                return None;
+            }
+        }
+        Some(Self::new(config, link))
+    }
+
+    pub fn link(&self) -> Option<&str> {
+        self.link.as_deref()
+    }
+
+    /// Whether a MAR entry can be uploaded on the current link. Data types which
+    /// are restricted to some links are not uploaded when the link is unknown.
+    pub fn allows(&self, metadata: &Metadata) -> bool {
+        match upload_data_type(metadata) {
+            Some(data_type) => self.allows_data_type(data_type),
+            None => true,
+        }
+    }
+
+    /// Whether some types of data can be uploaded on the current link.
+    pub fn allows_uploads(&self) -> bool {
+        UPLOAD_DATA_TYPES
+            .into_iter()
+            .any(|data_type| self.allows_data_type(data_type))
+    }
+
+    fn allows_data_type(&self, data_type: UploadDataType) -> bool {
+        let mut rules = self
+            .config
+            .link_rules
+            .iter()
+            .filter(|rule| rule.data_types.contains(&data_type))
+            .peekable();
+        if rules.peek().is_none() {
+            return true;
+        }
+        let link = match &self.link {
+            Some(link) => link,
+            None => return false,
+        };
+        rules.any(|rule| rule.links.contains(link))
+    }
+}
+
+fn upload_data_type(metadata: &Metadata) -> Option<UploadDataType> {
+    match metadata {
+        Metadata::DeviceAttributes { .. } => Some(UploadDataType::Attributes),
+        Metadata::DeviceConfig { .. } => None, // Always upload device config
+        Metadata::ElfCoredump { .. } => Some(UploadDataType::Coredumps),
+        Metadata::LinuxHeartbeat { .. } => Some(UploadDataType::Metrics),
+        Metadata::LinuxMetricReport { .. } => Some(UploadDataType::Metrics),
+        Metadata::LinuxLogs { .. } => Some(UploadDataType::Logs),
+        Metadata::LinuxReboot { .. } => Some(UploadDataType::Reboots),
+        Metadata::LinuxStackTrace { .. } => None, // Small enough for any link
+    }
+}
+
+/// Name of the current link, or None when offline.
+fn current_link(config: &NetworkPolicyConfig) -> Result<Option<String>> {
+    match &config.link_command {
+        Some(link_command) => {
+            let output = Command::new(link_command).output()?;
+            if !output.status.success() {
+                return Err(eyre!(
+                    "{} failed with status {}",
+                    link_command,
+                    output.status
+                ));
+            }
+            let link = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Ok((!link.is_empty()).then_some(link))
+        }
+        None => Ok(default_route_interface(&read_to_string(PROC_NET_ROUTE)?)),
+    }
+}
+
+/// Interface of the default route with the lowest metric in /proc/net/route.
+fn default_route_interface(proc_net_route: &str) -> Option<String> {
+    proc_net_route
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let destination = fields.get(1)?;
+            let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+            let metric: u32 = fields.get(6)?.parse().ok()?;
+            (*destination == "00000000" && flags & RTF_UP != 0).then_some((fields[0], metric))
+        })
+        .min_by_key(|(_, metric)| *metric)
+        .map(|(interface, _)| interface.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use rstest::rstest;
+    use serde_json::json;
+
+    use crate::reboot::{RebootReason, RebootReasonCode};
+
+    use super::*;
+
+    const ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wwan0\t00000000\t0100000A\t0003\t0\t0\t700\t00000000\t0\t0\t0
+wlan0\t00000000\t0102A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+wlan0\t0002A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0
+";
+
+    #[rstest]
+    #[case::lowest_metric(ROUTES, Some("wlan0"))]
+    #[case::no_default_route(
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n",
+        None
+    )]
+    #[case::route_down("Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\neth0\t00000000\t0102A8C0\t0002\t0\t0\t100\t00000000\t0\t0\t0\n", None)]
+    fn finds_default_route_interface(#[case] routes: &str, #[case] expected: Option<&str>) {
+        assert_eq!(default_route_interface(routes).as_deref(), expected);
+    }
+
+    fn config() -> NetworkPolicyConfig {
+        serde_json::from_value(json!({
+            "link_rules": [
+                {"data_types": ["logs", "coredumps"], "links": ["wlan0"]},
+                {"data_types": ["logs"], "links": ["eth0"]}
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn log() -> Metadata {
+        Metadata::new_log(
+            "system.log".to_string(),
+            uuid::Uuid::new_v4(),
+            uuid::Uuid::new_v4(),
+            crate::mar::CompressionAlgorithm::Zlib,
+        )
+    }
+
+    fn reboot() -> Metadata {
+        Metadata::new_reboot(RebootReason::from(RebootReasonCode::UserReset))
+    }
+
+    #[rstest]
+    #[case::allowed_link(log(), Some("eth0"), true)]
+    #[case::other_link(log(), Some("wwan0"), false)]
+    #[case::unknown_link(log(), None, false)]
+    #[case::unrestricted(reboot(), Some("wwan0"), true)]
+    #[case::unrestricted_unknown_link(reboot(), None, true)]
+    fn allows_data_types_on_links(
+        #[case] metadata: Metadata,
+        #[case] link: Option<&str>,
+        #[case] allowed: bool,
+    ) {
+        let config = config();
+        let policy = LinkPolicy::new(&config, link.map(String::from));
+        assert_eq!(policy.allows(&metadata), allowed);
+    }
+
+    #[rstest]
+    #[case::some_rules(config(), None, true)]
+    #[case::all_restricted(all_data_types_on_wlan0(), Some("wwan0"), false)]
+    #[case::all_restricted_unknown_link(all_data_types_on_wlan0(), None, false)]
+    #[case::all_restricted_allowed_link(all_data_types_on_wlan0(), Some("wlan0"), true)]
+    fn allows_uploads_on_links(
+        #[case] config: NetworkPolicyConfig,
+        #[case] link: Option<&str>,
+        #[case] allowed: bool,
+    ) {
+        let policy = LinkPolicy::new(&config, link.map(String::from));
+        assert_eq!(policy.allows_uploads(), allowed);
+    }
+
+    fn all_data_types_on_wlan0() -> NetworkPolicyConfig {
+        serde_json::from_value(json!({
+            "link_rules": [{
+                "data_types": ["attributes", "coredumps", "logs", "metrics", "reboots"],
+                "links": ["wlan0"]
+            }]
+        }))
+        .unwrap()
+    }
+
+    struct Unreachable;
+    impl CanConnect for Unreachable {
+        fn new(_timeout: Duration) -> Self {
+            Self
+        }
+
+        fn can_connect(&self, _ip_addr: &IpAddr, _port: u16) -> Result<()> {
+            Err(eyre!("Connection refused"))
+        }
+    }
+
+    #[rstest]
+    fn offline_when_connection_check_fails() {
+        let config: NetworkPolicyConfig = serde_json::from_value(json!({
+            "link_command": "uname",
+            "connection_check": {"host": "127.0.0.1", "port": 443}
+        }))
+        .unwrap();
+        assert!(LinkPolicy::detect::<Unreachable>(&config).is_none());
+    }
+}
@@ -6,25 +6,75 @@ use std::io::Read;
 use eyre::Result;
 #[cfg(test)]
 use mockall::automock;
+use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::config::{Config, NetworkConnectionConfig};
 use crate::util::io::StreamLen;
 
 mod client;
 pub use client::NetworkClientImpl;
 
+mod client_builder;
+
+mod directory_client;
+pub use directory_client::DirectoryClient;
+
+mod gateway_client;
+pub use gateway_client::GatewayClient;
+
+mod link_policy;
+pub use link_policy::LinkPolicy;
+
 mod requests;
 pub use requests::*;
 
+mod upload_client;
+pub use upload_client::UploadClient;
+
 #[cfg_attr(test, automock)]
 pub trait NetworkClient {
-    /// Upload a MAR file to Memfault
-    fn upload_mar_file<F: Read + StreamLen + Send + 'static>(&self, file: F) -> Result<()>;
+    /// Upload a MAR file to Memfault. A `gzipped` file is sent with `Content-Encoding: gzip`.
+    fn upload_mar_file<F: Read + StreamLen + Send + 'static>(
+        &self,
+        file: F,
+        gzipped: bool,
+    ) -> Result<()>;
+
+    /// Prepare the upload of a MAR file of `size` bytes which can be sent in
+    /// multiple chunks, and resumed after an interruption.
+    fn prepare_resumable_mar_upload(&self, size: u64, gzipped: bool) -> Result<ResumableUpload>;
+
+    /// Upload the chunk of a MAR file starting at `offset`.
+    /// Returns the number of bytes received by the server so far.
+    fn upload_mar_chunk(
+        &self,
+        upload: &ResumableUpload,
+        offset: u64,
+        chunk: Vec<u8>,
+    ) -> Result<u64>;
+
+    /// Returns the number of bytes of an interrupted upload received by the server.
+    fn resumable_upload_offset(&self, upload: &ResumableUpload) -> Result<u64>;
+
+    /// Notify Memfault that all the chunks of a MAR file were uploaded.
+    fn commit_resumable_mar_upload(&self, upload: &ResumableUpload) -> Result<()>;
 
     /// Fetch DeviceConfig from Memfault.
     fn fetch_device_config(&self) -> Result<DeviceConfigResponse>;
 }
 
+/// An upload which accepts a file in multiple ranges (see `NetworkClient::prepare_resumable_mar_upload`).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumableUpload {
+    pub upload_url: String,
+    pub token: String,
+    /// Total size of the file
+    pub size: u64,
+    /// Whether the file is sent with `Content-Encoding: gzip`
+    #[serde(default)]
+    pub gzipped: bool,
+}
+
 /// Internal representation of what is needed to talk to the backend.
 #[derive(Clone)]
 pub struct NetworkConfig {
@@ -34,6 +84,7 @@ pub struct NetworkConfig {
     pub hardware_version: String,
     pub software_version: String,
     pub software_type: String,
+    pub connection: NetworkConnectionConfig,
 }
 
 impl From<&Config> for NetworkConfig {
@@ -45,6 +96,7 @@ impl From<&Config> for NetworkConfig {
             hardware_version: config.device_info.hardware_version.clone(),
             software_type: config.software_type().to_string(),
             software_version: config.software_version().to_string(),
+            connection: config.network_connection_config().clone(),
         }
     }
 }
@@ -59,6 +111,7 @@ impl NetworkConfig {
             hardware_version: "DVT".to_owned(),
             software_version: "1.0.0".to_owned(),
             software_type: "test".to_owned(),
+            connection: NetworkConnectionConfig::default(),
         }
     }
 }
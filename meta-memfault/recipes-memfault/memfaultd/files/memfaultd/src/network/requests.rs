@@ -30,6 +30,8 @@ pub struct UploadPrepareRequest<'a> {
     content_encoding: Option<&'static str>,
     size: usize,
     device: UploadDeviceMetadata<'a>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    resumable: bool,
 }
 
 impl<'a> UploadPrepareRequest<'a> {
@@ -42,6 +44,15 @@ impl<'a> UploadPrepareRequest<'a> {
             content_encoding: if gzipped { Some("gzip") } else { None },
             size: filesize,
             device: UploadDeviceMetadata::from(config),
+            resumable: false,
+        }
+    }
+
+    /// Request an upload URL which accepts the file in multiple ranges.
+    pub fn resumable(self) -> Self {
+        Self {
+            resumable: true,
+            ..self
         }
     }
 }
@@ -0,0 +1,107 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use std::io::Read;
+
+use eyre::Result;
+
+use crate::config::{Config, UploadBackendConfig};
+use crate::util::io::StreamLen;
+
+use super::{
+    DeviceConfigResponse, DirectoryClient, GatewayClient, NetworkClient, NetworkClientImpl,
+    NetworkConfig, ResumableUpload,
+};
+
+/// The `NetworkClient` of the configured upload backend (see `UploadBackendConfig`).
+pub enum UploadClient {
+    Memfault(Box<NetworkClientImpl>),
+    Gateway(GatewayClient),
+    Directory(DirectoryClient),
+}
+
+impl UploadClient {
+    pub fn new(config: &Config) -> Result<Self> {
+        match config.upload_backend() {
+            UploadBackendConfig::Memfault => Ok(UploadClient::Memfault(Box::new(
+                NetworkClientImpl::new(NetworkConfig::from(config))?,
+            ))),
+            UploadBackendConfig::Gateway { url, auth_token } => {
+                Ok(UploadClient::Gateway(GatewayClient::new(url, auth_token)?))
+            }
+            UploadBackendConfig::Directory {
+                path,
+                max_files,
+                min_headroom,
+            } => Ok(UploadClient::Directory(DirectoryClient::new(
+                path.clone(),
+                *max_files,
+                *min_headroom as u64,
+            ))),
+        }
+    }
+
+    /// Whether the device config can be fetched with this client.
+    pub fn can_fetch_device_config(&self) -> bool {
+        matches!(self, UploadClient::Memfault(_))
+    }
+}
+
+impl NetworkClient for UploadClient {
+    fn upload_mar_file<F: Read + StreamLen + Send + 'static>(
+        &self,
+        file: F,
+        gzipped: bool,
+    ) -> Result<()> {
+        match self {
+            UploadClient::Memfault(client) => client.upload_mar_file(file, gzipped),
+            UploadClient::Gateway(client) => client.upload_mar_file(file, gzipped),
+            UploadClient::Directory(client) => client.upload_mar_file(file, gzipped),
+        }
+    }
+
+    fn prepare_resumable_mar_upload(&self, size: u64, gzipped: bool) -> Result<ResumableUpload> {
+        match self {
+            UploadClient::Memfault(client) => client.prepare_resumable_mar_upload(size, gzipped),
+            UploadClient::Gateway(client) => client.prepare_resumable_mar_upload(size, gzipped),
+            UploadClient::Directory(client) => client.prepare_resumable_mar_upload(size, gzipped),
+        }
+    }
+
+    fn upload_mar_chunk(
+        &self,
+        upload: &ResumableUpload,
+        offset: u64,
+        chunk: Vec<u8>,
+    ) -> Result<u64> {
+        match self {
+            UploadClient::Memfault(client) => client.upload_mar_chunk(upload, offset, chunk),
+            UploadClient::Gateway(client) => client.upload_mar_chunk(upload, offset, chunk),
+            UploadClient::Directory(client) => client.upload_mar_chunk(upload, offset, chunk),
+        }
+    }
+
+    fn resumable_upload_offset(&self, upload: &ResumableUpload) -> Result<u64> {
+        match self {
+            UploadClient::Memfault(client) => client.resumable_upload_offset(upload),
+            UploadClient::Gateway(client) => client.resumable_upload_offset(upload),
+            UploadClient::Directory(client) => client.resumable_upload_offset(upload),
+        }
+    }
+
+    fn commit_resumable_mar_upload(&self, upload: &ResumableUpload) -> Result<()> {
+        match self {
+            UploadClient::Memfault(client) => client.commit_resumable_mar_upload(upload),
+            UploadClient::Gateway(client) => client.commit_resumable_mar_upload(upload),
+            UploadClient::Directory(client) => client.commit_resumable_mar_upload(upload),
+        }
+    }
+
+    fn fetch_device_config(&self) -> Result<DeviceConfigResponse> {
+        match self {
+            UploadClient::Memfault(client) => client.fetch_device_config(),
+            UploadClient::Gateway(client) => client.fetch_device_config(),
+            UploadClient::Directory(client) => client.fetch_device_config(),
+        }
+    }
+}
@@ -6,6 +6,8 @@ use nix::sys::signal::Signal::SIGHUP;
 use crate::util::pid_file::send_signal_to_pid;
 
 use super::MemfaultdServiceManager;
+#[cfg(feature = "system-metrics")]
+use super::ServiceUnitInfo;
 
 pub struct DefaultServiceManager;
 
@@ -17,4 +19,9 @@ impl MemfaultdServiceManager for DefaultServiceManager {
     fn service_manager_status(&self) -> eyre::Result<super::ServiceManagerStatus> {
         Ok(super::ServiceManagerStatus::Unknown)
     }
+
+    #[cfg(feature = "system-metrics")]
+    fn service_unit_info(&self, _unit_name: &str) -> eyre::Result<ServiceUnitInfo> {
+        Err(eyre::eyre!("Service units are only supported with systemd"))
+    }
 }
@@ -34,6 +34,16 @@ pub enum ServiceManagerStatus {
     Unknown,
 }
 
+/// State of a service unit, as reported by the service manager.
+#[cfg(feature = "system-metrics")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServiceUnitInfo {
+    /// PID of the main process of the service, if it is running
+    pub main_pid: Option<u32>,
+    /// Number of automatic restarts of the service, if the service manager reports it
+    pub n_restarts: Option<u32>,
+}
+
 /// Trait for managing memfaultd services
 ///
 /// This trait is implemented for different service managers, such as systemd.
@@ -41,6 +51,8 @@ pub enum ServiceManagerStatus {
 pub trait MemfaultdServiceManager {
     fn restart_memfaultd_if_running(&self) -> eyre::Result<()>;
     fn service_manager_status(&self) -> eyre::Result<ServiceManagerStatus>;
+    #[cfg(feature = "system-metrics")]
+    fn service_unit_info(&self, unit_name: &str) -> eyre::Result<ServiceUnitInfo>;
 }
 
 impl TryFrom<&str> for ServiceManagerStatus {
@@ -3,7 +3,11 @@
 // See License.txt for details
 use std::ffi::{CStr, CString};
 
+#[cfg(feature = "system-metrics")]
+use crate::service_manager::ServiceUnitInfo;
 use crate::service_manager::{MemfaultdServiceManager, ServiceManagerStatus};
+#[cfg(feature = "system-metrics")]
+use memfaultc_sys::systemd::memfaultd_get_systemd_service_info;
 use memfaultc_sys::systemd::{
     memfaultd_get_systemd_bus_state, memfaultd_restart_systemd_service_if_running,
 };
@@ -39,4 +43,27 @@ impl MemfaultdServiceManager for SystemdServiceManager {
 
         Ok(status)
     }
+
+    #[cfg(feature = "system-metrics")]
+    fn service_unit_info(&self, unit_name: &str) -> eyre::Result<ServiceUnitInfo> {
+        let unit_cstring = CString::new(unit_name)?;
+        let mut main_pid = 0;
+        let mut has_n_restarts = false;
+        let mut n_restarts = 0;
+        if !unsafe {
+            memfaultd_get_systemd_service_info(
+                unit_cstring.as_ptr(),
+                &mut main_pid,
+                &mut has_n_restarts,
+                &mut n_restarts,
+            )
+        } {
+            return Err(eyre::eyre!("Failed to get info of {}", unit_name));
+        }
+
+        Ok(ServiceUnitInfo {
+            main_pid: (main_pid != 0).then_some(main_pid),
+            n_restarts: has_n_restarts.then_some(n_restarts),
+        })
+    }
 }
@@ -0,0 +1,18 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+//! StatsD
+//!
+//! Provides StatsdServer which receives StatsD datagrams over UDP and adds
+//! the readings to the ongoing metric reports.
+//!
+//! Counters (`c`) are scaled by their sample rate and added to a counter
+//! metric. Gauges (`g`) are reported as gauges; a value prefixed with `+` or
+//! `-` is relative to the previous value of the gauge. Timers (`ms`) and
+//! histograms (`h`) are reported as gauges so that their statistics can be
+//! configured with `metrics.histogram_statistics`.
+//!
+mod statsd_line;
+mod statsd_server;
+
+pub use statsd_server::StatsdServer;
@@ -0,0 +1,143 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use eyre::{eyre, Result};
+
+/// https://github.com/statsd/statsd/blob/master/docs/metric_types.md
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsdMetricType {
+    Counter,
+    Gauge,
+    Timer,
+    Histogram,
+}
+
+/// One line of a StatsD datagram: `<name>:<value>|<type>[|@<sample rate>][|#<tags>]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsdLine<'a> {
+    pub name: &'a str,
+    pub value: f64,
+    /// Gauge values prefixed with a sign are relative to the previous value.
+    pub relative: bool,
+    pub metric_type: StatsdMetricType,
+    pub sample_rate: f64,
+}
+
+impl<'a> StatsdLine<'a> {
+    pub fn parse(line: &'a str) -> Result<Self> {
+        let (name, rest) = line
+            .split_once(':')
+            .ok_or_else(|| eyre!("Missing ':' separator"))?;
+        if name.is_empty() {
+            return Err(eyre!("Empty metric name"));
+        }
+
+        let mut fields = rest.split('|');
+        let value_str = fields.next().unwrap_or_default();
+        let metric_type = match fields.next() {
+            Some("c") => StatsdMetricType::Counter,
+            Some("g") => StatsdMetricType::Gauge,
+            Some("ms") => StatsdMetricType::Timer,
+            Some("h") => StatsdMetricType::Histogram,
+            Some(t) => return Err(eyre!("Unsupported metric type '{}'", t)),
+            None => return Err(eyre!("Missing metric type")),
+        };
+
+        let value = value_str
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| eyre!("Invalid value '{}'", value_str))?;
+        let relative = metric_type == StatsdMetricType::Gauge && value_str.starts_with(['+', '-']);
+
+        let mut sample_rate = 1.0;
+        for field in fields {
+            // Tags (#) and other extensions are ignored.
+            if let Some(rate_str) = field.strip_prefix('@') {
+                sample_rate = rate_str
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|r| *r > 0.0 && *r <= 1.0)
+                    .ok_or_else(|| eyre!("Invalid sample rate '{}'", rate_str))?;
+            }
+        }
+
+        Ok(Self {
+            name,
+            value,
+            relative,
+            metric_type,
+            sample_rate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("requests:1|c", "requests", 1.0, false, StatsdMetricType::Counter, 1.0)]
+    #[case(
+        "requests:2|c|@0.1",
+        "requests",
+        2.0,
+        false,
+        StatsdMetricType::Counter,
+        0.1
+    )]
+    #[case("requests:-2|c", "requests", -2.0, false, StatsdMetricType::Counter, 1.0)]
+    #[case("queue:12.5|g", "queue", 12.5, false, StatsdMetricType::Gauge, 1.0)]
+    #[case("queue:+3|g", "queue", 3.0, true, StatsdMetricType::Gauge, 1.0)]
+    #[case("queue:-3|g", "queue", -3.0, true, StatsdMetricType::Gauge, 1.0)]
+    #[case(
+        "db.query:320|ms|@0.5|#table:users",
+        "db.query",
+        320.0,
+        false,
+        StatsdMetricType::Timer,
+        0.5
+    )]
+    #[case(
+        "size:42|h|#tag",
+        "size",
+        42.0,
+        false,
+        StatsdMetricType::Histogram,
+        1.0
+    )]
+    fn parse_valid_lines(
+        #[case] line: &str,
+        #[case] name: &str,
+        #[case] value: f64,
+        #[case] relative: bool,
+        #[case] metric_type: StatsdMetricType,
+        #[case] sample_rate: f64,
+    ) {
+        assert_eq!(
+            StatsdLine::parse(line).unwrap(),
+            StatsdLine {
+                name,
+                value,
+                relative,
+                metric_type,
+                sample_rate,
+            }
+        );
+    }
+
+    #[rstest]
+    #[case::no_separator("requests")]
+    #[case::empty_name(":1|c")]
+    #[case::no_type("requests:1")]
+    #[case::set("users:42|s")]
+    #[case::invalid_value("requests:one|c")]
+    #[case::nan_value("requests:NaN|c")]
+    #[case::zero_sample_rate("requests:1|c|@0")]
+    #[case::invalid_sample_rate("requests:1|c|@2")]
+    fn parse_invalid_lines(#[case] line: &str) {
+        assert!(StatsdLine::parse(line).is_err());
+    }
+}
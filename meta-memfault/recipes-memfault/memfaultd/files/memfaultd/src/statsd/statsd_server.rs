@@ -0,0 +1,250 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use std::{
+    collections::HashMap,
+    net::UdpSocket,
+    str::from_utf8,
+    sync::{Arc, Mutex},
+    thread::{spawn, JoinHandle},
+};
+
+use chrono::{Duration, Utc};
+use eyre::{eyre, Result, WrapErr};
+use log::{debug, warn};
+
+use crate::{
+    config::StatsdConfig,
+    metrics::{
+        KeyedMetricReading, MetricReading, MetricReportManager, MetricStringKey, MetricTimestamp,
+    },
+};
+
+use super::statsd_line::{StatsdLine, StatsdMetricType};
+
+/// Largest payload of a UDP datagram over IPv4.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Converts StatsD lines into metric readings.
+struct StatsdMetricMapper {
+    key_prefix: String,
+    allowlist: Option<Vec<String>>,
+    /// Last value of each gauge, to apply relative updates.
+    gauges: HashMap<MetricStringKey, f64>,
+}
+
+impl StatsdMetricMapper {
+    fn new(config: &StatsdConfig) -> Self {
+        Self {
+            key_prefix: config.key_prefix.clone(),
+            allowlist: config.allowlist.clone(),
+            gauges: HashMap::new(),
+        }
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        match &self.allowlist {
+            None => true,
+            Some(allowlist) => allowlist
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => name.starts_with(prefix),
+                    None => name == pattern,
+                }),
+        }
+    }
+
+    /// Returns the reading for this line, or None if the metric is not in the allowlist.
+    fn map(
+        &mut self,
+        line: &StatsdLine,
+        timestamp: MetricTimestamp,
+    ) -> Result<Option<KeyedMetricReading>> {
+        if !self.is_allowed(line.name) {
+            return Ok(None);
+        }
+        let name: MetricStringKey = format!("{}{}", self.key_prefix, line.name)
+            .parse()
+            .map_err(|e| eyre!("Invalid metric name: {}", e))?;
+
+        let reading = match line.metric_type {
+            StatsdMetricType::Counter => MetricReading::Counter {
+                value: line.value / line.sample_rate,
+                timestamp,
+            },
+            StatsdMetricType::Gauge => {
+                let last = self.gauges.entry(name.clone()).or_insert(0.0);
+                *last = if line.relative {
+                    *last + line.value
+                } else {
+                    line.value
+                };
+                MetricReading::Gauge {
+                    value: *last,
+                    timestamp,
+                    interval: Duration::zero(),
+                }
+            }
+            // Each sample is one value of the distribution, the sample rate does not change it.
+            StatsdMetricType::Timer | StatsdMetricType::Histogram => MetricReading::Gauge {
+                value: line.value,
+                timestamp,
+                interval: Duration::zero(),
+            },
+        };
+        Ok(Some(KeyedMetricReading::new(name, reading)))
+    }
+}
+
+/// A server that receives StatsD datagrams over UDP and adds the readings to the metric store.
+pub struct StatsdServer {
+    socket: UdpSocket,
+    mapper: StatsdMetricMapper,
+    metrics_store: Arc<Mutex<MetricReportManager>>,
+}
+
+impl StatsdServer {
+    pub fn bind(
+        config: &StatsdConfig,
+        metrics_store: Arc<Mutex<MetricReportManager>>,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind(config.bind_address)
+            .wrap_err_with(|| eyre!("Unable to bind StatsD socket to {}", config.bind_address))?;
+        Ok(Self {
+            socket,
+            mapper: StatsdMetricMapper::new(config),
+            metrics_store,
+        })
+    }
+
+    /// Receives datagrams on a new thread, forever.
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        spawn(move || {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            loop {
+                if let Err(e) = self.receive(&mut buf) {
+                    warn!("Error receiving StatsD datagram: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Waits for one datagram and processes it.
+    fn receive(&mut self, buf: &mut [u8]) -> Result<()> {
+        let (len, _) = self.socket.recv_from(buf)?;
+        self.handle_datagram(&buf[..len]);
+        Ok(())
+    }
+
+    fn handle_datagram(&mut self, datagram: &[u8]) {
+        let payload = match from_utf8(datagram) {
+            Ok(payload) => payload,
+            Err(e) => {
+                debug!("Ignoring StatsD datagram: {}", e);
+                return;
+            }
+        };
+
+        let timestamp = Utc::now();
+        let readings = payload
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                match StatsdLine::parse(line).and_then(|l| self.mapper.map(&l, timestamp)) {
+                    Ok(reading) => reading,
+                    Err(e) => {
+                        debug!("Ignoring StatsD line '{}': {}", line, e);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut metrics_store = self.metrics_store.lock().unwrap();
+        for reading in readings {
+            if let Err(e) = metrics_store.add_metric(reading) {
+                warn!("Invalid metric: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        net::UdpSocket,
+        sync::{Arc, Mutex},
+    };
+
+    use insta::assert_json_snapshot;
+    use rstest::rstest;
+
+    use crate::{config::StatsdConfig, metrics::MetricReportManager};
+
+    use super::{StatsdServer, MAX_DATAGRAM_SIZE};
+
+    fn server(key_prefix: &str, allowlist: Option<Vec<&str>>) -> StatsdServer {
+        let config = StatsdConfig {
+            bind_address: "127.0.0.1:0".parse().unwrap(),
+            key_prefix: key_prefix.to_string(),
+            allowlist: allowlist.map(|l| l.into_iter().map(String::from).collect()),
+        };
+        StatsdServer::bind(&config, Arc::new(Mutex::new(MetricReportManager::new()))).unwrap()
+    }
+
+    fn heartbeat_metrics(server: &StatsdServer) -> BTreeMap<String, serde_json::Value> {
+        server
+            .metrics_store
+            .lock()
+            .unwrap()
+            .take_heartbeat_metrics()
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), serde_json::to_value(v).unwrap()))
+            .collect()
+    }
+
+    #[rstest]
+    fn maps_metric_types() {
+        let mut server = server("", None);
+        server.handle_datagram(
+            b"requests:1|c\nrequests:2|c|@0.5\n\
+              queue:10|g\nqueue:+5|g\nqueue:-12|g\n\
+              db.query:100|ms|@0.1\ndb.query:300|ms\n\
+              size:42|h|#tag:value",
+        );
+
+        assert_json_snapshot!(heartbeat_metrics(&server));
+    }
+
+    #[rstest]
+    fn applies_prefix_and_allowlist() {
+        let mut server = server("app.", Some(vec!["requests", "db.*"]));
+        server.handle_datagram(b"requests:1|c\nrequests_total:1|c\ndb.query:100|ms\ndb:1|g");
+
+        assert_json_snapshot!(heartbeat_metrics(&server));
+    }
+
+    #[rstest]
+    fn ignores_invalid_lines() {
+        let mut server = server("", None);
+        server.handle_datagram(b"bogus\nusers:42|s\n\nrequests:1|c\n");
+
+        assert_json_snapshot!(heartbeat_metrics(&server));
+    }
+
+    #[rstest]
+    fn receives_datagrams() {
+        let mut server = server("", None);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .send_to(b"requests:3|c", server.socket.local_addr().unwrap())
+            .unwrap();
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        server.receive(&mut buf).unwrap();
+
+        assert_json_snapshot!(heartbeat_metrics(&server));
+    }
+}
@@ -9,11 +9,13 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use crate::util::fs::write_atomically;
+
 /// A serializable struct that is backed by a JSON file on disk.
 ///
 /// The disk version will be loaded on object creation. If it does not exist,
 /// or is invalid, the default value will be returned.
-pub struct DiskBacked<T: Eq + Serialize + Deserialize<'static>> {
+pub struct DiskBacked<T: PartialEq + Serialize + Deserialize<'static>> {
     path: PathBuf,
     cache: Option<T>,
     default: T,
@@ -25,14 +27,14 @@ pub enum UpdateStatus {
     Updated,
 }
 
-impl<T: Default + Eq + Serialize + DeserializeOwned> DiskBacked<T> {
+impl<T: Default + PartialEq + Serialize + DeserializeOwned> DiskBacked<T> {
     /// New instance from a path on disk
     pub fn from_path(path: &Path) -> Self {
         Self::from_path_with_default(path, T::default())
     }
 }
 
-impl<T: Eq + Serialize + DeserializeOwned> DiskBacked<T> {
+impl<T: PartialEq + Serialize + DeserializeOwned> DiskBacked<T> {
     pub fn from_path_with_default(path: &Path, default: T) -> Self {
         let cache = match File::open(path) {
             Ok(file) => {
@@ -68,8 +70,7 @@ impl<T: Eq + Serialize + DeserializeOwned> DiskBacked<T> {
         };
 
         if needs_writing {
-            let file = File::create(&self.path)?;
-            serde_json::to_writer_pretty(file, &new_value)?;
+            write_atomically(&self.path, &serde_json::to_vec_pretty(&new_value)?)?;
             self.cache = Some(new_value);
         }
 
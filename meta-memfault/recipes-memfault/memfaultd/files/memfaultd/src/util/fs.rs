@@ -2,7 +2,8 @@
 // Copyright (c) Memfault, Inc.
 // See License.txt for details
 use std::{
-    fs::{self},
+    fs::{self, File},
+    io::Write,
     path::{Path, PathBuf},
 };
 
@@ -34,6 +35,17 @@ pub fn get_files_sorted_by_mtime(dir: &Path) -> Result<Vec<PathBuf>> {
     Ok(entries.into_iter().map(|m| m.path()).collect())
 }
 
+/// Replaces the content of the file `path`. The content is written to a temporary file first,
+/// so that a crash while writing never leaves a truncated file behind.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
 /// Move a file. Try fs::rename first which is most efficient but only works if source
 /// and destination are on the same filesystem.
 /// Use Copy/Delete strategy if rename failed.
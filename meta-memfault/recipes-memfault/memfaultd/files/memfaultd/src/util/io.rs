@@ -5,6 +5,8 @@ use std::io::{
     self, copy, sink, BufReader, Chain, Cursor, ErrorKind, IoSlice, Read, Seek, SeekFrom, Write,
 };
 
+use flate2::{read::GzEncoder, Compression};
+
 /// A trait for getting the length of a stream.
 /// Note std::io::Seek also has a stream_len() method, but that method is fallible.
 pub trait StreamLen {
@@ -130,6 +132,41 @@ impl<T: Read> Seek for ForwardOnlySeeker<T> {
     }
 }
 
+/// A gzip compressed stream with a known length.
+///
+/// The length is measured by compressing the source once before the stream is
+/// read, so that it can be sent with a `Content-Length` without a temporary file.
+pub struct MeasuredGzEncoder<R: Read> {
+    encoder: GzEncoder<R>,
+    len: u64,
+}
+
+impl<R: Read> MeasuredGzEncoder<R> {
+    /// `source` is called twice and must return the same stream each time.
+    pub fn new(mut source: impl FnMut() -> R) -> io::Result<Self> {
+        let len = copy(
+            &mut GzEncoder::new(source(), Compression::default()),
+            &mut sink(),
+        )?;
+        Ok(Self {
+            encoder: GzEncoder::new(source(), Compression::default()),
+            len,
+        })
+    }
+}
+
+impl<R: Read> Read for MeasuredGzEncoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.encoder.read(buf)
+    }
+}
+
+impl<R: Read> StreamLen for MeasuredGzEncoder<R> {
+    fn stream_len(&self) -> u64 {
+        self.len
+    }
+}
+
 #[cfg(test)]
 mod test {
     use rstest::rstest;
@@ -182,6 +219,22 @@ mod test {
         assert_eq!(&out_buf, b"world");
     }
 
+    #[test]
+    fn test_measured_gz_encoder() {
+        let input = b"Hello world! Hello world! Hello world!";
+        let mut encoder = MeasuredGzEncoder::new(|| input.as_ref()).unwrap();
+
+        let mut compressed = Vec::new();
+        encoder.read_to_end(&mut compressed).unwrap();
+        assert_eq!(encoder.stream_len(), compressed.len() as u64);
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, input);
+    }
+
     #[rstest]
     #[case(SeekFrom::End(0))]
     #[case(SeekFrom::Current(-1))]
@@ -0,0 +1,46 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use std::net::IpAddr;
+
+/// Whether the address belongs to the local network: loopback, private (RFC 1918), link-local
+/// and IPv6 unique local addresses (RFC 4193).
+pub fn is_local_network_address(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local_network_address(&IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("127.0.0.1", true)]
+    #[case("10.1.2.3", true)]
+    #[case("172.16.0.1", true)]
+    #[case("192.168.1.10", true)]
+    #[case("169.254.0.5", true)]
+    #[case("::1", true)]
+    #[case("fd12:3456::1", true)]
+    #[case("fe80::1", true)]
+    #[case("::ffff:192.168.1.10", true)]
+    #[case("0.0.0.0", false)]
+    #[case("8.8.8.8", false)]
+    #[case("172.32.0.1", false)]
+    #[case("2001:db8::1", false)]
+    #[case("::ffff:8.8.8.8", false)]
+    fn detects_local_network_addresses(#[case] ip: &str, #[case] expected: bool) {
+        assert_eq!(is_local_network_address(&ip.parse().unwrap()), expected);
+    }
+}
@@ -10,6 +10,7 @@ pub use die::*;
 pub mod can_connect;
 pub mod fs;
 pub mod io;
+pub mod ip;
 pub mod ipc;
 pub mod math;
 pub mod mem;
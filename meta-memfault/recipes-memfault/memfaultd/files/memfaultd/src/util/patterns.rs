@@ -49,6 +49,34 @@ pub fn alphanum_slug_dots_colon_spaces_parens_slash_is_valid(
     }
 }
 
+/// Matches `text` against `pattern`, in which `*` matches any sequence of characters and `?` any
+/// single character.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern, and of the text it was matched against:
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the `*` match one more character:
+            backtrack = Some((star, matched + 1));
+            p = star + 1;
+            t = matched + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,4 +109,17 @@ mod tests {
             result
         );
     }
+
+    #[rstest]
+    #[case("/dev/shm/secret*", "/dev/shm/secret", true)]
+    #[case("/dev/shm/secret*", "/dev/shm/secret-keys", true)]
+    #[case("/dev/shm/secret*", "/dev/shm/public", false)]
+    #[case("/dev/shm/*.key", "/dev/shm/a.b.key", true)]
+    #[case("/dev/shm/*.key", "/dev/shm/a.key.bak", false)]
+    #[case("/dev/shm/key?", "/dev/shm/key1", true)]
+    #[case("/dev/shm/key?", "/dev/shm/key", false)]
+    #[case("*", "", true)]
+    fn test_wildcard_match(#[case] pattern: &str, #[case] text: &str, #[case] matches: bool) {
+        assert_eq!(wildcard_match(pattern, text), matches);
+    }
 }
@@ -36,6 +36,22 @@ impl<I> RateLimiter<I, clock::MonotonicClock> {
 }
 
 impl<I, C: clock::Clock> RateLimiter<I, C> {
+    /// Create a new rate limiter using the provided clock.
+    pub fn new_with_clock(capacity_per_minute: NonZeroU32, clock: &C) -> Self {
+        Self {
+            rate_limiter: GovRateLimiter::direct_with_clock(
+                Quota::per_minute(capacity_per_minute),
+                clock,
+            ),
+            limited_calls: None,
+        }
+    }
+
+    /// Whether some calls were rate limited since the last call which was allowed.
+    pub fn has_limited_calls(&self) -> bool {
+        self.limited_calls.is_some()
+    }
+
     /// Run the provided work function if the rate limitings limits have not been reached.
     pub fn run_within_limits<W>(&mut self, info: I, work: W) -> Result<()>
     where
@@ -63,7 +79,6 @@ impl<I, C: clock::Clock> RateLimiter<I, C> {
 mod tests {
     use std::{num::NonZeroU32, time::Duration};
 
-    use governor::clock;
     use governor::clock::FakeRelativeClock;
     use rstest::fixture;
     use rstest::rstest;
@@ -138,17 +153,4 @@ mod tests {
             c
         }
     }
-
-    impl<I, C: clock::Clock> RateLimiter<I, C> {
-        #[cfg(test)]
-        pub fn new_with_clock(capacity: NonZeroU32, clock: &C) -> Self {
-            use governor::Quota;
-            use governor::RateLimiter as GovRateLimiter;
-
-            Self {
-                rate_limiter: GovRateLimiter::direct_with_clock(Quota::per_minute(capacity), clock),
-                limited_calls: None,
-            }
-        }
-    }
 }
@@ -6,6 +6,7 @@ pub mod float_to_duration;
 pub mod kib_to_usize;
 pub mod milliseconds_to_duration;
 pub mod number_to_compression;
+pub mod optional_kib_to_usize;
 pub mod optional_milliseconds_to_duration;
 pub mod seconds_to_duration;
 pub mod sorted_map;
@@ -0,0 +1,21 @@
+//
+// Copyright (c) Memfault, Inc.
+// See License.txt for details
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub fn serialize<S>(size: &Option<usize>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match size {
+        Some(size) => super::kib_to_usize::serialize(size, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<usize>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<usize>::deserialize(deserializer)?.map(|size| size * 1024))
+}
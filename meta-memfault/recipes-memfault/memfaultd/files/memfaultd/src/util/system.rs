@@ -60,6 +60,15 @@ pub fn get_system_clock(clock: Clock) -> Result<std::time::Duration> {
     }
 }
 
+/// Number of clock ticks per second, the unit of the CPU times reported in /proc.
+pub fn get_clock_ticks_per_second() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as u64,
+        // USER_HZ is 100 on all the architectures supported by Linux.
+        _ => 100,
+    }
+}
+
 /// Provide some mock implementations for non-Linux systems. Designed for development. Not actual use.
 
 #[cfg(not(target_os = "linux"))]
@@ -20,9 +20,9 @@ use take_mut::take;
 /// length of the stream beforehand, only looking at the list of files and their sizes on disk.
 /// This is useful for example when needing to specify a Content-Length header for a HTTP request.
 /// Note it is very minimalistic in its implementation: it only supports "store" (no compression).
-/// It only supports the 32-bit zip format, so it is limited to max. 4GB file sizes and does not
-/// allow for more than 65,535 entries. File timestamps are not implemented and neither are UTF-8
-/// filenames.
+/// It switches to the ZIP64 format for the files and the archive when they exceed the limits of
+/// the 32-bit zip format (4GB sizes and offsets, 65,535 entries). File timestamps are not
+/// implemented and neither are UTF-8 filenames.
 /// Note that read() calls can copy less than the size of the caller's buffer, due to an
 /// implementation detail. Therefore it's recommended to use std::io::BufReader to wrap this stream.
 
@@ -35,11 +35,15 @@ use take_mut::take;
 // - After all files have been written, a "central directory" is written, which contains all the
 //   metadata of the files again, but in a slightly different, more elaborate format. This is used
 //   by the decoder/unarchiver to quickly access the list of files in the zip file.
+// - Sizes and offsets which do not fit in 32 bits are set to 0xFFFFFFFF and stored in a "ZIP64
+//   extended information" extra field of the headers instead. Likewise, when the number of entries
+//   or the size/offset of the central directory do not fit, a "ZIP64 end of central directory
+//   record" and its "locator" are written before the "end of central directory".
 
 pub struct ZipEncoder {
     files: Vec<ZipEntryInfo>,
     state: ZipEncoderState,
-    bytes_written: usize,
+    bytes_written: u64,
 }
 
 enum ZipEncoderState {
@@ -50,7 +54,7 @@ enum ZipEncoderState {
     },
     CentralDirectory {
         index: usize,
-        start_offset: usize,
+        start_offset: u64,
         reader: Cursor<Vec<u8>>,
     },
     EndOfCentralDirectory {
@@ -76,7 +80,7 @@ impl ZipEncoder {
         })
     }
 
-    fn new_central_directory_state(&self, index: usize, start_offset: usize) -> ZipEncoderState {
+    fn new_central_directory_state(&self, index: usize, start_offset: u64) -> ZipEncoderState {
         ZipEncoderState::CentralDirectory {
             index,
             start_offset,
@@ -87,13 +91,13 @@ impl ZipEncoder {
         }
     }
 
-    fn new_end_of_central_directory_state(&self, start_offset: usize) -> ZipEncoderState {
+    fn new_end_of_central_directory_state(&self, start_offset: u64) -> ZipEncoderState {
         let num_files = self.files.len();
         ZipEncoderState::EndOfCentralDirectory {
             reader: Cursor::new(make_end_of_central_directory(
-                num_files as u16,
-                (self.bytes_written - start_offset) as u32,
-                start_offset as u32,
+                num_files,
+                self.bytes_written - start_offset,
+                start_offset,
             )),
         }
     }
@@ -110,7 +114,7 @@ impl ZipEncoder {
 impl StreamLen for ZipEncoder {
     /// Length of the zip stream in bytes.
     fn stream_len(&self) -> u64 {
-        zip_stream_len(&self.files) as u64
+        zip_stream_len(&self.files)
     }
 }
 
@@ -134,7 +138,7 @@ impl Read for ZipEncoder {
                 }
             };
             let n = reader.read(buf)?;
-            self.bytes_written += n;
+            self.bytes_written += n as u64;
             if n > 0 || buf.is_empty() {
                 return Ok(n);
             }
@@ -145,7 +149,7 @@ impl Read for ZipEncoder {
                         self.files[*index].crc = crc;
                         let next_index = *index + 1;
                         if next_index < self.files.len() {
-                            self.files[next_index].offset = self.bytes_written as u32;
+                            self.files[next_index].offset = self.bytes_written;
                             self.new_local_files_state(next_index)?
                         } else {
                             self.new_central_directory_state(0, self.bytes_written)
@@ -182,7 +186,7 @@ pub struct ZipEntryInfo {
     size: u64,
     /// Offset from the start of the file to the local file header.
     /// Gets filled in by ZipEncoder before it reads the file.
-    offset: u32,
+    offset: u64,
     // Checksum of the (uncompressed) file data.
     // Gets filled in by ZipEncoder after the file is read.
     crc: u32,
@@ -211,80 +215,165 @@ enum FileHeaderKind {
     CentralDirectory,
 }
 
-pub fn zip_stream_len_empty() -> usize {
-    END_OF_CENTRAL_DIRECTORY_SIZE
+/// Sizes and offsets from this value on are stored in ZIP64 extra fields.
+const ZIP64_LIMIT: u64 = u32::MAX as u64;
+/// Number of entries from which on the ZIP64 end of central directory record is needed.
+const ZIP64_ENTRIES_LIMIT: usize = u16::MAX as usize;
+/// "Version needed to extract" for the ZIP64 format (4.5).
+const ZIP64_VERSION: &[u8] = b"\x2D\x00";
+
+/// Length of a zip stream, computed incrementally as files get added to it. Files are laid out
+/// in the order they are added, which determines which ones need a ZIP64 offset field.
+#[derive(Clone, Default)]
+pub struct ZipStreamLen {
+    num_files: usize,
+    local_files_len: u64,
+    central_directory_len: u64,
+}
+
+impl ZipStreamLen {
+    pub fn add(&mut self, info: &ZipEntryInfo) {
+        self.central_directory_len +=
+            central_directory_header_size(info, self.local_files_len) as u64;
+        self.local_files_len += local_file_len(info);
+        self.num_files += 1;
+    }
+
+    /// Size of the entire zip stream in bytes, including the end of central directory.
+    pub fn total_len(&self) -> u64 {
+        self.local_files_len
+            + self.central_directory_len
+            + end_of_central_directory_size(
+                self.num_files,
+                self.central_directory_len,
+                self.local_files_len,
+            ) as u64
+    }
 }
 
 /// Returns the size of the entire zip stream in bytes, given a slice of ZipEntryInfo.
-pub fn zip_stream_len(files: &[ZipEntryInfo]) -> usize {
-    files.iter().map(zip_stream_len_for_file).sum::<usize>() + zip_stream_len_empty()
+pub fn zip_stream_len(files: &[ZipEntryInfo]) -> u64 {
+    let mut len = ZipStreamLen::default();
+    for info in files {
+        len.add(info);
+    }
+    len.total_len()
 }
 
-/// Returns the size that a single ZipEntryInfo contributes to the size of the zip stream.
-pub fn zip_stream_len_for_file(file_info: &ZipEntryInfo) -> usize {
-    header_size(file_info, FileHeaderKind::Local)
-        + file_info.size as usize
-        + DATA_DESCRIPTOR_SIZE
-        + header_size(file_info, FileHeaderKind::CentralDirectory)
+/// Size of the local file header, file data and data descriptor of a file.
+fn local_file_len(info: &ZipEntryInfo) -> u64 {
+    (header_size(info, FileHeaderKind::Local) + data_descriptor_size(info)) as u64 + info.size
 }
 
 fn header_size(info: &ZipEntryInfo, kind: FileHeaderKind) -> usize {
     const LOCAL_FILE_HEADER_SIZE: usize = 30;
-    const DIRECTORY_HEADER_SIZE: usize = 46;
     let name_len = info.name.len();
     match kind {
-        FileHeaderKind::Local => LOCAL_FILE_HEADER_SIZE + name_len,
-        FileHeaderKind::CentralDirectory => DIRECTORY_HEADER_SIZE + name_len,
+        FileHeaderKind::Local => {
+            LOCAL_FILE_HEADER_SIZE + name_len + zip64_extra_field_size(&zip64_values(info, None))
+        }
+        FileHeaderKind::CentralDirectory => central_directory_header_size(info, info.offset),
+    }
+}
+
+fn central_directory_header_size(info: &ZipEntryInfo, offset: u64) -> usize {
+    const DIRECTORY_HEADER_SIZE: usize = 46;
+    DIRECTORY_HEADER_SIZE
+        + info.name.len()
+        + zip64_extra_field_size(&zip64_values(info, Some(offset)))
+}
+
+/// Values of the ZIP64 extended information extra field of a header, in order: uncompressed
+/// size, compressed size and offset of the local file header (central directory only). Only
+/// the values which do not fit in the 32-bit fields of the header are included.
+fn zip64_values(info: &ZipEntryInfo, offset: Option<u64>) -> Vec<u64> {
+    let mut values = Vec::with_capacity(3);
+    if info.size >= ZIP64_LIMIT {
+        values.extend([info.size, info.size]);
     }
+    match offset {
+        Some(offset) if offset >= ZIP64_LIMIT => values.push(offset),
+        _ => {}
+    }
+    values
+}
+
+fn zip64_extra_field_size(values: &[u64]) -> usize {
+    match values.len() {
+        0 => 0,
+        n => 4 + 8 * n,
+    }
+}
+
+fn make_zip64_extra_field(values: &[u64]) -> Vec<u8> {
+    let mut field = Vec::with_capacity(zip64_extra_field_size(values));
+    if !values.is_empty() {
+        // Header ID (ZIP64 extended information)
+        field.extend_from_slice(b"\x01\x00");
+        // Size of the values
+        field.extend_from_slice(&(8 * values.len() as u16).to_le_bytes());
+        for value in values {
+            field.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    field
+}
+
+/// Value of a 32-bit field, or 0xFFFFFFFF when the value is stored in a ZIP64 extra field.
+fn u32_or_zip64_marker(value: u64) -> [u8; 4] {
+    (value.min(ZIP64_LIMIT) as u32).to_le_bytes()
 }
 
 fn make_file_header(info: &ZipEntryInfo, kind: FileHeaderKind) -> Vec<u8> {
+    let zip64_values = zip64_values(
+        info,
+        match kind {
+            FileHeaderKind::Local => None,
+            FileHeaderKind::CentralDirectory => Some(info.offset),
+        },
+    );
     let mut header = Vec::with_capacity(header_size(info, kind));
     header.extend_from_slice(match &kind {
         FileHeaderKind::Local => {
             // Signature
-            // Version needed to extract
-            // General purpose bit flag (data descriptor enabled)
-            // Compression mode (store / no compression)
-            // File last modified time (all zeroes)
-            // File last modified date (all zeroes)
-            b"PK\x03\x04\
-            \x0A\x00\
-            \x08\x00\
-            \x00\x00\
-            \x00\x00\
-            \x00\x00\
-            "
+            b"PK\x03\x04"
         }
         FileHeaderKind::CentralDirectory => {
             // Signature
             // Version made by
-            // Version needed to extract
-            // General purpose bit flag (data descriptor enabled)
-            // Compression mode (store / no compression)
-            // File last modified time (all zeroes)
-            // File last modified date (all zeroes)
             b"PK\x01\x02\
             \x0A\x00\
-            \x0A\x00\
-            \x08\x00\
-            \x00\x00\
-            \x00\x00\
-            \x00\x00\
             "
         }
     });
+    // Version needed to extract
+    if zip64_values.is_empty() {
+        header.extend_from_slice(b"\x0A\x00");
+    } else {
+        header.extend_from_slice(ZIP64_VERSION);
+    }
+    // General purpose bit flag (data descriptor enabled)
+    // Compression mode (store / no compression)
+    // File last modified time (all zeroes)
+    // File last modified date (all zeroes)
+    header.extend_from_slice(
+        b"\x08\x00\
+        \x00\x00\
+        \x00\x00\
+        \x00\x00\
+        ",
+    );
     // CRC-32 of uncompressed data:
     header.extend_from_slice(&info.crc.to_le_bytes());
-    let size_slice = &(info.size as u32).to_le_bytes();
+    let size_slice = &u32_or_zip64_marker(info.size);
     // Compressed size:
     header.extend_from_slice(size_slice);
     // Uncompressed size:
     header.extend_from_slice(size_slice);
     // File name length:
     header.extend_from_slice(&(info.name.len() as u16).to_le_bytes());
-    // Extra field length (0 bytes):
-    header.extend_from_slice(b"\x00\x00");
+    // Extra field length:
+    header.extend_from_slice(&(zip64_extra_field_size(&zip64_values) as u16).to_le_bytes());
 
     if let FileHeaderKind::CentralDirectory = &kind {
         // File comment length (0 bytes)
@@ -300,34 +389,106 @@ fn make_file_header(info: &ZipEntryInfo, kind: FileHeaderKind) -> Vec<u8> {
         );
 
         // Relative offset of local file header:
-        header.extend_from_slice(&info.offset.to_le_bytes());
+        header.extend_from_slice(&u32_or_zip64_marker(info.offset));
     };
 
     // File name:
     header.extend_from_slice(&info.name);
+    // Extra field:
+    header.extend_from_slice(&make_zip64_extra_field(&zip64_values));
     header
 }
 
 const DATA_DESCRIPTOR_SIZE: usize = 16;
+const ZIP64_DATA_DESCRIPTOR_SIZE: usize = 24;
+
+/// Files with ZIP64 sizes in their local header also use 64-bit sizes in their data descriptor.
+fn data_descriptor_size(info: &ZipEntryInfo) -> usize {
+    if info.size >= ZIP64_LIMIT {
+        ZIP64_DATA_DESCRIPTOR_SIZE
+    } else {
+        DATA_DESCRIPTOR_SIZE
+    }
+}
 
-fn make_data_descriptor(crc: u32, size: u32) -> Vec<u8> {
-    let mut desc = Vec::with_capacity(DATA_DESCRIPTOR_SIZE);
+fn make_data_descriptor(crc: u32, size: u64, zip64: bool) -> Vec<u8> {
+    let mut desc = Vec::with_capacity(ZIP64_DATA_DESCRIPTOR_SIZE);
     // Signature:
     desc.extend_from_slice(b"PK\x07\x08");
     // CRC-32 of uncompressed data:
     desc.extend_from_slice(&crc.to_le_bytes());
-    let size_slice = &size.to_le_bytes();
-    // Compressed size:
-    desc.extend_from_slice(size_slice);
-    // Uncompressed size:
-    desc.extend_from_slice(size_slice);
+    for _ in 0..2 {
+        // Compressed size, then uncompressed size:
+        if zip64 {
+            desc.extend_from_slice(&size.to_le_bytes());
+        } else {
+            desc.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+    }
     desc
 }
 
 const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE: usize = 56;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIZE: usize = 20;
+
+fn needs_zip64_end_of_central_directory(num_files: usize, size: u64, offset: u64) -> bool {
+    num_files >= ZIP64_ENTRIES_LIMIT || size >= ZIP64_LIMIT || offset >= ZIP64_LIMIT
+}
+
+fn end_of_central_directory_size(num_files: usize, size: u64, offset: u64) -> usize {
+    if needs_zip64_end_of_central_directory(num_files, size, offset) {
+        ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE
+            + ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR_SIZE
+            + END_OF_CENTRAL_DIRECTORY_SIZE
+    } else {
+        END_OF_CENTRAL_DIRECTORY_SIZE
+    }
+}
+
+/// Makes the end of central directory record, preceded by the ZIP64 end of central directory
+/// record and locator when needed. `size` and `offset` are those of the central directory.
+fn make_end_of_central_directory(num_files: usize, size: u64, offset: u64) -> Vec<u8> {
+    let mut desc = Vec::with_capacity(end_of_central_directory_size(num_files, size, offset));
+    if needs_zip64_end_of_central_directory(num_files, size, offset) {
+        // Signature
+        desc.extend_from_slice(b"PK\x06\x06");
+        // Size of the remaining record
+        desc.extend_from_slice(&(ZIP64_END_OF_CENTRAL_DIRECTORY_SIZE as u64 - 12).to_le_bytes());
+        // Version made by
+        // Version needed to extract
+        desc.extend_from_slice(ZIP64_VERSION);
+        desc.extend_from_slice(ZIP64_VERSION);
+        // Number of this disk
+        // Disk where central directory starts
+        desc.extend_from_slice(
+            b"\x00\x00\x00\x00\
+            \x00\x00\x00\x00\
+            ",
+        );
+        let num_files_slice = &(num_files as u64).to_le_bytes();
+        // Number of central directory records on this disk:
+        desc.extend_from_slice(num_files_slice);
+        // Total number of central directory records
+        desc.extend_from_slice(num_files_slice);
+        // Size of central directory
+        desc.extend_from_slice(&size.to_le_bytes());
+        // Offset of start of central directory
+        desc.extend_from_slice(&offset.to_le_bytes());
+
+        // Locator signature
+        // Disk where the ZIP64 end of central directory record starts
+        desc.extend_from_slice(
+            b"PK\x06\x07\
+            \x00\x00\x00\x00\
+            ",
+        );
+        // Offset of the ZIP64 end of central directory record
+        desc.extend_from_slice(&(offset + size).to_le_bytes());
+        // Total number of disks
+        desc.extend_from_slice(b"\x01\x00\x00\x00");
+    }
 
-fn make_end_of_central_directory(num_files: u16, size: u32, offset: u32) -> Vec<u8> {
-    let mut desc = Vec::with_capacity(END_OF_CENTRAL_DIRECTORY_SIZE);
     desc.extend_from_slice(
         // Signature
         // Number of this disk
@@ -337,15 +498,15 @@ fn make_end_of_central_directory(num_files: u16, size: u32, offset: u32) -> Vec<
         \x00\x00\
         ",
     );
-    let num_files_slice = &num_files.to_le_bytes();
+    let num_files_slice = &(num_files.min(ZIP64_ENTRIES_LIMIT) as u16).to_le_bytes();
     // Number of central directory records on this disk:
     desc.extend_from_slice(num_files_slice);
     // Total number of central directory records
     desc.extend_from_slice(num_files_slice);
     // Size of central directory
-    desc.extend_from_slice(&size.to_le_bytes());
+    desc.extend_from_slice(&u32_or_zip64_marker(size));
     // Offset of start of central directory
-    desc.extend_from_slice(&offset.to_le_bytes());
+    desc.extend_from_slice(&u32_or_zip64_marker(offset));
     // Comment length
     desc.extend_from_slice(b"\x00\x00");
     desc
@@ -353,6 +514,10 @@ fn make_end_of_central_directory(num_files: u16, size: u32, offset: u32) -> Vec<
 
 struct LocalFileReader {
     state: LocalFileReaderState,
+    /// Number of bytes of file data read so far
+    data_len: u64,
+    /// Whether the data descriptor has 64-bit sizes
+    zip64: bool,
 }
 
 enum LocalFileReaderState {
@@ -369,6 +534,8 @@ impl LocalFileReader {
                 reader: Cursor::new(make_file_header(info, FileHeaderKind::Local)),
                 file: File::open(&info.path)?,
             },
+            data_len: 0,
+            zip64: data_descriptor_size(info) == ZIP64_DATA_DESCRIPTOR_SIZE,
         })
     }
 
@@ -392,9 +559,13 @@ impl Read for LocalFileReader {
                 }
             };
             let n = reader.read(buf)?;
+            if let LocalFileReaderState::Data { .. } = self.state {
+                self.data_len += n as u64;
+            }
             if n > 0 || buf.is_empty() {
                 return Ok(n);
             }
+            let (data_len, zip64) = (self.data_len, self.zip64);
             take(&mut self.state, |state| match state {
                 LocalFileReaderState::Header { file, .. } => LocalFileReaderState::Data {
                     reader: CrcReader::new(file),
@@ -402,7 +573,7 @@ impl Read for LocalFileReader {
                 LocalFileReaderState::Data { reader: crc_reader } => {
                     let crc = crc_reader.crc().sum();
                     LocalFileReaderState::DataDescriptor {
-                        reader: Cursor::new(make_data_descriptor(crc, crc_reader.crc().amount())),
+                        reader: Cursor::new(make_data_descriptor(crc, data_len, zip64)),
                         crc,
                     }
                 }
@@ -417,8 +588,10 @@ impl Read for LocalFileReader {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::OpenOptions;
     use std::io::{copy, Cursor};
-    use std::io::{Seek, SeekFrom};
+    use std::io::{Seek, SeekFrom, Write};
 
     use crate::test_utils::create_file_with_contents;
     use tempfile::tempdir;
@@ -471,6 +644,239 @@ mod tests {
         assert_eq!(stream_len as u64, zip_encoder.stream_len());
     }
 
+    /// Makes a zip with more entries than the 32-bit format allows.
+    #[test]
+    fn test_zip64_many_entries() {
+        let tmp = tempdir().unwrap();
+        let file_path = tmp.path().join("hello.txt");
+        create_file_with_contents(&file_path, b"Hello World").unwrap();
+        let file_info = ZipEntryInfo::new(file_path, tmp.path()).unwrap();
+
+        let num_files = ZIP64_ENTRIES_LIMIT + 10;
+        let file_infos = (0..num_files)
+            .map(|i| ZipEntryInfo {
+                name: format!("hello-{}.txt", i).into_bytes(),
+                ..file_info.clone()
+            })
+            .collect::<Vec<ZipEntryInfo>>();
+
+        let (mut zip, zip_encoder) = zip_round_trip(file_infos);
+        assert_eq!(zip.len(), num_files);
+
+        let mut contents = String::new();
+        zip.by_name(&format!("hello-{}.txt", num_files - 1))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "Hello World");
+
+        let stream_len = zip.into_inner().into_inner().len();
+        assert_eq!(stream_len as u64, zip_encoder.stream_len());
+    }
+
+    fn synthetic_entry(name: &str, size: u64, offset: u64) -> ZipEntryInfo {
+        ZipEntryInfo {
+            path: PathBuf::from(name),
+            name: name.as_bytes().to_vec(),
+            size,
+            offset,
+            crc: 0,
+        }
+    }
+
+    fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], pos: usize) -> u64 {
+        u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_zip64_local_file_header() {
+        let size = ZIP64_LIMIT + 10;
+        let info = synthetic_entry("large.bin", size, 0);
+        let header = make_file_header(&info, FileHeaderKind::Local);
+
+        assert_eq!(header.len(), header_size(&info, FileHeaderKind::Local));
+        assert_eq!(&header[4..6], ZIP64_VERSION);
+        // Compressed and uncompressed sizes
+        assert_eq!(u32_at(&header, 18), u32::MAX);
+        assert_eq!(u32_at(&header, 22), u32::MAX);
+        // ZIP64 extra field, after the file name
+        let extra = &header[30 + info.name.len()..];
+        assert_eq!(extra.len(), 20);
+        assert_eq!(&extra[..4], b"\x01\x00\x10\x00");
+        assert_eq!(u64_at(extra, 4), size);
+        assert_eq!(u64_at(extra, 12), size);
+    }
+
+    #[test]
+    fn test_zip64_central_directory_header() {
+        let offset = ZIP64_LIMIT + 20;
+        let info = synthetic_entry("small.txt", 11, offset);
+        let header = make_file_header(&info, FileHeaderKind::CentralDirectory);
+
+        assert_eq!(
+            header.len(),
+            header_size(&info, FileHeaderKind::CentralDirectory)
+        );
+        assert_eq!(&header[6..8], ZIP64_VERSION);
+        // Sizes fit in 32 bits
+        assert_eq!(u32_at(&header, 20), 11);
+        assert_eq!(u32_at(&header, 24), 11);
+        // Relative offset of local file header
+        assert_eq!(u32_at(&header, 42), u32::MAX);
+        // ZIP64 extra field, after the file name, only holds the offset
+        let extra = &header[46 + info.name.len()..];
+        assert_eq!(extra.len(), 12);
+        assert_eq!(&extra[..4], b"\x01\x00\x08\x00");
+        assert_eq!(u64_at(extra, 4), offset);
+    }
+
+    #[test]
+    fn test_zip64_end_of_central_directory() {
+        let size = 140;
+        let offset = ZIP64_LIMIT + 100;
+        let eocd = make_end_of_central_directory(2, size, offset);
+
+        assert_eq!(eocd.len(), end_of_central_directory_size(2, size, offset));
+        // ZIP64 end of central directory record
+        assert_eq!(&eocd[..4], b"PK\x06\x06");
+        assert_eq!(u64_at(&eocd, 24), 2);
+        assert_eq!(u64_at(&eocd, 32), 2);
+        assert_eq!(u64_at(&eocd, 40), size);
+        assert_eq!(u64_at(&eocd, 48), offset);
+        // ZIP64 end of central directory locator, pointing at the record
+        assert_eq!(&eocd[56..60], b"PK\x06\x07");
+        assert_eq!(u64_at(&eocd, 64), offset + size);
+        // End of central directory, with the offset stored in the ZIP64 record
+        assert_eq!(&eocd[76..80], b"PK\x05\x06");
+        assert_eq!(u32_at(&eocd, 88), size as u32);
+        assert_eq!(u32_at(&eocd, 92), u32::MAX);
+    }
+
+    #[test]
+    fn test_zip64_stream_len() {
+        let large_size = ZIP64_LIMIT + 10;
+        let files = [
+            synthetic_entry("large.bin", large_size, 0),
+            synthetic_entry("small.txt", 11, 0),
+        ];
+        // Large file: header with ZIP64 sizes, data and ZIP64 data descriptor
+        let large_len = 30 + 9 + 20 + large_size + 24;
+        // Small file: starts after 4GB, its offset is in the central directory's ZIP64 field
+        let small_len = 30 + 9 + 11 + 16;
+        let central_directory_len = (46 + 9 + 20) + (46 + 9 + 12);
+        let end_of_central_directory_len = 56 + 20 + 22;
+
+        assert_eq!(
+            zip_stream_len(&files),
+            large_len + small_len + central_directory_len + end_of_central_directory_len
+        );
+    }
+
+    /// Makes a zip with a file larger than 4GB, followed by a file at an offset larger than 4GB.
+    /// The large file is sparse and the zip stream is kept in a `SparseBuffer`, so that neither
+    /// takes 4GB of disk or memory.
+    #[test]
+    #[ignore = "Slow in debug builds: computes the CRC-32 of 4GB"]
+    fn test_zip64_large_file() {
+        let tmp = tempdir().unwrap();
+        let large_path = tmp.path().join("large.bin");
+        let large_size = ZIP64_LIMIT + 10;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&large_path)
+            .unwrap()
+            .set_len(large_size)
+            .unwrap();
+        let small_path = tmp.path().join("small.txt");
+        create_file_with_contents(&small_path, b"Hello World").unwrap();
+        let file_infos = vec![
+            ZipEntryInfo::new(large_path, tmp.path()).unwrap(),
+            ZipEntryInfo::new(small_path, tmp.path()).unwrap(),
+        ];
+
+        let mut zip_encoder = ZipEncoder::new(file_infos);
+        let mut buffer = SparseBuffer::default();
+        copy(&mut zip_encoder, &mut buffer).unwrap();
+        assert_eq!(buffer.len, zip_encoder.stream_len());
+
+        let mut zip = ZipArchive::new(buffer).unwrap();
+        assert_eq!(zip.by_name("large.bin").unwrap().size(), large_size);
+        let mut contents = String::new();
+        zip.by_name("small.txt")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "Hello World");
+    }
+
+    /// In-memory stream which does not store the chunks that only contain zeroes.
+    #[derive(Default)]
+    struct SparseBuffer {
+        chunks: BTreeMap<u64, Vec<u8>>,
+        len: u64,
+        position: u64,
+    }
+
+    impl Write for SparseBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            // Compare with memcmp, iterating over the bytes is too slow in debug builds.
+            const ZEROES: [u8; 4096] = [0; 4096];
+            if buf.chunks(ZEROES.len()).any(|c| c != &ZEROES[..c.len()]) {
+                self.chunks.insert(self.len, buf.to_vec());
+            }
+            self.len += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for SparseBuffer {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let pos = self.position;
+            let n = match self.chunks.range(..=pos).next_back() {
+                Some((start, chunk)) if pos < start + chunk.len() as u64 => {
+                    let chunk = &chunk[(pos - start) as usize..];
+                    let n = chunk.len().min(buf.len());
+                    buf[..n].copy_from_slice(&chunk[..n]);
+                    n
+                }
+                _ => {
+                    let next_chunk = self
+                        .chunks
+                        .range(pos..)
+                        .next()
+                        .map_or(self.len, |(start, _)| *start);
+                    let n =
+                        ((next_chunk.min(self.len) - pos.min(self.len)) as usize).min(buf.len());
+                    buf[..n].fill(0);
+                    n
+                }
+            };
+            self.position += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl Seek for SparseBuffer {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.position = match pos {
+                SeekFrom::Start(offset) => offset,
+                SeekFrom::End(offset) => (self.len as i64 + offset) as u64,
+                SeekFrom::Current(offset) => (self.position as i64 + offset) as u64,
+            };
+            Ok(self.position)
+        }
+    }
+
     fn zip_round_trip(
         source_files: Vec<ZipEntryInfo>,
     ) -> (ZipArchive<Cursor<Vec<u8>>>, ZipEncoder) {
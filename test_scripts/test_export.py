@@ -59,3 +59,19 @@ def test_export_chunk(
     memfault_service_tester.poll_until_not_raising(
         _check, timeout_seconds=5 * 60, poll_interval_seconds=1
     )
+
+
+def test_export_import(
+    qemu: QEMU, memfault_service_tester: MemfaultServiceTester, qemu_device_id: str
+) -> None:
+    qemu.exec_cmd("memfaultctl write-attributes import_works=true")
+
+    qemu.exec_cmd("memfaultctl export -o test.zip")
+    qemu.child().expect("Export saved and data cleared")
+
+    # importing puts the entries back in mar staging
+    qemu.exec_cmd("memfaultctl import test.zip")
+    qemu.child().expect("Imported 1 MAR entries")
+
+    qemu.exec_cmd("memfaultctl export -o test.zip")
+    qemu.child().expect("Export saved and data cleared")